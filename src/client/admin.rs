@@ -0,0 +1,144 @@
+use chrono::Utc;
+use indicatif::{HumanBytes, HumanDuration};
+use reqwest::RequestBuilder;
+use serde::{de::DeserializeOwned, Deserialize};
+use tracing::{debug, error, info};
+
+use crate::utils::{metadata::{FileMetadata, FileState}, stats::RelayStats};
+use super::{exit::Failure, http::Http, token::sign_with_keys_at, AdminDeleteArgs, AdminListArgs, AdminStatsArgs, ClientConfig};
+
+#[derive(Deserialize, Debug)]
+struct AdminChallenge {
+    challenge: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct AdminSession {
+    token: String,
+}
+
+async fn send<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, Failure> {
+    let res = request.send().await;
+    debug!("Request: {:?}", res);
+
+    let response = match res {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Failed to connect to Beam server: {:?}", e);
+            return Err(Failure::request(&e));
+        }
+    };
+    if !response.status().is_success() {
+        let status = response.status();
+        error!("Non-success response from Beam server: {:?}", response.text().await);
+        return Err(Failure::status(status));
+    }
+    response.json::<T>().await.map_err(|e| { error!("Failed to parse response: {:?}", e); Failure::Other })
+}
+
+// the relay hands out a challenge to sign with the admin's ssh key, and a short lived session for signing it
+// the session goes with the client it was made through, so later requests take the same proxy and certificate
+pub async fn sign_in(config: &ClientConfig) -> Result<(Http, String, String), Failure> {
+    let (server, username, key) = config.get_absolute();
+    let http = config.http(&server)?;
+    if username == "default" {
+        error!("Admin commands need an admin's username, set one with --username");
+        return Err(Failure::Other);
+    }
+
+    let issued: AdminChallenge = send(http.client().post(format!("{server}/admin/challenge"))
+        .form(&[("user", username.clone())])).await?;
+
+    let signatures = sign_with_keys_at(&issued.challenge, &key, config.key_passphrase_file.as_deref());
+    if signatures.is_empty() {
+        error!("Could not sign the admin challenge with any key in {}", key);
+        return Err(Failure::Other);
+    }
+    let signatures = match serde_json::to_string(&signatures) {
+        Ok(s) => s,
+        Err(_) => {
+            error!("Could not convert signatures to JSON");
+            return Err(Failure::Other);
+        }
+    };
+
+    let session: AdminSession = send(http.client().post(format!("{server}/admin/session"))
+        .form(&[("user", username.clone()), ("challenge", issued.challenge), ("signature", signatures)])).await?;
+    debug!("Signed in to {} as {}", server, username);
+    Ok((http, server, session.token))
+}
+
+fn print_json<T: serde::Serialize>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => error!("Could not print as JSON: {:?}", e),
+    }
+}
+
+pub async fn list(config: AdminListArgs) -> Result<(), Failure> {
+    let (http, server, session) = sign_in(&config.args).await?;
+    let mut files: Vec<FileMetadata> = send(http.client().get(format!("{server}/admin/tokens")).bearer_auth(&session)).await?;
+    if config.active {
+        files.retain(|file| file.get_states().0 == &FileState::InProgress || file.get_states().1 == &FileState::InProgress);
+    }
+
+    if config.json {
+        print_json(&files);
+        return Ok(());
+    }
+
+    if files.is_empty() {
+        match config.active {
+            true => println!("Nothing is transferring on {}", server),
+            false => println!("No tokens on {}", server)
+        }
+        return Ok(());
+    }
+    for file in files {
+        let (upload, download) = file.get_states();
+        let (uploaded, downloaded) = file.file_size.get_transferred();
+        let age = (Utc::now() - file.get_created()).to_std().unwrap_or_default();
+        let user = match file.get_challenge_details() {
+            Some((true, user, _)) => user.clone(),
+            Some((false, user, _)) => format!("{} (unverified)", user),
+            None => "-".to_string()
+        };
+        println!("{}  {}", file.get_token(), file.file_name);
+        println!("    up {} {}, down {} {}, by {}, {} old", upload.describe(), HumanBytes(uploaded as u64),
+            download.describe(), HumanBytes(downloaded as u64), user, HumanDuration(age));
+    }
+    Ok(())
+}
+
+pub async fn delete(config: AdminDeleteArgs) -> Result<(), Failure> {
+    let (http, server, session) = sign_in(&config.args).await?;
+    let file: FileMetadata = send(http.client().delete(format!("{server}/admin/tokens/{}", urlencoding::encode(&config.token))).bearer_auth(&session)).await?;
+    info!("Deleted {} ({})", file.get_token(), file.file_name);
+    Ok(())
+}
+
+pub async fn stats(config: AdminStatsArgs) -> Result<(), Failure> {
+    let (http, server, session) = sign_in(&config.args).await?;
+    let stats: RelayStats = send(http.client().get(format!("{server}/admin/stats")).bearer_auth(&session)).await?;
+
+    if config.json {
+        print_json(&stats);
+        return Ok(());
+    }
+
+    println!("Relay stats for {}", server);
+    println!("  Tokens held:       {}", stats.tokens);
+    println!("  Waiting to upload: {}", stats.waiting);
+    println!("  Transferring:      {}", stats.active_transfers);
+    println!("  Authenticated:     {}", stats.authenticated);
+    println!("  Users seen:        {}", stats.users);
+    println!("  Uploads finished:  {}", stats.uploads);
+    println!("  Bytes uploaded:    {}", HumanBytes(stats.bytes as u64));
+    if stats.draining {
+        println!("  Draining, no new uploads are accepted");
+    }
+    if stats.read_only {
+        println!("  Read-only, existing files can still be downloaded");
+    }
+    Ok(())
+}
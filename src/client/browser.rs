@@ -0,0 +1,26 @@
+use std::process::Stdio;
+use tokio::process::Command;
+
+use super::clipboard::missing;
+
+#[cfg(target_os = "macos")]
+const OPENER: (&str, &[&str]) = ("open", &[]);
+
+// going through cmd's start would need everything in the url escaped for cmd first
+#[cfg(windows)]
+const OPENER: (&str, &[&str]) = ("rundll32", &["url.dll,FileProtocolHandler"]);
+
+#[cfg(not(any(target_os = "macos", windows)))]
+const OPENER: (&str, &[&str]) = ("xdg-open", &[]);
+
+// hands the url to whatever the desktop opens links with
+pub async fn open(url: &str) -> Result<(), String> {
+    let (program, args) = OPENER;
+    let status = Command::new(program).args(args).arg(url)
+        .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+        .status().await.map_err(|e| missing(program, e))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(format!("{} failed with {}", program, status))
+    }
+}
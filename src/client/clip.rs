@@ -0,0 +1,102 @@
+use bytes::{Bytes, BytesMut};
+use tokio_stream::StreamExt;
+use tracing::{debug, error};
+
+use crate::utils::{checksum::{Checksum, ChecksumAlgorithm}, compression::Compression, digest::DigestWorker};
+use super::{clipboard::{self, Clip}, compression, copy::{download_args, upload_args}, download::open, exit::Failure, style, upload::{forward, Forwarded}, ClipArgs};
+
+const MAX_CLIP: usize = 64 * 1024 * 1024; // anything bigger than this is a file, not something to paste
+
+pub async fn clip(config: ClipArgs) -> Result<(), Failure> {
+    match config.paste {
+        Some(path) => paste(config.args, path).await,
+        None => share(config.args).await
+    }
+}
+
+// the clipboard is sent like any other file, just without ever being written to disk
+async fn share(args: super::ClientConfig) -> Result<(), Failure> {
+    let (file_name, data) = match clipboard::read().await {
+        Ok(Clip::Text(text)) => ("clipboard.txt", Bytes::from(text)),
+        Ok(Clip::Image(image)) => ("clipboard.png", Bytes::from(image)),
+        Err(e) => {
+            error!("{}", e);
+            return Err(Failure::Other);
+        }
+    };
+    debug!("Sending {} bytes from the clipboard as {}", data.len(), file_name);
+
+    let algorithm = ChecksumAlgorithm::default();
+    let checksum = match algorithm.hasher() {
+        Some(hasher) => DigestWorker::digest(hasher, data.clone()).await.map(|digest| Checksum { algorithm, digest }),
+        None => None
+    };
+    forward(upload_args(args, None, None, Compression::None, vec![]), Forwarded {
+        file_name: file_name.to_string(),
+        size: data.len() as u64,
+        compression: Compression::None,
+        encrypted: false,
+        checksum,
+        manifest: None,
+        message: None,
+        attributes: None,
+        stream: Box::pin(tokio_stream::once(Ok(data)))
+    }).await
+}
+
+async fn paste(args: super::ClientConfig, path: String) -> Result<(), Failure> {
+    let incoming = open(&download_args(args, None, false, path)).await?;
+    if incoming.encrypted {
+        error!("This file is end-to-end encrypted, download it with beam down instead");
+        return Err(Failure::Other);
+    }
+    if incoming.manifest.is_some() {
+        error!("This download has several files, only one can go on the clipboard");
+        return Err(Failure::Other);
+    }
+
+    let mut stream = match incoming.compression {
+        Compression::None => incoming.stream,
+        compression => compression::decompress_stream(incoming.stream, compression)
+    };
+    let mut data = BytesMut::new();
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(chunk) if data.len() + chunk.len() > MAX_CLIP => {
+                error!("This is too big for the clipboard, download it with beam down instead");
+                return Err(Failure::Other);
+            },
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(e) => {
+                error!("Failed to decode chunk: {}", e);
+                return Err(Failure::Other);
+            }
+        }
+    }
+    let data = data.freeze();
+
+    if let Some(expected) = incoming.checksum {
+        let actual = match expected.algorithm.hasher() {
+            Some(hasher) => DigestWorker::digest(hasher, data.clone()).await.unwrap_or_default(),
+            None => expected.digest.clone()
+        };
+        if actual != expected.digest {
+            error!("Checksum mismatch for {}: expected {}, got {}:{}. Not pasting it", incoming.file_name, expected, expected.algorithm, actual);
+            return Err(Failure::Other);
+        }
+    }
+
+    let clip = match Clip::from_bytes(data.to_vec()) {
+        Ok(clip) => clip,
+        Err(e) => {
+            error!("{}", e);
+            return Err(Failure::Other);
+        }
+    };
+    if let Err(e) = clipboard::write(&clip).await {
+        error!("{}", e);
+        return Err(Failure::Other);
+    }
+    style::say(format!("Copied {} to the clipboard ({} bytes)", incoming.file_name, data.len()));
+    Ok(())
+}
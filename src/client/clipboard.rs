@@ -0,0 +1,121 @@
+use std::{io::ErrorKind, process::Stdio};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+// what can go on or come off the clipboard, images are always png since every platform's tools can agree on that
+pub enum Clip {
+    Text(String),
+    Image(Vec<u8>),
+}
+
+impl Clip {
+    // a downloaded file is an image if it looks like one, otherwise it has to be text
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, String> {
+        if data.starts_with(PNG_MAGIC) {
+            return Ok(Clip::Image(data));
+        }
+        String::from_utf8(data).map(Clip::Text).map_err(|_| "This file is neither text nor a png image, so it can't go on the clipboard".to_string())
+    }
+}
+
+// the clipboard belongs to the desktop, so this goes through its own tools rather than talking to it directly (like
+// arboard would). on x11 and wayland whoever sets the clipboard has to stay running to hand it out, which a beam that
+// exits straight after can't do but xclip and wl-copy already do. the cost is that those tools have to be installed, and
+// that pbcopy and Get-Clipboard only deal in text, so images only go on or come off the clipboard on linux
+async fn run(program: &str, args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = Command::new(program).args(args).stdin(Stdio::null()).output().await.map_err(|e| missing(program, e))?;
+    if !output.status.success() {
+        return Err(format!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(output.stdout)
+}
+
+// xclip and wl-copy stay behind to hand the contents out, so only their exit is waited for and nothing is read back
+async fn pipe(program: &str, args: &[&str], data: &[u8]) -> Result<(), String> {
+    let mut child = Command::new(program).args(args).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null()).spawn().map_err(|e| missing(program, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(data).await.map_err(|e| format!("Could not send to {}: {}", program, e))?;
+    }
+    match child.wait().await {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} failed with {}", program, status)),
+        Err(e) => Err(format!("{} failed: {}", program, e))
+    }
+}
+
+pub fn missing(program: &str, e: std::io::Error) -> String {
+    match e.kind() {
+        ErrorKind::NotFound => format!("{} isn't installed, it's needed for this", program),
+        _ => format!("Could not run {}: {}", program, e)
+    }
+}
+
+fn non_empty(clip: Clip) -> Result<Clip, String> {
+    match &clip {
+        Clip::Text(text) if text.is_empty() => Err("The clipboard is empty".to_string()),
+        Clip::Image(image) if image.is_empty() => Err("The clipboard is empty".to_string()),
+        _ => Ok(clip)
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub async fn read() -> Result<Clip, String> {
+    let text = run("pbpaste", &[]).await?;
+    non_empty(Clip::Text(String::from_utf8_lossy(&text).into_owned())).map_err(|_| "There's no text on the clipboard, images can only be sent from Linux".to_string())
+}
+
+#[cfg(target_os = "macos")]
+pub async fn write(clip: &Clip) -> Result<(), String> {
+    match clip {
+        Clip::Text(text) => pipe("pbcopy", &[], text.as_bytes()).await,
+        Clip::Image(_) => Err("Only text can be put on the clipboard on macOS, save images with beam down instead".to_string())
+    }
+}
+
+#[cfg(windows)]
+pub async fn read() -> Result<Clip, String> {
+    let text = run("powershell", &["-NoProfile", "-Command", "[Console]::OutputEncoding = [Text.Encoding]::UTF8; Get-Clipboard -Raw"]).await?;
+    non_empty(Clip::Text(String::from_utf8_lossy(&text).trim_end_matches(['\r', '\n']).to_string())).map_err(|_| "There's no text on the clipboard, images can only be sent from Linux".to_string())
+}
+
+#[cfg(windows)]
+pub async fn write(clip: &Clip) -> Result<(), String> {
+    match clip {
+        Clip::Text(text) => pipe("powershell", &["-NoProfile", "-Command", "[Console]::InputEncoding = [Text.Encoding]::UTF8; Set-Clipboard -Value ([Console]::In.ReadToEnd())"], text.as_bytes()).await,
+        Clip::Image(_) => Err("Only text can be put on the clipboard on Windows, save images with beam down instead".to_string())
+    }
+}
+
+// wayland and x11 each have their own tool, and either can hold a png as well as text
+#[cfg(not(any(target_os = "macos", windows)))]
+fn wayland() -> bool {
+    std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+pub async fn read() -> Result<Clip, String> {
+    let (program, types, image, text): (&str, &[&str], &[&str], &[&str]) = match wayland() {
+        true => ("wl-paste", &["--list-types"], &["--no-newline", "--type", "image/png"], &["--no-newline"]),
+        false => ("xclip", &["-selection", "clipboard", "-t", "TARGETS", "-o"], &["-selection", "clipboard", "-t", "image/png", "-o"], &["-selection", "clipboard", "-o"])
+    };
+    let offered = String::from_utf8_lossy(&run(program, types).await?).into_owned();
+    match offered.lines().any(|offered| offered.trim() == "image/png") {
+        true => non_empty(Clip::Image(run(program, image).await?)),
+        false => non_empty(Clip::Text(String::from_utf8_lossy(&run(program, text).await?).into_owned()))
+    }
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+pub async fn write(clip: &Clip) -> Result<(), String> {
+    let (data, image) = match clip {
+        Clip::Text(text) => (text.as_bytes(), false),
+        Clip::Image(image) => (image.as_slice(), true)
+    };
+    match (wayland(), image) {
+        (true, true) => pipe("wl-copy", &["--type", "image/png"], data).await,
+        (true, false) => pipe("wl-copy", &[], data).await,
+        (false, true) => pipe("xclip", &["-selection", "clipboard", "-t", "image/png", "-i"], data).await,
+        (false, false) => pipe("xclip", &["-selection", "clipboard", "-i"], data).await
+    }
+}
@@ -1,165 +1,265 @@
 use async_stream::stream;
 use bytes::Bytes;
-use flate2::write::{GzEncoder, DeflateEncoder};
+use bytesize::ByteSize;
+use serde::Deserialize;
+use tokio::sync::mpsc;
 use tokio_stream::Stream;
-use std::sync::{Arc, Mutex};
-use std::io::Write;
+use std::{fmt, fs::File, path::PathBuf, pin::Pin, str::FromStr, sync::{Arc, Mutex}};
+use std::io::Read;
 use tokio_stream::StreamExt;
-use tracing::trace;
+use tracing::{debug, error, trace};
 
-use crate::utils::compression::Compression;
+use crate::utils::{bucket::TokenBucket, compression::{decoder, ChannelReader, Compression, Encoder, ZstdTuning}};
+
+// how many chunks can wait between reading, compressing, and sending before the earlier stage blocks
+const PIPELINE_DEPTH: usize = 8;
+const READ_SIZE: usize = 64 * 1024;
+const SAMPLE_SIZE: u64 = 256 * 1024;
+// zstd has to save at least this much of the sample to be worth the cpu, media and archives don't come close
+const WORTH_COMPRESSING: f64 = 0.9;
+
+// what --compression asks for, auto picks zstd or nothing for each upload by trying it on a sample
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Choice {
+    Auto,
+    Fixed(Compression),
+}
+
+impl FromStr for Choice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.eq_ignore_ascii_case("auto") {
+            true => Ok(Choice::Auto),
+            false => Compression::from_str(s).map(Choice::Fixed)
+        }
+    }
+}
+
+impl TryFrom<String> for Choice {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Choice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Choice::Auto => write!(f, "auto"),
+            Choice::Fixed(compression) => write!(f, "{}", compression)
+        }
+    }
+}
+
+// the start of each file until there's a whole sample, so one small file at the front doesn't decide for the rest
+fn sample(paths: &[PathBuf]) -> std::io::Result<Vec<u8>> {
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE as usize);
+    for path in paths.iter().filter(|path| path.is_file()) {
+        let remaining = SAMPLE_SIZE - sample.len() as u64;
+        if remaining == 0 {
+            break;
+        }
+        File::open(path)?.take(remaining).read_to_end(&mut sample)?;
+    }
+    Ok(sample)
+}
+
+/// Zstd if a sample of the files compresses well enough to be worth it, otherwise none.
+pub async fn pick(paths: Vec<PathBuf>) -> Compression {
+    let tried = tokio::task::spawn_blocking(move || {
+        let sample = sample(&paths)?;
+        let compressed = zstd::bulk::compress(&sample, 1)?;
+        Ok::<_, std::io::Error>((sample.len(), compressed.len()))
+    }).await;
+    match tried {
+        Ok(Ok((0, _))) => {
+            debug!("There's nothing to sample ahead of time, sending it uncompressed");
+            Compression::None
+        },
+        Ok(Ok((sampled, compressed))) if (compressed as f64) < sampled as f64 * WORTH_COMPRESSING => {
+            debug!("A {} sample compressed to {}, using zstd", ByteSize(sampled as u64).to_string_as(true), ByteSize(compressed as u64).to_string_as(true));
+            Compression::Zstd
+        },
+        Ok(Ok((sampled, compressed))) => {
+            debug!("A {} sample only compressed to {}, it's likely compressed already", ByteSize(sampled as u64).to_string_as(true), ByteSize(compressed as u64).to_string_as(true));
+            Compression::None
+        },
+        Ok(Err(e)) => {
+            debug!("Could not sample the files, sending them uncompressed: {}", e);
+            Compression::None
+        },
+        Err(e) => {
+            debug!("Could not sample the files, sending them uncompressed: {}", e);
+            Compression::None
+        }
+    }
+}
+
+// the other half of ProgressStream, undoing the compression on a blocking thread as the download comes in
+pub fn decompress_stream<S>(mut input: S, compression: Compression) -> Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>> where S: Stream<Item = Result<Bytes, std::io::Error>> + Unpin + Send + 'static {
+    let (raw_tx, raw_rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(PIPELINE_DEPTH);
+    let (out_tx, mut out_rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(PIPELINE_DEPTH);
+
+    tokio::spawn(async move {
+        while let Some(chunk) = input.next().await {
+            if raw_tx.send(chunk).await.is_err() {
+                return; // the decompressor went away, it has already reported why
+            }
+        }
+    });
+
+    tokio::task::spawn_blocking(move || {
+        let result = decoder(&compression, ChannelReader::new(raw_rx)).and_then(|mut reader| {
+            let mut buffer = vec![0; READ_SIZE];
+            loop {
+                let n = reader.read(&mut buffer)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                if out_tx.blocking_send(Ok(Bytes::copy_from_slice(&buffer[..n]))).is_err() {
+                    return Ok(()); // the download was dropped
+                }
+            }
+        });
+        if let Err(e) = result {
+            error!("Could not decompress the {} download: {}", compression, e);
+            let _ = out_tx.blocking_send(Err(e));
+        }
+    });
+
+    Box::pin(stream! {
+        while let Some(chunk) = out_rx.recv().await {
+            yield chunk;
+        }
+    })
+}
 
 pub struct ProgressStream<S> {
     reader_stream: S,
     int_read: Arc<Mutex<u64>>,
     progress_bar: indicatif::ProgressBar,
     compression: Compression,
+    tuning: ZstdTuning,
+    limiter: Option<TokenBucket>, // held back as the file is read, compression only makes what goes out smaller
 }
 
-impl<S> ProgressStream<S> where S: Stream<Item = Result<Bytes, std::io::Error>> + Unpin, {
+impl<S> ProgressStream<S> where S: Stream<Item = Result<Bytes, std::io::Error>> + Unpin + Send + 'static, {
     pub fn new(
-        reader_stream: S, 
-        int_read: Arc<Mutex<u64>>, 
+        reader_stream: S,
+        int_read: Arc<Mutex<u64>>,
         progress_bar: indicatif::ProgressBar,
         compression: Compression,
+        tuning: ZstdTuning,
+        limiter: Option<TokenBucket>,
     ) -> Self {
         Self {
             reader_stream,
             int_read,
             progress_bar,
             compression,
+            tuning,
+            limiter,
         }
     }
 
     pub fn into_stream(self) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
-        let Self { 
-            mut reader_stream, 
-            int_read, 
+        let Self {
+            mut reader_stream,
+            int_read,
             progress_bar: bar,
             compression,
+            tuning,
+            mut limiter,
         } = self;
 
-        stream! {
-            match compression {
-                Compression::None => {
-                    while let Some(chunk) = reader_stream.next().await {
-                        if let Ok(chunk) = &chunk {
-                            let mut b = int_read.lock().unwrap();
-                            *b += chunk.len() as u64;
-                            bar.set_position(*b);
+        let encoder = match Encoder::tuned(&compression, tuning) {
+            Ok(Some(encoder)) => encoder,
+            Ok(None) => return Box::pin(stream! {
+                while let Some(chunk) = reader_stream.next().await {
+                    if let Ok(chunk) = &chunk {
+                        if let Some(limiter) = &mut limiter {
+                            limiter.take(chunk.len()).await;
                         }
-                        yield chunk;
+                        let mut b = int_read.lock().unwrap();
+                        *b += chunk.len() as u64;
+                        bar.set_position(*b);
                     }
-                },
-                Compression::Gzip => {
-                    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
-                    while let Some(chunk) = reader_stream.next().await {
-                        if let Ok(chunk) = &chunk {
-                            {
-                                let mut b = int_read.lock().unwrap();
-                                *b += chunk.len() as u64;
-                                bar.set_position(*b);
-                            }
-                            
-                            if let Ok(_) = encoder.write_all(&chunk) {
-                                // Get a mutable reference to the underlying Vec<u8>
-                                let compressed_data = encoder.get_mut();
-                                let compressed_chunk = compressed_data.clone();
-                                compressed_data.clear();
-                                yield Ok(Bytes::from(compressed_chunk));
-                            }
-                        } else {
-                            yield chunk;
-                        }
-                    }
-                    if let Ok(remaining) = encoder.finish() {
-                        if !remaining.is_empty() {
-                            yield Ok(remaining.into());
-                        }
-                    }
-                },
-                Compression::Deflate => {
-                    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
-                    while let Some(chunk) = reader_stream.next().await {
-                        if let Ok(chunk) = &chunk {
-                            {
-                                let mut b = int_read.lock().unwrap();
-                                *b += chunk.len() as u64;
-                                bar.set_position(*b);
-                            }
-                            
-                            if let Ok(_) = encoder.write_all(&chunk) {
-                                let compressed_data = encoder.get_mut();
-                                let compressed_chunk = compressed_data.clone();
-                                compressed_data.clear();
-                                yield Ok(Bytes::from(compressed_chunk));
-                            }
-                        } else {
-                            yield chunk;
+                    yield chunk;
+                }
+            }) as std::pin::Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>,
+            Err(e) => {
+                error!("Could not start {} compression: {:?}", compression, e);
+                return Box::pin(stream! { yield Err(e); });
+            }
+        };
+
+        // reading stays on the runtime, compressing gets its own blocking thread, and the caller sends what comes out
+        let (raw_tx, mut raw_rx) = mpsc::channel::<Bytes>(PIPELINE_DEPTH);
+        let (out_tx, mut out_rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(PIPELINE_DEPTH);
+
+        let read_errors = out_tx.clone();
+        let label = bar.clone(); // the bar follows the file as it's read, what actually goes out is shown next to it
+        tokio::spawn(async move {
+            while let Some(chunk) = reader_stream.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        if let Some(limiter) = &mut limiter {
+                            limiter.take(chunk.len()).await;
                         }
-                    }
-                    if let Ok(remaining) = encoder.finish() {
-                        if !remaining.is_empty() {
-                            yield Ok(remaining.into());
+                        {
+                            let mut b = int_read.lock().unwrap();
+                            *b += chunk.len() as u64;
+                            bar.set_position(*b);
                         }
-                    }
-                },
-                Compression::Brotli => {
-                    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 1024*16, 7, 0);
-                    while let Some(chunk) = reader_stream.next().await {
-                        if let Ok(chunk) = &chunk {
-                            {
-                                let mut b = int_read.lock().unwrap();
-                                *b += chunk.len() as u64;
-                                bar.set_position(*b);
-                            }
-                            
-                            if let Ok(_) = encoder.write_all(&chunk) {
-                                let compressed_data = encoder.get_mut();
-                                let compressed_chunk = compressed_data.clone();
-                                compressed_data.clear();
-                                yield Ok(Bytes::from(compressed_chunk));
-                            }
-                        } else {
-                            yield chunk;
+                        if raw_tx.send(chunk).await.is_err() {
+                            return; // the compressor went away, it has already reported why
                         }
+                    },
+                    Err(e) => {
+                        let _ = read_errors.send(Err(e)).await;
+                        return;
                     }
-                    // clean up
-                    if let Ok(_) = encoder.flush() {
-                        let final_encoder = encoder.into_inner();
-                        if !final_encoder.is_empty() {
-                            yield Ok(Bytes::from(final_encoder));
+                }
+            }
+        });
+
+        tokio::task::spawn_blocking(move || {
+            let mut encoder = encoder;
+            let mut sent = 0;
+            while let Some(chunk) = raw_rx.blocking_recv() {
+                match encoder.write(&chunk) {
+                    Ok(compressed) => {
+                        sent += compressed.len() as u64;
+                        label.set_message(format!("({} as {})", ByteSize(sent).to_string_as(true), compression));
+                        if !compressed.is_empty() && out_tx.blocking_send(Ok(compressed.into())).is_err() {
+                            return; // nobody is sending anymore
                         }
+                    },
+                    Err(e) => {
+                        let _ = out_tx.blocking_send(Err(e));
+                        return;
                     }
+                }
+            }
+            trace!("Input done, finishing {} stream", compression);
+            match encoder.finish() {
+                Ok(remaining) => if !remaining.is_empty() {
+                    label.set_message(format!("({} as {})", ByteSize(sent + remaining.len() as u64).to_string_as(true), compression));
+                    let _ = out_tx.blocking_send(Ok(remaining.into()));
                 },
-                Compression::Zstd => {
-                    let mut encoder = zstd::stream::Encoder::new(Vec::new(), 3).unwrap();
-                    while let Some(chunk) = reader_stream.next().await {
-                        if let Ok(chunk) = &chunk {
-                            {
-                                let mut b = int_read.lock().unwrap();
-                                *b += chunk.len() as u64;
-                                bar.set_position(*b);
-                            }
-                            
-                            if let Ok(_) = encoder.write_all(&chunk) {
-                                let compressed_data = encoder.get_mut();
-                                let compressed_chunk = compressed_data.clone();
-                                compressed_data.clear();
-                                yield Ok(Bytes::from(compressed_chunk));
-                            }
-                        } else {
-                            trace!("Done?");
-                            yield chunk;
-                        }
-                    }
-                    if let Ok(final_buffer) = encoder.finish() {
-                        if !final_buffer.is_empty() {
-                            yield Ok(Bytes::from(final_buffer));
-                        }
-                    }
+                Err(e) => {
+                    let _ = out_tx.blocking_send(Err(e));
                 }
             }
-        }
+        });
+
+        Box::pin(stream! {
+            while let Some(chunk) = out_rx.recv().await {
+                yield chunk;
+            }
+        })
     }
-}
\ No newline at end of file
+}
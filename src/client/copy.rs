@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tracing::{debug, error};
+
+use crate::utils::{checksum::ChecksumAlgorithm, compression::Compression};
+use super::{compression::Choice, download::{download_manager, open}, exit::Failure, upload::{forward, upload}, ClientConfig, CopyArgs, DownloadArgs, UploadArgs};
+
+// splits "relay:rest" the way scp does, leaving urls, windows drive letters and ./paths alone
+fn parse_remote(spec: &str) -> Option<(&str, &str)> {
+    let (relay, rest) = spec.split_once(':')?;
+    if relay.len() < 2 || rest.starts_with("//") {
+        return None;
+    }
+    if !relay.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        return None;
+    }
+    Some((relay, rest))
+}
+
+fn resolve_profile(mut args: ClientConfig, relay: &str, profiles: &HashMap<String, ClientConfig>, keys: &HashMap<String, String>) -> Result<ClientConfig, Failure> {
+    match profiles.get(relay) {
+        Some(profile) => {
+            args.merge(profile.clone());
+            args.resolve_key(keys);
+            Ok(args)
+        },
+        None => {
+            let mut known: Vec<&String> = profiles.keys().collect();
+            known.sort();
+            error!("Unknown relay \"{}\". Relays are defined as [profiles.{}] in the config. Known relays: {:?}", relay, relay, known);
+            Err(Failure::Other)
+        }
+    }
+}
+
+// a link someone shared can be copied from too, it carries its own relay
+fn is_url(spec: &str) -> bool {
+    spec.starts_with("http://") || spec.starts_with("https://")
+}
+
+// token/key means this is going to an existing (reverse) upload, anything else is the name to upload as
+fn upload_target(target: &str) -> (Option<String>, Option<String>) {
+    match target {
+        "" => (None, None),
+        t if t.contains('/') => (Some(t.to_string()), None),
+        t => (None, Some(t.to_string()))
+    }
+}
+
+pub fn download_args(args: ClientConfig, output: Option<PathBuf>, yes: bool, path: String) -> DownloadArgs {
+    DownloadArgs {
+        args,
+        output,
+        yes,
+        limit_rate: None,
+        decrypt: false,
+        identity: vec![],
+        no_decompress: false,
+        tee: false,
+        relay_only: false,
+        local: false,
+        notify: false,
+        no_preserve: false,
+        path: Some(path),
+    }
+}
+
+pub fn upload_args(args: ClientConfig, token: Option<String>, name: Option<String>, compression: Compression, file: Vec<String>) -> UploadArgs {
+    UploadArgs {
+        args,
+        token,
+        name,
+        compression: Choice::Fixed(compression),
+        inline: false,
+        checksum: ChecksumAlgorithm::default(),
+        mmap: false,
+        limit_rate: None,
+        direct: false,
+        message: None,
+        max_downloads: 1,
+        expire: None,
+        token_name: None,
+        store: false,
+        p2p: false,
+        local: false,
+        remote: false,
+        copy: false,
+        open: false,
+        notify: false,
+        text: None,
+        as_text: false,
+        receiver: None,
+        encrypt: false,
+        recipient: vec![],
+        follow_symlinks: false,
+        keep_symlinks: false,
+        skip_symlinks: false,
+        exclude: vec![],
+        parallel: None,
+        delta: false,
+        zstd_long: false,
+        zstd_threads: None,
+        file,
+    }
+}
+
+// the download is sent on as it arrives, so it never touches the disk and is never decrypted
+async fn relay_to_relay(source: DownloadArgs, destination: ClientConfig, target: &str) -> Result<(), Failure> {
+    let forwarded = open(&source).await?;
+    let (token, name) = upload_target(target);
+    debug!("Forwarding {} to {} (token: {:?}, name: {:?})", forwarded.file_name, destination.server.as_deref().unwrap_or_default(), token, name);
+    forward(upload_args(destination, token, name, Compression::None, vec![]), forwarded).await
+}
+
+pub async fn copy(config: CopyArgs, profiles: &HashMap<String, ClientConfig>, keys: &HashMap<String, String>) -> Result<(), Failure> {
+    match (parse_remote(&config.source), parse_remote(&config.destination)) {
+        (Some((from, token)), Some((to, target))) => {
+            if token.is_empty() {
+                error!("No token given to copy from relay {}", from);
+                return Err(Failure::Other);
+            }
+            let source = resolve_profile(config.args.clone(), from, profiles, keys)?;
+            let destination = resolve_profile(config.args, to, profiles, keys)?;
+            debug!("Copying {} from relay {} to relay {}", token, from, to);
+            relay_to_relay(download_args(source, None, false, token.to_string()), destination, target).await
+        },
+        (None, Some((to, target))) if is_url(&config.source) => {
+            let destination = resolve_profile(config.args.clone(), to, profiles, keys)?;
+            debug!("Copying {} to relay {}", config.source, to);
+            relay_to_relay(download_args(config.args, None, false, config.source), destination, target).await
+        },
+        (None, None) => {
+            error!("Neither {} nor {} is a relay path. Use relay:token or relay:name for one of them", config.source, config.destination);
+            Err(Failure::Other)
+        },
+        (None, Some((relay, target))) => {
+            let args = resolve_profile(config.args, relay, profiles, keys)?;
+            let (token, name) = upload_target(target);
+            debug!("Copying {} to relay {} (token: {:?}, name: {:?})", config.source, relay, token, name);
+            upload(upload_args(args, token, name, config.compression, vec![config.source])).await
+        },
+        (Some((relay, token)), None) => {
+            if token.is_empty() {
+                error!("No token given to copy from relay {}", relay);
+                return Err(Failure::Other);
+            }
+            let args = resolve_profile(config.args, relay, profiles, keys)?;
+            let output = PathBuf::from(shellexpand::tilde(&config.destination).into_owned());
+            debug!("Copying {} from relay {} to {:?}", token, relay, output);
+            download_manager(download_args(args, Some(output), config.yes, token.to_string())).await
+        }
+    }
+}
@@ -0,0 +1,494 @@
+use std::{collections::HashMap, io::{self, Read, SeekFrom}, path::{Path, PathBuf}, time::Duration};
+use async_stream::stream;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}, sync::mpsc};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tracing::{debug, error, warn};
+use url::Url;
+
+use super::{encryption::ByteStream, exit::Failure, fileio::InputStream, http::Http};
+
+// a delta is a series of ops, the block size first and then blocks to copy from the downloader's copy or bytes to write as they are
+const START: u8 = 0;
+const COPY: u8 = 1;
+const LITERAL: u8 = 2;
+const MAX_LITERAL: usize = 1024 * 1024; // literals are cut up at this, so neither side holds more than this much of one
+
+// the downloader's copy, as checksums of each whole block
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Signatures {
+    block_size: usize,
+    blocks: Vec<BlockSum>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockSum {
+    weak: u32,
+    strong: String, // the first 16 bytes of the block's blake3 hash, in hex
+}
+
+// about as many blocks as each one is long, like rsync, within sizes where the checksums stay small next to the file
+fn block_size(len: u64) -> usize {
+    ((len as f64).sqrt() as usize).clamp(4096, 4 * 1024 * 1024)
+}
+
+// rsync's weak checksum, it can be moved along a byte at a time instead of summing the whole block again
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &x) in block.iter().enumerate() {
+            a = a.wrapping_add(x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(x as u32));
+        }
+        Self { a, b, len }
+    }
+
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> String {
+    blake3::hash(block).to_hex()[..32].to_string()
+}
+
+fn read_full(file: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n
+        }
+    }
+    Ok(filled)
+}
+
+// a short last block can't be matched by a whole window, so it's left out and sent as it is
+fn sign(path: &Path) -> io::Result<Signatures> {
+    let mut file = std::fs::File::open(path)?;
+    let block_size = block_size(file.metadata()?.len());
+    let mut blocks = vec![];
+    let mut block = vec![0; block_size];
+    while read_full(&mut file, &mut block)? == block_size {
+        blocks.push(BlockSum { weak: Rolling::new(&block).digest(), strong: strong(&block) });
+    }
+    Ok(Signatures { block_size, blocks })
+}
+
+fn start(block_size: usize) -> Bytes {
+    let mut op = BytesMut::with_capacity(9);
+    op.put_u8(START);
+    op.put_u64(block_size as u64);
+    op.freeze()
+}
+
+fn copy(index: usize) -> Bytes {
+    let mut op = BytesMut::with_capacity(9);
+    op.put_u8(COPY);
+    op.put_u64(index as u64);
+    op.freeze()
+}
+
+fn literal(data: &[u8]) -> Bytes {
+    let mut op = BytesMut::with_capacity(5 + data.len());
+    op.put_u8(LITERAL);
+    op.put_u32(data.len() as u32);
+    op.put_slice(data);
+    op.freeze()
+}
+
+// slides a block sized window over the file, anything the downloader has a block of is sent as the block's number
+fn encode_into(path: &Path, signatures: Signatures, out: &mpsc::Sender<io::Result<Bytes>>) -> io::Result<()> {
+    let send = |op: Bytes| out.blocking_send(Ok(op)).map_err(|_| io::Error::other("The upload stopped"));
+    let size = signatures.block_size;
+    let mut known: HashMap<u32, Vec<(usize, String)>> = HashMap::new();
+    for (index, block) in signatures.blocks.into_iter().enumerate() {
+        known.entry(block.weak).or_default().push((index, block.strong));
+    }
+    send(start(size))?;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut buf: Vec<u8> = vec![];
+    let mut read = vec![0; size.max(MAX_LITERAL)];
+    let mut eof = false;
+    let mut sent = 0; // everything before this has gone out
+    let mut pos = 0; // where the window starts
+    let mut rolling: Option<Rolling> = None;
+    loop {
+        while buf.len() < pos + size && !eof {
+            let n = read_full(&mut file, &mut read)?;
+            eof = n < read.len();
+            buf.extend_from_slice(&read[..n]);
+        }
+        if buf.len() < pos + size {
+            break;
+        }
+
+        let weak = rolling.get_or_insert_with(|| Rolling::new(&buf[pos..pos + size])).digest();
+        let matched = known.get(&weak).and_then(|candidates| {
+            let hash = strong(&buf[pos..pos + size]);
+            candidates.iter().find(|(_, candidate)| *candidate == hash).map(|(index, _)| *index)
+        });
+        match matched {
+            Some(index) => {
+                if sent < pos {
+                    send(literal(&buf[sent..pos]))?;
+                }
+                send(copy(index))?;
+                pos += size;
+                sent = pos;
+                rolling = None;
+            },
+            None => {
+                match (buf.get(pos + size), rolling.as_mut()) {
+                    (Some(&into), Some(rolling)) => rolling.roll(buf[pos], into),
+                    _ => rolling = None // the next byte hasn't been read yet
+                }
+                pos += 1;
+                if pos - sent >= MAX_LITERAL {
+                    send(literal(&buf[sent..pos]))?;
+                    sent = pos;
+                }
+            }
+        }
+
+        // only the literal still being gathered and the window are kept
+        if sent >= MAX_LITERAL {
+            buf.drain(..sent);
+            pos -= sent;
+            sent = 0;
+        }
+    }
+    if sent < buf.len() {
+        send(literal(&buf[sent..]))?;
+    }
+    Ok(())
+}
+
+/// The file at `path` as changes to the copy `signatures` came from.
+pub fn encode(path: PathBuf, signatures: Signatures) -> InputStream {
+    let (sender, receiver) = mpsc::channel(8);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = encode_into(&path, signatures, &sender) {
+            let _ = sender.blocking_send(Err(e));
+        }
+    });
+    Box::new(ReceiverStream::new(receiver))
+}
+
+enum Op {
+    Start(usize),
+    Copy(u64),
+    Literal(Bytes),
+}
+
+fn next_op(pending: &mut BytesMut) -> io::Result<Option<Op>> {
+    let kind = match pending.first() {
+        Some(kind) => *kind,
+        None => return Ok(None)
+    };
+    let needed = match kind {
+        START | COPY => 9,
+        LITERAL if pending.len() >= 5 => 5 + u32::from_be_bytes([pending[1], pending[2], pending[3], pending[4]]) as usize,
+        LITERAL => return Ok(None),
+        _ => return Err(io::Error::other(format!("The delta has an unknown op {}", kind)))
+    };
+    if pending.len() < needed {
+        return Ok(None);
+    }
+    let mut op = pending.split_to(needed);
+    op.advance(1);
+    Ok(Some(match kind {
+        START => Op::Start(op.get_u64() as usize),
+        COPY => Op::Copy(op.get_u64()),
+        _ => {
+            op.advance(4);
+            Op::Literal(op.freeze())
+        }
+    }))
+}
+
+async fn read_block(file: &mut File, index: u64, size: usize) -> io::Result<Bytes> {
+    if size == 0 {
+        return Err(io::Error::other("The delta copied a block before saying how big they are"));
+    }
+    file.seek(SeekFrom::Start(index * size as u64)).await?;
+    let mut block = vec![0; size];
+    file.read_exact(&mut block).await?;
+    Ok(Bytes::from(block))
+}
+
+/// Turns a delta back into the file, reading the blocks it copies from `basis`.
+pub fn apply(mut input: ByteStream, basis: PathBuf) -> ByteStream {
+    Box::pin(stream! {
+        let mut file = match File::open(&basis).await {
+            Ok(file) => file,
+            Err(e) => {
+                yield Err(e);
+                return;
+            }
+        };
+        let mut pending = BytesMut::new();
+        let mut size = 0;
+        while let Some(chunk) = input.next().await {
+            match chunk {
+                Ok(chunk) => pending.extend_from_slice(&chunk),
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+            loop {
+                match next_op(&mut pending) {
+                    Ok(None) => break,
+                    Ok(Some(Op::Start(block_size))) => size = block_size,
+                    Ok(Some(Op::Literal(data))) => yield Ok(data),
+                    Ok(Some(Op::Copy(index))) => match read_block(&mut file, index, size).await {
+                        Ok(block) => yield Ok(block),
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    },
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                }
+            }
+        }
+        if !pending.is_empty() {
+            yield Err(io::Error::other("The delta ended partway through an op"));
+        }
+    })
+}
+
+// the uploader's side, asking the relay to hold the download until the downloader says what it has
+pub async fn request(http: &Http, server: &str, token: &str, key: &str) -> bool {
+    match http.send(http.client().post(format!("{server}/{token}/delta")).form(&[("key", key)])).await {
+        Ok(response) if response.status().is_success() => true,
+        Ok(response) => {
+            warn!("The relay won't pass on block checksums, sending the whole file: {}", response.text().await.unwrap_or_default());
+            false
+        },
+        Err(e) => {
+            warn!("Could not ask for block checksums, sending the whole file: {}", e);
+            false
+        }
+    }
+}
+
+// none when the downloader has nothing to compare against, or didn't use beam down
+pub async fn wait(http: &Http, server: &str, token: &str, key: &str) -> Result<Option<Signatures>, Failure> {
+    loop {
+        let response = match http.send(http.client().get(format!("{server}/{token}/delta")).query(&[("key", key)])).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Failed to connect to Beam server: {}", e);
+                return Err(Failure::request(&e));
+            }
+        };
+        match response.status() {
+            StatusCode::ACCEPTED => tokio::time::sleep(Duration::from_secs(2)).await,
+            StatusCode::NO_CONTENT => return Ok(None),
+            status if status.is_success() => {
+                // the relay has marked the upload as a delta by now, so there's no going back to sending it whole
+                return response.json::<Signatures>().await.map(Some).map_err(|e| { error!("Could not read the downloader's block checksums: {:?}", e); Failure::Other });
+            },
+            status => {
+                error!("The relay answered {} while waiting for block checksums: {}", status, response.text().await.unwrap_or_default());
+                return Err(Failure::status(status));
+            }
+        }
+    }
+}
+
+// the downloader's side, checksums of the copy the download will replace, or nothing to have it sent whole
+pub async fn offer(http: &Http, download_path: &Url, token: &str, basis: Option<&Path>) -> Result<(), Failure> {
+    let url = match download_path.join(&format!("{token}/delta")) {
+        Ok(url) => url,
+        Err(e) => {
+            error!("Could not build the url to send block checksums to: {}", e);
+            return Err(Failure::Other);
+        }
+    };
+    let body = match basis.map(Path::to_path_buf) {
+        Some(basis) => match tokio::task::spawn_blocking(move || sign(&basis)).await {
+            Ok(Ok(signatures)) => {
+                debug!("Sending checksums of {} blocks of {} bytes", signatures.blocks.len(), signatures.block_size);
+                serde_json::to_vec(&signatures).unwrap_or_default()
+            },
+            Ok(Err(e)) => {
+                warn!("Could not read the existing file to compare against, it will be sent whole: {}", e);
+                vec![]
+            },
+            Err(e) => {
+                warn!("Could not checksum the existing file, it will be sent whole: {}", e);
+                vec![]
+            }
+        },
+        None => vec![]
+    };
+    match http.send(http.client().put(url).body(body)).await {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => {
+            let status = response.status();
+            error!("The relay didn't take the block checksums: {}", response.text().await.unwrap_or_default());
+            Err(Failure::status(status))
+        },
+        Err(e) => {
+            error!("Failed to connect to server: {}", e);
+            Err(Failure::request(&e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bytes that don't repeat within a block, so a match can only be the block it came from
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed.wrapping_mul(2654435761).max(1);
+        (0..len).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        }).collect()
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bytebeam-delta-test-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // what the downloader would end up with, and how many blocks of it came from its own copy
+    async fn round_trip(name: &str, old: &[u8], new: &[u8]) -> (Vec<u8>, usize) {
+        let dir = scratch(name);
+        let (basis, file) = (dir.join("old"), dir.join("new"));
+        std::fs::write(&basis, old).unwrap();
+        std::fs::write(&file, new).unwrap();
+
+        let mut encoded = encode(file, sign(&basis).unwrap());
+        let mut delta = BytesMut::new();
+        while let Some(op) = encoded.next().await {
+            delta.extend_from_slice(&op.unwrap());
+        }
+
+        let mut ops = delta.clone();
+        let mut copies = 0;
+        while let Some(op) = next_op(&mut ops).unwrap() {
+            if let Op::Copy(_) = op {
+                copies += 1;
+            }
+        }
+        assert!(ops.is_empty());
+
+        let mut rebuilt = apply(Box::pin(tokio_stream::once(Ok(delta.freeze()))), basis);
+        let mut out = vec![];
+        while let Some(chunk) = rebuilt.next().await {
+            out.extend_from_slice(&chunk.unwrap());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+        (out, copies)
+    }
+
+    #[test]
+    fn rolling_matches_summing_again() {
+        let data = noise(300, 1);
+        let size = 64;
+        let mut rolling = Rolling::new(&data[..size]);
+        for pos in 1..data.len() - size {
+            rolling.roll(data[pos - 1], data[pos + size - 1]);
+            assert_eq!(rolling.digest(), Rolling::new(&data[pos..pos + size]).digest(), "at {}", pos);
+        }
+    }
+
+    #[test]
+    fn blocks_grow_with_the_file() {
+        assert_eq!(block_size(0), 4096);
+        assert_eq!(block_size(1 << 30), 32768);
+        assert_eq!(block_size(u64::MAX), 4 * 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn unchanged_file_is_all_copies() {
+        let old = noise(10 * 4096 + 100, 2);
+        let (out, copies) = round_trip("unchanged", &old, &old).await;
+        assert_eq!(out, old);
+        assert_eq!(copies, 10); // the short last block goes as a literal
+    }
+
+    #[tokio::test]
+    async fn finds_blocks_after_an_insert() {
+        let old = noise(20 * 4096, 3);
+        let mut new = b"a few new bytes at the front".to_vec();
+        new.extend_from_slice(&old[..8 * 4096]);
+        new.extend_from_slice(&noise(5000, 4)); // and a changed stretch in the middle
+        new.extend_from_slice(&old[9 * 4096..]);
+        let (out, copies) = round_trip("insert", &old, &new).await;
+        assert_eq!(out, new);
+        assert_eq!(copies, 19);
+    }
+
+    #[tokio::test]
+    async fn sends_everything_without_a_copy() {
+        let new = noise(3 * MAX_LITERAL + 17, 5); // more than one literal's worth
+        let (out, copies) = round_trip("empty", &[], &new).await;
+        assert_eq!(out, new);
+        assert_eq!(copies, 0);
+    }
+
+    #[tokio::test]
+    async fn handles_files_shorter_than_a_block() {
+        let (out, copies) = round_trip("short", b"old", b"new").await;
+        assert_eq!(out, b"new");
+        assert_eq!(copies, 0);
+        let (out, _) = round_trip("nothing", b"old", b"").await;
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn reads_ops_only_once_they_are_whole() {
+        let mut pending = BytesMut::new();
+        pending.extend_from_slice(&literal(b"hello")[..4]);
+        assert!(next_op(&mut pending).unwrap().is_none());
+        pending.extend_from_slice(&literal(b"hello")[4..]);
+        assert!(matches!(next_op(&mut pending).unwrap(), Some(Op::Literal(data)) if data == "hello"));
+        assert!(pending.is_empty());
+
+        pending.extend_from_slice(&[9, 0, 0]);
+        assert!(next_op(&mut pending).is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_cut_off_delta() {
+        let dir = scratch("cut");
+        std::fs::write(dir.join("old"), b"").unwrap();
+        let mut delta = start(4096).to_vec();
+        delta.extend_from_slice(&literal(b"hello")[..7]);
+        let mut rebuilt = apply(Box::pin(tokio_stream::once(Ok(Bytes::from(delta)))), dir.join("old"));
+        let mut failed = false;
+        while let Some(chunk) = rebuilt.next().await {
+            failed |= chunk.is_err();
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+        assert!(failed);
+    }
+}
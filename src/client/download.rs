@@ -1,31 +1,127 @@
-use std::{io, io::Write, time::Duration};
+use std::{collections::hash_map::RandomState, hash::BuildHasher, io, io::Write, path::{Component, Path, PathBuf}, str::FromStr, time::Duration};
 
-use indicatif::{ProgressBar, ProgressStyle};
-use tokio::fs::File;
+use async_stream::stream;
+use bytes::Bytes;
+use bytesize::ByteSize;
 use tracing::{error, trace, warn};
+use reqwest::header::CONTENT_ENCODING;
 use url::Url;
 use urlencoding::decode;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio_util::io::StreamReader;
 use tokio_stream::StreamExt;
-use tokio::io::AsyncWriteExt;
 
-use crate::{client::token::do_run_upgrade_on_metadata, utils::metadata::FileMetadata};
+use crate::{client::token::do_run_upgrade_on_metadata, utils::{bucket::TokenBucket, compression::Compression, digest::DigestWorker, frames::{FRAMES, FRAMES_HEADER}, metadata::{Delta, FileMetadata, FileState, ManifestEntry, MANIFEST_HEADER}}};
+
+use super::{compression, delta, exit::Failure, encryption::{self, ByteStream, Key}, fileio::{self, OutputFile}, frames, http::Http, ipc::{self, IpcEvent}, local, notify, peer::{self, PeerHeader}, style, token::{get_upload_token, sign_with_keys_at}, upload::Forwarded, DownloadArgs};
+/// Receives a file the way `beam down` does, or without a path sets up a reverse upload and waits for it.
+/// Errors are logged with `tracing` as they happen, like [`upload`](super::upload), and the [`Failure`] says what kind it was.
+pub async fn download_manager(config: DownloadArgs) -> Result<(), Failure> {
+    if config.tee {
+        style::take_stdout();
+    }
+    let notify = config.notify.then(|| config.path.clone().or(config.output.as_ref().map(|output| output.display().to_string())).unwrap_or_default());
+    let result = match locate(&config).await {
+        Ok(source) => receive(config, source).await,
+        Err(failure) => Err(failure)
+    };
+    if let Some(name) = notify {
+        match result {
+            Ok(()) => notify::desktop("Download complete", format!("{} has been downloaded", name)).await,
+            Err(_) => notify::desktop("Download failed", format!("{} could not be downloaded", name)).await
+        }
+    }
+    result
+}
+
+/// Waits for `token` (a token or a link) and reads it as it downloads, for programs that want the data rather than a file.
+/// `config` is what `beam down` would get, [`download_args`](super::copy::download_args) makes one, and its path and
+/// output are ignored. `progress` is called with how much has arrived so far, and the total if the relay said.
+pub async fn download_stream(token: &str, mut config: DownloadArgs, progress: impl Fn(u64, Option<u64>) + Send + 'static) -> Result<impl AsyncRead + Send + Unpin, Failure> {
+    config.path = Some(token.to_string());
+    let source = locate(&config).await?;
+    let (encrypted, inner) = match &source {
+        Source::Peer(header, _) => (header.encrypted, header.compression.clone()),
+        Source::Relay { meta, .. } => (meta.is_encrypted(), meta.get_compression())
+    };
+    let key = match config.decrypt || encrypted {
+        true => Some(encryption::unlock_key(&config.identity)?),
+        false => None
+    };
+
+    let incoming = source.fetch(encrypted, inner.clone()).await?;
+    if incoming.manifest.is_some() {
+        error!("{} has several files, which can only be read one at a time by beam down", token);
+        return Err(Failure::Other);
+    }
+
+    let total = (incoming.length > 0).then_some(incoming.length);
+    let mut limiter = config.limit_rate.map(TokenBucket::per_second);
+    let mut network = incoming.body;
+    let received: ByteStream = Box::pin(stream! {
+        let mut so_far = 0;
+        while let Some(chunk) = network.next().await {
+            if let Ok(chunk) = &chunk {
+                if let Some(limiter) = &mut limiter {
+                    limiter.take(chunk.len()).await;
+                }
+                so_far += chunk.len() as u64;
+                progress(so_far, total);
+            }
+            yield chunk;
+        }
+    });
+    Ok(StreamReader::new(unwrap(received, incoming.compression, key, encrypted, inner, config.no_decompress)))
+}
+
+// the download as it comes off the wire, for beam cp to send on to another relay without undoing anything the uploader did
+pub async fn open(config: &DownloadArgs) -> Result<Forwarded, Failure> {
+    let source = locate(config).await?;
+    let (encrypted, inner, checksum, message, attributes) = match &source {
+        Source::Peer(header, _) => (header.encrypted, header.compression.clone(), header.checksum.clone(), header.message.clone(), header.attributes.clone()),
+        Source::Relay { meta, .. } => (meta.is_encrypted(), meta.get_compression(), meta.get_checksum(), meta.get_message().cloned(), meta.get_attributes().cloned())
+    };
+    let incoming = source.fetch(encrypted, inner.clone()).await?;
+    Ok(Forwarded {
+        file_name: incoming.name.unwrap_or("bytebeam".to_string()),
+        size: incoming.length,
+        compression: match encrypted { // the encryption goes along untouched, and the compression inside it with it
+            true => inner,
+            false => incoming.compression
+        },
+        encrypted,
+        checksum,
+        manifest: incoming.manifest,
+        message,
+        attributes,
+        stream: incoming.body
+    })
+}
+
+// waits for the upload wherever it's coming from, a relay, an uploader offering it directly, or the local network
+async fn locate(config: &DownloadArgs) -> Result<Source, Failure> {
+    // nothing about a relay is needed for a beam on the same network
+    if config.local {
+        let (header, body) = local::find(config.path.as_deref()).await?;
+        show_message(header.message.as_ref());
+        return Ok(Source::Peer(header, body));
+    }
 
-use super::{token::get_upload_token, DownloadArgs};
-pub async fn download_manager(config: DownloadArgs) -> Result<(), ()> {
     let (server, username, key) = config.args.get_absolute();
-    let download_path = match config.path {
+    let http = config.args.http(&server)?;
+    let download_path = match &config.path {
         Some(piece) => {
             // if piece has more than two total slashes, it is likely a path and not a url
             if piece.chars().filter(|c| *c == '/').count() > 2 && !piece.starts_with("http") {
                 warn!("{} is likely not a beam path and is instead a local path. If you are looking to do a reverse download, do -o [path] instead", piece);
             }
-            let url = match Url::parse(&piece) {
+            let url = match Url::parse(piece) {
                 Ok(url) => url,
                 Err(_) => match Url::parse(format!("{server}/{piece}").as_str()) {
                     Ok(url) => url,
                     Err(_) => {
                         error!("Invalid URL provided: {}", piece);
-                        return Err(());
+                        return Err(Failure::Other);
                     }
                 }
             };
@@ -36,7 +132,7 @@ pub async fn download_manager(config: DownloadArgs) -> Result<(), ()> {
         None => {
             if config.output.is_none() {
                 error!("No input or output provided. Please provide a Beam code to download, or create a reverse download using -o [output]");
-                return Err(());
+                return Err(Failure::Other);
             }
             // this is weird since a filename needs to be provided, as its defined here
             let op = config.output.clone().unwrap();
@@ -44,31 +140,29 @@ pub async fn download_manager(config: DownloadArgs) -> Result<(), ()> {
             let encoded_file = urlencoding::encode(&file_name);
             let download_path = format!("{server}/{encoded_file}");
 
-            match get_upload_token(&username, 0, download_path).await {
-                Some(meta) => {
+            match get_upload_token(&http, &username, 0, vec![], &server, download_path).await {
+                Ok(meta) => {
                     // lets try to sign it first
-                    let meta = do_run_upgrade_on_metadata(meta, &username, &key, &server).await;
+                    let meta = do_run_upgrade_on_metadata(&http, meta, &username, &key, &server, config.args.key_passphrase_file.as_deref()).await;
                     let download_path = format!("{server}/{}", meta.get_token());
                     match Url::parse(&download_path) {
                         Ok(url) => {
                             let upload_info = meta.get_upload_info();
                             let upload_path = format!("{server}/{}/{}", upload_info.0, upload_info.1);
-                            qr2term::print_qr(&upload_path).expect("Could not generate QR code");
-
-                            println!("\nUpload is available from: {}\n\n", upload_path);
+                            style::print_link("Upload is available from", &upload_path, config.args.plain);
 
                             // include some things about how to curl upload here
                             url
                         },
                         Err(_) => {
                             error!("Got token, but could not parse URL for {download_path}");
-                            return Err(());
+                            return Err(Failure::Other);
                         }
                     }
                 },
-                None => {
+                Err(failure) => {
                     error!("Failed to get upload token. Please check your authentication and try again.");
-                    return Err(());
+                    return Err(failure);
                 }
             }
 
@@ -81,137 +175,599 @@ pub async fn download_manager(config: DownloadArgs) -> Result<(), ()> {
     trace!("Downloading from URL {}", download_path);
 
     // we should wait until we can verify the metadata
-    println!("Waiting for download...");
-    loop {
-        let status = match reqwest::get(format!("{download_path}?status=true")).await {
+    style::say("Waiting for download...");
+    let mut offered = false;
+    let (meta, peer) = loop {
+        let status = match http.send(http.client().get(format!("{download_path}?status=true"))).await {
             Ok(req) => req,
             Err(e) => {
                 error!("Failed to connect to server for status: {}", e);
-                return Err(());
+                return Err(Failure::request(&e));
             }
         };
+        if !status.status().is_success() {
+            error!("The relay answered {} for {}", status.status(), download_path);
+            return Err(Failure::status(status.status()));
+        }
         match status.json::<FileMetadata>().await {
             Ok(meta) => {
-                if !meta.download_locked() && meta.upload_locked() {
-                    println!("Download is ready!");
-                    break;
+                // a stored upload can only be read back once all of it is on the relay
+                let ready = match meta.is_stored() {
+                    true => meta.get_states().0 == &FileState::Complete,
+                    false => meta.upload_locked()
+                };
+                if !meta.download_locked() && ready {
+                    style::say("Download is ready!");
+                    show_message(meta.get_message());
+                    break (meta, None);
+                }
+
+                // an uploader offering it directly is tried first, and only told to use the relay if that doesn't work
+                if let Some(offer) = meta.get_peer().filter(|offer| !offer.failed) {
+                    if !config.relay_only && !http.has_proxy() {
+                        style::say("Connecting to the uploader directly...");
+                        if let Some(found) = peer::connect(offer).await {
+                            style::say("Connected, the relay won't carry any of it");
+                            show_message(found.0.message.as_ref());
+                            break (meta, Some(found));
+                        }
+                        if offer.reflected.is_some() {
+                            style::say("Couldn't reach the uploader, punching through to it...");
+                            if let Some(found) = peer::punch_through(&http, &download_path, meta.get_token(), offer).await {
+                                style::say("Connected, the relay won't carry any of it");
+                                show_message(found.0.message.as_ref());
+                                break (meta, Some(found));
+                            }
+                        }
+                        style::say("Couldn't reach the uploader, waiting for it to come through the relay");
+                    }
+                    peer::give_up(&http, &download_path, meta.get_token()).await?;
+                }
+
+                // an uploader sending a delta waits to hear what the file being saved over looks like
+                if meta.get_delta() == Delta::Requested && !offered {
+                    offered = true;
+                    let basis = config.output.as_deref().filter(|output| output.is_file() && !config.no_decompress);
+                    if let Some(basis) = basis {
+                        style::say(format!("Comparing with {:?} so only what changed is sent...", basis));
+                    }
+                    delta::offer(&http, &download_path, meta.get_token(), basis).await?;
                 }
             }
             Err(e) => {
                 error!("Failed to parse download metadata: {:?}", e);
-                return Err(());
+                return Err(Failure::Other);
             }
         }
-        print!(".");
+        if !style::is_quiet() {
+            print!(".");
+        }
         std::thread::sleep(std::time::Duration::from_secs(15));
-    }
-    println!("download ready");
+    };
+    style::say("download ready");
 
-    // okay, now we can just download
+    let source = match peer {
+        Some((header, body)) => Source::Peer(header, body),
+        None => {
+            // a file sent to a named receiver is only handed over for a fresh signature from one of their keys
+            let mut query = vec![("raw", "true".to_string())]; // multi-file uploads are split up here, so they're asked for as sent instead of zipped
+            if let Some(receiver) = meta.get_receiver() {
+                // the relay only takes each challenge once, so someone who sees the url can't download with it too
+                let nonce = format!("{:016x}{:016x}", RandomState::new().hash_one(0u8), RandomState::new().hash_one(1u8));
+                let challenge = format!("download/{}:{}:{}:{}", meta.get_token(), receiver, chrono::Utc::now().timestamp(), nonce);
+                let signatures = sign_with_keys_at(&challenge, &key, config.args.key_passphrase_file.as_deref());
+                if signatures.is_empty() {
+                    error!("This file can only be downloaded by {}, and no key in {} could sign for them", receiver, key);
+                    return Err(Failure::Other);
+                }
+                let signatures = match serde_json::to_string(&signatures) {
+                    Ok(s) => s,
+                    Err(_) => {
+                        error!("Could not convert signatures to JSON");
+                        return Err(Failure::Other);
+                    }
+                };
+                query.push(("challenge", challenge));
+                query.push(("signature", signatures));
+            }
 
-    let req = reqwest::ClientBuilder::new()
-        .user_agent(format!("ByteBeam/{}", env!("CARGO_PKG_VERSION")))
-        .build().expect("Could not build download request")
-        .get(download_path)
-        .send();
+            Source::Relay { http, download_path, query, meta: Box::new(meta) }
+        }
+    };
+    Ok(source)
+}
 
+// where the download comes from, with what's known about it before it starts
+enum Source {
+    Relay { http: Http, download_path: Url, query: Vec<(&'static str, String)>, meta: Box<FileMetadata> },
+    Peer(PeerHeader, ByteStream),
+}
 
-    let request = match req.await {
-        Ok(req) => req,
-        Err(e) => {
-            error!("Failed to connect to server: {}", e);
-            return Err(());
+impl Source {
+    async fn fetch(self, encrypted: bool, compression: Compression) -> Result<Incoming, Failure> {
+        match self {
+            Source::Peer(header, body) => Ok(Incoming {
+                name: Some(header.file_name),
+                compression: match encrypted { // encrypted uploads are only compressed inside the encryption
+                    true => Compression::None,
+                    false => header.compression
+                },
+                manifest: header.manifest,
+                length: header.file_size,
+                body
+            }),
+            Source::Relay { http, download_path, query, meta } => from_relay(&http, download_path, &query, encrypted, compression, meta.file_size.get_content_length()).await
         }
+    }
+}
+
+async fn receive(config: DownloadArgs, source: Source) -> Result<(), Failure> {
+    // what the relay would say about the upload, which a direct one sends itself
+    let (encrypted, inner, checksum, attributes) = match &source {
+        Source::Peer(header, _) => (header.encrypted, header.compression.clone(), header.checksum.clone(), header.attributes.clone()),
+        Source::Relay { meta, .. } => (meta.is_encrypted(), meta.get_compression(), meta.get_checksum(), meta.get_attributes().cloned())
     };
+    let attributes = attributes.filter(|_| !config.no_preserve);
+    let delta = matches!(&source, Source::Relay { meta, .. } if meta.get_delta() == Delta::Sent);
 
-    if request.status() != reqwest::StatusCode::OK {
-        error!("Failed to download file: {}", request.status().to_string());
-        error!("Response: {}", request.text().await.expect("Could not get response"));
-        return Err(());
-    }
+    // asked before the download starts, since the relay lets only one go through
+    let key = match config.decrypt || encrypted {
+        true => {
+            style::say("The file is end-to-end encrypted.");
+            Some(encryption::unlock_key(&config.identity)?)
+        },
+        false => None
+    };
 
-    trace!("File headers: {:?}", request.headers());
+    let incoming = source.fetch(encrypted, inner.clone()).await?;
+    let compression = incoming.compression;
 
-    // can we get the file name?
+    // whatever compression is still on the file once it is saved
+    let kept = match config.no_decompress {
+        true if encrypted && key.is_some() => inner.clone(),
+        true => compression.clone(),
+        false => Compression::None
+    };
 
-    let write_path = match config.output {
-        Some(op) => op,
+    // a multi-file upload comes back to back with its manifest, and is saved as its files
+    let output = match &incoming.manifest {
+        Some(_) if kept != Compression::None => {
+            error!("This download has several files, which can only be split up once decompressed. Leave out --no-decompress");
+            return Err(Failure::Other);
+        },
+        Some(_) if config.tee => {
+            error!("This download has several files, --tee can only write one to stdout");
+            return Err(Failure::Other);
+        },
+        Some(_) if delta => {
+            error!("A delta is against one file, but this download has several");
+            return Err(Failure::Other);
+        },
+        Some(manifest) => {
+            let files = bundle_paths(config.output, manifest, config.yes)?;
+            style::say(format!("Downloading {} files", files.len()));
+            Output::Bundle(files)
+        },
         None => {
-            match request.url().path_segments().and_then(|segments| segments.last()) {
-                Some(name) => match decode(name) {
-                    Ok(name) => name.into_owned().into(),
+            let write_path = file_path(config.output, incoming.name, kept.extension(), config.yes)?;
+            let staged = match delta {
+                true => staged(&write_path),
+                false => write_path.clone()
+            };
+            let file = match OutputFile::create(staged.clone()).await {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Failed to create output file: {}", e);
+                    return Err(Failure::Other);
+                }
+            };
+            style::say(format!("Downloading to {:?}", write_path));
+            Output::File(write_path, file)
+        }
+    };
+
+    let bar = style::progress_bar(incoming.length, config.args.plain);
+    ipc::watch(&bar);
+    bar.enable_steady_tick(Duration::from_millis(100));
+
+    // once the compression is undone this is the same data the uploader hashed
+    let verifier = match kept {
+        Compression::None => checksum,
+        _ => {
+            style::say(format!("Keeping the file {} compressed, so its checksum can't be verified", kept));
+            None
+        }
+    }.and_then(|checksum| checksum.algorithm.hasher().map(|hasher| (checksum, DigestWorker::new(hasher))));
+
+    // reading slower leaves the rest in the socket, so tcp slows the sender down to match
+    let mut limiter = config.limit_rate.map(TokenBucket::per_second);
+
+    let mut network = incoming.body;
+    let progress = bar.clone();
+    let received: ByteStream = Box::pin(stream! {
+        while let Some(chunk) = network.next().await {
+            if let Ok(chunk) = &chunk {
+                if let Some(limiter) = &mut limiter {
+                    limiter.take(chunk.len()).await;
+                }
+                progress.inc(chunk.len() as u64);
+            }
+            yield chunk;
+        }
+    });
+
+    // the bar counts what comes over the network, how big it is once decompressed is shown next to it
+    let unpacking = !config.no_decompress && (compression != Compression::None || (encrypted && inner != Compression::None));
+    let mut stream = unwrap(received, compression, key, encrypted, inner, config.no_decompress);
+    let staged = match (&output, delta) {
+        (Output::File(write_path, _), true) => {
+            stream = delta::apply(stream, write_path.clone());
+            Some(staged(write_path))
+        },
+        _ => None
+    };
+    let (write_path, mut file) = match output {
+        Output::Bundle(files) => {
+            let result = save_bundle(&mut stream, files, !config.no_preserve).await;
+            bar.finish();
+            if result.is_ok() {
+                style::say("Download complete.");
+            }
+            return result;
+        },
+        Output::File(write_path, file) => (write_path, file)
+    };
+
+    // a pipe closing early (like head) only stops the copy on stdout, the file is still saved
+    let mut tee = config.tee.then(tokio::io::stdout);
+    let mut written = 0;
+    while let Some(chunk_result) = stream.next().await {
+        match chunk_result {
+            Ok(chunk) => {
+                    if unpacking {
+                        written += chunk.len() as u64;
+                        bar.set_message(format!("({} decompressed)", ByteSize(written).to_string_as(true)));
+                    }
+                    if let Some((_, worker)) = &verifier {
+                        worker.update(chunk.clone()).await;
+                    }
+                    if let Some(out) = &mut tee {
+                        if let Err(e) = out.write_all(&chunk).await {
+                            warn!("Stopped writing to stdout, the file is still being saved: {}", e);
+                            tee = None;
+                        }
+                    }
+                    match file.write(chunk).await {
+                    Ok(_) => (),
                     Err(e) => {
-                        error!("Failed to decode file name from request url: {:?}", e);
-                        return Err(());
+                        error!("Failed to write data to output file: {}", e);
+                        return Err(Failure::Other);
                     }
-                },
-                None => {
-                    error!("Could not determine file name to save to, and none was provided. Cancelling download");
-                    return Err(());
                 }
             }
+            Err(e) => {
+                error!("Failed to decode chunk: {}", e);
+                return Err(Failure::Other);
+            }
         }
-    };
+    }
 
-    if write_path.exists() && !config.yes {
-        print!("File already exists: {:?}. Overwrite? [y/N] ", write_path);
-        io::stdout().flush().expect("Could not flush stdout");
-        
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).expect("Could not read input");
-        
-        if !input.trim().eq_ignore_ascii_case("y") {
-            error!("Download cancelled - file exists");
-            return Err(());
+    bar.finish();
+
+    if let Some(mut out) = tee {
+        if let Err(e) = out.flush().await {
+            warn!("Could not finish writing to stdout: {}", e);
+        }
+    }
+
+    if let Err(e) = file.finish().await {
+        error!("Failed to finish writing the output file: {}", e);
+        return Err(Failure::Other);
+    }
+
+    if let Some((expected, worker)) = verifier {
+        let actual = worker.finish().await.unwrap_or_default();
+        if actual != expected.digest {
+            error!("Checksum mismatch for {:?}: expected {}, got {}:{}. The file is likely corrupted", write_path, expected, expected.algorithm, actual);
+            return Err(Failure::Checksum);
+        }
+        style::say(format!("Checksum verified ({}).", expected.algorithm));
+    }
+
+    if let Some(staged) = staged {
+        if let Err(e) = tokio::fs::rename(&staged, &write_path).await {
+            error!("Could not move the rebuilt file from {:?} to {:?}: {}", staged, write_path, e);
+            return Err(Failure::Other);
+        }
+    }
+
+    if let Some(attributes) = &attributes {
+        if let Err(e) = fileio::restore(&write_path, attributes) {
+            warn!("Could not set the permissions or modification time of {:?}: {}", write_path, e);
         }
     }
 
+    style::say("Download complete.");
+
+    Ok(())
+}
+
+// a delta reads blocks from the file it replaces, so it's written next to it until it's done
+fn staged(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.beam-partial", path.display()))
+}
+
+// undoes what the uploader did, in reverse order
+fn unwrap(received: ByteStream, compression: Compression, key: Option<Key>, encrypted: bool, inner: Compression, no_decompress: bool) -> ByteStream {
+    // an age file uploaded with curl can still have been compressed on the way, which has to come off before decrypting
+    let received = match compression {
+        Compression::None => received,
+        _ if no_decompress => received,
+        _ => compression::decompress_stream(received, compression)
+    };
+
+    // encrypted uploads are only compressed inside the encryption, so that is undone after decrypting
+    match key {
+        Some(key) => encryption::decrypt_stream(received, key, match encrypted && !no_decompress {
+            true => inner,
+            false => Compression::None
+        }),
+        None => received
+    }
+}
+
+fn show_message(message: Option<&String>) {
+    if let Some(message) = message {
+        style::say(format!("Message from the sender: {}", message));
+        ipc::emit(IpcEvent::Message { text: message.clone() });
+    }
+}
+
+// what's needed to save the download, whether the relay or the uploader itself is sending it
+struct Incoming {
+    name: Option<String>,
+    compression: Compression, // still on the bytes as they arrive
+    manifest: Option<Vec<ManifestEntry>>,
+    length: u64, // 0 if it isn't known ahead of time
+    body: ByteStream,
+}
 
-    let mut file = match File::create(&write_path).await {
-        Ok(file) => file,
+async fn from_relay(http: &Http, download_path: Url, query: &[(&str, String)], encrypted: bool, compression: Compression, file_size: Option<usize>) -> Result<Incoming, Failure> {
+    let client = http.builder()
+        .user_agent(format!("ByteBeam/{}", env!("CARGO_PKG_VERSION")))
+        // decompressing is done below, so --no-decompress can keep the file as it was sent
+        .no_gzip().no_brotli().no_zstd().no_deflate()
+        .build().expect("Could not build download request");
+    let req = http.send(client.get(download_path)
+        .header(FRAMES_HEADER, FRAMES) // each frame is checked as it comes in, and a damaged one asked for again
+        .query(query));
+
+    let request = match req.await {
+        Ok(req) => req,
         Err(e) => {
-            error!("Failed to create output file: {}", e);
-            return Err(());
+            error!("Failed to connect to server: {}", e);
+            return Err(Failure::request(&e));
         }
     };
 
-    println!("Downloading to {:?}", write_path);
+    if request.status() != reqwest::StatusCode::OK {
+        let status = request.status();
+        error!("Failed to download file: {}", status.to_string());
+        error!("Response: {}", request.text().await.expect("Could not get response"));
+        return Err(Failure::status(status));
+    }
+
+    trace!("File headers: {:?}", request.headers());
 
-    let content_length = request
+    // the relay labels what it can, a missing label on a compressed upload means something in between dropped it
+    let compression = match request.headers().get(CONTENT_ENCODING).and_then(|v| v.to_str().ok()) {
+        Some(encoding) => match Compression::from_str(encoding) {
+            Ok(compression) => compression,
+            Err(e) => {
+                warn!("{}, saving the file as it was sent", e);
+                Compression::None
+            }
+        },
+        None if !encrypted => compression,
+        None => Compression::None
+    };
+
+    let manifest: Option<Vec<ManifestEntry>> = request.headers().get(MANIFEST_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| decode(header).ok())
+        .and_then(|header| serde_json::from_str(&header).ok());
+
+    // the relay redirects to the uploaded name
+    let name = match request.url().path_segments().and_then(|mut segments| segments.next_back()) {
+        Some(name) => match decode(name) {
+            Ok(name) => Some(name.into_owned()),
+            Err(e) => {
+                error!("Failed to decode file name from request url: {:?}", e);
+                return Err(Failure::Other);
+            }
+        },
+        None => None
+    };
+
+    let length = request
         .headers()
         .get("content-length")
         .and_then(|v| v.to_str().ok())
         .and_then(|v| v.parse::<u64>().ok())
+        .or(file_size.map(|size| size as u64)) // framed downloads are longer than the file, so they don't say
         .unwrap_or(0);
 
-    let bar = ProgressBar::new(content_length);
-    bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>7}/{total_bytes:7} {msg}")
-        .unwrap());
-    bar.enable_steady_tick(Duration::from_millis(100));
+    Ok(Incoming {
+        name,
+        compression,
+        manifest,
+        length,
+        body: frames::checked(client, request)
+    })
+}
 
-    let mut stream = request.bytes_stream();
-    while let Some(chunk_result) = stream.next().await {
-        match chunk_result {
-            Ok(chunk) => {
-                    bar.inc(chunk.len() as u64);
-                    match file.write_all(&chunk).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Failed to write data to output file: {}", e);
-                        return Err(());
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Failed to decode chunk: {:?}", e);
-                return Err(());
+enum Output {
+    File(PathBuf, OutputFile),
+    Bundle(Vec<(PathBuf, ManifestEntry)>),
+}
+
+fn confirm_overwrite(path: &PathBuf, yes: bool) -> bool {
+    if !path.exists() || yes {
+        return true;
+    }
+    match style::stdout_taken() {
+        true => {
+            eprint!("File already exists: {:?}. Overwrite? [y/N] ", path);
+            io::stderr().flush().expect("Could not flush stderr");
+        },
+        false => {
+            print!("File already exists: {:?}. Overwrite? [y/N] ", path);
+            io::stdout().flush().expect("Could not flush stdout");
+        }
+    }
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).expect("Could not read input");
+    input.trim().eq_ignore_ascii_case("y")
+}
+
+// an output directory keeps the uploaded name, just like cp. a file kept compressed gets the matching extension unless -o names it
+fn file_path(output: Option<PathBuf>, name: Option<String>, extension: Option<&str>, yes: bool) -> Result<PathBuf, Failure> {
+    // the name comes from the sender, so only the last part of it is used
+    let url_name: Option<PathBuf> = name.as_deref().and_then(|name| std::path::Path::new(name).file_name()).map(|name| {
+        let name = name.to_string_lossy();
+        match extension {
+            Some(extension) => format!("{name}.{extension}"),
+            None => name.into_owned()
+        }.into()
+    });
+
+    let write_path = match (output, url_name) {
+        (Some(op), Some(name)) if op.is_dir() => op.join(name),
+        (Some(op), _) => op,
+        (None, Some(name)) => name,
+        (None, None) => {
+            error!("Could not determine file name to save to, and none was provided. Cancelling download");
+            return Err(Failure::Other);
+        }
+    };
+
+    if !confirm_overwrite(&write_path, yes) {
+        error!("Download cancelled - file exists");
+        return Err(Failure::Aborted);
+    }
+    Ok(write_path)
+}
+
+// bundles always go into a directory, the current one unless -o says otherwise
+fn bundle_paths(output: Option<PathBuf>, manifest: &[ManifestEntry], yes: bool) -> Result<Vec<(PathBuf, ManifestEntry)>, Failure> {
+    let directory = output.unwrap_or(PathBuf::from("."));
+    if directory.exists() && !directory.is_dir() {
+        error!("{:?} is a file, but this download has {} files. Give a directory with -o instead", directory, manifest.len());
+        return Err(Failure::Other);
+    }
+    if let Err(e) = std::fs::create_dir_all(&directory) {
+        error!("Could not create {:?}: {}", directory, e);
+        return Err(Failure::Other);
+    }
+
+    let mut paths = vec![];
+    for file in manifest {
+        // the names come from the sender, so only the last part of them is used
+        let name = match std::path::Path::new(&file.name).file_name() {
+            Some(name) => name.to_owned(),
+            None => {
+                error!("The download has a file without a usable name: {:?}", file.name);
+                return Err(Failure::Other);
             }
+        };
+        let path = directory.join(name);
+        if !confirm_overwrite(&path, yes) {
+            error!("Download cancelled - file exists");
+            return Err(Failure::Aborted);
         }
+        paths.push((path, file.clone()));
     }
+    Ok(paths)
+}
 
-    bar.finish();
+// a kept symlink is only made when it stays inside the folder, nothing absolute and no ..
+fn contained(target: &str) -> bool {
+    let target = Path::new(target);
+    !target.as_os_str().is_empty() && target.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
 
-    println!("Download complete.");
+// splits the stream back up at the sizes from the manifest
+async fn save_bundle(stream: &mut ByteStream, files: Vec<(PathBuf, ManifestEntry)>, preserve: bool) -> Result<(), Failure> {
+    let mut pending = Bytes::new();
+    for (path, entry) in files {
+        // writing through a symlink that's already there could put the file anywhere, so it's replaced instead
+        if path.is_symlink() {
+            if let Err(e) = std::fs::remove_file(&path) {
+                error!("Could not replace the symlink {:?}: {}", path, e);
+                return Err(Failure::Other);
+            }
+        }
+        if let Some(target) = &entry.link {
+            match contained(target) {
+                true => match fileio::symlink(Path::new(target), &path) {
+                    Ok(()) => trace!("Linked {:?} to {}", path, target),
+                    Err(e) => warn!("Could not make the symlink {:?} to {}: {}", path, target, e)
+                },
+                false => warn!("Leaving out the symlink {:?}, it points outside the folder to {}", path, target)
+            }
+            continue;
+        }
 
+        let mut file = match OutputFile::create(path.clone()).await {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Failed to create {:?}: {}", path, e);
+                return Err(Failure::Other);
+            }
+        };
+        let mut remaining = entry.size;
+        while remaining > 0 {
+            if pending.is_empty() {
+                pending = match stream.next().await {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(e)) => {
+                        error!("Failed to decode chunk: {}", e);
+                        return Err(Failure::Other);
+                    },
+                    None => {
+                        error!("The download ended {} bytes before the end of {:?}", remaining, path);
+                        return Err(Failure::Other);
+                    }
+                };
+                continue;
+            }
+            let part = pending.split_to(remaining.min(pending.len() as u64) as usize);
+            remaining -= part.len() as u64;
+            if let Err(e) = file.write(part).await {
+                error!("Failed to write data to {:?}: {}", path, e);
+                return Err(Failure::Other);
+            }
+        }
+        if let Err(e) = file.finish().await {
+            error!("Failed to finish writing {:?}: {}", path, e);
+            return Err(Failure::Other);
+        }
+        if let Some(attributes) = entry.attributes.filter(|_| preserve) {
+            if let Err(e) = fileio::restore(&path, &attributes) {
+                warn!("Could not set the permissions or modification time of {:?}: {}", path, e);
+            }
+        }
+        trace!("Saved {:?}", path);
+    }
+
+    // reading to the end is what lets the relay mark the download complete
+    let mut extra = pending.len();
+    while let Some(Ok(chunk)) = stream.next().await {
+        extra += chunk.len();
+    }
+    if extra > 0 {
+        warn!("The download had {} bytes more than its manifest listed, they were left out", extra);
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,240 @@
+use std::{io::{self, Read, Write}, path::PathBuf, pin::Pin, str::FromStr};
+use age::{secrecy::SecretString, Decryptor, Encryptor};
+use async_stream::stream;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, error, trace};
+
+use crate::utils::compression::{decoder, ChannelReader, Compression};
+use super::exit::Failure;
+
+// same as compression, how many chunks wait between the network and the (de|en)crypting thread
+const PIPELINE_DEPTH: usize = 8;
+const READ_SIZE: usize = 64 * 1024; // age works in 64KiB chunks anyway
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>>;
+
+// what the downloader unlocks with. age identities aren't Send, so files are only read in the decrypting thread
+pub enum Key {
+    Passphrase(SecretString),
+    Identities(Vec<PathBuf>),
+}
+
+// BEAM_PASSPHRASE is there for scripts, everyone else is asked without it echoing
+fn read_passphrase(confirm: bool) -> Result<SecretString, Failure> {
+    if let Ok(passphrase) = std::env::var("BEAM_PASSPHRASE") {
+        if !passphrase.is_empty() {
+            debug!("Using the passphrase from BEAM_PASSPHRASE");
+            return Ok(SecretString::from(passphrase));
+        }
+    }
+
+    let passphrase = match rpassword::prompt_password("Passphrase: ") {
+        Ok(passphrase) => passphrase,
+        Err(e) => {
+            error!("Could not read the passphrase: {}", e);
+            return Err(Failure::Other);
+        }
+    };
+    if passphrase.is_empty() {
+        error!("The passphrase can't be empty");
+        return Err(Failure::Other);
+    }
+    if confirm && rpassword::prompt_password("Confirm passphrase: ").ok().as_ref() != Some(&passphrase) {
+        error!("Passphrases did not match");
+        return Err(Failure::Other);
+    }
+    Ok(SecretString::from(passphrase))
+}
+
+// a recipient can also be a file of them, like someone's id_ed25519.pub
+fn expand_recipients(recipients: &[String]) -> Result<Vec<String>, Failure> {
+    let mut expanded = vec![];
+    for recipient in recipients {
+        let path = PathBuf::from(shellexpand::tilde(recipient).into_owned());
+        if !path.is_file() {
+            expanded.push(recipient.trim().to_string());
+            continue;
+        }
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => expanded.extend(contents.lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| line.to_string())),
+            Err(e) => {
+                error!("Could not read recipients from {:?}: {}", path, e);
+                return Err(Failure::Other);
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+// no recipients means a passphrase, age won't mix the two
+pub fn encryptor(recipients: &[String]) -> Result<Encryptor, Failure> {
+    if recipients.is_empty() {
+        return Ok(Encryptor::with_user_passphrase(read_passphrase(true)?));
+    }
+
+    let mut parsed: Vec<Box<dyn age::Recipient + Send>> = vec![];
+    for recipient in expand_recipients(recipients)? {
+        if recipient.starts_with("age1") {
+            match age::x25519::Recipient::from_str(&recipient) {
+                Ok(recipient) => parsed.push(Box::new(recipient)),
+                Err(e) => {
+                    error!("Invalid age recipient {}: {}", recipient, e);
+                    return Err(Failure::Other);
+                }
+            }
+        } else {
+            match age::ssh::Recipient::from_str(&recipient) {
+                Ok(recipient) => parsed.push(Box::new(recipient)),
+                Err(e) => {
+                    error!("{} is not an age recipient or a supported ssh public key: {:?}", recipient, e);
+                    return Err(Failure::Other);
+                }
+            }
+        }
+    }
+    debug!("Encrypting to {} recipients", parsed.len());
+
+    match Encryptor::with_recipients(parsed.iter().map(|r| r.as_ref() as &dyn age::Recipient)) {
+        Ok(encryptor) => Ok(encryptor),
+        Err(e) => {
+            error!("Could not encrypt to the given recipients: {}", e);
+            Err(Failure::Other)
+        }
+    }
+}
+
+pub fn unlock_key(identities: &[PathBuf]) -> Result<Key, Failure> {
+    match identities.is_empty() {
+        true => Ok(Key::Passphrase(read_passphrase(false)?)),
+        false => Ok(Key::Identities(identities.iter().map(|path| PathBuf::from(shellexpand::tilde(&path.to_string_lossy()).into_owned())).collect()))
+    }
+}
+
+// each file can be an age identity file or an unencrypted ssh private key
+fn load_identities(paths: &[PathBuf]) -> io::Result<Vec<Box<dyn age::Identity>>> {
+    let mut identities = vec![];
+    for path in paths {
+        let contents = std::fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("Could not read identity {:?}: {}", path, e)))?;
+        if let Ok(file) = age::IdentityFile::from_buffer(contents.as_bytes()) {
+            identities.extend(file.into_identities().map_err(|e| io::Error::other(e.to_string()))?);
+            continue;
+        }
+        match age::ssh::Identity::from_buffer(contents.as_bytes(), Some(path.to_string_lossy().to_string())) {
+            Ok(age::ssh::Identity::Unencrypted(key)) => identities.push(Box::new(age::ssh::Identity::Unencrypted(key)) as Box<dyn age::Identity>),
+            Ok(age::ssh::Identity::Encrypted(_)) => return Err(io::Error::other(format!("{:?} is passphrase protected, which is not supported yet", path))),
+            Ok(age::ssh::Identity::Unsupported(_)) | Err(_) => return Err(io::Error::other(format!("{:?} is not an age identity or a supported ssh private key", path))),
+        }
+    }
+    Ok(identities)
+}
+
+// hands the encrypting thread each finished age chunk as it is written
+struct ChannelWriter(mpsc::Sender<Result<Bytes, io::Error>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.blocking_send(Ok(Bytes::copy_from_slice(buf))) {
+            Ok(_) => Ok(buf.len()),
+            Err(_) => Err(io::ErrorKind::BrokenPipe.into()) // nobody is sending anymore
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// runs after compression, since ciphertext doesn't compress
+pub fn encrypt_stream<S>(mut input: S, encryptor: Encryptor) -> ByteStream where S: Stream<Item = Result<Bytes, io::Error>> + Unpin + Send + 'static {
+    let (raw_tx, mut raw_rx) = mpsc::channel::<Bytes>(PIPELINE_DEPTH);
+    let (out_tx, mut out_rx) = mpsc::channel::<Result<Bytes, io::Error>>(PIPELINE_DEPTH);
+
+    let read_errors = out_tx.clone();
+    tokio::spawn(async move {
+        while let Some(chunk) = input.next().await {
+            match chunk {
+                Ok(chunk) => if raw_tx.send(chunk).await.is_err() {
+                    return; // the encryptor went away, it has already reported why
+                },
+                Err(e) => {
+                    let _ = read_errors.send(Err(e)).await;
+                    return;
+                }
+            }
+        }
+    });
+
+    tokio::task::spawn_blocking(move || {
+        let errors = out_tx.clone();
+        let result = encryptor.wrap_output(ChannelWriter(out_tx)).and_then(|mut writer| {
+            while let Some(chunk) = raw_rx.blocking_recv() {
+                writer.write_all(&chunk)?;
+            }
+            trace!("Input done, finishing encryption");
+            writer.finish().map(|_| ())
+        });
+        if let Err(e) = result {
+            let _ = errors.blocking_send(Err(e));
+        }
+    });
+
+    Box::pin(stream! {
+        while let Some(chunk) = out_rx.recv().await {
+            yield chunk;
+        }
+    })
+}
+
+fn decrypt(chunks: mpsc::Receiver<Result<Bytes, io::Error>>, key: Key, compression: &Compression, out: &mpsc::Sender<Result<Bytes, io::Error>>) -> io::Result<()> {
+    let decryptor = Decryptor::new(ChannelReader::new(chunks)).map_err(|e| io::Error::other(e.to_string()))?;
+    let identities: Vec<Box<dyn age::Identity>> = match key {
+        Key::Passphrase(_) if !decryptor.is_scrypt() => return Err(io::Error::other("The file is encrypted to age recipients, pass the matching key with --identity")),
+        Key::Identities(_) if decryptor.is_scrypt() => return Err(io::Error::other("The file is encrypted with a passphrase, leave out --identity to be asked for it")),
+        Key::Passphrase(passphrase) => vec![Box::new(age::scrypt::Identity::new(passphrase))],
+        Key::Identities(paths) => load_identities(&paths)?,
+    };
+
+    let reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref())).map_err(|e| io::Error::other(e.to_string()))?;
+    let mut reader = decoder(compression, reader)?;
+    let mut buffer = vec![0; READ_SIZE];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(());
+        }
+        if out.blocking_send(Ok(Bytes::copy_from_slice(&buffer[..n]))).is_err() {
+            return Ok(()); // the download was dropped
+        }
+    }
+}
+
+// the relay only saw ciphertext, so decompressing happens here too instead of in reqwest
+pub fn decrypt_stream<S>(mut input: S, key: Key, compression: Compression) -> ByteStream where S: Stream<Item = Result<Bytes, io::Error>> + Unpin + Send + 'static {
+    let (raw_tx, raw_rx) = mpsc::channel::<Result<Bytes, io::Error>>(PIPELINE_DEPTH);
+    let (out_tx, mut out_rx) = mpsc::channel::<Result<Bytes, io::Error>>(PIPELINE_DEPTH);
+
+    tokio::spawn(async move {
+        while let Some(chunk) = input.next().await {
+            if raw_tx.send(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = decrypt(raw_rx, key, &compression, &out_tx) {
+            let _ = out_tx.blocking_send(Err(e));
+        }
+    });
+
+    Box::pin(stream! {
+        while let Some(chunk) = out_rx.recv().await {
+            yield chunk;
+        }
+    })
+}
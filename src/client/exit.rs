@@ -0,0 +1,53 @@
+use std::fmt;
+use reqwest::StatusCode;
+
+// what beam exits with, so scripts can tell failures apart without reading the logs.
+// errors are logged where they happen and passed up as one of these, only main turns it into the exit code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    Other = 1,
+    Auth = 2, // the relay refused the user or their signature
+    NotFound = 3, // no such token, or it expired
+    Unreachable = 4, // the relay (or the proxy in front of it) couldn't be reached, even after retrying
+    Checksum = 5, // the download didn't match what the uploader hashed, or a self-update didn't match its signed checksums
+    Aborted = 6, // the user said no to a prompt, or pressed ctrl-c
+}
+
+impl Failure {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    // a relay answering with an error says why itself
+    pub fn status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Failure::Auth,
+            StatusCode::NOT_FOUND | StatusCode::GONE => Failure::NotFound,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => Failure::Unreachable, // only seen once the retries ran out
+            _ => Failure::Other
+        }
+    }
+
+    // out of retries, the network (or the proxy in front of the relay) is why it failed
+    pub fn request(error: &reqwest::Error) -> Self {
+        match error.is_connect() || error.is_timeout() {
+            true => Failure::Unreachable,
+            false => Failure::Other
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Other => write!(f, "failed"),
+            Failure::Auth => write!(f, "not authorized"),
+            Failure::NotFound => write!(f, "not found"),
+            Failure::Unreachable => write!(f, "relay unreachable"),
+            Failure::Checksum => write!(f, "checksum mismatch"),
+            Failure::Aborted => write!(f, "aborted"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
@@ -0,0 +1,96 @@
+use std::{fs::Metadata, io, path::Path, time::{Duration, UNIX_EPOCH}};
+use bytes::Bytes;
+use tokio::fs::File;
+use tokio_stream::Stream;
+
+use crate::utils::metadata::FileAttributes;
+
+// local file access for uploads and downloads, through io_uring when built with the uring feature on linux
+
+pub type InputStream = Box<dyn Stream<Item = Result<Bytes, io::Error>> + Unpin + Send>;
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub fn input_stream(_file: File, path: &Path) -> InputStream {
+    Box::new(super::uring::read_stream(path.to_path_buf()))
+}
+
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+pub fn input_stream(file: File, _path: &Path) -> InputStream {
+    Box::new(tokio_util::io::ReaderStream::new(file))
+}
+
+// only the permission bits are sent, setuid and the like shouldn't come from someone else's disk
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> Option<u32> {
+    Some(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o777)
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(mode & 0o777))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+pub fn attributes(metadata: &Metadata) -> FileAttributes {
+    FileAttributes {
+        mode: mode(metadata),
+        modified: metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|since| since.as_secs() as i64)
+    }
+}
+
+// once the file is written and closed, so nothing writing to it afterwards moves the time again.
+// the time goes first, a read-only mode would stop it being opened to set it
+pub fn restore(path: &Path, attributes: &FileAttributes) -> io::Result<()> {
+    if let Some(modified) = attributes.modified.and_then(|secs| u64::try_from(secs).ok()) {
+        std::fs::File::options().write(true).open(path)?.set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+    }
+    if let Some(mode) = attributes.mode {
+        set_mode(path, mode)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+pub fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, path)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn symlink(_target: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "symlinks can't be made here"))
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub use super::uring::UringWriter as OutputFile;
+
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+pub struct OutputFile(File);
+
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+impl OutputFile {
+    pub async fn create(path: std::path::PathBuf) -> io::Result<Self> {
+        Ok(Self(File::create(path).await?))
+    }
+
+    pub async fn write(&mut self, chunk: Bytes) -> io::Result<()> {
+        tokio::io::AsyncWriteExt::write_all(&mut self.0, &chunk).await
+    }
+
+    pub async fn finish(mut self) -> io::Result<()> {
+        tokio::io::AsyncWriteExt::flush(&mut self.0).await
+    }
+}
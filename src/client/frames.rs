@@ -0,0 +1,177 @@
+use std::{io, sync::Arc};
+use async_stream::stream;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use reqwest::{multipart::{Form, Part}, Body, Client, Response, StatusCode};
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::utils::frames::{frame, Deframer, Frame, FRAMES, FRAMES_HEADER, MAX_FRAME, RESEND_HEADER, RESUME_HEADER};
+
+use super::encryption::ByteStream;
+
+// how much of the upload is kept to send again, more than can be on its way to the relay when it finds a damaged frame
+const KEEP: usize = 16 * 1024 * 1024;
+// past this many damaged frames the connection is too broken for sending them again to be worth it
+const MAX_RESENDS: usize = 8;
+// a frame sent again can be damaged on the way too
+const RESEND_TRIES: usize = 3;
+
+// the upload as it's read, with the end of it kept so a damaged part can go again without reading the file twice
+struct Sent {
+    source: ByteStream,
+    start: u64, // where the first byte kept is in the upload
+    kept: BytesMut,
+}
+
+impl Sent {
+    async fn next(&mut self, offset: u64) -> Option<io::Result<Bytes>> {
+        if offset < self.start {
+            return Some(Err(io::Error::other("That part of the upload is no longer kept to send again")));
+        }
+        let from = (offset - self.start) as usize;
+        if from < self.kept.len() {
+            return Some(Ok(Bytes::copy_from_slice(&self.kept[from..(from + MAX_FRAME).min(self.kept.len())])));
+        }
+        let chunk = match self.source.next().await? {
+            Ok(chunk) => chunk,
+            Err(e) => return Some(Err(e))
+        };
+        self.kept.put_slice(&chunk);
+        if self.kept.len() > KEEP {
+            let dropped = self.kept.len() - KEEP;
+            self.kept.advance(dropped);
+            self.start += dropped as u64;
+        }
+        Some(Ok(chunk))
+    }
+}
+
+// framed from offset on, what's kept first and then the rest as it's read
+fn replay(sent: Arc<Mutex<Sent>>, mut offset: u64) -> ByteStream {
+    Box::pin(stream! {
+        loop {
+            let next = sent.lock().await.next(offset).await;
+            match next {
+                Some(Ok(data)) => {
+                    offset += data.len() as u64;
+                    yield Ok(frame(&data));
+                },
+                Some(Err(e)) => {
+                    yield Err(e);
+                    break;
+                },
+                None => break
+            }
+        }
+    })
+}
+
+fn file_part(sent: &Arc<Mutex<Sent>>, from: u64) -> Part {
+    Part::stream(Body::wrap_stream(replay(sent.clone(), from)))
+}
+
+// sends the upload framed, and again from wherever the relay got to each time it turns a damaged frame away
+pub async fn send(client: &Client, url: Url, form: Form, source: ByteStream) -> reqwest::Result<Response> {
+    let sent = Arc::new(Mutex::new(Sent { source, start: 0, kept: BytesMut::new() }));
+    let mut response = client.post(url.clone()).multipart(form.text("frames", FRAMES).part("file", file_part(&sent, 0))).send().await;
+    let mut from = None; // where the relay last said it got to
+    for _ in 0..MAX_RESENDS {
+        let from = *from.insert(match &response {
+            Ok(response) if response.status() == StatusCode::UNPROCESSABLE_ENTITY => match response.headers().get(RESUME_HEADER).and_then(|from| from.to_str().ok()).and_then(|from| from.parse::<u64>().ok()) {
+                Some(from) => from,
+                None => break // the whole file didn't match its checksum, sending it again won't change that
+            },
+            // the relay answers as soon as it finds the damage, which can reach reqwest as the connection breaking instead.
+            // it drops whatever it already has, so starting early only costs sending that again
+            Err(_) => match from {
+                Some(from) => from,
+                None => sent.lock().await.start
+            },
+            _ => break
+        });
+        if from < sent.lock().await.start {
+            break;
+        }
+        warn!("Part of the upload was damaged on the way to the relay, sending it again from byte {}", from);
+        let form = Form::new().text("frames", FRAMES).text("resume-from", from.to_string()).part("file", file_part(&sent, from));
+        let retried = client.post(url.clone()).multipart(form).send().await;
+        // a connection that really broke is better reported as that than as whatever the relay says to the retry
+        if response.is_err() && !retried.as_ref().is_ok_and(|retried| retried.status().is_success() || retried.status() == StatusCode::UNPROCESSABLE_ENTITY) {
+            break;
+        }
+        response = retried;
+    }
+    response
+}
+
+// the download with every frame checked. a damaged one is asked for again on the side, and the rest carries on after it
+pub fn checked(client: Client, response: Response) -> ByteStream {
+    let framed = response.headers().get(FRAMES_HEADER).is_some_and(|frames| frames == FRAMES);
+    let key = response.headers().get(RESEND_HEADER).and_then(|key| key.to_str().ok()).map(|key| key.to_string());
+    let url = response.url().clone();
+    let mut body = response.bytes_stream();
+    let key = match key.filter(|_| framed) {
+        Some(key) => key,
+        None => return Box::pin(body.map(|chunk| chunk.map_err(io::Error::other)))
+    };
+
+    Box::pin(stream! {
+        let mut deframer = Deframer::default();
+        let mut ended = false;
+        let mut resent = 0;
+        loop {
+            match deframer.next() {
+                Some(Frame::Data(data)) => yield Ok(data),
+                Some(Frame::Damaged(offset)) => {
+                    resent += 1;
+                    if resent > MAX_RESENDS {
+                        yield Err(io::Error::other("Too much of the download was damaged on the way"));
+                        break;
+                    }
+                    warn!("Part of the download was damaged on the way, asking for it again from byte {}", offset);
+                    match resend(&client, &url, &key, offset).await {
+                        Ok(data) => {
+                            deframer.replaced(data.len());
+                            yield Ok(data);
+                        },
+                        Err(e) => {
+                            yield Err(io::Error::other(e));
+                            break;
+                        }
+                    }
+                },
+                None if ended => if deframer.finish() {
+                    break;
+                },
+                None => match body.next().await {
+                    Some(Ok(chunk)) => deframer.push(&chunk),
+                    Some(Err(e)) => {
+                        yield Err(io::Error::other(e));
+                        break;
+                    },
+                    None => ended = true
+                }
+            }
+        }
+    })
+}
+
+async fn resend(client: &Client, url: &Url, key: &str, offset: u64) -> Result<Bytes, String> {
+    let mut url = url.clone();
+    url.query_pairs_mut().clear().append_pair("resend", key).append_pair("offset", &offset.to_string());
+    for _ in 0..RESEND_TRIES {
+        let response = client.get(url.clone()).send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("The relay could not send the damaged part again: {}", response.text().await.unwrap_or_default()));
+        }
+        let mut deframer = Deframer::default();
+        deframer.push(&response.bytes().await.map_err(|e| e.to_string())?);
+        if let (Some(Frame::Data(data)), true) = (deframer.next(), deframer.finish()) {
+            return Ok(data);
+        }
+        debug!("The frame at {} was damaged again", offset);
+    }
+    Err("The damaged part of the download was damaged every time it was sent again".to_string())
+}
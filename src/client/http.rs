@@ -0,0 +1,167 @@
+use std::time::Duration;
+use reqwest::{header::RETRY_AFTER, Client, ClientBuilder, Identity, Proxy, RequestBuilder, Response, StatusCode};
+use tracing::{debug, error, warn};
+use url::Url;
+
+use super::{exit::Failure, ClientConfig};
+
+// tor's default SOCKS port, used for .onion relays when no proxy is configured
+const TOR_SOCKS: &str = "socks5h://127.0.0.1:9050";
+pub const DEFAULT_RETRIES: u32 = 3;
+pub const DEFAULT_RETRY_DELAY: u64 = 500; // milliseconds before the first retry, doubled after each one
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// how one command talks to its relay. the client is built once the proxy and certificate are known, so requests share
+// connections, and over https multiplex on one with http/2. cloning it shares them too
+#[derive(Clone)]
+pub struct Http {
+    client: Client,
+    proxy: Option<String>,
+    identity: Option<Identity>,
+    retries: u32,
+    delay: Duration,
+}
+
+impl Http {
+    pub fn new(server: &str, config: &ClientConfig) -> Result<Self, Failure> {
+        let proxy = proxy(server, config.proxy.as_ref())?;
+        let identity = match (&config.cert, &config.cert_key) {
+            (Some(cert), Some(key)) => Some(identity(cert, key)?),
+            (None, None) => None,
+            _ => {
+                error!("cert and cert_key have to be set together");
+                return Err(Failure::Other);
+            }
+        };
+        let mut http = Http {
+            client: Client::new(),
+            proxy,
+            identity,
+            retries: config.retries.unwrap_or(DEFAULT_RETRIES),
+            delay: Duration::from_millis(config.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY))
+        };
+        http.client = match http.builder().build() {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Could not set up the HTTP client: {}", e);
+                return Err(Failure::Other);
+            }
+        };
+        Ok(http)
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn has_proxy(&self) -> bool {
+        self.proxy.is_some()
+    }
+
+    // for requests that need settings of their own, still through the same proxy and with the same certificate
+    pub fn builder(&self) -> ClientBuilder {
+        let mut builder = Client::builder();
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        match &self.proxy {
+            Some(proxy) => builder.proxy(Proxy::all(proxy).expect("Proxy was checked when it was set")),
+            None => builder
+        }
+    }
+
+    // sends again after a growing wait when the network or a proxy fails. a streamed body can't be sent twice, so those only get the one try,
+    // and a POST that timed out may have been acted on already, so it isn't sent again either
+    pub async fn send(&self, mut request: RequestBuilder) -> reqwest::Result<Response> {
+        let repeatable = request.try_clone().and_then(|request| request.build().ok()).is_some_and(|request| request.method().is_idempotent());
+        let mut delay = self.delay;
+        let mut attempt = 0;
+        loop {
+            let next = match attempt < self.retries {
+                true => request.try_clone(),
+                false => None
+            };
+            let result = request.send().await;
+            let next = match next {
+                Some(next) => next,
+                None => return result
+            };
+            let wait = match &result {
+                Ok(response) => match retry_wait(response, repeatable, delay) {
+                    Some(wait) => {
+                        warn!("{} answered {}, retrying in {:?}", response.url(), response.status(), wait);
+                        wait
+                    },
+                    None => return result
+                },
+                Err(e) if e.is_connect() || (e.is_timeout() && repeatable) => {
+                    warn!("Could not reach the server ({}), retrying in {:?}", e, delay);
+                    delay
+                },
+                _ => return result
+            };
+            tokio::time::sleep(wait).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+            attempt += 1;
+            request = next;
+        }
+    }
+}
+
+// an unusable proxy is an error instead of a fallback, connecting directly could be exactly what the user wanted to avoid
+fn proxy(server: &str, proxy: Option<&String>) -> Result<Option<String>, Failure> {
+    let proxy = match proxy {
+        Some(proxy) => Some(proxy.clone()),
+        None => match Url::parse(server).ok().and_then(|u| u.host_str().map(|h| h.ends_with(".onion"))) {
+            Some(true) => {
+                debug!("{} is an onion service, connecting through tor at {}", server, TOR_SOCKS);
+                Some(TOR_SOCKS.to_string())
+            },
+            _ => None
+        }
+    };
+
+    if let Some(proxy) = &proxy {
+        if let Err(e) = Proxy::all(proxy) {
+            error!("Invalid proxy {}: {}", proxy, e);
+            return Err(Failure::Other);
+        }
+        // socks5:// resolves names locally, which can't work for .onion and leaks the lookup
+        if proxy.starts_with("socks5://") && server.contains(".onion") {
+            warn!("{} resolves names locally, use socks5h:// to reach onion services", proxy);
+        }
+    }
+    Ok(proxy)
+}
+
+fn identity(cert: &str, key: &str) -> Result<Identity, Failure> {
+    let read = |path: &str| {
+        let path = shellexpand::tilde(path).into_owned();
+        std::fs::read(&path).map_err(|e| { error!("Could not read {}: {}", path, e); Failure::Other })
+    };
+    match Identity::from_pkcs8_pem(&read(cert)?, &read(key)?) {
+        Ok(identity) => {
+            debug!("Presenting client certificate {}", cert);
+            Ok(identity)
+        },
+        Err(e) => {
+            error!("Could not use {} as a client certificate, the key has to be PKCS#8 PEM: {}", cert, e);
+            Err(Failure::Other)
+        }
+    }
+}
+
+// what a proxy in front of the relay answers while it restarts or is overloaded, worth asking again. a 503 is only temporary when it
+// says when to come back (and that's soon enough to wait for), and a 504 means the relay may still have acted on it
+fn retry_wait(response: &Response, repeatable: bool, delay: Duration) -> Option<Duration> {
+    match response.status() {
+        StatusCode::BAD_GATEWAY => Some(delay),
+        StatusCode::SERVICE_UNAVAILABLE => response.headers().get(RETRY_AFTER)
+            .and_then(|after| after.to_str().ok())
+            .and_then(|after| after.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .filter(|after| *after <= MAX_RETRY_DELAY),
+        StatusCode::GATEWAY_TIMEOUT if repeatable => Some(delay),
+        _ => None
+    }
+}
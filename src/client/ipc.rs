@@ -0,0 +1,159 @@
+use std::{io, path::PathBuf, sync::{Mutex, OnceLock}, time::Duration};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Notify};
+use tracing::{debug, warn};
+
+// live transfer state for GUI frontends, one JSON object per line on a local socket
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum IpcEvent {
+    Link { label: String, url: String },
+    Progress { bytes: u64, total: Option<u64>, bytes_per_second: f64 },
+    Message { text: String },
+    Finished { success: bool },
+}
+
+// frontends send these back, also one per line
+#[derive(Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum IpcCommand {
+    Status, // repeats the links and the latest progress
+    Cancel,
+}
+
+struct Ipc {
+    events: broadcast::Sender<IpcEvent>,
+    links: Mutex<Vec<IpcEvent>>, // kept so a frontend that connects late still gets them
+    progress: Mutex<Option<IpcEvent>>,
+    cancel: Notify, // a frontend asked to stop, main is the one that ends the transfer
+}
+
+static IPC: OnceLock<Ipc> = OnceLock::new();
+
+#[cfg(unix)]
+pub fn listen(path: PathBuf) -> io::Result<()> {
+    use tokio::net::UnixListener;
+
+    // a socket left behind by an earlier run would make the bind fail
+    if path.exists() {
+        debug!("Removing old socket at {:?}", path);
+        let _ = std::fs::remove_file(&path);
+    }
+    let listener = UnixListener::bind(&path)?;
+
+    let (events, _) = broadcast::channel(64);
+    let _ = IPC.set(Ipc { events, links: Mutex::new(vec![]), progress: Mutex::new(None), cancel: Notify::new() });
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    debug!("Frontend connected");
+                    tokio::spawn(serve(stream));
+                },
+                Err(e) => warn!("Could not accept frontend connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn listen(path: PathBuf) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("frontend sockets are only supported on unix for now, not listening on {:?}", path)))
+}
+
+// resolves once a frontend sends cancel, never without a socket
+pub async fn cancelled() {
+    match IPC.get() {
+        Some(ipc) => ipc.cancel.notified().await,
+        None => std::future::pending().await
+    }
+}
+
+fn snapshot(ipc: &Ipc) -> Vec<IpcEvent> {
+    let mut events = ipc.links.lock().unwrap().clone();
+    events.extend(ipc.progress.lock().unwrap().clone());
+    events
+}
+
+#[cfg(unix)]
+async fn serve(stream: tokio::net::UnixStream) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let ipc = match IPC.get() {
+        Some(ipc) => ipc,
+        None => return
+    };
+    let mut receiver = ipc.events.subscribe();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let mut pending = snapshot(ipc);
+    loop {
+        for event in pending.drain(..) {
+            let mut line = serde_json::to_string(&event).unwrap_or_default();
+            line.push('\n');
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                return; // the frontend went away
+            }
+        }
+
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => pending.push(event),
+                Err(broadcast::error::RecvError::Lagged(_)) => pending = snapshot(ipc), // progress is replaced anyway
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => match serde_json::from_str::<IpcCommand>(&line) {
+                    Ok(IpcCommand::Status) => pending = snapshot(ipc),
+                    Ok(IpcCommand::Cancel) => {
+                        warn!("Transfer cancelled by a frontend");
+                        ipc.cancel.notify_one(); // every connection, this one too, hears finished once main stops
+                    },
+                    Err(e) => warn!("Ignoring frontend command {}: {}", line, e),
+                },
+                _ => return,
+            }
+        }
+    }
+}
+
+pub fn emit(event: IpcEvent) {
+    if let Some(ipc) = IPC.get() {
+        match &event {
+            IpcEvent::Link { .. } => ipc.links.lock().unwrap().push(event.clone()),
+            IpcEvent::Progress { .. } => *ipc.progress.lock().unwrap() = Some(event.clone()),
+            _ => ()
+        }
+        let _ = ipc.events.send(event); // nobody may be connected yet
+    }
+}
+
+// reports a progress bar's state twice a second until it finishes
+pub fn watch(bar: &ProgressBar) {
+    if IPC.get().is_none() {
+        return;
+    }
+    let bar = bar.clone();
+    tokio::spawn(async move {
+        loop {
+            emit(IpcEvent::Progress { bytes: bar.position(), total: bar.length(), bytes_per_second: bar.per_sec() });
+            if bar.is_finished() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    });
+}
+
+pub async fn finish(success: bool) {
+    if let Some(ipc) = IPC.get() {
+        emit(IpcEvent::Finished { success });
+        if ipc.events.receiver_count() > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await; // let the connections write it out before exiting
+        }
+    }
+}
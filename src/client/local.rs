@@ -0,0 +1,430 @@
+use std::{collections::hash_map::RandomState, fmt, hash::BuildHasher, io, net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket as StdUdpSocket}, time::Duration};
+use indicatif::HumanBytes;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, time::Instant};
+use tracing::{debug, error, trace};
+
+use crate::utils::metadata::PeerOffer;
+
+use super::{encryption::ByteStream, exit::Failure, peer::{self, Offer, PeerHeader}, style};
+
+// beams on the same network find each other with multicast dns (RFC 6762) and dns service discovery (RFC 6763), no relay involved
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &str = "_bytebeam._tcp.local";
+const TTL: u32 = 120;
+const BROWSE_TIME: Duration = Duration::from_secs(2);
+const MAX_PACKET: usize = 9000;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000; // records only this machine answers for
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+// what one uploader is sharing, as it's advertised
+#[derive(Debug, Clone)]
+pub struct LocalBeam {
+    pub instance: String,
+    pub file_name: String,
+    pub size: u64, // 0 when compression or encryption changes it
+    pub address: SocketAddr,
+}
+
+impl fmt::Display for LocalBeam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.size {
+            0 => write!(f, "{}  {}  from {}", self.instance, self.file_name, self.address.ip()),
+            size => write!(f, "{}  {} ({})  from {}", self.instance, self.file_name, HumanBytes(size), self.address.ip())
+        }
+    }
+}
+
+// short enough to type, the std hasher keys are random for every process
+fn instance_name() -> String {
+    format!("beam-{:04x}", RandomState::new().hash_one(0u8) & 0xffff)
+}
+
+// the address this machine sends multicast from, for the address record
+fn multicast_address() -> Option<Ipv4Addr> {
+    let socket = StdUdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect((MDNS_GROUP, MDNS_PORT)).ok()?; // nothing is sent, this only picks the interface
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None
+    }
+}
+
+fn put_u16(packet: &mut Vec<u8>, value: u16) {
+    packet.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label);
+    }
+    packet.push(0);
+}
+
+fn put_record(packet: &mut Vec<u8>, name: &str, record_type: u16, class: u16, data: &[u8]) {
+    put_name(packet, name);
+    put_u16(packet, record_type);
+    put_u16(packet, class);
+    packet.extend_from_slice(&TTL.to_be_bytes());
+    put_u16(packet, data.len() as u16);
+    packet.extend_from_slice(data);
+}
+
+fn header(id: u16, flags: u16, questions: u16, answers: u16, additional: u16) -> Vec<u8> {
+    let mut packet = vec![];
+    for value in [id, flags, questions, answers, 0, additional] {
+        put_u16(&mut packet, value);
+    }
+    packet
+}
+
+// one file being shared, answering anyone on the network who asks for beams
+struct Advert {
+    instance: String,
+    port: u16,
+    file_name: String,
+    size: u64,
+    address: Option<Ipv4Addr>,
+}
+
+impl Advert {
+    fn full_name(&self) -> String {
+        format!("{}.{}", self.instance, SERVICE)
+    }
+
+    fn host_name(&self) -> String {
+        format!("{}.local", self.instance)
+    }
+
+    // the pointer to this beam, with where to connect and what it is alongside so nobody has to ask again
+    fn response(&self, id: u16) -> Vec<u8> {
+        let full_name = self.full_name();
+        let mut packet = header(id, FLAG_RESPONSE | FLAG_AUTHORITATIVE, 0, 1, match self.address {
+            Some(_) => 3,
+            None => 2
+        });
+
+        let mut pointer = vec![];
+        put_name(&mut pointer, &full_name);
+        put_record(&mut packet, SERVICE, TYPE_PTR, CLASS_IN, &pointer);
+
+        let mut service = vec![0, 0, 0, 0]; // priority and weight, there's only ever the one
+        put_u16(&mut service, self.port);
+        put_name(&mut service, &self.host_name());
+        put_record(&mut packet, &full_name, TYPE_SRV, CLASS_IN | CACHE_FLUSH, &service);
+
+        let mut text = vec![];
+        for entry in [format!("name={}", self.file_name), format!("size={}", self.size)] {
+            let entry = &entry.as_bytes()[..entry.len().min(255)];
+            text.push(entry.len() as u8);
+            text.extend_from_slice(entry);
+        }
+        put_record(&mut packet, &full_name, TYPE_TXT, CLASS_IN | CACHE_FLUSH, &text);
+
+        if let Some(address) = self.address {
+            put_record(&mut packet, &self.host_name(), TYPE_A, CLASS_IN | CACHE_FLUSH, &address.octets());
+        }
+        packet
+    }
+}
+
+enum RecordData {
+    Pointer(String),
+    Service(u16),
+    Text(Vec<String>),
+    Other,
+}
+
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+// just enough of a dns message to find beams in, other services' traffic is skipped over
+struct Message {
+    id: u16,
+    response: bool,
+    questions: Vec<(String, u16)>,
+    records: Vec<Record>,
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.packet.get(self.at..self.at + length)?;
+        self.at += length;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    // names can point back at earlier ones to save space, the jumps are limited so a bad packet can't loop forever
+    fn name(&mut self) -> Option<String> {
+        let mut labels = vec![];
+        let mut at = self.at;
+        let mut jumped = false;
+        for _ in 0..32 {
+            let length = *self.packet.get(at)? as usize;
+            match length {
+                0 => {
+                    if !jumped {
+                        self.at = at + 1;
+                    }
+                    return Some(labels.join("."));
+                },
+                _ if length & 0xc0 == 0xc0 => {
+                    let target = ((length & 0x3f) << 8) | *self.packet.get(at + 1)? as usize;
+                    if !jumped {
+                        self.at = at + 2;
+                        jumped = true;
+                    }
+                    at = target;
+                },
+                _ => {
+                    let label = self.packet.get(at + 1..at + 1 + length)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    at += 1 + length;
+                }
+            }
+        }
+        None
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let name = self.name()?;
+        let record_type = self.u16()?;
+        self.bytes(6)?; // class and ttl
+        let length = self.u16()? as usize;
+        let end = self.at + length;
+        let data = match record_type {
+            TYPE_PTR => RecordData::Pointer(self.name()?),
+            TYPE_SRV => {
+                self.bytes(4)?;
+                RecordData::Service(self.u16()?)
+            },
+            TYPE_TXT => {
+                let mut entries = vec![];
+                let mut text = Reader { packet: self.bytes(length)?, at: 0 };
+                while let Some(entry_length) = text.bytes(1) {
+                    entries.push(String::from_utf8_lossy(text.bytes(entry_length[0] as usize)?).into_owned());
+                }
+                RecordData::Text(entries)
+            },
+            _ => RecordData::Other
+        };
+        self.at = end;
+        Some(Record { name, data })
+    }
+}
+
+impl Message {
+    fn parse(packet: &[u8]) -> Option<Message> {
+        let mut reader = Reader { packet, at: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+        let mut questions = vec![];
+        for _ in 0..counts[0] {
+            let name = reader.name()?;
+            let question_type = reader.u16()?;
+            reader.u16()?; // class, with the unicast bit
+            questions.push((name, question_type));
+        }
+        let mut records = vec![];
+        for _ in 0..(counts[1] as usize + counts[2] as usize + counts[3] as usize) {
+            records.push(reader.record()?);
+        }
+        Some(Message { id, response: flags & FLAG_RESPONSE != 0, questions, records })
+    }
+
+    fn asks_for_beams(&self) -> bool {
+        !self.response && self.questions.iter().any(|(name, question_type)| name.eq_ignore_ascii_case(SERVICE) && matches!(*question_type, TYPE_PTR | TYPE_ANY))
+    }
+
+    // every beam this answer points at, along with the port and file from the records next to it
+    fn beams(&self, from: SocketAddr) -> Vec<LocalBeam> {
+        let mut beams = vec![];
+        for record in &self.records {
+            let full_name = match &record.data {
+                RecordData::Pointer(full_name) if record.name.eq_ignore_ascii_case(SERVICE) => full_name,
+                _ => continue
+            };
+            let mut port = None;
+            let mut file_name = String::new();
+            let mut size = 0;
+            for record in self.records.iter().filter(|record| record.name.eq_ignore_ascii_case(full_name)) {
+                match &record.data {
+                    RecordData::Service(service_port) => port = Some(*service_port),
+                    RecordData::Text(entries) => for entry in entries {
+                        match entry.split_once('=') {
+                            Some(("name", name)) => file_name = name.to_string(),
+                            Some(("size", length)) => size = length.parse().unwrap_or(0),
+                            _ => ()
+                        }
+                    },
+                    _ => ()
+                }
+            }
+            if let Some(port) = port {
+                beams.push(LocalBeam {
+                    instance: full_name.split('.').next().unwrap_or_default().to_string(),
+                    file_name,
+                    size,
+                    address: SocketAddr::new(from.ip(), port) // the answer came from the uploader, so that's where it can be reached
+                });
+            }
+        }
+        beams
+    }
+}
+
+// joined to the mdns group on the shared port, where the system's own responder usually already is
+fn responder_socket() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?; // so a download on this same machine can find it
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+async fn respond(socket: UdpSocket, advert: Advert) {
+    let mut buffer = vec![0; MAX_PACKET];
+    loop {
+        let (length, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Stopped answering on the local network: {}", e);
+                return;
+            }
+        };
+        match Message::parse(&buffer[..length]) {
+            Some(message) if message.asks_for_beams() => {
+                // a one-shot query from another port gets its answer directly, like beam down --local sends
+                let to = match from.port() {
+                    MDNS_PORT => SocketAddr::from((MDNS_GROUP, MDNS_PORT)),
+                    _ => from
+                };
+                trace!("Answering {} for {}", from, advert.instance);
+                if let Err(e) = socket.send_to(&advert.response(message.id), to).await {
+                    debug!("Could not answer {}: {}", from, e);
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+// starts answering for the file, and listens for whoever picks it
+pub async fn advertise(file_name: &str, size: u64) -> Result<(Offer, String), Failure> {
+    let listener = peer::listen().await.map_err(|e| { error!("Could not listen for a local download: {}", e); Failure::Other })?;
+    let port = listener.local_addr().map_err(|e| { error!("Could not listen for a local download: {}", e); Failure::Other })?.port();
+    let socket = responder_socket().map_err(|e| { error!("Could not join the local network's mDNS group: {}", e); Failure::Other })?;
+
+    let advert = Advert {
+        instance: instance_name(),
+        port,
+        file_name: file_name.to_string(),
+        size,
+        address: multicast_address()
+    };
+    debug!("Advertising {} as {} on port {}", file_name, advert.full_name(), port);
+    let instance = advert.instance.clone();
+    let announcer = tokio::spawn(respond(socket, advert));
+    // the instance is the secret, so a downloader that connects is one that asked for this beam
+    Ok((Offer::unrelayed(listener, instance.clone(), announcer), instance))
+}
+
+// asks the network once, and collects the answers that come back in the next couple of seconds
+pub async fn browse() -> io::Result<Vec<LocalBeam>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_multicast_loop_v4(true)?;
+    let mut query = header(0, 0, 1, 0, 0);
+    put_name(&mut query, SERVICE);
+    put_u16(&mut query, TYPE_PTR);
+    put_u16(&mut query, CLASS_IN);
+    socket.send_to(&query, (MDNS_GROUP, MDNS_PORT)).await?;
+
+    let deadline = Instant::now() + BROWSE_TIME;
+    let mut beams: Vec<LocalBeam> = vec![];
+    let mut buffer = vec![0; MAX_PACKET];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (length, from) = received?;
+        let found = match Message::parse(&buffer[..length]) {
+            Some(message) if message.response => message.beams(from),
+            _ => continue
+        };
+        for beam in found {
+            if !beams.iter().any(|seen| seen.instance == beam.instance) {
+                beams.push(beam);
+            }
+        }
+    }
+    Ok(beams)
+}
+
+// the one asked for by instance or file name, or the only one there is
+pub async fn find(wanted: Option<&str>) -> Result<(PeerHeader, ByteStream), Failure> {
+    style::say("Looking for beams on the local network...");
+    let beams = browse().await.map_err(|e| { error!("Could not search the local network: {}", e); Failure::Other })?;
+    let mut matching: Vec<&LocalBeam> = beams.iter().filter(|beam| match wanted {
+        Some(wanted) => beam.instance.eq_ignore_ascii_case(wanted) || beam.file_name == wanted,
+        None => true
+    }).collect();
+
+    let beam = match matching.len() {
+        0 => {
+            match wanted {
+                Some(wanted) => error!("Nothing called {} is being shared on the local network", wanted),
+                None => error!("Nothing is being shared on the local network. Is beam up --local running on the same network?")
+            }
+            for beam in &beams {
+                style::say(format!("  {}", beam));
+            }
+            return Err(Failure::Other);
+        },
+        1 => matching.remove(0),
+        _ => {
+            error!("Several beams are being shared, pick one with beam down --local [name]");
+            for beam in matching {
+                style::say(format!("  {}", beam));
+            }
+            return Err(Failure::Other);
+        }
+    };
+    style::say(format!("Found {}", beam));
+
+    let offer = PeerOffer {
+        addresses: vec![beam.address.to_string()],
+        secret: beam.instance.clone(),
+        failed: false,
+        reflected: None,
+        answer: None
+    };
+    match peer::connect(&offer).await {
+        Some(found) => Ok(found),
+        None => {
+            error!("Could not connect to {} at {}", beam.instance, beam.address);
+            Err(Failure::Other)
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use std::{fs::File, io};
+use bytes::Bytes;
+use memmap2::Mmap;
+use tokio_stream::Stream;
+
+// big enough to keep syscalls and per-chunk overhead down, and a multiple of every common page size
+const MMAP_SLICE: usize = 4 * 1024 * 1024;
+
+// maps the whole file and hands out slices of it without copying, the kernel pages it in as the slices are read
+pub fn mmap_stream(file: &File) -> io::Result<impl Stream<Item = Result<Bytes, io::Error>> + Unpin + Send> {
+    // safety: the file is only read, but if something else truncates it while it is mapped the upload will fault.
+    // that is the trade-off for the speed, which is why this is opt in
+    let map = unsafe { Mmap::map(file)? };
+    #[cfg(unix)]
+    map.advise(memmap2::Advice::Sequential)?;
+
+    let len = map.len();
+    let data = Bytes::from_owner(map);
+    Ok(tokio_stream::iter((0..len).step_by(MMAP_SLICE).map(move |start| {
+        Ok(data.slice(start..usize::min(start + MMAP_SLICE, len)))
+    })))
+}
@@ -1,15 +1,44 @@
-use std::path::PathBuf;
-use clap::{Args, ValueEnum};
+use std::{collections::HashMap, path::{Path, PathBuf}};
+use clap::{Args, Subcommand, ValueEnum};
 use serde::Deserialize;
+use tracing::{debug, error};
 
-use crate::utils::compression::Compression;
+use crate::utils::{checksum::ChecksumAlgorithm, compression::{Compression, ZstdTuning}};
+use exit::Failure;
 
 pub mod upload;
 pub mod download;
-mod token;
+pub mod update;
+pub mod copy;
+pub mod clip;
+pub mod style;
+pub mod stats;
+pub mod token;
+pub mod admin;
+pub mod exit;
+pub mod tui;
+mod http;
+pub mod ipc;
 mod compression;
+mod encryption;
+mod mmap;
+mod fileio;
+mod frames;
+mod ratelimit;
+mod watch;
+mod peer;
+mod local;
+mod clipboard;
+mod delta;
+mod browser;
+mod notify;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
 
-#[derive(Args, Deserialize, Debug)]
+pub use upload::{upload, upload_stream, TransferHandle};
+pub use download::{download_manager as download, download_stream};
+
+#[derive(Args, Deserialize, Debug, Clone)]
 pub struct UploadArgs {
     #[command(flatten)]
     pub args: ClientConfig,
@@ -22,17 +51,134 @@ pub struct UploadArgs {
     #[arg(short, long)]
     name: Option<String>,
 
-    /// Compression to use when sending, defaults to none
+    /// Compression to use when sending, defaults to none. auto tries zstd on a sample and only uses it if it helps
     #[arg(short, long, default_value = "none")]
-    compression: Compression,
+    compression: compression::Choice,
+
+    /// Ask browsers to show the file in the tab instead of saving it, only images, audio, video, pdfs, and plain text are shown
+    #[arg(long)]
+    inline: bool,
+
+    /// Integrity hash sent with the file: blake3, sha256, sha512, or none
+    #[arg(long, default_value = "blake3")]
+    checksum: ChecksumAlgorithm,
+
+    /// Memory map the file instead of reading it in small pieces, faster for very large files on fast disks
+    #[arg(long)]
+    mmap: bool,
+
+    /// Maximum upload speed per second, like 500K or 2MiB
+    #[arg(long, value_parser = ratelimit::parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Send browsers straight to the file instead of the download page
+    #[arg(long)]
+    direct: bool,
+
+    /// A short note shown to whoever downloads the file
+    #[arg(short, long)]
+    message: Option<String>,
+
+    /// Let this many people download the file instead of just one, the relay keeps a copy on disk while they do
+    #[arg(long, default_value = "1")]
+    max_downloads: usize,
+
+    /// How long the link waits to be used, like 30m, 12h, or 2d, up to the server's limit. Defaults to the server's cull time
+    #[arg(long, value_parser = token::parse_expiry)]
+    expire: Option<i64>,
+
+    /// Ask for this download token instead of a generated one, like friday-release. Needs a user the relay has keys for
+    #[arg(long, value_name = "NAME")]
+    token_name: Option<String>,
+
+    /// Have the relay keep the upload until it's downloaded or expires, so you don't have to stay online. Needs a relay with storage set up
+    #[arg(long)]
+    store: bool,
+
+    /// Send straight to the downloader when they can connect to this machine, or punch through with the relay's reflector. The relay only introduces you and carries it if that fails
+    #[arg(long)]
+    p2p: bool,
+
+    /// Share on the local network instead of through a relay, for beam down --local to find
+    #[arg(long)]
+    local: bool,
+
+    /// Put the link on the clipboard once it's made
+    #[arg(long)]
+    copy: bool,
+
+    /// Open the link in the default browser once it's made
+    #[arg(long)]
+    open: bool,
+
+    /// Show a desktop notification once the file has been downloaded
+    #[arg(long)]
+    notify: bool,
+
+    /// Send this text as a snippet instead of a file, browsers show it on the page with a copy button
+    #[arg(long, value_name = "TEXT", conflicts_with = "file")]
+    text: Option<String>,
+
+    /// Send the file, or stdin without one, as a snippet like --text
+    #[arg(long)]
+    as_text: bool,
+
+    /// Have the relay download the file from an http or https url itself instead of sending it from here. Needs a user the relay can authenticate
+    #[arg(long)]
+    remote: bool,
+
+    /// Only let this user download, they have to sign a challenge with one of the ssh keys the relay has for them
+    #[arg(long, value_name = "USER")]
+    receiver: Option<String>,
+
+    /// Encrypt before sending so the relay only ever sees ciphertext, asks for a passphrase unless --recipient is given
+    #[arg(short, long)]
+    encrypt: bool,
+
+    /// Encrypt to an age recipient or ssh public key (or a file of them) instead of a passphrase, can be repeated
+    #[arg(short, long)]
+    recipient: Vec<String>,
+
+    /// Send the files symlinks point to, the default
+    #[arg(long, conflicts_with_all = ["keep_symlinks", "skip_symlinks"])]
+    follow_symlinks: bool,
+
+    /// Send symlinks as links, beam down recreates them if they stay inside the folder they're saved to
+    #[arg(long, conflicts_with = "skip_symlinks")]
+    keep_symlinks: bool,
+
+    /// Leave out symlinks
+    #[arg(long)]
+    skip_symlinks: bool,
+
+    /// Leave out files matching this pattern, like '*.tmp', can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Give every file its own link and send this many at once, instead of all of them together under one link
+    #[arg(long, value_name = "N")]
+    parallel: Option<usize>,
+
+    /// Send only the blocks that changed since the copy the downloader already has, when they save over it with beam down -o. Not with --encrypt, the relay sees what changed
+    #[arg(long)]
+    delta: bool,
+
+    /// Let zstd look back 128 MiB for repeats instead of a few, which helps multi-GB uploads. Browsers can't decompress it themselves
+    #[arg(long)]
+    zstd_long: bool,
+
+    /// Compress with zstd on this many threads, 0 for one per core, so compression keeps up with a fast link
+    #[arg(long, value_name = "N")]
+    zstd_threads: Option<u32>,
 
     // this is not done at all yet
     /// Format for when sending a folder, defaults to zip
     //#[arg(short, long, default_value = "zip")]
     //archve: Archive,
 
-    /// the file to beam
-    file: String,
+    /// the file to beam, or several to send them together under one link. Patterns like 'logs/**/*.gz' are expanded by beam itself. With --remote, the url to have the relay fetch
+    #[arg(required_unless_present_any = ["text", "as_text"])]
+    file: Vec<String>,
 }
 
 #[derive(Deserialize, Debug, Clone, ValueEnum)]
@@ -42,10 +188,60 @@ enum Archive {
 }
 
 impl UploadArgs {
-    fn get_file_path(&self) -> PathBuf {
-        let expanded = shellexpand::tilde(&self.file).into_owned();
-        let p = PathBuf::new().join(expanded);
-        p
+    fn get_file_paths(&self) -> Vec<PathBuf> {
+        self.file.iter().map(|file| PathBuf::new().join(shellexpand::tilde(file).into_owned())).collect()
+    }
+
+    fn zstd_tuning(&self) -> ZstdTuning {
+        let threads = match self.zstd_threads {
+            Some(0) => std::thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1),
+            Some(threads) => threads,
+            None => 0
+        };
+        ZstdTuning { long: self.zstd_long, threads }
+    }
+
+    // patterns are expanded here instead of by the shell, which windows doesn't do and quoting stops, then --exclude takes out whatever it matches
+    fn expand_patterns(&mut self) -> Result<(), Failure> {
+        if self.follow_symlinks {
+            debug!("Following symlinks, like without --follow-symlinks");
+        }
+        let excludes = self.exclude.iter().map(|pattern| glob::Pattern::new(pattern).map_err(|e| { error!("Invalid --exclude pattern {}: {}", pattern, e); Failure::Other })).collect::<Result<Vec<_>, Failure>>()?;
+        let excluded = |path: &Path| excludes.iter().any(|pattern| pattern.matches_path(path) || path.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy())));
+
+        let mut files = Vec::with_capacity(self.file.len());
+        for file in &self.file {
+            let expanded = shellexpand::tilde(file).into_owned();
+            if file == "-" || !expanded.contains(['*', '?', '[']) {
+                let path = Path::new(&expanded);
+                if !(excluded(path) || self.skip_symlinks && path.is_symlink()) {
+                    files.push(file.clone());
+                }
+                continue;
+            }
+            let paths = glob::glob(&expanded).map_err(|e| { error!("Invalid pattern {}: {}", file, e); Failure::Other })?;
+            let before = files.len();
+            for path in paths {
+                match path {
+                    Ok(path) if excluded(&path) => (),
+                    Ok(path) if path.is_symlink() && self.skip_symlinks => (),
+                    Ok(path) if path.is_file() || (path.is_symlink() && self.keep_symlinks) => files.push(path.to_string_lossy().into_owned()),
+                    Ok(_) => (), // folders aren't sent yet
+                    Err(e) => debug!("Skipping {:?} while matching {}: {}", e.path(), file, e.error())
+                }
+            }
+            if files.len() == before {
+                error!("Nothing matched {}", file);
+                return Err(Failure::Other);
+            }
+        }
+        if files.is_empty() && !self.file.is_empty() {
+            error!("--exclude and --skip-symlinks left no files to send");
+            return Err(Failure::Other);
+        }
+        debug!("Sending {} files after expanding patterns", files.len());
+        self.file = files;
+        Ok(())
     }
 }
 
@@ -62,10 +258,196 @@ pub struct DownloadArgs {
     #[arg(short, long)]
     yes: bool,
 
+    /// Maximum download speed per second, like 500K or 2MB
+    #[arg(long, value_parser = ratelimit::parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Decrypt even if the relay doesn't say the file is encrypted, like an age file uploaded with curl
+    #[arg(long)]
+    decrypt: bool,
+
+    /// age identity file or ssh private key to decrypt with, otherwise the passphrase is asked for
+    #[arg(short, long)]
+    identity: Vec<PathBuf>,
+
+    /// Save the file the way it was sent instead of decompressing it, like report.txt.zst
+    #[arg(long)]
+    no_decompress: bool,
+
+    /// Also write the file to stdout as it's saved, to pipe into something like sha256sum. Everything else is printed to stderr
+    #[arg(long)]
+    tee: bool,
+
+    /// Always download through the relay, even when the uploader offers to send it directly
+    #[arg(long)]
+    relay_only: bool,
+
+    /// Find the file on the local network instead of a relay, from someone running beam up --local. The path can name which one
+    #[arg(long)]
+    local: bool,
+
+    /// Show a desktop notification when the download finishes, or fails, like when a reverse upload finally arrives
+    #[arg(long)]
+    notify: bool,
+
+    /// Don't give the file the permissions and modification time it had on the uploader's disk
+    #[arg(long)]
+    no_preserve: bool,
+
     /// The URL/token to download. If blank, create a reverse-upload
     path: Option<String>,
 }
 
+#[derive(Args, Deserialize, Debug)]
+pub struct ClipArgs {
+    #[command(flatten)]
+    pub args: ClientConfig,
+
+    /// Download this URL/token into the clipboard instead of sending what's on it
+    #[arg(long, value_name = "TOKEN")]
+    paste: Option<String>,
+}
+
+#[derive(Args, Deserialize, Debug)]
+pub struct CopyArgs {
+    #[command(flatten)]
+    pub args: ClientConfig,
+
+    /// Compression to use when uploading, defaults to none
+    #[arg(short, long, default_value = "none")]
+    compression: Compression,
+
+    /// Overwrite if needed when downloading
+    #[arg(short, long)]
+    yes: bool,
+
+    /// A local file, relay:token, or a download link to copy from. From a relay to another relay it's passed straight through
+    source: String,
+
+    /// A local path, or relay:name to upload as (relay: keeps the file name, relay:token/key uploads to an existing token)
+    destination: String,
+}
+
+#[derive(Args, Deserialize, Debug)]
+pub struct SelfUpdateArgs {
+    /// Only check if there is a newer release, without installing it
+    #[arg(long)]
+    check: bool,
+
+    /// The release feed to check, expected to be a GitHub style latest release
+    #[arg(long, env = "BEAM_RELEASE_FEED", default_value = "https://api.github.com/repos/lholliger/bytebeam/releases/latest")]
+    feed: String,
+
+    /// OpenSSH public key the release checksums must be signed with
+    #[arg(long, env = "BEAM_RELEASE_KEY")]
+    signing_key: Option<String>,
+
+    /// Install even if the release is the current version. Older releases are always refused
+    #[arg(short, long)]
+    force: bool,
+}
+
+#[derive(Args, Deserialize, Debug)]
+pub struct StatsArgs {
+    #[command(flatten)]
+    pub args: ClientConfig,
+
+    /// Print the raw JSON instead of a summary
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Deserialize, Debug)]
+pub struct TokenArgs {
+    #[command(subcommand)]
+    pub command: TokenCommand,
+}
+
+#[derive(Subcommand, Deserialize, Debug)]
+pub enum TokenCommand {
+    /// Create an upload token without sending anything, to upload to later with beam up --token
+    New(NewTokenArgs),
+}
+
+#[derive(Args, Deserialize, Debug)]
+pub struct NewTokenArgs {
+    #[command(flatten)]
+    pub args: ClientConfig,
+
+    /// File name the download will have, the upload can still change it with --name
+    #[arg(short, long, default_value = "bytebeam")]
+    name: String,
+
+    /// How long the token waits for its upload, like 90m, 12h, or 2d. Defaults to the server's cull time
+    #[arg(short, long, value_parser = token::parse_expiry)]
+    expires: Option<i64>,
+}
+
+#[derive(Args, Deserialize, Debug)]
+pub struct AdminArgs {
+    #[command(subcommand)]
+    pub command: AdminCommand,
+}
+
+#[derive(Subcommand, Deserialize, Debug)]
+pub enum AdminCommand {
+    /// List the tokens the relay is holding, with who made them and how far along they are
+    List(AdminListArgs),
+
+    /// Delete a token, stopping anything still transferring through it
+    Delete(AdminDeleteArgs),
+
+    /// Show totals for the whole relay
+    Stats(AdminStatsArgs),
+}
+
+#[derive(Args, Deserialize, Debug)]
+pub struct AdminListArgs {
+    #[command(flatten)]
+    pub args: ClientConfig,
+
+    /// Only show tokens with an upload or download streaming right now
+    #[arg(long)]
+    active: bool,
+
+    /// Print the raw JSON instead of a summary
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Deserialize, Debug)]
+pub struct AdminDeleteArgs {
+    #[command(flatten)]
+    pub args: ClientConfig,
+
+    /// The token to delete
+    token: String,
+}
+
+#[derive(Args, Deserialize, Debug)]
+pub struct AdminStatsArgs {
+    #[command(flatten)]
+    pub args: ClientConfig,
+
+    /// Print the raw JSON instead of a summary
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Deserialize, Debug)]
+pub struct TuiArgs {
+    #[command(flatten)]
+    pub args: ClientConfig,
+
+    /// Follow every token on the relay, signing in as an admin
+    #[arg(long)]
+    all: bool,
+
+    /// Tokens or links to follow
+    #[arg(required_unless_present = "all")]
+    tokens: Vec<String>,
+}
+
 #[derive(Args, Deserialize, Debug, Clone)]
 pub struct ClientConfig {
     /// the ByteBeam server to connect to
@@ -76,33 +458,121 @@ pub struct ClientConfig {
     #[arg(short, long, default_value = "default")]
     username: Option<String>,
 
-    /// Path for a key or keys to sign with
+    /// Path for a key or keys to sign with, or the name of a key from [keys] in the config
     #[arg(short, long, default_value = "~/.ssh")]
     key: Option<String>,
+
+    /// File holding the passphrase for an encrypted key, instead of being asked for it
+    #[arg(long, value_name = "FILE", env = "BEAM_KEY_PASSPHRASE_FILE")]
+    key_passphrase_file: Option<String>,
+
+    /// Proxy to reach the server through, like socks5h://127.0.0.1:9050 for tor. .onion servers use tor's default port without one
+    #[arg(long, env = "BEAM_PROXY")]
+    proxy: Option<String>,
+
+    /// PEM client certificate for relays that trust its CA, signs in as its common name instead of with an ssh key
+    #[arg(long, value_name = "FILE", requires = "cert_key")]
+    cert: Option<String>,
+
+    /// Private key for --cert
+    #[arg(long, value_name = "FILE", requires = "cert")]
+    cert_key: Option<String>,
+
+    /// Times to try a request again when the network drops or a proxy answers 502, 503, or 504
+    #[arg(long, env = "BEAM_RETRIES", default_value = "3")]
+    retries: Option<u32>,
+
+    /// Milliseconds to wait before the first retry, doubling after each one
+    #[arg(long, value_name = "MS", default_value = "500")]
+    retry_delay: Option<u64>,
+
+    /// ntfy topic to tell when someone starts and finishes downloading an upload, like https://ntfy.sh/my-beams
+    #[arg(long, value_name = "URL", env = "BEAM_NTFY")]
+    ntfy: Option<String>,
+
+    /// URL to POST a JSON event to when someone starts and finishes downloading an upload
+    #[arg(long, value_name = "URL", env = "BEAM_WEBHOOK")]
+    webhook: Option<String>,
+
+    // set from --plain or NO_COLOR for the whole command, not per server
+    #[arg(skip)]
+    #[serde(skip)]
+    pub plain: bool,
 }
 
 impl ClientConfig {
     pub fn merge(&mut self, config: ClientConfig) {
-        match config.server {
-            Some(server) => if server != "http://localhost:3000" {
-                self.server = Some(server);
-            },
-            None => (),
+        if let Some(server) = config.server.filter(|server| server != "http://localhost:3000") {
+            self.server = Some(server);
+        }
+
+        if let Some(username) = config.username.filter(|username| username != "default") {
+            self.username = Some(username);
+        }
+
+        if let Some(key) = config.key.filter(|key| key != "~/.ssh") {
+            self.key = Some(key);
         }
 
-        match config.username {
-            Some(username) => if username != "default" {
-                self.username = Some(username);
-            },
-            None => (),
+        if config.key_passphrase_file.is_some() {
+            self.key_passphrase_file = config.key_passphrase_file;
         }
 
-        match config.key {
-            Some(key) => if key != "~/.ssh" {
-                self.key = Some(key);
-            },
-            None => (),
+        if config.proxy.is_some() {
+            self.proxy = config.proxy;
         }
+
+        if config.cert.is_some() {
+            self.cert = config.cert;
+            self.cert_key = config.cert_key;
+        }
+
+        if let Some(retries) = config.retries.filter(|retries| *retries != http::DEFAULT_RETRIES) {
+            self.retries = Some(retries);
+        }
+
+        if let Some(delay) = config.retry_delay.filter(|delay| *delay != http::DEFAULT_RETRY_DELAY) {
+            self.retry_delay = Some(delay);
+        }
+
+        if config.ntfy.is_some() {
+            self.ntfy = config.ntfy;
+        }
+
+        if config.webhook.is_some() {
+            self.webhook = config.webhook;
+        }
+    }
+
+    // a profile for the same server brings its own username and key, so each relay can sign with a different identity
+    pub fn apply_matching_profile(&mut self, profiles: &HashMap<String, ClientConfig>) {
+        let server = self.get_absolute().0;
+        let matching = profiles.iter().find(|(_, profile)| match &profile.server {
+            Some(s) => s.trim_end_matches('/') == server.trim_end_matches('/'),
+            None => false
+        });
+        if let Some((name, profile)) = matching {
+            debug!("Using profile {} for {}", name, server);
+            self.merge(profile.clone());
+        }
+    }
+
+    // keys can be named in [keys] and referred to by that name instead of a path
+    pub fn resolve_key(&mut self, keys: &HashMap<String, String>) {
+        if let Some(path) = self.key.as_ref().and_then(|key| keys.get(key)) {
+            debug!("Using named key {:?} at {}", self.key, path);
+            self.key = Some(path.clone());
+        }
+    }
+
+    // the proxy, certificate, and retries every request the command makes to `server` goes out with
+    fn http(&self, server: &str) -> Result<http::Http, Failure> {
+        http::Http::new(server, self)
+    }
+
+    // the uploader's phone hears about the download the same way however it was sent
+    fn push(&self) -> notify::Push {
+        notify::Push { ntfy: self.ntfy.clone(), webhook: self.webhook.clone() }
     }
 
     pub fn get_absolute(&self) -> (String, String, String) {
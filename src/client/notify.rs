@@ -0,0 +1,70 @@
+use notify_rust::Notification;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use super::http::Http;
+
+// transfers can take hours, so whoever started one has likely tabbed away by the time it ends
+pub async fn desktop(summary: &str, body: String) {
+    let summary = summary.to_string();
+    let shown = tokio::task::spawn_blocking(move || {
+        Notification::new().appname("ByteBeam").summary(&summary).body(&body).show().map(|_| ())
+    }).await;
+    match shown {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => warn!("Could not show a desktop notification: {}", e),
+        Err(e) => warn!("Could not show a desktop notification: {}", e)
+    }
+}
+
+// where beam up tells the uploader's phone about the download
+#[derive(Clone, Debug, Default)]
+pub struct Push {
+    pub ntfy: Option<String>, // topic url
+    pub webhook: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PushEvent {
+    DownloadStarted,
+    DownloadFinished,
+}
+
+#[derive(Serialize)]
+struct WebhookBody<'a> {
+    event: PushEvent,
+    token: &'a str,
+    link: &'a str,
+}
+
+// only ever a convenience, a push that doesn't go through is logged and the transfer carries on
+pub async fn push(http: &Http, targets: &Push, event: PushEvent, token: &str, link: &str) {
+    if let Some(topic) = &targets.ntfy {
+        let (title, tag) = match event {
+            PushEvent::DownloadStarted => ("Download started", "arrow_down"),
+            PushEvent::DownloadFinished => ("Download finished", "white_check_mark")
+        };
+        let request = http.client().post(topic)
+            .header("Title", title)
+            .header("Tags", tag)
+            .header("Click", link)
+            .body(format!("{} {}", link, match event {
+                PushEvent::DownloadStarted => "is being downloaded",
+                PushEvent::DownloadFinished => "has been downloaded"
+            }));
+        report(topic, http.send(request).await);
+    }
+    if let Some(url) = &targets.webhook {
+        let request = http.client().post(url).json(&WebhookBody { event, token, link });
+        report(url, http.send(request).await);
+    }
+}
+
+fn report(url: &str, result: reqwest::Result<reqwest::Response>) {
+    match result {
+        Ok(response) if response.status().is_success() => debug!("Pushed to {}", url),
+        Ok(response) => warn!("{} refused the notification with {}", url, response.status()),
+        Err(e) => warn!("Could not send a notification to {}: {}", url, e)
+    }
+}
@@ -0,0 +1,478 @@
+use std::{io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket}, time::Duration};
+use async_stream::stream;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpSocket, TcpStream}, task::JoinHandle, time::Instant};
+use tokio_stream::StreamExt;
+use tracing::{debug, error, trace, warn};
+use url::Url;
+
+use crate::utils::{checksum::Checksum, compression::Compression, metadata::{FileAttributes, FileMetadata, ManifestEntry, PeerOffer}};
+
+use super::{encryption::ByteStream, exit::Failure, http::Http, token::server_info};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3); // for each address, most of them won't answer at all
+const SECRET_TIMEOUT: Duration = Duration::from_secs(5);
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_HEADER: u64 = 64 * 1024;
+const MAX_FRAME: usize = 1024 * 1024;
+const PUNCH_TIME: Duration = Duration::from_secs(20); // each side keeps trying this long, the uploader only hears about it on its next status check
+const PUNCH_ATTEMPT: Duration = Duration::from_secs(1);
+const PUNCH_RETRY: Duration = Duration::from_millis(250); // after a refusal, which comes back at once while the other NAT isn't open yet
+const MAX_REFLECTION: u64 = 64;
+
+// sent by the uploader ahead of the file, in place of everything the relay would have told the downloader
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerHeader {
+    pub file_name: String,
+    pub file_size: u64, // 0 when compression or encryption changes it
+    pub compression: Compression,
+    #[serde(default)]
+    pub encrypted: bool,
+    #[serde(default)]
+    pub checksum: Option<Checksum>,
+    #[serde(default)]
+    pub manifest: Option<Vec<ManifestEntry>>,
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub attributes: Option<FileAttributes>,
+}
+
+// the uploader's side, listening until the downloader connects or says it can't
+pub struct Offer {
+    listener: TcpListener,
+    secret: String,
+    punch: Option<SocketAddr>, // where to punch out from once the downloader answers, when the relay's reflector saw this side
+    status: Option<(Http, String)>, // the relay's status for the token, without one only a connection ends the wait
+    announcer: Option<JoinHandle<()>>, // whatever is telling downloaders where to find this, stopped along with the offer
+}
+
+impl Drop for Offer {
+    fn drop(&mut self) {
+        if let Some(announcer) = &self.announcer {
+            announcer.abort();
+        }
+    }
+}
+
+// the address this machine reaches the relay from, which is the one a downloader on the same network can reach it on
+fn local_address(server: &str) -> Option<IpAddr> {
+    let relay = Url::parse(server).ok()?.socket_addrs(|| None).ok()?.into_iter().next()?;
+    let socket = UdpSocket::bind(match relay {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0"
+    }).ok()?;
+    socket.connect(relay).ok()?; // nothing is sent, this only picks the route
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified()).then_some(ip)
+}
+
+// the listening port is shared with the connections punched out of it, so a NAT that keeps one mapping per port maps them all the same
+pub async fn listen() -> io::Result<TcpListener> {
+    match shared_socket(true).and_then(|socket| { socket.bind(any_address(true, 0))?; socket.listen(128) }) {
+        Ok(listener) => Ok(listener),
+        Err(_) => { // no ipv6 on this machine
+            let socket = shared_socket(false)?;
+            socket.bind(any_address(false, 0))?;
+            socket.listen(128)
+        }
+    }
+}
+
+fn any_address(v6: bool, port: u16) -> SocketAddr {
+    match v6 {
+        true => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+        false => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)
+    }
+}
+
+fn shared_socket(v6: bool) -> io::Result<TcpSocket> {
+    let socket = match v6 {
+        true => TcpSocket::new_v6()?,
+        false => TcpSocket::new_v4()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuseport(true)?; // linux won't share a port with a listener on reuseaddr alone
+    Ok(socket)
+}
+
+// a connection out of a port something else may be using too. an ipv6 socket reaches ipv4 addresses through their mapped form
+async fn connect_from(local: SocketAddr, remote: SocketAddr) -> io::Result<TcpStream> {
+    let remote = match (local, remote) {
+        (SocketAddr::V6(_), SocketAddr::V4(remote)) => SocketAddr::new(IpAddr::V6(remote.ip().to_ipv6_mapped()), remote.port()),
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => return Err(io::Error::other("an ipv4 socket can't reach an ipv6 address")),
+        _ => remote
+    };
+    let socket = shared_socket(local.is_ipv6())?;
+    socket.bind(local)?;
+    socket.connect(remote).await
+}
+
+// the address and port the NAT gives connections out of this local port, as the relay's reflector sees them. with the local
+// address actually used, since asking from port 0 picks one
+async fn reflect(local: SocketAddr, server: &str, port: u16) -> Option<(SocketAddr, SocketAddr)> {
+    let host = Url::parse(server).ok()?.host_str()?.trim_matches(|c| c == '[' || c == ']').to_string();
+    let relays = match tokio::net::lookup_host((host.as_str(), port)).await {
+        Ok(relays) => relays,
+        Err(e) => {
+            debug!("Could not look up the relay's reflector: {}", e);
+            return None;
+        }
+    };
+    for relay in relays {
+        let socket = match tokio::time::timeout(CONNECT_TIMEOUT, connect_from(local, relay)).await {
+            Ok(Ok(socket)) => socket,
+            Ok(Err(e)) => {
+                debug!("Could not reach the reflector at {}: {}", relay, e);
+                continue;
+            },
+            Err(_) => {
+                debug!("Timed out reaching the reflector at {}", relay);
+                continue;
+            }
+        };
+        let port = match socket.local_addr() {
+            Ok(bound) => bound.port(),
+            Err(_) => continue
+        };
+        let mut line = String::new();
+        let mut reader = BufReader::new(socket).take(MAX_REFLECTION);
+        match tokio::time::timeout(SECRET_TIMEOUT, reader.read_line(&mut line)).await {
+            Ok(Ok(_)) => match line.trim().parse::<SocketAddr>() {
+                Ok(reflected) => {
+                    debug!("The relay sees port {} as {}", port, reflected);
+                    return Some((any_address(local.is_ipv6(), port), reflected));
+                },
+                Err(_) => debug!("The reflector answered something that isn't an address: {:?}", line)
+            },
+            _ => debug!("The reflector at {} didn't answer", relay)
+        }
+    }
+    None
+}
+
+// both sides connect to each other at the same time, so each NAT has seen a packet go out to the other before the other's come in,
+// and the two connection attempts meet as one. a NAT that maps every destination to a new port can't be punched through this way
+async fn punch(local: SocketAddr, remote: SocketAddr) -> Option<TcpStream> {
+    let deadline = Instant::now() + PUNCH_TIME;
+    while Instant::now() < deadline {
+        match tokio::time::timeout(PUNCH_ATTEMPT, connect_from(local, remote)).await {
+            Ok(Ok(socket)) => {
+                debug!("Punched through to {}", remote);
+                return Some(socket);
+            },
+            Ok(Err(e)) => {
+                trace!("Punching through to {} failed for now: {}", remote, e);
+                tokio::time::sleep(PUNCH_RETRY).await;
+            },
+            Err(_) => trace!("Nothing back from {} yet", remote)
+        }
+    }
+    debug!("Could not punch through to {}", remote);
+    None
+}
+
+// None means the relay wouldn't pass the offer on, and the upload should just go through it
+pub async fn offer(http: &Http, server: &str, token: &str, key: &str) -> Option<Offer> {
+    let listener = match listen().await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Could not listen for a direct connection, sending through the relay: {}", e);
+            return None;
+        }
+    };
+    let bound = listener.local_addr().ok()?;
+    let port = bound.port();
+
+    let mut form = vec![("key", key.to_string()), ("port", port.to_string())];
+    if let Some(ip) = local_address(server) {
+        form.push(("addresses", ip.to_string()));
+    }
+    // with a reflector on the relay, a downloader that can't reach any of those can still punch through to this
+    let punch = match server_info(http, server).await.and_then(|info| info.reflector) {
+        Some(reflector) => match reflect(bound, server, reflector).await {
+            Some((local, reflected)) => {
+                form.push(("reflected", reflected.to_string()));
+                Some(local)
+            },
+            None => {
+                warn!("Could not ask the relay what address this is seen on, only downloaders that can reach this machine can connect directly");
+                None
+            }
+        },
+        None => None
+    };
+    let response = match http.send(http.client().post(format!("{server}/{token}/peer")).form(&form)).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Could not offer the file directly, sending through the relay: {}", e);
+            return None;
+        }
+    };
+    if !response.status().is_success() {
+        warn!("The relay won't pass on a direct offer, sending through it instead: {}", response.text().await.unwrap_or_default());
+        return None;
+    }
+    let meta = match response.json::<FileMetadata>().await {
+        Ok(meta) => meta,
+        Err(e) => {
+            warn!("Could not read the relay's answer to the direct offer, sending through it instead: {:?}", e);
+            return None;
+        }
+    };
+    let peer = match meta.get_peer() {
+        Some(peer) => peer,
+        None => {
+            warn!("The relay didn't keep the direct offer, it may be out of date. Sending through it instead");
+            return None;
+        }
+    };
+    debug!("Offering {} directly on {:?}", token, peer.addresses);
+
+    Some(Offer {
+        listener,
+        secret: peer.secret.clone(),
+        punch: punch.filter(|_| peer.reflected.is_some()),
+        status: Some((http.clone(), format!("{server}/{token}?status=true"))),
+        announcer: None
+    })
+}
+
+impl Offer {
+    // nobody in between to give up through, this waits until someone with the secret connects
+    pub fn unrelayed(listener: TcpListener, secret: String, announcer: JoinHandle<()>) -> Self {
+        Offer {
+            listener,
+            secret,
+            punch: None,
+            status: None,
+            announcer: Some(announcer)
+        }
+    }
+
+    // Some once the downloader has shown it got the offer from the relay, None when it's going through the relay instead
+    pub async fn wait(self) -> Option<TcpStream> {
+        let mut check = tokio::time::interval(STATUS_INTERVAL);
+        let mut punching: Option<(String, Option<JoinHandle<Option<TcpStream>>>)> = None; // the downloader's answer, with the attempt while it runs
+        let found = loop {
+            tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((socket, address)) => match self.verify(socket).await {
+                        Some(socket) => {
+                            debug!("{} connected directly", address);
+                            break Some(socket);
+                        },
+                        None => debug!("{} connected without the secret, ignoring it", address)
+                    },
+                    Err(e) => {
+                        warn!("Stopped listening for a direct connection: {}", e);
+                        break None;
+                    }
+                },
+                punched = async { punching.as_mut().and_then(|(_, task)| task.as_mut()).expect("only polled while punching").await }, if punching.as_ref().is_some_and(|(_, task)| task.is_some()) => {
+                    if let Some((_, task)) = punching.as_mut() {
+                        *task = None; // not tried again until a different answer comes
+                    }
+                    if let Ok(Some(socket)) = punched {
+                        match self.verify(socket).await {
+                            Some(socket) => break Some(socket),
+                            None => debug!("The downloader punched through without the secret, ignoring it")
+                        }
+                    }
+                },
+                _ = check.tick() => match self.check().await {
+                    Check::GivenUp => break None,
+                    Check::Answered(answer) if punching.as_ref().is_none_or(|(tried, _)| *tried != answer) => match (self.punch, answer.parse::<SocketAddr>()) {
+                        (Some(local), Ok(remote)) => {
+                            debug!("The downloader is punching through from {}, connecting back", remote);
+                            if let Some((_, Some(task))) = punching.take() {
+                                task.abort();
+                            }
+                            punching = Some((answer, Some(tokio::spawn(punch(local, remote)))));
+                        },
+                        _ => debug!("Can't punch through to the downloader at {}", answer)
+                    },
+                    _ => {}
+                }
+            }
+        };
+        if let Some((_, Some(task))) = punching {
+            task.abort();
+        }
+        found
+    }
+
+    async fn verify(&self, mut socket: TcpStream) -> Option<TcpStream> {
+        let mut presented = vec![0; self.secret.len() + 1];
+        match tokio::time::timeout(SECRET_TIMEOUT, socket.read_exact(&mut presented)).await {
+            Ok(Ok(_)) if presented == format!("{}\n", self.secret).as_bytes() => Some(socket),
+            _ => None
+        }
+    }
+
+    // a browser can't connect directly, and starting a download through the relay is as good as giving up
+    async fn check(&self) -> Check {
+        let (http, status) = match &self.status {
+            Some(status) => status,
+            None => return Check::Waiting
+        };
+        let meta = match http.client().get(status).send().await {
+            Ok(response) => response.json::<FileMetadata>().await,
+            Err(e) => {
+                debug!("Could not check on the direct offer: {}", e);
+                return Check::Waiting;
+            }
+        };
+        match meta {
+            Ok(meta) if meta.download_locked() => Check::GivenUp,
+            Ok(meta) => match meta.get_peer() {
+                Some(peer) if peer.failed => Check::GivenUp,
+                Some(peer) => match &peer.answer {
+                    Some(answer) => Check::Answered(answer.clone()),
+                    None => Check::Waiting
+                },
+                None => Check::GivenUp
+            },
+            Err(e) => {
+                error!("Failed to parse download metadata. Was the upload deleted? {:?}", e);
+                Check::GivenUp
+            }
+        }
+    }
+}
+
+// what the relay says about the offer on each status check
+enum Check {
+    Waiting,
+    Answered(String), // the downloader is punching through from here
+    GivenUp,
+}
+
+// the file goes in length prefixed pieces after the header, so a connection dropping early can't pass for the end of it
+pub async fn send(mut socket: TcpStream, header: &PeerHeader, mut stream: ByteStream) -> io::Result<()> {
+    let mut line = serde_json::to_vec(header)?;
+    line.push(b'\n');
+    socket.write_all(&line).await?;
+
+    while let Some(chunk) = stream.next().await {
+        for frame in chunk?.chunks(MAX_FRAME) {
+            socket.write_u32(frame.len() as u32).await?;
+            socket.write_all(frame).await?;
+        }
+    }
+    socket.write_u32(0).await?;
+    socket.shutdown().await
+}
+
+// tries each address the uploader offered, the first one that takes the secret sends its header and then the file
+pub async fn connect(offer: &PeerOffer) -> Option<(PeerHeader, ByteStream)> {
+    for address in &offer.addresses {
+        match tokio::time::timeout(CONNECT_TIMEOUT, fetch(address, &offer.secret)).await {
+            Ok(Ok(found)) => return Some(found),
+            Ok(Err(e)) => debug!("Could not get the file from {}: {}", address, e),
+            Err(_) => debug!("Timed out connecting to {}", address)
+        }
+    }
+    None
+}
+
+async fn fetch(address: &str, secret: &str) -> io::Result<(PeerHeader, ByteStream)> {
+    let socket = TcpStream::connect(address).await?;
+    receive(socket, secret, address).await
+}
+
+// when none of the offered addresses answer, the downloader tells the uploader (through the relay) where its NAT will have it
+// coming from, and both connect to each other at once
+pub async fn punch_through(http: &Http, download_path: &Url, token: &str, offer: &PeerOffer) -> Option<(PeerHeader, ByteStream)> {
+    let remote: SocketAddr = offer.reflected.as_ref()?.parse().ok()?;
+    let server = download_path.join(".").ok()?.as_str().trim_end_matches('/').to_string();
+    let reflector = server_info(http, &server).await?.reflector?;
+    let (local, reflected) = match reflect(any_address(true, 0), &server, reflector).await {
+        Some(reflected) => reflected,
+        None => reflect(any_address(false, 0), &server, reflector).await? // no ipv6 on this machine
+    };
+
+    let url = download_path.join(&format!("{token}/peer")).ok()?;
+    match http.send(http.client().post(url).form(&[("answer", reflected.to_string())])).await {
+        Ok(response) if response.status().is_success() => {},
+        Ok(response) => {
+            debug!("The relay didn't pass on where to punch through to: {}", response.text().await.unwrap_or_default());
+            return None;
+        },
+        Err(e) => {
+            debug!("Could not tell the uploader where to punch through to: {}", e);
+            return None;
+        }
+    }
+
+    let socket = punch(local, remote).await?;
+    match receive(socket, &offer.secret, &remote.to_string()).await {
+        Ok(found) => Some(found),
+        Err(e) => {
+            debug!("Punched through to {}, but could not get the file: {}", remote, e);
+            None
+        }
+    }
+}
+
+// the secret goes first, then the uploader's header comes back before the file
+async fn receive(mut socket: TcpStream, secret: &str, address: &str) -> io::Result<(PeerHeader, ByteStream)> {
+    socket.write_all(format!("{secret}\n").as_bytes()).await?;
+
+    let mut reader = BufReader::new(socket);
+    let mut line = vec![];
+    (&mut reader).take(MAX_HEADER).read_until(b'\n', &mut line).await?;
+    let header: PeerHeader = serde_json::from_slice(&line)?;
+    debug!("Got {} directly from {}", header.file_name, address);
+
+    let body: ByteStream = Box::pin(stream! {
+        loop {
+            let length = match reader.read_u32().await {
+                Ok(length) => length as usize,
+                Err(e) => {
+                    yield Err(io::Error::new(e.kind(), "the uploader disconnected before sending all of it"));
+                    return;
+                }
+            };
+            if length == 0 {
+                return;
+            }
+            if length > MAX_FRAME {
+                yield Err(io::Error::other("the uploader sent more than expected at once"));
+                return;
+            }
+            let mut frame = vec![0; length];
+            if let Err(e) = reader.read_exact(&mut frame).await {
+                yield Err(e);
+                return;
+            }
+            yield Ok(Bytes::from(frame));
+        }
+    });
+    Ok((header, body))
+}
+
+// tells the uploader, through the relay, to stop waiting and send it there instead
+pub async fn give_up(http: &Http, download_path: &Url, token: &str) -> Result<(), Failure> {
+    let url = match download_path.join(&format!("{token}/peer")) {
+        Ok(url) => url,
+        Err(e) => {
+            error!("Could not build the url to give up on the direct download: {}", e);
+            return Err(Failure::Other);
+        }
+    };
+    match http.send(http.client().post(url).form(&[("failed", "true")])).await {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => {
+            let status = response.status();
+            error!("The relay didn't take the direct download being given up: {}", response.text().await.unwrap_or_default());
+            Err(Failure::status(status))
+        },
+        Err(e) => {
+            error!("Failed to connect to server: {}", e);
+            Err(Failure::request(&e))
+        }
+    }
+}
@@ -0,0 +1,11 @@
+use std::str::FromStr;
+use bytesize::ByteSize;
+
+// rates are given like curl's --limit-rate, "500K" or "2MB" are both per second
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    match ByteSize::from_str(rate) {
+        Ok(size) if size.as_u64() > 0 => Ok(size.as_u64()),
+        Ok(_) => Err("The rate limit must be more than zero".to_string()),
+        Err(e) => Err(format!("Invalid rate {}: {}", rate, e)),
+    }
+}
@@ -0,0 +1,83 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher};
+use indicatif::HumanBytes;
+use tracing::{debug, error};
+
+use crate::utils::stats::StatsReport;
+use super::{exit::Failure, token::sign_with_keys_at, StatsArgs};
+
+pub async fn stats(config: StatsArgs) -> Result<(), Failure> {
+    let (server, username, key) = config.args.get_absolute();
+    let http = config.args.http(&server)?;
+    if username == "default" {
+        error!("Stats are only kept for authenticated users, set a username with --username");
+        return Err(Failure::Other);
+    }
+
+    // the relay only takes each challenge once, the nonce just has to be different every time
+    let nonce = format!("{:016x}{:016x}", RandomState::new().hash_one(0u8), RandomState::new().hash_one(1u8));
+    let challenge = format!("stats:{}:{}:{}", username, chrono::Utc::now().timestamp(), nonce);
+    let signatures = sign_with_keys_at(&challenge, &key, config.args.key_passphrase_file.as_deref());
+    if signatures.is_empty() {
+        error!("Could not sign the stats request with any key in {}", key);
+        return Err(Failure::Other);
+    }
+
+    let signatures = match serde_json::to_string(&signatures) {
+        Ok(s) => s,
+        Err(_) => {
+            error!("Could not convert signatures to JSON");
+            return Err(Failure::Other);
+        }
+    };
+
+    let res = http.client().post(format!("{server}/api/v1/me/stats"))
+        .form(&[("user", username.clone()), ("challenge", challenge), ("signature", signatures)])
+        .send().await;
+    debug!("Request: {:?}", res);
+
+    let response = match res {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Failed to connect to Beam server: {:?}", e);
+            return Err(Failure::request(&e));
+        }
+    };
+    if !response.status().is_success() {
+        let status = response.status();
+        error!("Non-success response from Beam server: {:?}", response.text().await);
+        return Err(Failure::status(status));
+    }
+
+    let report = match response.json::<StatsReport>().await {
+        Ok(report) => report,
+        Err(e) => {
+            error!("Failed to parse stats: {:?}", e);
+            return Err(Failure::Other);
+        }
+    };
+
+    if config.json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => error!("Could not print stats as JSON: {:?}", e),
+        }
+        return Ok(());
+    }
+
+    println!("Stats for {} on {}", report.user, server);
+    println!("  Tokens created:   {}", report.tokens);
+    println!("  Uploads finished: {}", report.uploads);
+    println!("  Bytes uploaded:   {}", HumanBytes(report.bytes as u64));
+    match report.average_speed {
+        Some(speed) => println!("  Average speed:    {}/s", HumanBytes(speed)),
+        None => println!("  Average speed:    -"),
+    }
+    println!("  Top compression:  {}", report.top_compression.unwrap_or("-".to_string()));
+    if !report.bytes_by_month.is_empty() {
+        println!("  By month:");
+        for (month, bytes) in report.bytes_by_month {
+            println!("    {}  {}", month, HumanBytes(bytes as u64));
+        }
+    }
+    Ok(())
+}
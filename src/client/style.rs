@@ -0,0 +1,123 @@
+use std::{fmt::Display, sync::{atomic::{AtomicBool, Ordering}, Mutex}};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use super::ipc::{self, IpcEvent};
+
+// beam down --tee writes the file to stdout, so anything said along the way has to go to stderr instead
+static STDOUT_TAKEN: AtomicBool = AtomicBool::new(false);
+
+pub fn take_stdout() {
+    STDOUT_TAKEN.store(true, Ordering::Relaxed);
+}
+
+pub fn stdout_taken() -> bool {
+    STDOUT_TAKEN.load(Ordering::Relaxed)
+}
+
+// --quiet leaves only errors, and the link since it's what beam up is for
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// beam up --parallel draws every upload's bar together, under one for the whole queue
+static BARS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+pub fn start_queue(files: u64) -> ProgressBar {
+    if is_quiet() {
+        return ProgressBar::hidden();
+    }
+    let bars = MultiProgress::new();
+    let queue = bars.add(ProgressBar::new(files));
+    queue.set_style(ProgressStyle::with_template("[{elapsed_precise}] {pos}/{len} files {msg}").unwrap());
+    *BARS.lock().unwrap() = Some(bars);
+    queue
+}
+
+pub fn end_queue() {
+    *BARS.lock().unwrap() = None;
+}
+
+fn queued() -> bool {
+    BARS.lock().unwrap().is_some()
+}
+
+fn write(message: impl Display) {
+    if let Some(bars) = BARS.lock().unwrap().as_ref() { // printed above the bars so they aren't torn up
+        let _ = bars.println(message.to_string());
+        return;
+    }
+    match stdout_taken() {
+        true => eprintln!("{}", message),
+        false => println!("{}", message)
+    }
+}
+
+pub fn say(message: impl Display) {
+    if !is_quiet() {
+        write(message);
+    }
+}
+
+// something the command is run to get, like a key. --quiet keeps the value on its own line and drops the label
+pub fn say_value(label: &str, value: impl Display) {
+    match is_quiet() {
+        true => write(value),
+        false => write(format!("{}: {}", label, value))
+    }
+}
+
+// https://no-color.org, any non-empty value counts
+pub fn no_color_requested() -> bool {
+    match std::env::var("NO_COLOR") {
+        Ok(v) => !v.is_empty(),
+        Err(_) => false
+    }
+}
+
+// without a length (stdin, or a relay that didn't say) there's nothing to fill a bar or estimate from, so it only counts.
+// plain output is for limited terminals and screen readers: no colors, no block art, ascii bars
+pub fn progress_style(known_length: bool, plain: bool) -> ProgressStyle {
+    match (known_length, plain) {
+        (true, true) => ProgressStyle::with_template("[{elapsed_precise}] [{bar:40}] {bytes:>7}/{total_bytes:7} {binary_bytes_per_sec:>11} eta {eta:3} {msg}")
+            .unwrap()
+            .progress_chars("=> "),
+        (true, false) => ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>7}/{total_bytes:7} {binary_bytes_per_sec:>11} eta {eta:3} {msg}")
+            .unwrap(),
+        (false, true) => ProgressStyle::with_template("[{elapsed_precise}] {spinner} {bytes:>7} {binary_bytes_per_sec:>11} {msg}")
+            .unwrap()
+            .tick_chars("-\\|/ "),
+        (false, false) => ProgressStyle::with_template("[{elapsed_precise}] {spinner:.cyan} {bytes:>7} {binary_bytes_per_sec:>11} {msg}")
+            .unwrap()
+    }
+}
+
+// hidden with --quiet, ipc still follows it
+pub fn progress_bar(len: u64, plain: bool) -> ProgressBar {
+    let bar = ProgressBar::new(len);
+    bar.set_style(progress_style(len > 0, plain));
+    if is_quiet() {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+    } else if let Some(bars) = BARS.lock().unwrap().as_ref() {
+        return bars.add(bar);
+    }
+    bar
+}
+
+// the link is always printed as text, the QR code is only extra
+pub fn print_link(label: &str, url: &str, plain: bool) {
+    ipc::emit(IpcEvent::Link { label: label.to_string(), url: url.to_string() });
+    if is_quiet() { // on its own, for scripts to read
+        write(url);
+        return;
+    }
+    if !plain && !stdout_taken() && !queued() { // the code can only be drawn on stdout, and not between bars
+        qr2term::print_qr(url).expect("Could not generate QR code");
+    }
+    say(format!("\n{}: {}\n\n", label, url));
+}
@@ -1,128 +1,319 @@
-use std::{fs, path::{Path, PathBuf}};
+use std::{collections::BTreeMap, fs, io::Write, path::{Path, PathBuf}, process::{Command, Stdio}, sync::{Mutex, Once}};
 
 use ssh_key::{PrivateKey, SshSig};
 use tracing::{debug, error, trace, warn};
 
-use crate::utils::metadata::FileMetadata;
+use crate::utils::{info::ServerInfo, metadata::FileMetadata};
 
-pub async fn get_upload_token(username: &String, file_len: usize, request_path: String) -> Option<FileMetadata> {
-    let params = [("user", username.clone()), ("file-size", file_len.to_string())];
+use super::{exit::Failure, http::Http, style, NewTokenArgs};
 
-    let client = reqwest::Client::new();
-    let res = client.post(request_path)
-        .form(&params)
-        .send().await;
+// asked once for each relay, beam cp can be talking to two of them
+static INFO: Mutex<BTreeMap<String, Option<ServerInfo>>> = Mutex::new(BTreeMap::new());
+
+// None for relays from before /api/info, which only get the server header checked
+pub async fn server_info(http: &Http, server: &str) -> Option<ServerInfo> {
+    if let Some(info) = cached_info(server) {
+        return info;
+    }
+    let info = match http.send(http.client().get(format!("{server}/api/info"))).await {
+        Ok(response) if response.status().is_success() => match response.json::<ServerInfo>().await {
+            Ok(info) => {
+                debug!("{} runs ByteBeam {} with {:?}", server, info.version, info.features);
+                Some(info)
+            },
+            Err(e) => {
+                debug!("Could not read {}'s info: {:?}", server, e);
+                None
+            }
+        },
+        Ok(response) => {
+            debug!("{} has no /api/info ({}), it may be out of date", server, response.status());
+            None
+        },
+        Err(e) => {
+            debug!("Could not ask {} for its info: {}", server, e);
+            None
+        }
+    };
+    INFO.lock().unwrap().insert(server.to_string(), info.clone());
+    info
+}
+
+fn cached_info(server: &str) -> Option<Option<ServerInfo>> {
+    INFO.lock().unwrap().get(server).cloned()
+}
+
+// options are any extra create parameters, like expires or receiver
+pub async fn get_upload_token(http: &Http, username: &str, file_len: usize, options: Vec<(&str, String)>, server: &str, request_path: String) -> Result<FileMetadata, Failure> {
+    let mut params = vec![("user", username.to_string()), ("file-size", file_len.to_string())];
+    params.extend(options);
+    request_upload_token(http, &params, server, request_path).await
+}
+
+async fn request_upload_token(http: &Http, params: &Vec<(&str, String)>, server: &str, request_path: String) -> Result<FileMetadata, Failure> {
+    let info = server_info(http, server).await;
+    let res = http.send(http.client().post(request_path)
+        .form(params)).await;
 
     debug!("Request: {:?}", res);
 
-    let parsed = parse_response(res).await;
+    let parsed = parse_response(res, info.as_ref()).await;
 
     match parsed {
-        Some(metadata) => {
+        Ok(metadata) => {
             debug!("File metadata received: {:?}", metadata);
-            Some(metadata)
+            Ok(metadata)
         },
-        None => {
+        Err(failure) => {
             error!("Error parsing response");
-            None
+            Err(failure)
         }
     }
 }
 
+// expiry is given as a number and a unit, "90m", "12h", or "2d", and sent to the server in seconds
+pub fn parse_expiry(expiry: &str) -> Result<i64, String> {
+    let expiry = expiry.trim();
+    let split = expiry.find(|c: char| !c.is_ascii_digit()).unwrap_or(expiry.len());
+    let (number, unit) = expiry.split_at(split);
+    let number: i64 = match number.parse() {
+        Ok(n) if n > 0 => n,
+        _ => return Err(format!("Invalid expiry {}, expected something like 12h", expiry)),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" | "" => 60 * 60, // a bare number is hours
+        "d" => 60 * 60 * 24,
+        _ => return Err(format!("Unknown unit {} in expiry, use s, m, h, or d", unit)),
+    };
+    number.checked_mul(multiplier).ok_or(format!("Expiry {} is too long", expiry))
+}
+
+// hands out a destination ahead of time, whoever has the key can upload to it later
+pub async fn new_token(config: NewTokenArgs) -> Result<(), Failure> {
+    let (server, username, key) = config.args.get_absolute();
+    let http = config.args.http(&server)?;
+
+    let mut params = vec![("user", username.clone())];
+    if let Some(expires) = config.expires {
+        params.push(("expires", expires.to_string()));
+    }
+
+    let request_path = format!("{server}/{}", urlencoding::encode(&config.name));
+    let metadata = match request_upload_token(&http, &params, &server, request_path).await {
+        Ok(metadata) => do_run_upgrade_on_metadata(&http, metadata, &username, &key, &server, config.args.key_passphrase_file.as_deref()).await,
+        Err(failure) => {
+            error!("Failed to get upload token");
+            return Err(failure);
+        }
+    };
+
+    let (token, upload_key) = metadata.get_upload_info();
+    if config.expires.is_some() && metadata.get_expiry().is_none() {
+        warn!("The server did not accept an expiry, the token will be culled like any other once it goes stale");
+    }
+
+    style::say(format!("Token: {}", token));
+    style::say(format!("Key: {}", upload_key));
+    style::say_value("Upload URL", format!("{server}/{token}/{upload_key}"));
+    if let Some(expires) = metadata.get_expiry() {
+        style::say(format!("Expires: {}", expires.to_rfc2822()));
+    }
+    style::say(format!("\nUpload later with: beam up --token {token}/{upload_key} [FILE]"));
+    let send_path = match std::env::var("PROXIED_SERVER") {
+        Ok(s) => format!("{s}/{token}"),
+        Err(_) => format!("{server}/{token}")
+    };
+    style::print_link("Download will be available from", &send_path, config.args.plain);
+    Ok(())
+}
+
+
+static BANNER: Once = Once::new();
+
+// the server can send an announcement with each token, but it only needs to be shown once
+fn print_banner(metadata: &FileMetadata) {
+    if let Some(banner) = &metadata.banner {
+        BANNER.call_once(|| style::say(format!("Server message: {}\n", banner)));
+    }
+}
 
-async fn parse_response(res: Result<reqwest::Response, reqwest::Error>) -> Option<FileMetadata> {
+async fn parse_response(res: Result<reqwest::Response, reqwest::Error>, info: Option<&ServerInfo>) -> Result<FileMetadata, Failure> {
     match res {
         Ok(response) => {
             if !response.status().is_success() {
+                let status = response.status();
                 error!(
                     "Non-success response from Beam server: {:?}", response.text().await
                 );
-                return None;
+                return Err(Failure::status(status));
             }
             let wanted_version = format!("ByteBeam/{}", env!("CARGO_PKG_VERSION"));
-            // warn if the versions are different
-            match response.headers().get("server") {
-                Some(version) => match version.to_str() {
+            // a relay that says what it can do is adapted to instead, the version alone doesn't matter then
+            match (info, response.headers().get("server")) {
+                (Some(info), _) => if info.version != env!("CARGO_PKG_VERSION") {
+                    debug!("ByteBeam Server is {}, this is {}", info.version, wanted_version);
+                },
+                (None, Some(version)) => match version.to_str() {
                     Ok(version_str) => if version_str != wanted_version {
                         warn!("ByteBeam Server version does not match the expected version. It may be outdated and there may be instability! Got {}, wanted {}", version_str, wanted_version);
                     }
                     Err(_) => warn!("ByteBeam Server did not return a proper version string. It may be outdated and there may be instability!")
                 }
-                None => {
+                (None, None) => {
                     warn!("ByteBeam Server did not return a version. It may be outdated and there may be instability!");
                 }
             }
             match response.json::<FileMetadata>().await {
-                Ok(metadata) => Some(metadata),
+                Ok(metadata) => {
+                    print_banner(&metadata);
+                    Ok(metadata)
+                },
                 Err(e) => {
                     error!("Failed to parse file metadata: {:?}.", e);
-                    return None;
+                    Err(Failure::Other)
                 }
             }
         },
         Err(e) => {
             error!("Failed to connect to Beam server: {:?}", e);
-            return None;
+            Err(Failure::request(&e))
         }
     }
 }
 
-pub async fn get_upgrade(current_path: &String, challenge: &Vec<String>) -> Option<FileMetadata> {
+pub async fn get_upgrade(http: &Http, server: &str, current_path: &String, challenge: &Vec<String>) -> Result<FileMetadata, Failure> {
     let cstr = match serde_json::to_string(&challenge) {
         Ok(cstr) => cstr,
         Err(_) => {
             error!("Could not convert challenge to JSON");
-            return None
+            return Err(Failure::Other)
         }
     };
     let params = [("challenge", cstr)];
 
-    let client = reqwest::Client::new();
-    let res = client.post(current_path)
-        .form(&params)
-        .send().await;
+    let res = http.send(http.client().post(current_path)
+        .form(&params)).await;
 
         debug!("Request: {:?}", res);
 
-        let parsed = parse_response(res).await;
+        let parsed = parse_response(res, cached_info(server).flatten().as_ref()).await;
     
         match parsed {
-            Some(metadata) => {
+            Ok(metadata) => {
                 debug!("File metadata received: {:?}", metadata);
-                Some(metadata)
+                Ok(metadata)
             },
-            None => {
+            Err(failure) => {
                 error!("Error parsing response");
-                None
+                Err(failure)
             }
         }
 }
 
-pub fn sign_challenge(challenge: &String, keys: &Vec<PrivateKey>) -> Vec<SshSig> {
+// armored, so they can go straight into a request
+pub fn sign_challenge(challenge: &String, keys: &Vec<(PathBuf, PrivateKey)>) -> Vec<String> {
     let mut output = vec![];
-    for key in keys {
-        match key.sign("bytebeam", ssh_key::HashAlg::Sha512, challenge.as_bytes()) {
+    for (path, key) in keys {
+        let signed = match key.public_key().key_data().is_sk_ed25519() || key.public_key().key_data().is_sk_ecdsa_p256() {
+            true => sign_with_security_key(challenge, path),
+            false => key.sign("bytebeam", ssh_key::HashAlg::Sha512, challenge.as_bytes()).map_err(|e| format!("{:?}", e))
+        };
+        match signed {
             Ok(signature) => {
                 debug!("Signed {} with key: {}", challenge, key.fingerprint(ssh_key::HashAlg::Sha512));
-                output.push(signature);
+                match signature.to_pem(ssh_key::LineEnding::default()) {
+                    Ok(pem) => output.push(match certificate_for(path) {
+                        Some(certificate) => format!("{}\n{}", pem.trim_end(), certificate),
+                        None => pem
+                    }),
+                    Err(e) => error!("Failed to parse PEM: {}", e),
+                }
             },
-            Err(e) => error!("Failed to sign with key: {:?}", e),
+            Err(e) => error!("Failed to sign with key: {}", e),
         }
     }
     output
 }
 
-pub fn get_privkey(data: &String) -> Option<PrivateKey> {
-    match ssh_key::PrivateKey::from_openssh(data) {
-        Ok(key) => Some(key),
+// a certificate next to the key, like id_ed25519-cert.pub, goes along after the armor for relays that trust the CA that signed it
+fn certificate_for(path: &Path) -> Option<String> {
+    let mut certificate_path = path.as_os_str().to_owned();
+    certificate_path.push("-cert.pub");
+    let certificate = fs::read_to_string(&certificate_path).ok()?;
+    debug!("Sending certificate {:?} along with the signature", certificate_path);
+    Some(certificate.trim().to_string())
+}
+
+// the private half of a security key never leaves it, so ssh-keygen has it (or the agent holding it) sign, which is also what asks for the touch
+fn sign_with_security_key(challenge: &String, path: &Path) -> Result<SshSig, String> {
+    debug!("{} is a security key, signing with ssh-keygen", path.display());
+    let mut child = Command::new("ssh-keygen").args(["-Y", "sign", "-n", "bytebeam", "-f"]).arg(path)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::inherit())
+        .spawn().map_err(|e| super::clipboard::missing("ssh-keygen", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(challenge.as_bytes()).map_err(|e| format!("Could not send the challenge to ssh-keygen: {}", e))?;
+    }
+    let signed = child.wait_with_output().map_err(|e| format!("ssh-keygen failed: {}", e))?;
+    if !signed.status.success() {
+        return Err(format!("ssh-keygen could not sign with {}, it exited with {}", path.display(), signed.status));
+    }
+    String::from_utf8_lossy(&signed.stdout).parse::<SshSig>().map_err(|e| format!("ssh-keygen gave back something that isn't a signature: {:?}", e))
+}
+
+// armored signatures from every key at a path, for challenges that aren't tied to a token
+pub fn sign_with_keys_at(challenge: &String, key: &String, passphrase_file: Option<&str>) -> Vec<String> {
+    let keys = get_key_or_keys_from_path(&PathBuf::new().join(shellexpand::tilde(key).into_owned()), passphrase_file);
+    sign_challenge(challenge, &keys)
+}
+
+// the file is for scripts, otherwise each encrypted key asks for its own passphrase
+fn key_passphrase(path: &Path, passphrase_file: Option<&str>) -> Option<String> {
+    if let Some(file) = passphrase_file {
+        let file = shellexpand::tilde(file).into_owned();
+        return match fs::read_to_string(&file) {
+            Ok(passphrase) => Some(passphrase.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) => {
+                error!("Could not read the key passphrase from {}: {}", file, e);
+                None
+            }
+        };
+    }
+    match rpassword::prompt_password(format!("Passphrase for {} (empty to skip it): ", path.display())) {
+        Ok(passphrase) if passphrase.is_empty() => None,
+        Ok(passphrase) => Some(passphrase),
+        Err(e) => {
+            error!("{} is encrypted and the passphrase could not be read: {}. Use --key-passphrase-file when there's no terminal", path.display(), e);
+            None
+        }
+    }
+}
+
+pub fn get_privkey(data: &String, path: &Path, passphrase_file: Option<&str>) -> Option<PrivateKey> {
+    let key = match ssh_key::PrivateKey::from_openssh(data) {
+        Ok(key) => key,
         Err(e) => {
             error!("Failed to parse private key: {:?}", e);
+            return None
+        }
+    };
+    if !key.is_encrypted() {
+        return Some(key)
+    }
+    let passphrase = key_passphrase(path, passphrase_file)?;
+    match key.decrypt(passphrase) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            error!("Could not decrypt {}, is the passphrase right? {}", path.display(), e);
             None
         }
     }
 }
 
-pub fn get_key_or_keys_from_path(path: &Path) -> Vec<PrivateKey> {
+// the path is kept along with each key, security keys are signed with by pointing ssh-keygen at it
+// the passphrase file is for every encrypted key there, without it each one is asked for
+pub fn get_key_or_keys_from_path(path: &Path, passphrase_file: Option<&str>) -> Vec<(PathBuf, PrivateKey)> {
     let mut output = vec![];
     // test if a folder
     if path.is_dir() { // we need to scan each file now
@@ -161,29 +352,33 @@ pub fn get_key_or_keys_from_path(path: &Path) -> Vec<PrivateKey> {
                         continue
                     }  
                 };
-                match get_privkey(&data) {
-                    Some(key) => output.push(key),
-                    None => error!("Failed to parse private key from file: {:?}", file_path),
+                match get_privkey(&data, &file_path, passphrase_file) {
+                    Some(key) => output.push((file_path, key)),
+                    None => error!("Failed to load private key from file: {:?}", file_path),
                 }
             }
         }
     } else { // we need to check if it is a file
         let data = fs::read_to_string(path).expect("Failed to read file");
-        match get_privkey(&data) {
-            Some(key) => output.push(key),
-            None => error!("Failed to parse private key from file: {:?}", path),
+        match get_privkey(&data, path, passphrase_file) {
+            Some(key) => output.push((path.to_path_buf(), key)),
+            None => error!("Failed to load private key from file: {:?}", path),
         }
     }
 
     output
 }
 
-pub async fn do_run_upgrade_on_metadata(metadata: FileMetadata, username: &String, key: &String, server: &String) -> FileMetadata {
-    if *username != "default".to_string() { // this is worth authentication now
+pub async fn do_run_upgrade_on_metadata(http: &Http, metadata: FileMetadata, username: &String, key: &String, server: &String, passphrase_file: Option<&str>) -> FileMetadata {
+    if metadata.authenticated() { // the relay took the client certificate instead
+        debug!("Already authenticated as {}, no challenge to sign", username);
+        return metadata
+    }
+    if username != "default" { // this is worth authentication now
         // we need to expand the key
         let expanded = shellexpand::tilde(&key).into_owned();
         let config_path = PathBuf::new().join(&expanded);
-        let keys = get_key_or_keys_from_path(&config_path);
+        let keys = get_key_or_keys_from_path(&config_path, passphrase_file);
         let challenges = match metadata.get_challenge_details() {
             Some(challenge) => {
                 if *username != challenge.1.clone() {
@@ -197,21 +392,12 @@ pub async fn do_run_upgrade_on_metadata(metadata: FileMetadata, username: &Strin
             }
         };
         // now we can try to update things
-        if challenges.len() == 0 {
+        if challenges.is_empty() {
             warn!("Could not sign the challenge, running with no authentication!");
             return metadata
         } else {
-            let mut testing_val = vec![];
-            for chal in challenges {
-                match chal.to_pem(ssh_key::LineEnding::default()) {
-                    Ok(pem) => testing_val.push(pem),
-                    Err(e) => error!("Failed to parse PEM: {}", e),
-                }
-            }
-
-
-            match get_upgrade(&format!("{server}/{}", metadata.get_upload_info().0), &testing_val).await {
-                Some(meta) => {
+            match get_upgrade(http, server, &format!("{server}/{}", metadata.get_upload_info().0), &challenges).await {
+                Ok(meta) => {
                     if !meta.authenticated() {
                         warn!("Server returned metadata but it was not authenticated! Proceeding with new data!");
                     } else {
@@ -219,7 +405,7 @@ pub async fn do_run_upgrade_on_metadata(metadata: FileMetadata, username: &Strin
                     }
                     return meta
                 },
-                None => {
+                Err(_) => {
                     warn!("Could not properly authenticate, proceeding normally!");
                     return metadata
                 }
@@ -227,5 +413,5 @@ pub async fn do_run_upgrade_on_metadata(metadata: FileMetadata, username: &Strin
         }
     }
     trace!("Using default user. No authentication will happen");
-    return metadata
+    metadata
 }
\ No newline at end of file
@@ -0,0 +1,174 @@
+use std::{thread, time::Duration};
+use indicatif::HumanBytes;
+use ratatui::{crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers}, layout::{Constraint, Layout}, style::{Modifier, Style}, text::Line, widgets::{Block, Row, Table, TableState}, DefaultTerminal, Frame};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tracing::error;
+
+use crate::utils::metadata::{FileMetadata, FileState};
+use super::{admin, clipboard::{self, Clip}, exit::Failure, http::Http, TuiArgs};
+
+// where the rows come from, links polled one at a time or the whole relay for an admin
+enum Source {
+    Links(Vec<String>),
+    Relay { server: String, session: String },
+}
+
+struct Transfer {
+    link: String,
+    meta: Result<FileMetadata, String>, // why it couldn't be read, so one expired token doesn't hide the others
+}
+
+fn link(server: &str, token: &str) -> String {
+    match token.starts_with("http://") || token.starts_with("https://") {
+        true => token.trim_end_matches('/').to_string(),
+        false => format!("{server}/{}", urlencoding::encode(token))
+    }
+}
+
+// nothing is logged from here on, it would be drawn over the table
+async fn status(http: &Http, link: &str) -> Result<FileMetadata, String> {
+    let response = http.client().get(format!("{link}?status=true")).send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("The relay answered {}", response.status()));
+    }
+    response.json::<FileMetadata>().await.map_err(|e| e.to_string())
+}
+
+impl Source {
+    async fn fetch(&self, http: &Http) -> Result<Vec<Transfer>, String> {
+        match self {
+            Source::Links(links) => {
+                let mut transfers = Vec::with_capacity(links.len());
+                for link in links {
+                    transfers.push(Transfer { link: link.clone(), meta: status(http, link).await });
+                }
+                Ok(transfers)
+            },
+            Source::Relay { server, session } => {
+                let response = http.client().get(format!("{server}/admin/tokens")).bearer_auth(session).send().await.map_err(|e| e.to_string())?;
+                if !response.status().is_success() {
+                    return Err(format!("The relay answered {}", response.status()));
+                }
+                let files = response.json::<Vec<FileMetadata>>().await.map_err(|e| e.to_string())?;
+                Ok(files.into_iter()
+                    .filter(|file| file.get_states().0 == &FileState::InProgress || file.get_states().1 == &FileState::InProgress)
+                    .map(|file| Transfer { link: format!("{server}/{}", file.get_token()), meta: Ok(file) })
+                    .collect())
+            }
+        }
+    }
+
+    // an admin deletes through the admin api, anyone else the same way a p2p upload takes its token back
+    async fn cancel(&self, http: &Http, transfer: &Transfer) -> Result<(), String> {
+        let request = match (self, &transfer.meta) {
+            (Source::Relay { server, session }, Ok(meta)) => http.client().delete(format!("{server}/admin/tokens/{}", urlencoding::encode(meta.get_token()))).bearer_auth(session),
+            _ => http.client().delete(&transfer.link)
+        };
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(format!("The relay answered {}", response.status()))
+        }
+    }
+}
+
+fn row(transfer: &Transfer) -> Row<'static> {
+    let token = transfer.link.rsplit('/').next().unwrap_or_default().to_string();
+    match &transfer.meta {
+        Ok(meta) => {
+            let (upload, download) = meta.get_states();
+            let (uploaded, downloaded) = meta.file_size.get_transferred();
+            Row::new(vec![token, meta.file_name.clone(),
+                format!("{} {}", upload.describe(), HumanBytes(uploaded as u64)),
+                format!("{} {}", download.describe(), HumanBytes(downloaded as u64))])
+        },
+        Err(e) => Row::new(vec![token, e.clone(), String::new(), String::new()])
+    }
+}
+
+fn draw(frame: &mut Frame, title: &str, transfers: &[Transfer], table: &mut TableState, note: &str) {
+    let [main, footer] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+    let widths = [Constraint::Length(24), Constraint::Fill(1), Constraint::Length(24), Constraint::Length(24)];
+    let header = Row::new(["Token", "Name", "Upload", "Download"]).style(Style::new().add_modifier(Modifier::BOLD));
+    let rows = Table::new(transfers.iter().map(row), widths)
+        .header(header)
+        .block(Block::bordered().title(format!(" {} ", title)))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(rows, main, table);
+    frame.render_widget(Line::from(format!(" ↑↓ select  c copy link  x cancel  q quit  {}", note)), footer);
+}
+
+async fn run(terminal: &mut DefaultTerminal, http: &Http, source: &Source, title: &str, mut pressed: UnboundedReceiver<KeyEvent>) -> Result<(), String> {
+    let mut transfers = Vec::new();
+    let mut table = TableState::default().with_selected(0);
+    let mut note = String::new();
+    let mut refresh = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        terminal.draw(|frame| draw(frame, title, &transfers, &mut table, &note)).map_err(|e| format!("Could not draw: {}", e))?;
+        tokio::select! {
+            _ = refresh.tick() => match source.fetch(http).await {
+                Ok(fetched) => transfers = fetched,
+                Err(e) => note = e
+            },
+            key = pressed.recv() => {
+                let Some(key) = key else {
+                    return Ok(());
+                };
+                let selected = table.selected().and_then(|i| transfers.get(i));
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()), // raw mode keeps ctrl-c from being a signal
+                    KeyCode::Up | KeyCode::Char('k') => table.select_previous(),
+                    KeyCode::Down | KeyCode::Char('j') => table.select_next(),
+                    KeyCode::Char('c') => if let Some(transfer) = selected {
+                        note = match clipboard::write(&Clip::Text(transfer.link.clone())).await {
+                            Ok(()) => format!("Copied {}", transfer.link),
+                            Err(e) => e
+                        };
+                    },
+                    KeyCode::Char('x') => if let Some(transfer) = selected {
+                        note = match source.cancel(http, transfer).await {
+                            Ok(()) => format!("Cancelled {}", transfer.link),
+                            Err(e) => e
+                        };
+                        refresh.reset_immediately();
+                    },
+                    _ => ()
+                }
+            }
+        }
+    }
+}
+
+pub async fn tui(config: TuiArgs) -> Result<(), Failure> {
+    let (server, _, _) = config.args.get_absolute();
+    let (http, source, title) = match config.all {
+        true => {
+            let (http, server, session) = admin::sign_in(&config.args).await?;
+            (http, Source::Relay { server: server.clone(), session }, server)
+        },
+        false => {
+            let http = config.args.http(&server)?;
+            (http, Source::Links(config.tokens.iter().map(|token| link(&server, token)).collect()), server)
+        }
+    };
+
+    // crossterm only has a blocking read, so a thread waits on it and passes the key presses over
+    let (keys, pressed) = mpsc::unbounded_channel();
+    thread::spawn(move || loop {
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                if keys.send(key).is_err() {
+                    break;
+                }
+            },
+            Ok(_) => (),
+            Err(_) => break
+        }
+    });
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &http, &source, &title, pressed).await;
+    ratatui::restore();
+    result.map_err(|e| { error!("{}", e); Failure::Other })
+}
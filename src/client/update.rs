@@ -0,0 +1,282 @@
+use std::{env, fs};
+use serde::Deserialize;
+use sha2::Sha256;
+use ssh_key::{PublicKey, SshSig};
+use tracing::{debug, error, info};
+
+use crate::utils::digest::DigestWorker;
+use super::{exit::Failure, style, SelfUpdateArgs};
+
+// release builds can bake in the signing key so users don't need to provide it
+const BUILT_IN_SIGNING_KEY: Option<&str> = option_env!("BEAM_RELEASE_KEY");
+const SIGNATURE_NAMESPACE: &str = "bytebeam-release";
+
+#[derive(Deserialize, Debug)]
+struct Release {
+    tag_name: String,
+    assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReleaseAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn get_asset(&self, name: &str) -> Option<&ReleaseAsset> {
+        self.assets.iter().find(|asset| asset.name == name)
+    }
+}
+
+// releases are published as beam-[os]-[arch], for example beam-linux-x86_64
+fn binary_asset_name() -> String {
+    let name = format!("beam-{}-{}", env::consts::OS, env::consts::ARCH);
+    if env::consts::OS == "windows" {
+        format!("{name}.exe")
+    } else {
+        name
+    }
+}
+
+// compares dotted versions numerically, ignoring a leading v
+fn is_newer(latest: &str, current: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split(['.', '-'])
+            .map_while(|part| part.parse().ok())
+            .collect()
+    };
+    parse(latest) > parse(current)
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) -> Option<bytes::Bytes> {
+    debug!("Fetching {}", url);
+    match client.get(url).send().await {
+        Ok(response) => {
+            if !response.status().is_success() {
+                error!("Failed to fetch {}: {}", url, response.status());
+                return None;
+            }
+            match response.bytes().await {
+                Ok(b) => Some(b),
+                Err(e) => {
+                    error!("Failed to read {}: {:?}", url, e);
+                    None
+                }
+            }
+        },
+        Err(e) => {
+            error!("Failed to connect for {}: {:?}", url, e);
+            None
+        }
+    }
+}
+
+// the feed isn't signed, so the version it names is only trusted once the signed checksums say the same.
+// SHA256SUMS carries it as a "# version [x.y.z]" line, which sha256sum -c skips like any other comment
+fn signed_version(sums: &str) -> Option<&str> {
+    sums.lines()
+        .filter_map(|line| line.trim().strip_prefix('#'))
+        .find_map(|comment| comment.trim().strip_prefix("version "))
+        .map(|version| version.trim().trim_start_matches('v'))
+        .filter(|version| !version.is_empty())
+}
+
+// SHA256SUMS is in the sha256sum format of "[hash]  [file name]"
+fn find_checksum(sums: &str, name: &str) -> Option<String> {
+    sums.lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, file)| file.trim().trim_start_matches('*') == name)
+        .map(|(hash, _)| hash.to_ascii_lowercase())
+}
+
+pub async fn self_update(config: SelfUpdateArgs) -> Result<(), Failure> {
+    let current = env!("CARGO_PKG_VERSION");
+    let client = reqwest::ClientBuilder::new()
+        .user_agent(format!("ByteBeam/{}", current))
+        .build().expect("Could not build update client");
+
+    let release = match fetch(&client, &config.feed).await {
+        Some(body) => match serde_json::from_slice::<Release>(&body) {
+            Ok(release) => release,
+            Err(e) => {
+                error!("Failed to parse the release feed: {:?}", e);
+                return Err(Failure::Other);
+            }
+        },
+        None => return Err(Failure::Other),
+    };
+
+    let newer = is_newer(&release.tag_name, current);
+    if !newer && !config.force {
+        style::say(format!("ByteBeam is up to date ({})", current));
+        return Ok(());
+    }
+
+    if config.check {
+        if newer {
+            style::say(format!("ByteBeam {} is available (currently {}). Run `beam self-update` to install it", release.tag_name, current));
+        } else {
+            style::say(format!("ByteBeam is up to date ({})", current));
+        }
+        return Ok(());
+    }
+
+    let signing_key = match config.signing_key.as_deref().or(BUILT_IN_SIGNING_KEY) {
+        Some(key) => match PublicKey::from_openssh(key) {
+            Ok(key) => key,
+            Err(e) => {
+                error!("Could not parse the release signing key: {:?}", e);
+                return Err(Failure::Other);
+            }
+        },
+        None => {
+            error!("No release signing key is known. Provide one with --signing-key so the download can be verified");
+            return Err(Failure::Other);
+        }
+    };
+
+    let asset_name = binary_asset_name();
+    let (binary, sums, sums_sig) = match (release.get_asset(&asset_name), release.get_asset("SHA256SUMS"), release.get_asset("SHA256SUMS.sig")) {
+        (Some(binary), Some(sums), Some(sig)) => (binary, sums, sig),
+        (None, _, _) => {
+            error!("Release {} has no build for this platform ({})", release.tag_name, asset_name);
+            return Err(Failure::Other);
+        },
+        _ => {
+            error!("Release {} is missing its signed checksums, refusing to update", release.tag_name);
+            return Err(Failure::Other);
+        }
+    };
+
+    // the checksum list is verified first so nothing unverified gets downloaded to disk
+    let sums = match fetch(&client, &sums.browser_download_url).await {
+        Some(s) => s,
+        None => return Err(Failure::Other),
+    };
+    let signature = match fetch(&client, &sums_sig.browser_download_url).await {
+        Some(s) => match String::from_utf8_lossy(&s).parse::<SshSig>() {
+            Ok(sig) => sig,
+            Err(e) => {
+                error!("Failed to parse checksum signature: {:?}", e);
+                return Err(Failure::Other);
+            }
+        },
+        None => return Err(Failure::Other),
+    };
+
+    if let Err(e) = signing_key.verify(SIGNATURE_NAMESPACE, &sums, &signature) {
+        error!("Release checksums are not signed by the expected key: {:?}", e);
+        return Err(Failure::Other);
+    }
+    debug!("Checksum signature verified");
+    let sums = String::from_utf8_lossy(&sums);
+
+    // otherwise an old release, signed back when it was current, could be passed off as the latest to roll users back
+    let version = match signed_version(&sums) {
+        Some(version) => version,
+        None => {
+            error!("Release {} doesn't say its version in its signed checksums, refusing to update", release.tag_name);
+            return Err(Failure::Other);
+        }
+    };
+    if version != release.tag_name.trim_start_matches('v') {
+        error!("The release feed says {} but its signed checksums are for {}, refusing to update", release.tag_name, version);
+        return Err(Failure::Other);
+    }
+    if is_newer(current, version) {
+        error!("Release {} is older than this one ({}), refusing to go back to it", version, current);
+        return Err(Failure::Other);
+    }
+
+    let expected = match find_checksum(&sums, &asset_name) {
+        Some(hash) => hash,
+        None => {
+            error!("No checksum is listed for {}", asset_name);
+            return Err(Failure::Other);
+        }
+    };
+
+    style::say(format!("Downloading ByteBeam {}...", version));
+    let new_binary = match fetch(&client, &binary.browser_download_url).await {
+        Some(b) => b,
+        None => return Err(Failure::Other),
+    };
+
+    let actual = match DigestWorker::digest(Sha256::default(), new_binary.clone()).await {
+        Some(hash) => hash,
+        None => return Err(Failure::Other),
+    };
+    if actual != expected {
+        error!("Checksum mismatch for {}: expected {}, got {}", asset_name, expected, actual);
+        return Err(Failure::Checksum);
+    }
+
+    let current_exe = match env::current_exe() {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Could not find the running binary: {:?}", e);
+            return Err(Failure::Other);
+        }
+    };
+
+    // write next to the binary then rename so a failure never leaves a half-written executable
+    let staged = current_exe.with_extension("update");
+    if let Err(e) = fs::write(&staged, &new_binary) {
+        error!("Failed to write the new binary to {:?}: {:?}", staged, e);
+        return Err(Failure::Other);
+    }
+
+    // a binary that can't be run would leave beam unusable once it's renamed over this one
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = fs::set_permissions(&staged, fs::Permissions::from_mode(0o755)) {
+            error!("Could not mark the new binary as executable: {:?}", e);
+            let _ = fs::remove_file(&staged);
+            return Err(Failure::Other);
+        }
+    }
+
+    if let Err(e) = fs::rename(&staged, &current_exe) {
+        error!("Failed to replace {:?}: {:?}", current_exe, e);
+        let _ = fs::remove_file(&staged);
+        return Err(Failure::Other);
+    }
+
+    info!("Replaced {:?}", current_exe);
+    style::say(format!("Updated ByteBeam from {} to {}", current, version));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUMS: &str = "# version 1.4.0\n\
+        0123abcd  beam-linux-x86_64\n\
+        4567EF01 *beam-windows-x86_64.exe\n";
+
+    #[test]
+    fn reads_the_signed_version() {
+        assert_eq!(signed_version(SUMS), Some("1.4.0"));
+        assert_eq!(signed_version("# version v2.0.1\n"), Some("2.0.1"));
+        assert_eq!(signed_version("0123abcd  beam-linux-x86_64\n"), None);
+        assert_eq!(signed_version("# version \n"), None);
+    }
+
+    #[test]
+    fn finds_checksums_by_name() {
+        assert_eq!(find_checksum(SUMS, "beam-linux-x86_64").as_deref(), Some("0123abcd"));
+        assert_eq!(find_checksum(SUMS, "beam-windows-x86_64.exe").as_deref(), Some("4567ef01")); // binary mode marker
+        assert_eq!(find_checksum(SUMS, "beam-linux"), None);
+    }
+
+    #[test]
+    fn compares_versions_numerically() {
+        assert!(is_newer("v1.10.0", "1.9.3"));
+        assert!(!is_newer("1.4.0", "1.4.0"));
+        assert!(!is_newer("1.3.9", "1.4.0"));
+    }
+}
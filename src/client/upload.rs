@@ -1,38 +1,514 @@
-use std::{sync::{Arc, Mutex}, thread, time::Duration};
+use std::{collections::hash_map::RandomState, hash::BuildHasher, path::PathBuf, sync::{Arc, Mutex}, time::Duration};
+use async_stream::stream;
 use bytes::Bytes;
 use bytesize::ByteSize;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::ProgressBar;
 use reqwest::Body;
-use tokio::io;
+use tokio::{io::{self, AsyncRead, AsyncReadExt}, sync::Semaphore};
 use tokio_util::io::ReaderStream;
 use tracing::{debug, error, warn};
-use tokio_stream::Stream;
+use tokio_stream::{Stream, StreamExt};
 use url::Url;
 
-use crate::{client::token::{do_run_upgrade_on_metadata, get_upload_token}, utils::{compression::Compression, metadata::FileMetadata}};
+use crate::{client::token::{do_run_upgrade_on_metadata, get_upload_token, server_info, sign_with_keys_at}, utils::{bucket::TokenBucket, checksum::{checksum_file, Checksum, ChecksumAlgorithm}, compression::Compression, frames::FRAMES, metadata::{Disposition, FileAttributes, ManifestEntry, MAX_TEXT_SIZE}, digest::DigestWorker}};
 
-use super::{compression::ProgressStream, UploadArgs};
+use super::{browser, clipboard::{self, Clip}, compression::{self, Choice, ProgressStream}, delta, encryption::{self, ByteStream}, exit::Failure, fileio::{self, input_stream, InputStream}, frames, ipc, local, mmap::mmap_stream, notify, peer::{self, PeerHeader}, style, watch, UploadArgs};
 
-pub async fn upload(config: UploadArgs) -> Result<(), ()> {
-    let filepath = config.get_file_path();
+// an upload that was compressed and encrypted by whoever sent it first, passed on to another relay as it is
+pub struct Forwarded {
+    pub file_name: String,
+    pub size: u64, // as it's sent, 0 if it isn't known
+    pub compression: Compression,
+    pub encrypted: bool,
+    pub checksum: Option<Checksum>,
+    pub manifest: Option<Vec<ManifestEntry>>,
+    pub message: Option<String>,
+    pub attributes: Option<FileAttributes>,
+    pub stream: ByteStream,
+}
+
+/// Sends a file the way `beam up` does, printing the link and waiting for the download unless it's stored.
+/// Errors are logged with `tracing` as they happen, the [`Failure`] only says what kind it was, for the exit code.
+pub async fn upload(mut config: UploadArgs) -> Result<(), Failure> {
+    if !config.remote {
+        config.expand_patterns()?;
+    }
+    match (config.remote, config.parallel) {
+        (true, _) => remote(config).await,
+        (false, Some(parallel)) if config.file.len() > 1 => queue(config, parallel).await,
+        (false, _) => upload_from(config, None).await.map(|_| ())
+    }
+}
+
+// beam cp between relays, nothing is decrypted or decompressed on the way through
+pub async fn forward(config: UploadArgs, forwarded: Forwarded) -> Result<(), Failure> {
+    upload_from(config, Some(forwarded)).await.map(|_| ())
+}
+
+// --parallel, every file gets a link of its own and up to `parallel` of them are sent (and waited on) at once
+async fn queue(config: UploadArgs, parallel: usize) -> Result<(), Failure> {
+    if config.token.is_some() || config.token_name.is_some() || config.name.is_some() || config.local || config.text.is_some() || config.as_text || config.file.iter().any(|file| file == "-") {
+        error!("--parallel makes a new link for each file, so it can't be used with --token, --token-name, --name, --local, --text, --as-text, or stdin");
+        return Err(Failure::Other);
+    }
+    if config.encrypt && config.recipient.is_empty() {
+        error!("--parallel can't ask for a passphrase for every file at once, use --recipient instead");
+        return Err(Failure::Other);
+    }
+
+    let overall = style::start_queue(config.file.len() as u64);
+    let slots = Arc::new(Semaphore::new(parallel.max(1)));
+    let tasks: Vec<_> = config.file.iter().map(|file| {
+        let mut single = config.clone();
+        single.file = vec![file.clone()];
+        let slots = slots.clone();
+        let overall = overall.clone();
+        tokio::spawn(async move {
+            let _slot = slots.acquire_owned().await;
+            let result = upload_from(single, None).await;
+            overall.inc(1);
+            result
+        })
+    }).collect();
+
+    let mut results = Vec::with_capacity(tasks.len());
+    for (file, task) in config.file.iter().zip(tasks) {
+        results.push((file, task.await.unwrap_or(Err(Failure::Other))));
+    }
+    overall.finish_and_clear();
+    style::end_queue();
+
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    let first = results.iter().find_map(|(_, result)| result.as_ref().err().copied()); // the exit code says why the first one failed
+    for (file, result) in &results {
+        match result {
+            Ok(Some(link)) => style::say(format!("  sent    {}  {}", file, link)),
+            Ok(None) => style::say(format!("  sent    {}", file)),
+            Err(_) => style::say(format!("  failed  {}", file))
+        }
+    }
+    match first {
+        None => Ok(()),
+        Some(failure) => {
+            error!("{} of {} uploads failed", failed, results.len());
+            Err(failure)
+        }
+    }
+}
+
+// an upload_stream still sending, or waiting for its download
+pub struct TransferHandle {
+    pub link: String,
+    task: tokio::task::JoinHandle<Result<(), Failure>>,
+}
+
+impl TransferHandle {
+    /// Waits for everything to be sent, and for the download too unless the relay is keeping the upload.
+    pub async fn finish(self) -> Result<(), Failure> {
+        self.task.await.unwrap_or(Err(Failure::Other))
+    }
+
+    /// Stops sending. The relay sees the upload drop, the same as `beam up` being interrupted.
+    pub fn cancel(self) {
+        self.task.abort();
+    }
+}
+
+/// Sends whatever `reader` gives under a new link, for programs that have the data rather than a file.
+/// `config` is what `beam up` would get, [`upload_args`](super::copy::upload_args) makes one, and its file list is ignored.
+/// It returns once the link is made, `progress` is called with how much of `reader` has been sent so far.
+pub async fn upload_stream<R>(reader: R, config: UploadArgs, progress: impl Fn(u64) + Send + 'static) -> Result<TransferHandle, Failure> where R: AsyncRead + Unpin + Send + 'static {
     let (server, username, key) = config.args.get_absolute();
+    let http = config.args.http(&server)?;
+
+    let encryptor = match config.encrypt || !config.recipient.is_empty() {
+        true => Some(encryption::encryptor(&config.recipient)?),
+        false => None
+    };
+    let encrypted = encryptor.is_some();
+    let requested = match &config.compression {
+        Choice::Fixed(compression) => compression.clone(),
+        Choice::Auto => Compression::None // there's nothing to sample before the reader is sent
+    };
+    let compression = match server_info(&http, &server).await {
+        Some(info) if !info.accepts(&requested) => {
+            warn!("{} can't read {}, sending it uncompressed", server, requested);
+            Compression::None
+        },
+        _ => requested
+    };
+
+    let name = config.name.clone().unwrap_or("bytebeam".to_string());
+    let request_path = format!("{server}/{}", urlencoding::encode(&name));
+    let metadata = match get_upload_token(&http, &username, 0, token_options(&config), &server, request_path).await {
+        Ok(metadata) => do_run_upgrade_on_metadata(&http, metadata, &username, &key, &server, config.args.key_passphrase_file.as_deref()).await,
+        Err(failure) => {
+            error!("Failed to get upload token");
+            return Err(failure);
+        }
+    };
+    if config.store && !metadata.is_stored() {
+        error!("The server did not agree to keep the upload, it may be out of date");
+        return Err(Failure::Other);
+    }
+    let (token, upload_key) = metadata.get_upload_info();
+    let upload_path = match Url::parse(&format!("{server}/{token}/{upload_key}")) {
+        Ok(u) => u,
+        Err(e) => {
+            error!("Invalid URL, is the server correct? {:?}", e);
+            return Err(Failure::Other);
+        }
+    };
+    let link = format!("{server}/{token}");
+
+    let mut input = ReaderStream::new(reader);
+    let counted = Box::pin(stream! {
+        let mut sent = 0;
+        while let Some(chunk) = input.next().await {
+            if let Ok(chunk) = &chunk {
+                sent += chunk.len() as u64;
+                progress(sent);
+            }
+            yield chunk;
+        }
+    });
+    let progress_stream = ProgressStream::new(counted, Arc::new(Mutex::new(0)), ProgressBar::hidden(), compression.clone(), config.zstd_tuning(), config.limit_rate.map(TokenBucket::per_second));
+    let body: ByteStream = match encryptor {
+        Some(encryptor) => encryption::encrypt_stream(progress_stream.into_stream(), encryptor),
+        None => Box::pin(progress_stream.into_stream())
+    };
+
+    // the size isn't known ahead of time, the same as stdin
+    let mut form = reqwest::multipart::Form::new()
+        .text("file-size", "0")
+        .text("compression", compression.to_string())
+        .text("disposition", match config.inline {
+            true => Disposition::Inline.to_string(),
+            false => Disposition::Attachment.to_string()
+        })
+        .text("direct", config.direct.to_string());
+    if config.max_downloads > 1 {
+        form = form.text("max-downloads", config.max_downloads.to_string());
+    }
+    if encrypted {
+        form = form.text("encrypted", "true");
+    }
+    if let Some(message) = &config.message {
+        form = form.text("message", message.clone());
+    }
+    let form = form.part("file", reqwest::multipart::Part::stream(Body::wrap_stream(body)));
+
+    let store = config.store;
+    let push = config.args.push();
+    let task = tokio::spawn(async move {
+        let watcher = tokio::spawn(watch::wait_for_download(http.clone(), push, server, token, upload_key));
+        let sent = match http.send(http.client().post(upload_path).multipart(form)).await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                error!("Non-success response from Beam server: {}", response.text().await.unwrap_or_default());
+                Err(Failure::status(status))
+            },
+            Err(e) => {
+                error!("Failed to connect to Beam server: {}", e);
+                Err(Failure::request(&e))
+            }
+        };
+        if let Err(failure) = sent {
+            watcher.abort();
+            return Err(failure);
+        }
+        if store {
+            watcher.abort(); // the relay has it now, nobody needs to wait for the download
+            return Ok(());
+        }
+        match watcher.await.unwrap_or(false) {
+            true => Ok(()),
+            false => Err(Failure::Other)
+        }
+    });
+
+    Ok(TransferHandle { link, task })
+}
+
+// only ever a convenience, the link has already been printed if either of these doesn't work
+async fn share_link(copy: bool, open: bool, url: &str) {
+    if copy {
+        match clipboard::write(&Clip::Text(url.to_string())).await {
+            Ok(()) => style::say("Copied the link to the clipboard"),
+            Err(e) => warn!("Could not copy the link: {}", e)
+        }
+    }
+    if open {
+        if let Err(e) = browser::open(url).await {
+            warn!("Could not open the link: {}", e);
+        }
+    }
+}
+
+// what the relay is asked for along with a new token
+fn token_options(config: &UploadArgs) -> Vec<(&'static str, String)> {
+    let mut options = vec![];
+    if let Some(expire) = config.expire {
+        options.push(("expires", expire.to_string()));
+    }
+    if let Some(receiver) = &config.receiver {
+        options.push(("receiver", receiver.clone()));
+    }
+    if let Some(token_name) = &config.token_name {
+        options.push(("token-name", token_name.clone()));
+        options.extend(sign_token_name(config, token_name));
+    }
+    if config.store {
+        options.push(("store", "true".to_string()));
+    }
+    options
+}
+
+// the relay only holds a name for someone who proves who they are first. a relay that takes a client certificate doesn't need this,
+// so a key that can't sign is left for it to refuse
+fn sign_token_name(config: &UploadArgs, token_name: &str) -> Vec<(&'static str, String)> {
+    let (_, username, key) = config.args.get_absolute();
+    let nonce = format!("{:016x}{:016x}", RandomState::new().hash_one(0u8), RandomState::new().hash_one(1u8));
+    let challenge = format!("token-name/{}:{}:{}:{}", token_name, username, chrono::Utc::now().timestamp(), nonce);
+    let signatures = sign_with_keys_at(&challenge, &key, config.args.key_passphrase_file.as_deref());
+    if signatures.is_empty() {
+        return vec![];
+    }
+    match serde_json::to_string(&signatures) {
+        Ok(signatures) => vec![("name-challenge", challenge), ("name-signature", signatures)],
+        Err(_) => vec![]
+    }
+}
+
+// the relay downloads the file itself, so nothing goes through this machine and there's no progress to show until it's downloaded
+async fn remote(config: UploadArgs) -> Result<(), Failure> {
+    let (server, username, key) = config.args.get_absolute();
+    let http = config.args.http(&server)?;
+
+    if config.file.len() != 1 || config.encrypt || !config.recipient.is_empty() || config.compression != Choice::Fixed(Compression::None) || config.p2p || config.local || config.token.is_some() || config.max_downloads > 1 {
+        error!("--remote takes one url that the relay sends on as it is, so it can't be used with --encrypt, --recipient, --compression, --p2p, --local, --token, or --max-downloads");
+        return Err(Failure::Other);
+    }
+    if server_info(&http, &server).await.is_some_and(|info| !info.supports("fetch")) {
+        error!("{} doesn't fetch urls for uploads", server);
+        return Err(Failure::Other);
+    }
+    let url = match Url::parse(&config.file[0]) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        _ => {
+            error!("--remote needs an http or https url, not {}", config.file[0]);
+            return Err(Failure::Other);
+        }
+    };
+
+    let file_name = config.name.clone()
+        .or(url.path_segments().and_then(|mut segments| segments.next_back()).filter(|name| !name.is_empty()).map(|name| urlencoding::decode(name).map(|name| name.into_owned()).unwrap_or(name.to_string())))
+        .unwrap_or("bytebeam".to_string());
+    let metadata = match get_upload_token(&http, &username, 0, token_options(&config), &server, format!("{server}/{}", urlencoding::encode(&file_name))).await {
+        Ok(metadata) => do_run_upgrade_on_metadata(&http, metadata, &username, &key, &server, config.args.key_passphrase_file.as_deref()).await,
+        Err(failure) => {
+            error!("Failed to get upload token");
+            return Err(failure);
+        }
+    };
+    if !metadata.authenticated() {
+        error!("The relay only fetches urls for users it can authenticate, and {} wasn't", username);
+        return Err(Failure::Other);
+    }
+    if config.store && !metadata.is_stored() {
+        error!("The server did not agree to keep the upload, it may be out of date");
+        return Err(Failure::Other);
+    }
+    if config.receiver.is_some() && metadata.get_receiver().is_none() {
+        error!("The server did not lock the link to {}, it may be out of date. Not uploading", config.receiver.as_deref().unwrap_or_default());
+        return Err(Failure::Other);
+    }
+
+    let (token, upload_key) = metadata.get_upload_info();
+    let mut form = vec![("key", upload_key.clone()), ("url", url.to_string())];
+    if let Some(name) = &config.name {
+        form.push(("file-name", name.clone()));
+    }
+    if let Some(message) = &config.message {
+        form.push(("message", message.clone()));
+    }
+    match http.send(http.client().post(format!("{server}/{token}/fetch")).form(&form)).await {
+        Ok(response) if response.status().is_success() => (),
+        Ok(response) => {
+            let status = response.status();
+            error!("The relay could not fetch {}: {}", url, response.text().await.unwrap_or_default());
+            return Err(Failure::status(status));
+        },
+        Err(e) => {
+            error!("Failed to connect to server: {}", e);
+            return Err(Failure::request(&e));
+        }
+    }
+
+    let send_path = match std::env::var("PROXIED_SERVER") {
+        Ok(s) => format!("{s}/{token}"),
+        Err(_) => format!("{server}/{token}")
+    };
+    style::print_link("Download is available from", &send_path, config.args.plain);
+    share_link(config.copy, config.open, &send_path).await;
+    if config.store {
+        style::say(format!("The relay is fetching {} and will keep it until it's downloaded", url));
+        return Ok(());
+    }
+    style::say(format!("The relay is fetching {}, waiting for the download...", url));
+    if watch::wait_for_download(http, config.args.push(), server, token, upload_key).await && config.notify {
+        notify::desktop("Download finished", format!("{} was downloaded", file_name)).await;
+    }
+    Ok(())
+}
+
+// the link is given back when a new one was made, for --parallel to list at the end
+async fn upload_from(config: UploadArgs, forwarded: Option<Forwarded>) -> Result<Option<String>, Failure> {
+    let filepaths = config.get_file_paths();
+    let filepath = filepaths.first().cloned().unwrap_or_default(); // clap makes sure there is at least one, a forwarded upload doesn't need any
+    let (server, username, key) = config.args.get_absolute();
+    let http = config.args.http(&server)?;
+
+    if (config.text.is_some() || config.as_text) && (filepaths.len() > 1 || config.encrypt || !config.recipient.is_empty() || config.compression != Choice::Fixed(Compression::None)) {
+        error!("--text and --as-text send one piece of plain text, so they can't be used with several files, --encrypt, --recipient, or --compression");
+        return Err(Failure::Other);
+    }
+
+    // the block checksums and the changed bytes go through the relay as they are, so a delta would undo the encryption
+    if config.delta && (config.encrypt || !config.recipient.is_empty()) {
+        error!("--delta can't be used with --encrypt or --recipient, the relay would see checksums of the downloader's copy and the bytes that changed");
+        return Err(Failure::Other);
+    }
+
+    // asked up front so a typo doesn't leave a token behind
+    let encryptor = match config.encrypt || !config.recipient.is_empty() {
+        true => Some(encryption::encryptor(&config.recipient)?),
+        false => None
+    };
+
+    // auto tries a sample, so media and archives that are already compressed aren't compressed again for nothing
+    let chosen = match &config.compression {
+        Choice::Fixed(compression) => compression.clone(),
+        Choice::Auto if forwarded.is_some() => Compression::None, // it's already as compressed as it's going to be
+        Choice::Auto if config.text.is_some() || config.as_text => Compression::None, // snippets are shown as they are
+        Choice::Auto => compression::pick(filepaths.clone()).await
+    };
+
+    // a relay that says it can't read the compression couldn't transcode it for browsers, so it goes without
+    let info = match config.local {
+        true => None,
+        false => server_info(&http, &server).await
+    };
+    let requested = match &info {
+        Some(info) if !info.accepts(&chosen) => {
+            warn!("{} can't read {}, sending it uncompressed", server, chosen);
+            Compression::None
+        },
+        _ => chosen
+    };
+    if (config.zstd_long || config.zstd_threads.is_some()) && requested != Compression::Zstd {
+        warn!("--zstd-long and --zstd-threads only change zstd, the upload is sent with {}", requested);
+    }
+    if config.store && info.as_ref().is_some_and(|info| !info.supports("store")) {
+        error!("{} doesn't keep uploads, --store needs a relay with storage set up", server);
+        return Err(Failure::Other);
+    }
+
+    // what was already done to a forwarded upload is only labelled, not done again
+    let (compression, encrypted, compressor) = match &forwarded {
+        Some(forwarded) => (forwarded.compression.clone(), forwarded.encrypted, Compression::None),
+        None => (requested.clone(), encryptor.is_some(), requested)
+    };
+    let message = config.message.clone().or(forwarded.as_ref().and_then(|forwarded| forwarded.message.clone()));
+
+    // a direct send goes around everything the relay would have to see the download for
+    if config.p2p && (config.token.is_some() || config.store || config.max_downloads > 1 || config.receiver.is_some()) {
+        error!("--p2p only works on a new link for one download, not with --token, --store, --max-downloads, or --receiver");
+        return Err(Failure::Other);
+    }
+    if (config.copy || config.open) && (config.local || config.token.is_some()) {
+        error!("--copy and --open share a new link from the relay, --local and --token don't make one");
+        return Err(Failure::Other);
+    }
+    if config.local && (config.token.is_some() || config.token_name.is_some() || config.store || config.max_downloads > 1 || config.receiver.is_some() || config.expire.is_some() || config.p2p) {
+        error!("--local doesn't use a relay, so it can't be used with --token, --token-name, --store, --max-downloads, --receiver, --expire, or --p2p");
+        return Err(Failure::Other);
+    }
+    // a delta is made against one downloader's copy of one file, before anything is sent
+    if config.delta && (config.token.is_some() || config.store || config.max_downloads > 1 || config.p2p || config.local || config.text.is_some() || config.as_text || forwarded.is_some() || filepaths.len() != 1 || !filepath.is_file()) {
+        error!("--delta sends one file on a new link for one download, not with --token, --store, --max-downloads, --p2p, --local, or --text");
+        return Err(Failure::Other);
+    }
+    let delta = config.delta && match &info {
+        Some(info) if !info.supports("delta") => {
+            warn!("{} can't pass on block checksums, sending the whole file", server);
+            false
+        },
+        _ => true
+    };
 
+    // a snippet is read up front, it has to be small enough for a page and has to be text
+    let snippet = match (&config.text, config.as_text) {
+        (Some(text), _) => Some((text.clone(), "snippet.txt".to_string())),
+        (None, true) => Some(read_snippet(&filepath).await?),
+        (None, false) => None
+    };
+    if snippet.as_ref().is_some_and(|(text, _)| text.len() > MAX_TEXT_SIZE) {
+        error!("Snippets can be up to {}, send it as a file instead", ByteSize(MAX_TEXT_SIZE as u64).to_string_as(true));
+        return Err(Failure::Other);
+    }
+
+    let options = token_options(&config);
+    let tuning = config.zstd_tuning();
     let token = config.token;
+    // a token minted ahead of time already has a name, --name can still replace it
+    let rename = match &token {
+        Some(_) => config.name.clone(),
+        None => None
+    };
 
     let mut file_name = "bytebeam".to_string();
     let mut file_len = 0;
+    let mut checksum = None;
+    let mut manifest = None;
+    let mut attributes = None;
+    let mut content_type = None;
 
-    let reader_stream = if !filepath.exists() {
+    let reader_stream = if let Some(forwarded) = forwarded {
+        file_name = forwarded.file_name;
+        file_len = forwarded.size;
+        checksum = forwarded.checksum;
+        manifest = forwarded.manifest;
+        attributes = forwarded.attributes;
+        Box::new(forwarded.stream) as Box<dyn Stream<Item = Result<Bytes, io::Error>> + Unpin + Send>
+    } else if let Some((text, name)) = &snippet {
+        let data = Bytes::from(text.clone());
+        file_name = name.clone();
+        file_len = data.len() as u64;
+        checksum = match config.checksum.hasher() {
+            Some(hasher) => DigestWorker::digest(hasher, data.clone()).await.map(|digest| Checksum { algorithm: config.checksum.clone(), digest }),
+            None => None
+        };
+        Box::new(tokio_stream::once(Ok(data))) as Box<dyn Stream<Item = Result<Bytes, io::Error>> + Unpin + Send>
+    } else if filepaths.len() > 1 || (config.keep_symlinks && filepath.is_symlink()) { // a kept link only has somewhere to go in a manifest
+        let (files, stream) = bundle_stream(&filepaths, config.keep_symlinks).await?;
+        file_name = "bundle".to_string();
+        file_len = files.iter().map(|file| file.size).sum();
+        debug!("Sending {} files together, {} total", files.len(), ByteSize(file_len).to_string_as(true));
+        manifest = Some(files);
+        stream
+    } else if !filepath.exists() {
         let filepath_str = filepath.to_str().expect("Could not convert path to string");
         if filepath_str == "-" {
             if config.name.is_none() {
                 warn!("No file name specified. Defaulting to \"bytebeam\". This can be defined using --name [FILENAME]");
             }
-            debug!("Reading from stdin...");
+            debug!("Reading from stdin, it can't be checksummed ahead of time");
             Box::new(ReaderStream::new(Box::new(tokio::io::stdin()))) as Box<dyn Stream<Item = Result<Bytes, io::Error>> + Unpin + Send>
         } else {
             error!("Path does not exist: {}", filepath_str);
-            return Err(());
+            return Err(Failure::Other);
         }
     } else {
         // see if file is a folder, so we need to send the whole thing
@@ -40,14 +516,46 @@ pub async fn upload(config: UploadArgs) -> Result<(), ()> {
             //let mut file_list = tokio::fs::read_dir(&filepath).await.unwrap();
 
             error!("Folder support is not ready yet");
-            return Err(());
+            return Err(Failure::Other);
         } else {
             let file = tokio::fs::File::open(&filepath).await.unwrap();
-            file_len = file.metadata().await.expect("Could not read metadata").len();
+            let file_metadata = file.metadata().await.expect("Could not read metadata");
+            file_len = file_metadata.len();
+            attributes = Some(fileio::attributes(&file_metadata));
+            // only the start is read, and the relay goes by the name instead if nothing matches
+            content_type = infer::get_from_path(&filepath).ok().flatten().map(|kind| kind.mime_type().to_string());
             debug!("Found file length: {}", ByteSize(file_len).to_string_as(true));
             file_name = std::path::Path::new(&filepath).file_name().unwrap_or_default().to_string_lossy().to_string();
-            
-            Box::new(ReaderStream::new(file)) as Box<dyn Stream<Item = Result<Bytes, io::Error>> + Unpin + Send>
+
+            // age already authenticates every chunk, and a hash of the plaintext would let the relay confirm a guess at the contents
+            let algorithm = match encrypted {
+                true => ChecksumAlgorithm::None,
+                false => config.checksum.clone()
+            };
+            checksum = match checksum_file(filepath.clone(), algorithm).await {
+                Ok(checksum) => checksum,
+                Err(e) => {
+                    error!("Could not checksum {:?}: {}", filepath, e);
+                    return Err(Failure::Other);
+                }
+            };
+            debug!("File checksum: {:?}", checksum);
+
+            let mapped = match config.mmap && file_len > 0 { // empty files can't be mapped
+                true => match std::fs::File::open(&filepath).and_then(|f| mmap_stream(&f)) {
+                    Ok(stream) => Some(stream),
+                    Err(e) => {
+                        warn!("Could not memory map {:?}, falling back to regular reads: {}", filepath, e);
+                        None
+                    }
+                },
+                false => None
+            };
+
+            match mapped {
+                Some(stream) => Box::new(stream) as Box<dyn Stream<Item = Result<Bytes, io::Error>> + Unpin + Send>,
+                None => input_stream(file, &filepath)
+            }
         }
     };
 
@@ -55,17 +563,41 @@ pub async fn upload(config: UploadArgs) -> Result<(), ()> {
 
     // if we already have a token, we can skip much of the next part
 
-    let mut thread: Option<std::thread::JoinHandle<()>> = None;
+    let mut watcher: Option<tokio::task::JoinHandle<bool>> = None;
+    let mut framed = false; // only known for tokens made here, an older relay would take the frames as the file
+    let mut link = None;
+    let mut offer: Option<(peer::Offer, Option<String>)> = None; // with the relay's token, if there is one
+    let mut signatures = None;
+    let sent_name = config.name.clone().unwrap_or(file_name.clone());
+    let file_size = match compression { // output size changes
+        Compression::None if !encrypted => file_len,
+        _ => 0
+    };
 
     let upload_path = match token {
+        _ if config.local => {
+            let (local_offer, instance) = local::advertise(&sent_name, file_size).await?;
+            style::say(format!("Sharing {} on the local network as {}", sent_name, instance));
+            style::say(format!("Download it with: beam down --local {}", instance));
+            offer = Some((local_offer, None));
+            None
+        },
+        Some(_) if config.receiver.is_some() => {
+            error!("--receiver can only lock a new link, not a token that was made earlier");
+            return Err(Failure::Other);
+        },
+        Some(_) if config.token_name.is_some() => {
+            error!("--token-name picks the token for a new link, it can't be used with --token");
+            return Err(Failure::Other);
+        },
         Some(tok) => {
             match Url::parse(&tok) {
-                Ok(u) => u,
+                Ok(u) => Some(u),
                 Err(_) => match Url::parse(format!("{server}/{tok}").as_str()) {
-                    Ok(u) => u,
+                    Ok(u) => Some(u),
                     Err(_) => {
                         error!("Invalid upload URL: {}", tok);
-                        return Err(());
+                        return Err(Failure::Other);
                     },
                 }
             }
@@ -80,81 +612,97 @@ pub async fn upload(config: UploadArgs) -> Result<(), ()> {
         
             // so we need to get the download
         
-            let metadata = match get_upload_token(&username, file_len as usize, upload_path).await {
-                Some(metadata) => do_run_upgrade_on_metadata(metadata, &username, &key, &server).await,
-                None => {
+            let metadata = match get_upload_token(&http, &username, file_len as usize, options, &server, upload_path).await {
+                Ok(metadata) => do_run_upgrade_on_metadata(&http, metadata, &username, &key, &server, config.args.key_passphrase_file.as_deref()).await,
+                Err(failure) => {
                     error!("Failed to get upload token");
-                    return Err(());
+                    return Err(failure);
                 }
             };
+            if config.expire.is_some() && metadata.get_expiry().is_none() {
+                warn!("The server did not accept an expiry, the link will be culled like any other once it goes stale");
+            }
+            if config.store && !metadata.is_stored() {
+                error!("The server did not agree to keep the upload, it may be out of date");
+                return Err(Failure::Other);
+            }
+            if config.receiver.is_some() && metadata.get_receiver().is_none() {
+                error!("The server did not lock the link to {}, it may be out of date. Not uploading", config.receiver.as_deref().unwrap_or_default());
+                return Err(Failure::Other);
+            }
         
             let ul = metadata.get_upload_info();
+            framed = metadata.frames.as_deref() == Some(FRAMES);
             let upload_path = match Url::parse(format!("{server}/{}/{}", ul.0, ul.1).as_str()) {
                 Ok(u) => u,
                 Err(e) => {
                     error!("Invalid URL, is the server correct? {:?}", e);
-                    return Err(());
+                    return Err(Failure::Other);
                 }
             };
-            let check_url = format!("{server}/{}?status=true", ul.0);
 
             let send_path = match std::env::var("PROXIED_SERVER") {
                 Ok(s) => format!("{s}/{}", ul.0),
                 Err(_) => format!("{server}/{}", ul.0)
             };
 
-            qr2term::print_qr(&send_path).expect("Could not generate QR code");
-            println!("\nDownload is available from: {}\n\n", send_path);
+            style::print_link("Download is available from", &send_path, config.args.plain);
+            share_link(config.copy, config.open, &send_path).await;
+            link = Some(send_path);
+
+            if config.p2p {
+                match http.has_proxy() {
+                    true => warn!("--p2p would go around the proxy, sending through the relay instead"),
+                    false => offer = peer::offer(&http, &server, &ul.0, &ul.1).await.map(|offer| (offer, Some(ul.0.clone())))
+                }
+            }
 
             // we need to keepalive!
-            thread = Some(thread::spawn(move || {
-                let rt = tokio::runtime::Runtime::new().unwrap();
-                rt.block_on(async {
-                    let mut is_downloading = false;
-                    loop {
-                        let status = match reqwest::get(&check_url).await {
-                            Ok(req) => req,
-                            Err(e) => {
-                                error!("Failed to connect to server for status: {}", e);
-                                break;
-                            }
-                        };
-                
-                        match status.json::<FileMetadata>().await {
-                            Ok(meta) => {
-                                if meta.download_locked() && !is_downloading {
-                                    println!("Client has begun downloading!");
-                                    is_downloading = true;
-                                }
-                                if meta.download_finished() {
-                                    println!("done!");
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                error!("Failed to parse download metadata. Was the upload deleted? {:?}", e);
-                                break;
-                            }
-                        }
-                        if is_downloading {
-                            std::thread::sleep(std::time::Duration::from_secs(5));
-                        } else {
-                            std::thread::sleep(std::time::Duration::from_secs(10));
-
-                        }
-                    }
-                });
-            }));
+            watcher = Some(tokio::spawn(watch::wait_for_download(http.clone(), config.args.push(), server.clone(), ul.0.clone(), ul.1.clone())));
 
+            if delta && delta::request(&http, &server, &ul.0, &ul.1).await {
+                style::say("Waiting for the downloader to say what they already have...");
+                signatures = delta::wait(&http, &server, &ul.0, &ul.1).await?;
+                if signatures.is_none() {
+                    style::say("The downloader has no copy to compare against, sending all of it");
+                }
+            }
 
-            upload_path
+            Some(upload_path)
         }
     };
+    // the downloader's checksums say which blocks they have, only the rest of the file goes out
+    let (reader_stream, file_len, file_size) = match signatures {
+        Some(signatures) => {
+            style::say("Sending only what changed since the downloader's copy");
+            (delta::encode(filepath.clone(), signatures), 0, 0)
+        },
+        None => (reader_stream, file_len, file_size)
+    };
+
+    // nothing has been read yet, so whichever way it goes gets the whole file
+    let direct = match offer {
+        Some((offer, token)) => {
+            match token {
+                Some(_) => style::say("Waiting for the downloader to connect directly..."),
+                None => style::say("Waiting for someone on the local network to download it...")
+            }
+            match offer.wait().await {
+                Some(socket) => Some((socket, token)),
+                None if token.is_none() => return Err(Failure::Other), // there's no relay to fall back to
+                None => {
+                    style::say("The downloader isn't connecting directly, sending through the relay");
+                    None
+                }
+            }
+        },
+        None => None
+    };
+
     // okay, now we just upload
 
-    let bar = ProgressBar::new(file_len as u64);
-    bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>7}/{total_bytes:7} {msg}")
-        .unwrap());
+    let bar = style::progress_bar(file_len, config.args.plain);
+    ipc::watch(&bar);
     bar.enable_steady_tick(Duration::from_millis(100));
     let read_so_far: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
 
@@ -162,38 +710,114 @@ pub async fn upload(config: UploadArgs) -> Result<(), ()> {
         reader_stream,
         read_so_far.clone(),
         bar.clone(),
-        config.compression.clone()
+        compressor,
+        tuning,
+        config.limit_rate.map(TokenBucket::per_second)
     );
 
-    let async_stream = progress_stream.into_stream();
-    
-    
-    let client = reqwest::Client::new();
-    let form = reqwest::multipart::Form::new()
+    let async_stream: ByteStream = match encryptor {
+        Some(encryptor) => encryption::encrypt_stream(progress_stream.into_stream(), encryptor),
+        None => Box::pin(progress_stream.into_stream())
+    };
+
+    if let Some((socket, token)) = direct {
+        let header = PeerHeader {
+            file_name: sent_name,
+            file_size,
+            compression: compression.clone(),
+            encrypted,
+            checksum,
+            manifest,
+            message: message.clone(),
+            attributes
+        };
+        if let Err(e) = peer::send(socket, &header, async_stream).await {
+            bar.abandon();
+            error!("The direct connection to the downloader failed: {}", e);
+            return Err(Failure::Other);
+        }
+        bar.finish();
+        let fin_bytes = *read_so_far.lock().unwrap();
+        style::say(format!("File sent directly. ({} bytes)", &fin_bytes));
+        if config.notify {
+            notify::desktop("Download finished", format!("{} was downloaded", header.file_name)).await;
+        }
+
+        // the relay never had any of it, so the token is only left to be culled
+        if let Some(watcher) = watcher {
+            watcher.abort();
+        }
+        if let Some(token) = token {
+            if let Err(e) = http.send(http.client().delete(format!("{server}/{token}"))).await {
+                debug!("Could not remove {} from the relay: {}", token, e);
+            }
+        }
+        return Ok(link);
+    }
 
-        .text("file-size", match config.compression { // output size changes
-            Compression::None => file_len.to_string(),
-            _ => "0".to_string()
+    let upload_path = upload_path.expect("Only local shares have no relay, and they never get this far");
+    let mut form = reqwest::multipart::Form::new()
+
+        .text("file-size", file_size.to_string())
+        .text("compression", compression.to_string())
+        .text("disposition", match config.inline || snippet.is_some() { // a browser without the script opens the text instead of saving it
+            true => Disposition::Inline.to_string(),
+            false => Disposition::Attachment.to_string()
         })
-        .text("compression", config.compression.to_string())
-        .part("file", reqwest::multipart::Part::stream(Body::wrap_stream(async_stream)));
+        .text("direct", config.direct.to_string());
+    if let Some(name) = rename {
+        form = form.text("file-name", name);
+    }
+    if config.max_downloads > 1 {
+        form = form.text("max-downloads", config.max_downloads.to_string());
+    }
+    if encrypted {
+        form = form.text("encrypted", "true");
+    }
+    if snippet.is_some() {
+        form = form.text("text", "true");
+    }
+    if let Some(message) = &message {
+        form = form.text("message", message.clone());
+    }
+    if let Some(manifest) = &manifest { // after compression and encryption, they decide whether the relay can split it up
+        form = form.text("manifest", serde_json::to_string(manifest).unwrap_or_default());
+    }
+    if let Some(attributes) = &attributes {
+        form = form.text("attributes", serde_json::to_string(attributes).unwrap_or_default());
+    }
+    if let Some(content_type) = &content_type {
+        form = form.text("content-type", content_type.clone());
+    }
+    if let Some(checksum) = &checksum { // has to come before the file so the server can check it on the way through
+        form = form.text("checksum", checksum.to_string());
+    }
+    // a damaged frame is turned away by the relay and sent again, instead of ending up in the file
+    let response = match framed {
+        true => frames::send(http.client(), upload_path, form, async_stream).await,
+        false => http.send(http.client().post(upload_path)
+            .multipart(form.part("file", reqwest::multipart::Part::stream(Body::wrap_stream(async_stream))))).await
+    };
 
-    match client.post(upload_path)
-        .multipart(form)
-        .send().await {
+    match response {
             Ok(response) => {
                 if !response.status().is_success() {
+                    let status = response.status();
                     error!(
                         "Non-success response from Beam server: {}",
                         response.text().await.unwrap()
                     );
+                    bar.abandon();
+                    return Err(Failure::status(status));
                 }
                 bar.finish();
-                let fin_bytes = read_so_far.clone().lock().unwrap().clone();
-                println!("File uploaded successfully. ({} bytes)", &fin_bytes);
+                let fin_bytes = *read_so_far.lock().unwrap();
+                style::say(format!("File uploaded successfully. ({} bytes)", &fin_bytes));
             },
             Err(e) => {
                 error!("Failed to connect to Beam server: {}", e);
+                bar.abandon();
+                return Err(Failure::request(&e));
             }
         }
 
@@ -211,13 +835,118 @@ pub async fn upload(config: UploadArgs) -> Result<(), ()> {
         );
     }*/
 
-    match thread {
-        Some(thread) => {
-            println!("Waiting for client to download...");
-            thread.join().unwrap();
+    match watcher {
+        Some(watcher) if config.store => {
+            watcher.abort(); // the relay has it now, nobody needs to wait for the download
+            style::say("The relay is keeping the file until it's downloaded or expires, you can go offline now.");
+        },
+        Some(watcher) => {
+            style::say("Waiting for client to download...");
+            if watcher.await.unwrap_or(false) && config.notify {
+                notify::desktop("Download finished", format!("{} was downloaded", sent_name)).await;
+            }
         },
         None => {}
     }
 
-    Ok(())
+    Ok(link)
+}
+
+// several files go out back to back under one token, the manifest tells the other side where each one ends
+// stdin (or "-") when there's no file, either way only as much as a snippet can be is read
+async fn read_snippet(path: &PathBuf) -> Result<(String, String), Failure> {
+    let mut data = vec![];
+    let (read, name) = match path.as_os_str().is_empty() || path.as_os_str() == "-" {
+        true => (tokio::io::stdin().take(MAX_TEXT_SIZE as u64 + 1).read_to_end(&mut data).await, "snippet.txt".to_string()),
+        false => match tokio::fs::File::open(path).await {
+            Ok(file) => (file.take(MAX_TEXT_SIZE as u64 + 1).read_to_end(&mut data).await, path.file_name().unwrap_or_default().to_string_lossy().to_string()),
+            Err(e) => {
+                error!("Could not open {:?}: {}", path, e);
+                return Err(Failure::Other);
+            }
+        }
+    };
+    if let Err(e) = read {
+        error!("Could not read the snippet: {}", e);
+        return Err(Failure::Other);
+    }
+    match String::from_utf8(data) {
+        Ok(text) => Ok((text, name)),
+        Err(_) => {
+            error!("{} isn't text, send it as a file instead", name);
+            Err(Failure::Other)
+        }
+    }
+}
+
+async fn bundle_stream(paths: &[PathBuf], keep_symlinks: bool) -> Result<(Vec<ManifestEntry>, InputStream), Failure> {
+    let mut manifest: Vec<ManifestEntry> = vec![];
+    let mut files = vec![];
+    for path in paths {
+        // files from different folders can share a name, but not in the bundle
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let mut unique = name.clone();
+        let mut n = 1;
+        while manifest.iter().any(|file| file.name == unique) {
+            unique = format!("{n}-{name}");
+            n += 1;
+        }
+
+        // only where it points is sent, whether that's safe to recreate is up to the downloader
+        if keep_symlinks && path.is_symlink() {
+            let target = match std::fs::read_link(path) {
+                Ok(target) => target,
+                Err(e) => {
+                    error!("Could not read the symlink {:?}: {}", path, e);
+                    return Err(Failure::Other);
+                }
+            };
+            manifest.push(ManifestEntry { name: unique, size: 0, attributes: None, link: Some(target.to_string_lossy().into_owned()) });
+            continue;
+        }
+
+        if !path.is_file() {
+            error!("{:?} is not a file, only files can be sent together for now", path);
+            return Err(Failure::Other);
+        }
+        let file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Could not open {:?}: {}", path, e);
+                return Err(Failure::Other);
+            }
+        };
+        let (size, attributes) = match file.metadata().await {
+            Ok(metadata) => (metadata.len(), fileio::attributes(&metadata)),
+            Err(e) => {
+                error!("Could not read metadata for {:?}: {}", path, e);
+                return Err(Failure::Other);
+            }
+        };
+        manifest.push(ManifestEntry { name: unique, size, attributes: Some(attributes), link: None });
+        files.push((file, path.clone(), size));
+    }
+
+    let stream = stream! {
+        for (file, path, size) in files {
+            let mut sent: u64 = 0;
+            let mut input = input_stream(file, &path);
+            while let Some(chunk) = input.next().await {
+                if let Ok(chunk) = &chunk {
+                    sent += chunk.len() as u64;
+                }
+                if sent > size {
+                    yield Err(io::Error::other(format!("{:?} grew while it was being sent", path)));
+                    return;
+                }
+                yield chunk;
+            }
+            // the boundaries are already in the manifest, so a file changing now would shift everything after it
+            if sent != size {
+                yield Err(io::Error::other(format!("{:?} shrank while it was being sent", path)));
+                return;
+            }
+        }
+    };
+    Ok((manifest, Box::new(Box::pin(stream))))
 }
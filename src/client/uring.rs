@@ -0,0 +1,108 @@
+use std::{io, path::PathBuf, thread};
+use bytes::Bytes;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::Stream;
+use tracing::error;
+
+// io_uring needs its own runtime, so every file gets a thread running one and talks to the main runtime over channels
+const URING_CHUNK: usize = 1024 * 1024;
+const URING_QUEUE: usize = 8;
+
+pub fn read_stream(path: PathBuf) -> impl Stream<Item = Result<Bytes, io::Error>> + Unpin + Send {
+    let (tx, rx) = mpsc::channel(URING_QUEUE);
+    thread::spawn(move || {
+        tokio_uring::start(async move {
+            let file = match tokio_uring::fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            let mut position = 0;
+            loop {
+                let (res, mut buffer) = file.read_at(Vec::with_capacity(URING_CHUNK), position).await;
+                match res {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        position += read as u64;
+                        buffer.truncate(read);
+                        if tx.send(Ok(Bytes::from(buffer))).await.is_err() {
+                            break; // the upload went away
+                        }
+                    },
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        break;
+                    }
+                }
+            }
+            let _ = file.close().await;
+        });
+    });
+    tokio_stream::wrappers::ReceiverStream::new(rx)
+}
+
+// sequential writes for downloads, finish() reports the first error if there was one
+pub struct UringWriter {
+    sender: mpsc::Sender<Bytes>,
+    result: oneshot::Receiver<io::Result<()>>,
+}
+
+impl UringWriter {
+    pub async fn create(path: PathBuf) -> io::Result<Self> {
+        let (sender, mut receiver) = mpsc::channel::<Bytes>(URING_QUEUE);
+        let (opened_tx, opened) = oneshot::channel();
+        let (done, result) = oneshot::channel();
+        thread::spawn(move || {
+            let outcome = tokio_uring::start(async move {
+                let file = match tokio_uring::fs::File::create(&path).await {
+                    Ok(file) => {
+                        let _ = opened_tx.send(Ok(()));
+                        file
+                    },
+                    Err(e) => {
+                        let _ = opened_tx.send(Err(e));
+                        return Ok(());
+                    }
+                };
+                let mut position = 0;
+                while let Some(mut chunk) = receiver.recv().await {
+                    // short writes hand the buffer back, so keep going with what is left of it
+                    while !chunk.is_empty() {
+                        let (res, written) = file.write_at(chunk, position).await;
+                        let n = res?;
+                        if n == 0 {
+                            return Err(io::Error::new(io::ErrorKind::WriteZero, "io_uring wrote nothing"));
+                        }
+                        position += n as u64;
+                        chunk = written.slice(n..);
+                    }
+                }
+                file.sync_all().await?;
+                file.close().await
+            });
+            let _ = done.send(outcome);
+        });
+        match opened.await {
+            Ok(Ok(())) => Ok(Self { sender, result }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "io_uring writer stopped")),
+        }
+    }
+
+    pub async fn write(&mut self, chunk: Bytes) -> io::Result<()> {
+        self.sender.send(chunk).await.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "io_uring writer stopped"))
+    }
+
+    pub async fn finish(self) -> io::Result<()> {
+        drop(self.sender);
+        match self.result.await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                error!("io_uring writer thread went away");
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "io_uring writer stopped"))
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, error, warn};
+use url::Url;
+
+use crate::utils::{metadata::FileMetadata, status::StatusUpdate};
+
+use super::{http::Http, notify::{self, Push, PushEvent}, style};
+
+// what the uploader has already been told, so switching from the websocket to polling doesn't repeat it
+struct Watcher {
+    http: Http,
+    push: Push,
+    token: String,
+    link: String,
+    downloading: bool,
+}
+
+impl Watcher {
+    // true once there is nothing left to wait for
+    async fn seen(&mut self, meta: &FileMetadata) -> bool {
+        if meta.download_locked() && !self.downloading {
+            style::say("Client has begun downloading!");
+            self.downloading = true;
+            notify::push(&self.http, &self.push, PushEvent::DownloadStarted, &self.token, &self.link).await;
+        }
+        if meta.download_finished() {
+            style::say("done!");
+            notify::push(&self.http, &self.push, PushEvent::DownloadFinished, &self.token, &self.link).await;
+            return true;
+        }
+        false
+    }
+}
+
+// keeps the token alive until the download is done, over the relay's websocket when it can, polling ?status=true otherwise
+// true once the downloader has all of it, false if the relay cancelled it or stopped answering
+pub async fn wait_for_download(http: Http, push: Push, server: String, token: String, key: String) -> bool {
+    let proxied = http.has_proxy();
+    let mut watcher = Watcher { http, push, link: format!("{server}/{token}"), token: token.clone(), downloading: false };
+    // the websocket can't go through the proxy, and going around it could be exactly what the user wanted to avoid
+    if !proxied {
+        match follow_socket(&server, &token, &key, &mut watcher).await {
+            Ok(finished) => return finished,
+            Err(e) => debug!("Live status is not available ({}), polling instead", e)
+        }
+    }
+    poll(&format!("{server}/{token}?status=true"), &mut watcher).await
+}
+
+// Err means the socket couldn't be used (an older relay, or it dropped), anything the relay says itself is Ok
+async fn follow_socket(server: &str, token: &str, key: &str, watcher: &mut Watcher) -> Result<bool, String> {
+    let mut url = Url::parse(&format!("{server}/ws/{token}")).map_err(|e| e.to_string())?;
+    let scheme = match url.scheme() {
+        "https" => "wss",
+        _ => "ws"
+    };
+    url.set_scheme(scheme).map_err(|_| format!("can't use {} for a websocket", server))?;
+    url.query_pairs_mut().append_pair("key", key);
+
+    let (mut socket, _) = connect_async(url.as_str()).await.map_err(|e| e.to_string())?;
+    debug!("Following {} over a websocket", token);
+
+    while let Some(message) = socket.next().await {
+        let text = match message.map_err(|e| e.to_string())? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue
+        };
+        match serde_json::from_str::<StatusUpdate>(&text) {
+            Ok(StatusUpdate::Status { metadata }) => if watcher.seen(&metadata).await {
+                return Ok(true);
+            },
+            Ok(StatusUpdate::Event { event }) => if event["event"] == "error" {
+                warn!("The relay reported a problem: {}", event["message"].as_str().unwrap_or("unknown"));
+            },
+            Ok(StatusUpdate::Cancelled { reason }) => {
+                error!("{}", reason);
+                return Ok(false);
+            },
+            Err(e) => debug!("Skipping a status update that couldn't be read: {}", e)
+        }
+    }
+    Err("the relay closed the websocket before the download finished".to_string())
+}
+
+async fn poll(check_url: &str, watcher: &mut Watcher) -> bool {
+    loop {
+        let status = match watcher.http.client().get(check_url).send().await {
+            Ok(req) => req,
+            Err(e) => {
+                error!("Failed to connect to server for status: {}", e);
+                return false;
+            }
+        };
+
+        match status.json::<FileMetadata>().await {
+            Ok(meta) => if watcher.seen(&meta).await {
+                return true;
+            },
+            Err(e) => {
+                error!("Failed to parse download metadata. Was the upload deleted? {:?}", e);
+                return false;
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(match watcher.downloading {
+            true => 5,
+            false => 10
+        })).await;
+    }
+}
@@ -0,0 +1,16 @@
+//! ByteBeam streams a file from one machine to another through a relay.
+//!
+//! [`client::upload`] and [`client::download`] do what `beam up` and `beam down` do. Their arguments are the
+//! same as the command's flags, and can be built with serde using the flag names in snake case. With the
+//! `server` feature, [`server::router`] is the whole relay as an axum `Router`, to serve next to anything else.
+//! Built for `wasm32`, only [`utils`] and the browser uploader in `wasm` are available.
+
+pub mod utils; // this is needed in both server and client
+#[cfg(not(target_arch = "wasm32"))]
+pub mod client;
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod server;
+
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
@@ -1,19 +1,12 @@
-use std::path::Path;
-use clap::{Parser, Subcommand};
-use client::{download::download_manager, upload::upload, ClientConfig, DownloadArgs, UploadArgs};
+use std::{collections::HashMap, path::{Path, PathBuf}};
+use clap::{Parser, Subcommand, ValueEnum};
+use bytebeam::client::{self, admin, clip::clip, copy::copy, download::download_manager, exit::Failure, stats::stats, token::new_token, tui::tui, update::self_update, upload::upload, AdminArgs, AdminCommand, ClientConfig, ClipArgs, CopyArgs, DownloadArgs, SelfUpdateArgs, StatsArgs, TokenArgs, TokenCommand, TuiArgs, UploadArgs};
 use serde::Deserialize;
 use tracing::{error, trace, Level};
 use dotenv::dotenv;
 
-mod utils; // this is needed in both server and client
-mod client;
-
-#[cfg(feature = "server")]
-mod server;
-#[cfg(feature = "server")]
-use server::server::server;
 #[cfg(feature = "server")]
-use server::{ServerConfig, ServerArgs};
+use bytebeam::server::{server::server, ServerConfig, ServerArgs};
 
 #[derive(Parser, Deserialize, Debug)]
 #[command(name = "ByteBeam")]
@@ -29,10 +22,27 @@ struct Cli {
 
     /// Turn debugging information on
     #[arg(short, long, default_value="info", env="LOGLEVEL")]
-    loglevel: String
+    loglevel: String,
+
+    /// Log as plain text, or as one JSON object per line for log collectors
+    #[arg(long, global = true, value_name = "FORMAT", env = "LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+
+    /// Plain output without colors or QR codes, also enabled by NO_COLOR
+    #[arg(long, global = true)]
+    plain: bool,
+
+    /// Only print errors, and the link a new upload can be downloaded from
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Unix socket to publish transfer progress on as JSON lines, for GUI frontends
+    #[arg(long, global = true, value_name = "SOCKET", env = "BEAM_IPC")]
+    ipc: Option<PathBuf>
 }
 
 #[derive(Subcommand, Deserialize, Debug)]
+#[allow(clippy::large_enum_variant)] // there's only ever the one that was parsed
 enum Commands {
     #[cfg(feature = "server")]
     /// Runs the ByteBeam server
@@ -42,23 +52,72 @@ enum Commands {
     Up(UploadArgs),
 
     /// Download a file
-    Down(DownloadArgs)
+    Down(DownloadArgs),
+
+    /// Copy to or from a relay profile, scp style (beam cp file relay: or beam cp relay:token ./dir/), or between two relays (beam cp relay:token other:)
+    Cp(CopyArgs),
+
+    /// Send what's on the clipboard, or put a download on it with --paste (images only on Linux)
+    Clip(ClipArgs),
+
+    /// Manage upload tokens without transferring anything
+    Token(TokenArgs),
+
+    /// Show your transfer statistics on a server
+    Stats(StatsArgs),
+
+    /// Look after a relay you are an admin of
+    Admin(AdminArgs),
+
+    /// Follow transfers as they happen, and cancel them or copy their links
+    Tui(TuiArgs),
+
+    /// Update this binary to the latest release
+    SelfUpdate(SelfUpdateArgs)
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, ValueEnum, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    Text,
+    Json
 }
 
 #[derive(Deserialize, Debug, Clone)]
 struct Config {
+    log_format: Option<LogFormat>, // --log-format wins over this
+
     client: Option<ClientConfig>,
 
+    #[serde(default)]
+    profiles: HashMap<String, ClientConfig>, // named relays, used by cp as [name]:[path] and matched by server for everything else
+
+    #[serde(default)]
+    keys: HashMap<String, String>, // named key paths, a profile or --key can use the name instead
+
     #[cfg(feature = "server")]
     server: Option<ServerConfig>
 }
 
+// the [client] section first, then whichever profile is for the same server, then named keys
+fn resolve_client(args: &mut ClientConfig, config: &Option<Config>, plain: bool) {
+    args.plain = plain;
+    if let Some(kconfig) = config {
+        if let Some(cconfig) = &kconfig.client {
+            args.merge(cconfig.clone());
+        }
+        args.apply_matching_profile(&kconfig.profiles);
+        args.resolve_key(&kconfig.keys);
+    }
+}
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
     let cli: Cli = Cli::parse();
 
     let subscriber_level = match cli.loglevel.to_ascii_uppercase().as_str() {
+        _ if cli.quiet => Level::ERROR,
         "TRACE" => Level::TRACE,
         "DEBUG" => Level::DEBUG,
         "INFO" => Level::INFO,
@@ -67,59 +126,131 @@ async fn main() {
         _ => Level::INFO, // default if the environment variable is not set or invalid
     };
 
-    tracing_subscriber::fmt().with_max_level(subscriber_level).init();
+    let plain = cli.plain || client::style::no_color_requested();
+    client::style::set_quiet(cli.quiet);
 
-    // lets see if there's a config file
+    // lets see if there's a config file, it's read before logging starts since it can pick the log format
     let expanded = shellexpand::tilde(&cli.config).into_owned();
     let config_path = Path::new(&expanded);
-    let config: Option<Config> = if config_path.exists() {
-        // okay now we can try to parse it
-         match toml::from_str(&std::fs::read_to_string(config_path).unwrap()) {
-            Ok(c) => Some(c),
-            Err(e) => {
-                error!("Failed to parse config file: {:?}", e);
-                None
-            }  
-        }
-    } else {
-        None
+    let parsed: Option<Result<Config, toml::de::Error>> = match config_path.exists() {
+        true => Some(toml::from_str(&std::fs::read_to_string(config_path).unwrap())), // okay now we can try to parse it
+        false => None
     };
+    let log_format = cli.log_format
+        .or(parsed.as_ref().and_then(|config| config.as_ref().ok()).and_then(|config| config.log_format))
+        .unwrap_or(LogFormat::Text);
+
+    // logs go wherever everything else said along the way does, which is stderr once beam down --tee has stdout
+    let subscriber = tracing_subscriber::fmt().with_max_level(subscriber_level)
+        .with_writer(|| -> Box<dyn std::io::Write> {
+            match client::style::stdout_taken() {
+                true => Box::new(std::io::stderr()),
+                false => Box::new(std::io::stdout())
+            }
+        });
+    match log_format {
+        LogFormat::Json => subscriber.json().flatten_event(true).init(), // fields like token and bytes become keys of their own
+        LogFormat::Text => subscriber.with_ansi(!plain).init()
+    }
+
+    let config: Option<Config> = match parsed {
+        Some(Ok(c)) => Some(c),
+        Some(Err(e)) => {
+            error!("Failed to parse config file: {:?}", e);
+            None
+        },
+        None => None
+    };
+
+    if let Some(socket) = cli.ipc {
+        if let Err(e) = client::ipc::listen(socket) {
+            error!("Could not listen for frontends: {}", e);
+            std::process::exit(Failure::Other.code());
+        }
+    }
 
     // You can check for the existence of subcommands, and if found use their
     // matches just as you would the top level cmd
 
-    match cli.command {
+    let run = async { match cli.command {
         #[cfg(feature = "server")]
         Commands::Server (args)  => {
-            let config = if let Some(kconfig) = config {
-                if let Some(mut sconfig) = kconfig.server {
-                     sconfig.apply_args(args);
-                     sconfig
-                } else {
-                    ServerConfig::default()
-                }
-            } else {
-                ServerConfig::default()
-            };
-            let _ = server(config).await;
+            let mut config = config.and_then(|kconfig| kconfig.server).unwrap_or_default();
+            config.apply_args(args);
+            server(config).await.map_err(|e| {
+                error!("The relay stopped: {:#}", e);
+                Failure::Other
+            })
         },
 
         Commands::Up (mut args) => {
-            if let Some(kconfig) = config {
-                if let Some(cconfig) = kconfig.client {
-                    args.args.merge(cconfig);
-                }
-            }
+            resolve_client(&mut args.args, &config, plain);
             trace!("Running upload with args {:?}", args);
-            let _ = upload(args).await;
+            upload(args).await
         },
         Commands::Down (mut args) => {
-            if let Some(kconfig) = config {
-                if let Some(cconfig) = kconfig.client {
-                    args.args.merge(cconfig);
-                }
+            resolve_client(&mut args.args, &config, plain);
+            download_manager(args).await
+        },
+        Commands::Cp (mut args) => {
+            args.args.plain = plain;
+            let (profiles, keys) = match config {
+                Some(kconfig) => {
+                    if let Some(cconfig) = kconfig.client {
+                        args.args.merge(cconfig);
+                    }
+                    (kconfig.profiles, kconfig.keys)
+                },
+                None => (HashMap::new(), HashMap::new())
+            };
+            copy(args, &profiles, &keys).await
+        },
+        Commands::Clip (mut args) => {
+            resolve_client(&mut args.args, &config, plain);
+            clip(args).await
+        },
+        Commands::Token (args) => match args.command {
+            TokenCommand::New (mut args) => {
+                resolve_client(&mut args.args, &config, plain);
+                new_token(args).await
+            }
+        },
+        Commands::Stats (mut args) => {
+            resolve_client(&mut args.args, &config, plain);
+            stats(args).await
+        },
+        Commands::Admin (args) => match args.command {
+            AdminCommand::List (mut args) => {
+                resolve_client(&mut args.args, &config, plain);
+                admin::list(args).await
+            },
+            AdminCommand::Delete (mut args) => {
+                resolve_client(&mut args.args, &config, plain);
+                admin::delete(args).await
+            },
+            AdminCommand::Stats (mut args) => {
+                resolve_client(&mut args.args, &config, plain);
+                admin::stats(args).await
             }
-           let _ = download_manager(args).await;
+        },
+        Commands::Tui (mut args) => {
+            resolve_client(&mut args.args, &config, plain);
+            tui(args).await
+        },
+        Commands::SelfUpdate (args) => {
+            self_update(args).await
         }
+    }};
+
+    // the transfer stops where it is, the relay sees it drop like any other interrupted one
+    let result = tokio::select! {
+        result = run => result,
+        _ = tokio::signal::ctrl_c() => Err(Failure::Aborted),
+        _ = client::ipc::cancelled() => Err(Failure::Aborted)
+    };
+
+    client::ipc::finish(result.is_ok()).await;
+    if let Err(failure) = result {
+        std::process::exit(failure.code());
     }
 }
@@ -0,0 +1,397 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use axum::{extract::{Path, State}, http::StatusCode, response::IntoResponse};
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use reqwest::{header::{CONTENT_TYPE, LOCATION}, Client, Response};
+use ring::{rand::SystemRandom, signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING}};
+use rustls::ServerConfig;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+
+use super::{spoolio::private_options, tls};
+
+const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+const RENEW_WITHIN_DAYS: i64 = 30; // Let's Encrypt certificates last 90 days and they suggest renewing with a third left
+const CHECK_EVERY: Duration = Duration::from_secs(12 * 60 * 60);
+
+// gets certificates from Let's Encrypt (or another ACME CA) by itself, instead of tls_cert and tls_key
+#[derive(Deserialize, Debug, Clone)]
+pub struct AcmeConfig {
+    domains: Vec<String>, // every name the relay is reached by, each one has to point at this machine
+    contact: Option<String>, // email the CA can warn about expiring certificates
+    cache_dir: Option<String>, // keeps the account key and certificates across restarts, defaults to ~/.config/bytebeam/acme
+    staging: Option<bool>, // Let's Encrypt's staging CA, for trying out a setup without running into rate limits
+    directory_url: Option<String>, // a different ACME CA's directory
+}
+
+// http-01 tokens the CA is about to ask for, answered on the plain http listener
+pub type Challenges = Arc<RwLock<HashMap<String, String>>>;
+
+// GET /.well-known/acme-challenge/{token}
+pub async fn answer_challenge(State(challenges): State<Challenges>, Path(token): Path<String>) -> impl IntoResponse {
+    match challenges.read().await.get(&token) {
+        Some(key_authorization) => {
+            debug!("Answering ACME challenge {}", token);
+            (StatusCode::OK, key_authorization.clone())
+        },
+        None => (StatusCode::NOT_FOUND, "No such challenge".to_string())
+    }
+}
+
+fn b64(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    authorizations: Vec<String>,
+    finalize: String,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: Option<String>,
+}
+
+// one conversation with the CA, every request is signed by the account key and uses the nonce from the reply before it
+struct Session {
+    http: Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    nonce: Option<String>,
+    kid: Option<String>, // the account url, which replaces the public key once the account exists
+}
+
+impl Session {
+    async fn new(directory_url: &str, key: EcdsaKeyPair) -> Result<Self, String> {
+        let http = Client::builder()
+            .user_agent(format!("ByteBeam/{}", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let directory = http.get(directory_url).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Could not reach the ACME directory: {}", e))?
+            .json::<Directory>().await
+            .map_err(|e| format!("Could not read the ACME directory: {}", e))?;
+        Ok(Session { http, directory, key, rng: SystemRandom::new(), nonce: None, kid: None })
+    }
+
+    // the members in lexical order without whitespace, since the thumbprint is taken over exactly this text
+    fn jwk(&self) -> String {
+        let public = self.key.public_key().as_ref(); // 0x04, then x and y
+        format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, b64(&public[1..33]), b64(&public[33..65]))
+    }
+
+    // what the relay answers the challenge with, proving it holds the account key
+    fn key_authorization(&self, token: &str) -> String {
+        let thumbprint = ring::digest::digest(&ring::digest::SHA256, self.jwk().as_bytes());
+        format!("{}.{}", token, b64(thumbprint.as_ref()))
+    }
+
+    async fn nonce(&mut self) -> Result<String, String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self.http.head(&self.directory.new_nonce).send().await.map_err(|e| e.to_string())?;
+        replay_nonce(&response).ok_or("The CA did not hand out a nonce".to_string())
+    }
+
+    // without a payload this is a POST-as-GET, which is how ACME reads anything tied to the account
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<Response, String> {
+        let payload = match payload {
+            Some(payload) => b64(payload.to_string().as_bytes()),
+            None => String::new()
+        };
+        let mut attempt = 0;
+        loop {
+            let mut protected = json!({"alg": "ES256", "nonce": self.nonce().await?, "url": url});
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = serde_json::from_str(&self.jwk()).map_err(|e| e.to_string())?
+            }
+            let protected = b64(protected.to_string().as_bytes());
+            let signature = self.key.sign(&self.rng, format!("{}.{}", protected, payload).as_bytes()).map_err(|_| "Could not sign the ACME request".to_string())?;
+            let body = json!({"protected": protected, "payload": payload, "signature": b64(signature.as_ref())});
+
+            let response = self.http.post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send().await
+                .map_err(|e| e.to_string())?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let status = response.status();
+            let problem: Value = response.json().await.unwrap_or_default();
+            // the CA can turn down a nonce at any time, the reply carries a fresh one to try again with
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && attempt < 3 {
+                debug!("ACME nonce was rejected, retrying");
+                attempt += 1;
+                continue;
+            }
+            return Err(format!("{} from the CA: {}", status, problem["detail"].as_str().unwrap_or("no details given")));
+        }
+    }
+
+    // authorizations and orders are worked on by the CA in the background, so this asks until they settle
+    async fn wait(&mut self, url: &str) -> Result<Value, String> {
+        for _ in 0..30 {
+            let value: Value = self.post(url, None).await?.json().await.map_err(|e| e.to_string())?;
+            match value["status"].as_str() {
+                Some("pending") | Some("processing") => tokio::time::sleep(Duration::from_secs(2)).await,
+                _ => return Ok(value)
+            }
+        }
+        Err(format!("The CA took too long with {}", url))
+    }
+
+    // returns the PEM certificate chain and its private key
+    async fn issue(&mut self, config: &AcmeConfig, challenges: &Challenges) -> Result<(String, String), String> {
+        let mut account = json!({"termsOfServiceAgreed": true});
+        if let Some(contact) = &config.contact {
+            account["contact"] = json!([format!("mailto:{}", contact)]);
+        }
+        let new_account = self.directory.new_account.clone();
+        let response = self.post(&new_account, Some(account)).await?; // an existing account is returned as is
+        self.kid = Some(location(&response)?);
+
+        let identifiers: Vec<Value> = config.domains.iter().map(|domain| json!({"type": "dns", "value": domain})).collect();
+        let new_order = self.directory.new_order.clone();
+        let response = self.post(&new_order, Some(json!({"identifiers": identifiers}))).await?;
+        let order_url = location(&response)?;
+        let order: Order = response.json().await.map_err(|e| format!("Could not read the ACME order: {}", e))?;
+
+        for url in &order.authorizations {
+            let authorization: Authorization = self.post(url, None).await?.json().await.map_err(|e| format!("Could not read the ACME authorization: {}", e))?;
+            if authorization.status == "valid" { // proven recently enough that the CA still remembers
+                continue;
+            }
+            let domain = authorization.identifier.value;
+            let (challenge_url, token) = match authorization.challenges.into_iter().find(|challenge| challenge.kind == "http-01") {
+                Some(Challenge { url, token: Some(token), .. }) => (url, token),
+                _ => return Err(format!("The CA offered no http-01 challenge for {}", domain))
+            };
+
+            info!("Proving control of {} to the CA", domain);
+            challenges.write().await.insert(token.clone(), self.key_authorization(&token));
+            let result = match self.post(&challenge_url, Some(json!({}))).await {
+                Ok(_) => self.wait(url).await,
+                Err(e) => Err(e)
+            };
+            challenges.write().await.remove(&token);
+            let result = result?;
+            if result["status"] != "valid" {
+                let detail = result["challenges"].as_array()
+                    .and_then(|challenges| challenges.iter().find_map(|challenge| challenge["error"]["detail"].as_str()))
+                    .unwrap_or("no details given");
+                return Err(format!("{} could not be validated, the CA has to reach http://{}/.well-known/acme-challenge/ on port 80: {}", domain, domain, detail));
+            }
+        }
+
+        let key = rcgen::KeyPair::generate().map_err(|e| e.to_string())?;
+        let csr = rcgen::CertificateParams::new(config.domains.clone())
+            .and_then(|mut params| {
+                params.distinguished_name = rcgen::DistinguishedName::new(); // the CA only looks at the names, not rcgen's placeholder subject
+                params.serialize_request(&key)
+            })
+            .map_err(|e| format!("Could not make the certificate request: {}", e))?;
+        self.post(&order.finalize, Some(json!({"csr": b64(csr.der())}))).await?;
+
+        let order = self.wait(&order_url).await?;
+        let certificate_url = match (order["status"].as_str(), order["certificate"].as_str()) {
+            (Some("valid"), Some(url)) => url.to_string(),
+            (status, _) => return Err(format!("The order ended up {} instead of being issued", status.unwrap_or("unknown")))
+        };
+        let chain = self.post(&certificate_url, None).await?.text().await.map_err(|e| e.to_string())?;
+        Ok((chain, key.serialize_pem()))
+    }
+}
+
+fn replay_nonce(response: &Response) -> Option<String> {
+    response.headers().get("replay-nonce").and_then(|nonce| nonce.to_str().ok()).map(|nonce| nonce.to_string())
+}
+
+fn location(response: &Response) -> Result<String, String> {
+    response.headers().get(LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .map(|location| location.to_string())
+        .ok_or("The CA did not say where to find what it created".to_string())
+}
+
+// whole days until the certificate in the file expires, None if there isn't a readable one
+fn days_left(path: &PathBuf) -> Option<i64> {
+    let pem = std::fs::read(path).ok()?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&pem).ok()?;
+    let cert = pem.parse_x509().ok()?;
+    Some(cert.validity().time_to_expiration().map(|left| left.whole_days()).unwrap_or(0))
+}
+
+#[derive(Clone)]
+pub struct Acme {
+    config: AcmeConfig,
+    dir: PathBuf,
+    challenges: Challenges,
+    client_ca: Vec<String>,
+}
+
+impl Acme {
+    pub fn new(config: AcmeConfig, client_ca: Vec<String>) -> Result<Self, String> {
+        if config.domains.is_empty() {
+            return Err("acme needs at least one domain".to_string());
+        }
+        let dir = PathBuf::from(shellexpand::tilde(config.cache_dir.as_deref().unwrap_or("~/.config/bytebeam/acme")).into_owned());
+        std::fs::create_dir_all(&dir).map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+        Ok(Acme { config, dir, challenges: Challenges::default(), client_ca })
+    }
+
+    pub fn challenges(&self) -> Challenges {
+        self.challenges.clone()
+    }
+
+    fn directory_url(&self) -> &str {
+        match (&self.config.directory_url, self.config.staging.unwrap_or(false)) {
+            (Some(url), _) => url,
+            (None, true) => LETS_ENCRYPT_STAGING,
+            (None, false) => LETS_ENCRYPT
+        }
+    }
+
+    // named after the first domain, so changing the domains asks for a new certificate instead of serving the old one
+    fn cert_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.crt", self.config.domains[0]))
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.key", self.config.domains[0]))
+    }
+
+    fn needs_renewal(&self) -> bool {
+        match days_left(&self.cert_path()) {
+            Some(days) => {
+                debug!("ACME certificate has {} days left", days);
+                days < RENEW_WITHIN_DAYS
+            },
+            None => true
+        }
+    }
+
+    // the same account is used every time, the CA only rate limits new ones more strictly
+    fn account_key(&self) -> Result<EcdsaKeyPair, String> {
+        let path = self.dir.join("account.pk8");
+        let rng = SystemRandom::new();
+        let pkcs8 = match std::fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(_) => {
+                info!("Creating an ACME account key in {}", path.display());
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).map_err(|_| "Could not generate an account key".to_string())?;
+                write_private(&path, pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng).map_err(|e| format!("{} is not a usable account key: {}", path.display(), e))
+    }
+
+    async fn renew(&self) -> Result<(), String> {
+        info!("Requesting a certificate for {} from {}", self.config.domains.join(", "), self.directory_url());
+        let mut session = Session::new(self.directory_url(), self.account_key()?).await?;
+        let (chain, key) = session.issue(&self.config, &self.challenges).await?;
+        // both are written out before either is swapped in, so a restart in between never pairs a key with the wrong certificate
+        let staged_key = stage_private(&self.key_path(), key.as_bytes())?;
+        let staged_cert = stage_private(&self.cert_path(), chain.as_bytes()).inspect_err(|_| discard(&staged_key))?;
+        replace(&staged_key, &self.key_path()).inspect_err(|_| discard(&staged_cert))?;
+        replace(&staged_cert, &self.cert_path())?;
+        info!("Got a new certificate for {}", self.config.domains.join(", "));
+        Ok(())
+    }
+
+    fn load(&self) -> Result<ServerConfig, String> {
+        tls::server_config(&self.cert_path().to_string_lossy(), &self.key_path().to_string_lossy(), &self.client_ca)
+    }
+
+    // the cached certificate if it's good for a while yet, otherwise a new one. the http listener has to be up for this
+    pub async fn server_config(&self) -> Result<ServerConfig, String> {
+        if self.needs_renewal() {
+            if let Err(e) = self.renew().await {
+                match self.load() { // an old certificate that hasn't run out is better than none
+                    Ok(config) if days_left(&self.cert_path()).unwrap_or(0) > 0 => {
+                        warn!("Could not renew the certificate, using the cached one for now: {}", e);
+                        return Ok(config);
+                    },
+                    _ => return Err(e)
+                }
+            }
+        }
+        self.load()
+    }
+
+    // checks twice a day and swaps the new certificate in without restarting the listener
+    pub async fn keep_renewed(self, tls: RustlsConfig) {
+        loop {
+            tokio::time::sleep(CHECK_EVERY).await;
+            if !self.needs_renewal() {
+                continue;
+            }
+            match self.renew().await.and_then(|_| self.load()) {
+                Ok(config) => tls.reload_from_config(Arc::new(config)),
+                Err(e) => error!("Could not renew the certificate, trying again later: {}", e)
+            }
+        }
+    }
+}
+
+fn write_private(path: &std::path::Path, data: &[u8]) -> Result<(), String> {
+    replace(&stage_private(path, data)?, path)
+}
+
+// written beside the file it will replace, only the relay's user can read it and nothing already there is written through
+fn stage_private(path: &std::path::Path, data: &[u8]) -> Result<PathBuf, String> {
+    use std::io::Write;
+    let staged = path.with_file_name(format!(".{}.{}", path.file_name().unwrap_or_default().to_string_lossy(), uuid::Uuid::new_v4()));
+    let mut file = private_options().open(&staged).map_err(|e| format!("Could not save {}: {}", path.display(), e))?;
+    file.write_all(data).and_then(|_| file.sync_all()).map_err(|e| {
+        discard(&staged);
+        format!("Could not save {}: {}", path.display(), e)
+    })?;
+    Ok(staged)
+}
+
+// a rename is all or nothing, anything reading the path gets the old file or the new one and never half of one
+fn replace(staged: &std::path::Path, path: &std::path::Path) -> Result<(), String> {
+    std::fs::rename(staged, path).map_err(|e| {
+        discard(staged);
+        format!("Could not save {}: {}", path.display(), e)
+    })
+}
+
+fn discard(staged: &std::path::Path) {
+    let _ = std::fs::remove_file(staged);
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use axum::{extract::{Path, State}, http::{header::COOKIE, HeaderMap, StatusCode}, Form, Json};
+use chrono::{DateTime, Duration, Utc};
+use maud::{html, Markup};
+use serde::Serialize;
+use utoipa::ToSchema;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::utils::{metadata::{FileMetadata, FileState}, stats::RelayStats};
+
+use super::{appstate::AppState, eventlog::LoggedEvent, openapi::SignedForm, routes};
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct RelayStatus {
+    draining: bool,
+    read_only: bool,
+    active_transfers: usize,
+    waiting: usize, // tokens issued but not uploaded to yet, which can still start while draining
+    idle: bool, // draining with nothing in flight or waiting to start, so the relay can be restarted
+}
+
+// everything the relay knows about one token, nothing redacted
+#[derive(Serialize, Debug, ToSchema)]
+pub struct TokenDetails {
+    metadata: FileMetadata,
+    events: Vec<LoggedEvent>,
+}
+
+const CHALLENGE_LIFETIME: i64 = 60; // seconds to sign and return a challenge
+pub const MAX_CHALLENGES: usize = 8; // outstanding for one admin, so signing in from a second tab doesn't throw away the first
+pub const SESSION_LIFETIME: i64 = 15; // minutes
+
+#[derive(Debug, Clone)]
+pub struct AdminChallenge {
+    pub text: String,
+    pub user: String,
+    pub expires: DateTime<Utc>,
+}
+
+impl AdminChallenge {
+    pub fn new(user: &str) -> Self {
+        AdminChallenge {
+            text: format!("admin:{}", Uuid::new_v4()),
+            user: user.to_string(),
+            expires: Utc::now() + Duration::seconds(CHALLENGE_LIFETIME),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        Utc::now() < self.expires
+    }
+
+    pub fn matches(&self, user: &String) -> bool {
+        self.is_valid() && &self.user == user
+    }
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct AdminSession {
+    pub token: String,
+    pub user: String,
+    pub expires: DateTime<Utc>,
+}
+
+impl AdminSession {
+    pub fn new(user: &str) -> Self {
+        AdminSession {
+            token: Uuid::new_v4().to_string(),
+            user: user.to_string(),
+            expires: Utc::now() + Duration::minutes(SESSION_LIFETIME),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        Utc::now() < self.expires
+    }
+
+    // every byte is compared whatever the ones before it were, so how long a wrong token takes doesn't tell how much of it was right
+    pub fn matches(&self, token: &str) -> bool {
+        self.token.len() == token.len() && self.token.bytes().zip(token.bytes()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ChallengeResponse {
+    challenge: String,
+    expires_in: i64,
+}
+
+pub const SESSION_COOKIE: &str = "beam_admin";
+
+// the session token from "Authorization: Bearer [token]", or the cookie the dashboard sets after signing in
+fn session_token(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers.get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    bearer.or_else(|| headers.get_all(COOKIE).iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(SESSION_COOKIE).and_then(|rest| rest.strip_prefix('='))))
+}
+
+pub async fn admin_user(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    match session_token(headers) {
+        Some(token) => state.check_admin_session(token).await,
+        None => None
+    }
+}
+
+// admin routes are authenticated with a session token from /admin/session
+pub async fn authorize(state: &AppState, headers: &HeaderMap) -> Result<String, (StatusCode, Markup)> {
+    match admin_user(state, headers).await {
+        Some(user) => Ok(user),
+        None => {
+            warn!("Rejected unauthorized admin request");
+            Err((StatusCode::UNAUTHORIZED, html! {"Unauthorized"}))
+        }
+    }
+}
+
+// step one of signing in: POST user=[admin], sign the returned challenge with "ssh-keygen -Y sign -n bytebeam"
+#[utoipa::path(post, path = routes::ADMIN_CHALLENGE, tag = "admin",
+    request_body(content = String, description = "user=[admin]", content_type = "application/x-www-form-urlencoded"),
+    responses((status = 200, body = ChallengeResponse), (status = 401, description = "Not an admin")))]
+pub async fn get_challenge(State(state): State<AppState>, Form(params): Form<HashMap<String, String>>) -> Result<Json<ChallengeResponse>, (StatusCode, Markup)> {
+    let user = match params.get("user") {
+        Some(user) => user,
+        None => return Err((StatusCode::BAD_REQUEST, html! {"user is required"})),
+    };
+
+    if !state.is_admin(user) {
+        warn!("Admin challenge requested for {}, who is not an admin", user);
+        return Err((StatusCode::UNAUTHORIZED, html! {"Unauthorized"}));
+    }
+
+    Ok(Json(ChallengeResponse {
+        challenge: state.issue_admin_challenge(user).await,
+        expires_in: CHALLENGE_LIFETIME,
+    }))
+}
+
+// step two: POST user, challenge, and signature (one armored signature or a JSON list of them) for a session token
+#[utoipa::path(post, path = routes::ADMIN_SESSION, tag = "admin",
+    request_body(content = SignedForm, content_type = "application/x-www-form-urlencoded"),
+    responses((status = 200, description = "The token goes in Authorization: Bearer", body = AdminSession), (status = 401, description = "Not an admin, or the challenge failed")))]
+pub async fn create_session(State(state): State<AppState>, Form(params): Form<HashMap<String, String>>) -> Result<Json<AdminSession>, (StatusCode, Markup)> {
+    let (user, challenge, signatures) = match (params.get("user"), params.get("challenge"), params.get("signature")) {
+        (Some(user), Some(challenge), Some(signature)) => (user, challenge, signature),
+        _ => return Err((StatusCode::BAD_REQUEST, html! {"user, challenge, and signature are required"})),
+    };
+
+    let signatures: Vec<String> = match serde_json::from_str(signatures) {
+        Ok(s) => s,
+        Err(_) => vec![signatures.to_string()],
+    };
+
+    if !state.is_admin(user) {
+        warn!("Admin session requested for {}, who is not an admin", user);
+        return Err((StatusCode::UNAUTHORIZED, html! {"Unauthorized"}));
+    }
+
+    match state.start_admin_session(user, challenge, &signatures).await {
+        Some(session) => {
+            info!("Admin session started for {}", user);
+            Ok(Json(session))
+        },
+        None => {
+            debug!("Admin challenge for {} failed verification", user);
+            Err((StatusCode::UNAUTHORIZED, html! {"Challenge failed"}))
+        }
+    }
+}
+
+async fn relay_status(state: &AppState) -> RelayStatus {
+    let draining = state.is_draining();
+    let active_transfers = state.active_transfers().await;
+    let waiting = state.waiting_uploads().await;
+    RelayStatus {
+        draining,
+        read_only: state.is_read_only(),
+        active_transfers,
+        waiting,
+        idle: draining && active_transfers == 0 && waiting == 0
+    }
+}
+
+fn parse_enabled(params: &HashMap<String, String>) -> bool {
+    match params.get("enabled") {
+        Some(e) => e.parse().unwrap_or(true),
+        None => true
+    }
+}
+
+#[utoipa::path(get, path = routes::ADMIN_STATUS, tag = "admin", security(("admin_session" = [])), responses((status = 200, body = RelayStatus), (status = 401)))]
+pub async fn get_status(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<RelayStatus>, (StatusCode, Markup)> {
+    authorize(&state, &headers).await?;
+    Ok(Json(relay_status(&state).await))
+}
+
+// POST with enabled=false to cancel a drain, anything else starts one
+#[utoipa::path(post, path = routes::ADMIN_DRAIN, tag = "admin", security(("admin_session" = [])), request_body(content = String, description = "enabled=false cancels the drain", content_type = "application/x-www-form-urlencoded"),
+    responses((status = 200, body = RelayStatus), (status = 401)))]
+pub async fn set_drain(State(state): State<AppState>, headers: HeaderMap, Form(params): Form<HashMap<String, String>>) -> Result<Json<RelayStatus>, (StatusCode, Markup)> {
+    let admin = authorize(&state, &headers).await?;
+    let enabled = parse_enabled(&params);
+
+    state.set_draining(enabled);
+    if enabled {
+        info!("Relay is now draining (by {}), no new uploads will be accepted", admin);
+    } else {
+        info!("Relay drain cancelled by {}, accepting uploads again", admin);
+    }
+
+    Ok(Json(relay_status(&state).await))
+}
+
+// POST with enabled=false to allow uploads again
+#[utoipa::path(post, path = routes::ADMIN_READ_ONLY, tag = "admin", security(("admin_session" = [])), request_body(content = String, description = "enabled=false allows uploads again", content_type = "application/x-www-form-urlencoded"),
+    responses((status = 200, body = RelayStatus), (status = 401)))]
+pub async fn set_read_only(State(state): State<AppState>, headers: HeaderMap, Form(params): Form<HashMap<String, String>>) -> Result<Json<RelayStatus>, (StatusCode, Markup)> {
+    let admin = authorize(&state, &headers).await?;
+    let enabled = parse_enabled(&params);
+
+    state.set_read_only(enabled);
+    if enabled {
+        info!("Relay is now read-only (by {}), existing files can still be downloaded", admin);
+    } else {
+        info!("Relay is no longer read-only (by {})", admin);
+    }
+
+    Ok(Json(relay_status(&state).await))
+}
+
+// every token the relay is holding, oldest first, with upload keys and file names left in
+#[utoipa::path(get, path = routes::ADMIN_TOKENS, tag = "admin", security(("admin_session" = [])), responses((status = 200, body = Vec<FileMetadata>), (status = 401)))]
+pub async fn list_tokens(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<Vec<FileMetadata>>, (StatusCode, Markup)> {
+    authorize(&state, &headers).await?;
+    Ok(Json(state.inspect_files().await))
+}
+
+#[utoipa::path(get, path = routes::ADMIN_TOKEN, tag = "admin", security(("admin_session" = [])), params(("token" = String, Path)), responses((status = 200, body = TokenDetails), (status = 401), (status = 404)))]
+pub async fn get_token(State(state): State<AppState>, headers: HeaderMap, Path(token): Path<String>) -> Result<Json<TokenDetails>, (StatusCode, Markup)> {
+    authorize(&state, &headers).await?;
+    match state.inspect_file(&token).await {
+        Some(metadata) => Ok(Json(TokenDetails { metadata, events: state.get_events(&token).await })),
+        None => Err((StatusCode::NOT_FOUND, html! {"Token not found"}))
+    }
+}
+
+// stops whatever is transferring and forgets the token, handing back what it was
+#[utoipa::path(delete, path = routes::ADMIN_TOKEN, tag = "admin", security(("admin_session" = [])), params(("token" = String, Path)), responses((status = 200, description = "What the token was", body = FileMetadata), (status = 401), (status = 404)))]
+pub async fn delete_token(State(state): State<AppState>, headers: HeaderMap, Path(token): Path<String>) -> Result<Json<FileMetadata>, (StatusCode, Markup)> {
+    let admin = authorize(&state, &headers).await?;
+    match state.force_delete(&token).await {
+        Some(metadata) => {
+            info!("Token {} deleted by {}", token, admin);
+            Ok(Json(metadata))
+        },
+        None => Err((StatusCode::NOT_FOUND, html! {"Token not found"}))
+    }
+}
+
+#[utoipa::path(get, path = routes::ADMIN_STATS, tag = "admin", security(("admin_session" = [])), responses((status = 200, body = RelayStats), (status = 401)))]
+pub async fn get_stats(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<RelayStats>, (StatusCode, Markup)> {
+    authorize(&state, &headers).await?;
+    let files = state.inspect_files().await;
+    let users = state.get_all_user_stats().await;
+    Ok(Json(RelayStats {
+        tokens: files.len(),
+        waiting: files.iter().filter(|file| file.get_states().0 == &FileState::NotStarted).count(),
+        active_transfers: files.iter().filter(|file| file.is_transferring()).count(),
+        authenticated: files.iter().filter(|file| file.authenticated()).count(),
+        users: users.len(),
+        uploads: users.values().map(|stats| stats.uploads()).sum(),
+        bytes: users.values().map(|stats| stats.bytes()).sum(),
+        draining: state.is_draining(),
+        read_only: state.is_read_only(),
+    }))
+}
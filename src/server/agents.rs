@@ -0,0 +1,82 @@
+use regex::Regex;
+use serde::Deserialize;
+use tracing::warn;
+
+// chat apps fetch a link to preview it, and given the file instead of the page they use up the download
+const PREVIEWERS: [&str; 6] = ["TelegramBot", "Slackbot", "Discordbot", "facebookexternalhit", "Twitterbot", "^WhatsApp"];
+const BROWSERS: [&str; 2] = ["^Mozilla", "^WhatsApp"];
+
+// which requests get the landing page and which get the file, for what the Accept header doesn't settle
+#[derive(Deserialize, Debug, Clone)]
+pub struct AgentRules {
+    landing: Option<Vec<String>>, // regexes for agents that always get the landing page whatever they accept, defaults to the chat previewers
+    direct: Option<Vec<String>>, // regexes for agents that always get the file, checked first, like a script whose agent looks like a browser
+    browsers: Option<Vec<String>>, // regexes for agents that get the landing page when there is no Accept header, defaults to Mozilla and WhatsApp
+    default: Option<AgentDefault>, // for requests without an Accept header that no browsers pattern matches, download unless set
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentDefault {
+    Landing,
+    #[default]
+    Download,
+}
+
+#[derive(Debug, Clone)]
+pub struct Agents {
+    landing: Vec<Regex>,
+    direct: Vec<Regex>,
+    browsers: Vec<Regex>, // only for requests without an Accept header
+    default: AgentDefault,
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns.iter()
+        .map(|pattern| Regex::new(pattern).map_err(|e| format!("Bad user agent pattern {}: {}", pattern, e)))
+        .collect()
+}
+
+impl Agents {
+    // browser_agents is the prefix list from before there were rules, it's still read as user_agents.browsers
+    pub fn new(rules: Option<AgentRules>, browser_agents: Option<Vec<String>>) -> Result<Self, String> {
+        let (landing, direct, browsers, default) = match rules {
+            Some(rules) => (rules.landing, rules.direct, rules.browsers, rules.default),
+            None => (None, None, None, None)
+        };
+        let browsers = match (browsers, browser_agents) {
+            (Some(browsers), Some(_)) => {
+                warn!("browser_agents is deprecated and ignored, user_agents.browsers is set");
+                browsers
+            },
+            (None, Some(prefixes)) => {
+                warn!("browser_agents is deprecated, use user_agents.browsers with a ^ in front of each prefix instead");
+                prefixes.iter().map(|prefix| format!("^{}", regex::escape(prefix))).collect()
+            },
+            (Some(browsers), None) => browsers,
+            (None, None) => BROWSERS.iter().map(|agent| agent.to_string()).collect()
+        };
+        Ok(Agents {
+            landing: compile(&landing.unwrap_or(PREVIEWERS.iter().map(|agent| agent.to_string()).collect()))?,
+            direct: compile(&direct.unwrap_or_default())?,
+            browsers: compile(&browsers)?,
+            default: default.unwrap_or_default()
+        })
+    }
+
+    // Some when a rule decides whatever the request accepts
+    pub fn ruled(&self, user_agent: &str) -> Option<bool> {
+        if self.direct.iter().any(|rule| rule.is_match(user_agent)) {
+            return Some(false);
+        }
+        if self.landing.iter().any(|rule| rule.is_match(user_agent)) {
+            return Some(true);
+        }
+        None
+    }
+
+    // a request that didn't say what it accepts
+    pub fn guess(&self, user_agent: &str) -> bool {
+        self.browsers.iter().any(|rule| rule.is_match(user_agent)) || self.default == AgentDefault::Landing
+    }
+}
@@ -1,44 +1,228 @@
-use std::{collections::HashMap, sync::Arc, thread};
+use std::{collections::HashMap, path::PathBuf, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc}, thread, time::Instant};
+use bytes::Bytes;
 use reqwest::StatusCode;
-use tokio::sync::{mpsc::{channel, Receiver, Sender}, Mutex};
-use tracing::{debug, trace};
+use chrono::{NaiveDate, TimeDelta, Utc};
+use tokio::{sync::{mpsc::{channel, Receiver, Sender}, Mutex}, task::JoinHandle};
+use tracing::{debug, error, info, trace, warn};
 
-use crate::utils::{compression::Compression, metadata::FileMetadata};
+use crate::utils::{checksum::Checksum, compression::Compression, metadata::{Delta, Disposition, FileAttributes, FileMetadata, FileState, ManifestEntry, PeerOffer}};
 
-use super::{keymanager::KeyManager, serveropts::ServerOptions};
+use super::{admin::{AdminChallenge, AdminSession, MAX_CHALLENGES}, agents::Agents, broadcast::Spool, chunked::ChunkedUpload, frames::{Parked, ResendWindow, Verifier, RESUME_WAIT}, spill::spill_over, eventlog::{crossed_milestone, LoggedEvent, TokenEvent}, forwarded::PublicUrl, keymanager::KeyManager, sealed::SealingKey, serveropts::{Group, ServerOptions}, shared::{Change, TokenStore}, stats::{self, CullStats, UserStats}, storage::ObjectStore, throttle::{address_key, SlidingWindow}};
+
+// how stale the other relays' idea of when a token was last used can get
+const SHARED_ACCESS: TimeDelta = TimeDelta::seconds(30);
+
+// live byte counts for a token, the handlers add to these directly and readers fold them into the metadata
+#[derive(Debug, Default)]
+pub struct TransferCounters {
+    uploaded: AtomicUsize,
+    downloaded: AtomicUsize,
+    cancelled: AtomicBool, // the token was deleted out from under the transfer
+    quota: AtomicUsize, // most bytes the upload may send, set when it starts, 0 when there is no limit
+}
+
+impl TransferCounters {
+    pub fn uploaded(&self) -> usize {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> usize {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn over_quota(&self) -> bool {
+        let quota = self.quota.load(Ordering::Relaxed);
+        quota > 0 && self.uploaded() > quota
+    }
+}
+
+const RESERVED_TOKENS: [&str; 7] = ["admin", "api", "assets", "bundle", "healthz", "readyz", "ws"]; // these would be shadowed by routes
+
+// custom tokens end up in urls and links people read out, so they're kept short and plain
+fn valid_token_name(name: &str) -> bool {
+    (3..=64).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !name.starts_with('-') && !name.ends_with('-')
+        && !RESERVED_TOKENS.contains(&name)
+}
+
+fn fold_counters(file: &mut FileMetadata, counters: &TransferCounters) {
+    // broadcast downloads all add to the same count, it can't say more than that the upload has been read
+    let downloaded = match file.is_broadcast() {
+        true => counters.downloaded().min(counters.uploaded()),
+        false => counters.downloaded()
+    };
+    file.file_size.set_transferred(counters.uploaded(), downloaded);
+}
+
+type ByToken<T> = Arc<Mutex<HashMap<String, T>>>;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     files: Arc<Mutex<HashMap<String, FileMetadata>>>,
     downloads: Arc<Mutex<HashMap<String, Receiver<Vec<u8>>>>>,
     uploads: Arc<Mutex<HashMap<String, Sender<Vec<u8>>>>>,
+    events: Arc<Mutex<HashMap<String, Vec<LoggedEvent>>>>,
+    counters: Arc<Mutex<HashMap<String, Arc<TransferCounters>>>>,
+    bundles: Arc<Mutex<HashMap<String, Vec<String>>>>, // one download link for several tokens
+    spools: ByToken<(Arc<Spool>, usize)>, // broadcast tokens that have started, with how many are downloading right now
+    chunked: Arc<Mutex<HashMap<String, Arc<Mutex<ChunkedUpload>>>>>, // uploads coming in as a series of requests from the web page
+    signatures: Arc<Mutex<HashMap<String, Vec<u8>>>>, // block checksums from a downloader, until the uploader takes them for a delta
+    stats: Arc<Mutex<HashMap<String, UserStats>>>, // per authenticated user
+    stats_path: Option<PathBuf>, // where they are saved between restarts
+    stats_changed: Arc<AtomicBool>, // since they were last saved
+    used_challenges: Arc<Mutex<HashMap<String, Instant>>>, // single use challenges seen recently, with when
+    parked: Arc<Mutex<HashMap<String, Parked>>>, // uploads stopped by a damaged frame, waiting to be sent again
+    resends: Arc<Mutex<HashMap<String, Arc<std::sync::Mutex<ResendWindow>>>>>, // the last frames of each framed download
+    culls: Arc<Mutex<CullStats>>,
+    daily_usage: Arc<Mutex<HashMap<String, (NaiveDate, usize)>>>, // bytes each authenticated user has uploaded today, for max_bytes_per_day
+    address_usage: Arc<Mutex<HashMap<String, (NaiveDate, usize)>>>, // the same for public uploads, by client address
+    payers: ByToken<String>, // the address each public upload's bytes count against, from when it started
     reg_options: ServerOptions, // for all users w/o keysigning
     auth_options: ServerOptions, // for verified users
-    keys: KeyManager
+    groups: Vec<(String, Group)>, // by name, verified users in one of these get its options instead
+    keys: KeyManager,
+    admins: Vec<String>,
+    admin_challenges: Arc<Mutex<HashMap<String, AdminChallenge>>>, // by challenge text, up to MAX_CHALLENGES outstanding for each admin
+    admin_sessions: Arc<Mutex<HashMap<String, AdminSession>>>, // by session token
+    draining: Arc<AtomicBool>, // when set, no new tokens are handed out but existing transfers continue
+    read_only: Arc<AtomicBool>, // when set, nothing new can be uploaded but existing files can still be downloaded
+    banner: Option<String>,
+    agents: Agents, // which requests get the landing page instead of the file
+    allow_inline_override: bool,
+    members_only: bool, // no public tier, tokens have to be upgraded before they can be used
+    hide_upload_form: bool,
+    web_uploader: Option<PathBuf>, // where the browser uploader's files are served from
+    direct_mode: bool, // no download landing pages for any token
+    transcode: bool, // compressed uploads are converted for downloaders that don't accept their compression
+    public_url: PublicUrl, // the prefix and forwarded headers links are made with when behind a reverse proxy
+    token_limit: Option<Arc<SlidingWindow>>, // new tokens per client address
+    store: Option<ObjectStore>, // where uploads that asked to be kept are written instead of waiting for a downloader
+    storing: ByToken<JoinHandle<Result<(), String>>>, // writes to the store still going, by token
+    store_keys: Arc<Mutex<HashMap<String, SealingKey>>>, // what each kept upload is sealed with, only ever in memory
+    fetch_private: bool, // fetches for authenticated uploads can reach the relay's own network
+    shared: Arc<dyn TokenStore>, // the other relays serving the same tokens, if there are any
+    remote_uploads: Arc<Mutex<HashMap<String, String>>>, // tokens whose upload another relay is taking, with which one
+    started: Instant, // for the uptime health checks report
+    reflector: Option<u16>, // the port peer_reflector listens on, for direct mode to punch through NATs
+}
+
+// everything the relay is started with, as read from its config
+pub struct StateConfig {
+    pub reg_options: ServerOptions,
+    pub auth_options: ServerOptions,
+    pub groups: Vec<(String, Group)>,
+    pub keyserver: Option<String>,
+    pub keyserver_ttl: std::time::Duration,
+    pub users: Vec<String>,
+    pub user_ca: Vec<String>,
+    pub admins: Vec<String>,
+    pub read_only: bool,
+    pub banner: Option<String>,
+    pub agents: Agents,
+    pub allow_inline_override: bool,
+    pub members_only: bool,
+    pub hide_upload_form: bool,
+    pub web_uploader: Option<PathBuf>,
+    pub direct_mode: bool,
+    pub transcode: bool,
+    pub public_url: PublicUrl,
+    pub token_limit: Option<SlidingWindow>,
+    pub store: Option<ObjectStore>,
+    pub fetch_private: bool,
+    pub stats_path: Option<PathBuf>,
+    pub shared: Arc<dyn TokenStore>,
+    pub reflector: Option<u16>,
 }
 
 impl AppState {
-    pub async fn new(reg_options: ServerOptions, auth_options: ServerOptions, keyserver: Option<String>, users: Vec<String>) -> Self {
+    pub async fn new(config: StateConfig) -> Self {
+        let StateConfig { reg_options, auth_options, groups, keyserver, keyserver_ttl, users, user_ca, admins, read_only, banner, agents,
+            allow_inline_override, members_only, hide_upload_form, web_uploader, direct_mode, transcode, public_url, token_limit, store,
+            fetch_private, stats_path, shared, reflector } = config;
         let state = AppState {
             files: Arc::new(Mutex::new(HashMap::new())),
             downloads: Arc::new(Mutex::new(HashMap::new())),
             uploads: Arc::new(Mutex::new(HashMap::new())),
-            keys: KeyManager::new_checking_keyserver(keyserver, users).await,
+            events: Arc::new(Mutex::new(HashMap::new())),
+            counters: Arc::new(Mutex::new(HashMap::new())),
+            bundles: Arc::new(Mutex::new(HashMap::new())),
+            spools: Arc::new(Mutex::new(HashMap::new())),
+            chunked: Arc::new(Mutex::new(HashMap::new())),
+            signatures: Arc::new(Mutex::new(HashMap::new())),
+            stats: Arc::new(Mutex::new(stats_path.as_ref().map(stats::load).unwrap_or_default())),
+            stats_path,
+            stats_changed: Arc::new(AtomicBool::new(false)),
+            used_challenges: Arc::new(Mutex::new(HashMap::new())),
+            parked: Arc::new(Mutex::new(HashMap::new())),
+            resends: Arc::new(Mutex::new(HashMap::new())),
+            culls: Arc::new(Mutex::new(CullStats::default())),
+            daily_usage: Arc::new(Mutex::new(HashMap::new())),
+            address_usage: Arc::new(Mutex::new(HashMap::new())),
+            payers: Arc::new(Mutex::new(HashMap::new())),
+            keys: KeyManager::new_checking_keyserver(keyserver, users, user_ca, keyserver_ttl).await,
             reg_options,
-            auth_options
+            auth_options,
+            groups,
+            admins,
+            admin_challenges: Arc::new(Mutex::new(HashMap::new())),
+            admin_sessions: Arc::new(Mutex::new(HashMap::new())),
+            draining: Arc::new(AtomicBool::new(false)),
+            read_only: Arc::new(AtomicBool::new(read_only)),
+            banner,
+            agents,
+            allow_inline_override,
+            members_only,
+            hide_upload_form,
+            web_uploader,
+            direct_mode,
+            transcode,
+            public_url,
+            token_limit: token_limit.map(Arc::new),
+            store,
+            storing: Arc::new(Mutex::new(HashMap::new())),
+            store_keys: Arc::new(Mutex::new(HashMap::new())),
+            fetch_private,
+            shared,
+            remote_uploads: Arc::new(Mutex::new(HashMap::new())),
+            started: Instant::now(),
+            reflector
         };
+        state.join_shared().await;
 
         let cull_state = state.clone();
         thread::spawn(move || {
             let rt = tokio::runtime::Runtime::new().unwrap();
             rt.block_on(async {
                 trace!("Starting cull loop");
+                let mut reported_idle = false;
                 loop {
                     tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
                     let culls = cull_state.cull().await;
+                    if let Some(limit) = &cull_state.token_limit {
+                        limit.prune().await;
+                    }
+                    cull_state.address_usage.lock().await.retain(|_, (day, _)| *day == Utc::now().date_naive());
                     if culls > 0 {
                         debug!("Culled {} uploads (expired)", culls);
                     }
+                    cull_state.save_stats().await;
+
+                    if cull_state.is_draining() {
+                        let active = cull_state.active_transfers().await + cull_state.waiting_uploads().await;
+                        if active == 0 && !reported_idle {
+                            info!("Relay is drained and idle, it is safe to shut down");
+                            reported_idle = true;
+                        } else if active > 0 {
+                            reported_idle = false;
+                        }
+                    } else {
+                        reported_idle = false;
+                    }
                 }
             });
         });
@@ -46,86 +230,615 @@ impl AppState {
         state
     }
 
-    pub async fn generate_file_upload(&self, file_name: &String, user: Option<&String>) -> Option<FileMetadata> {
+    // tokens the other relays already have, and a task applying what they do from now on
+    async fn join_shared(&self) {
+        for meta in self.shared.load().await {
+            self.adopt(meta).await;
+        }
+        if let Some(mut changes) = self.shared.changes() {
+            let state = self.clone();
+            tokio::spawn(async move {
+                while let Some(change) = changes.recv().await {
+                    state.apply(change).await;
+                }
+            });
+        }
+    }
+
+    async fn apply(&self, change: Change) {
+        match change {
+            Change::Saved(meta) => self.adopt(*meta).await,
+            Change::Removed(ticket) => {
+                self.forget(&ticket).await;
+            },
+            Change::UploadStarted { ticket, relay } => {
+                let stored = self.files.lock().await.get(&ticket).map(|meta| meta.is_stored()).unwrap_or(true);
+                if stored { // kept uploads are sealed with a key only that relay has, so they're downloaded from it
+                    return;
+                }
+                self.remote_uploads.lock().await.insert(ticket.clone(), relay);
+                // a download already waiting here gets the bytes now, otherwise begin_download claims them
+                if !self.downloads.lock().await.contains_key(&ticket) {
+                    self.claim_remote(&ticket).await;
+                }
+            },
+            Change::Claimed(ticket) => match self.downloads.lock().await.remove(&ticket) {
+                Some(rx) => self.shared.forward(&ticket, rx),
+                None => debug!("Another relay claimed {}, but its upload isn't waiting here", ticket)
+            }
+        }
+    }
+
+    // a token another relay made or changed. new ones get a channel here too, the upload and download can come to either relay.
+    // locks are taken in the same order as generate_file_upload, so the two can't wait on each other
+    async fn adopt(&self, meta: FileMetadata) {
+        let ticket = meta.get_token().clone();
+        let mut uploads = self.uploads.lock().await;
+        let mut downloads = self.downloads.lock().await;
+        let mut files = self.files.lock().await;
+        if !files.contains_key(&ticket) {
+            let (tx, rx) = channel(self.reg_options.get_cache_size());
+            uploads.insert(ticket.clone(), tx);
+            downloads.insert(ticket.clone(), rx);
+            self.counters.lock().await.insert(ticket.clone(), Arc::new(TransferCounters::default()));
+        }
+        files.insert(ticket, meta);
+    }
+
+    // the download here reads this relay's channel, so when the upload went to another relay its bytes are fed into it
+    async fn claim_remote(&self, ticket: &String) {
+        let relay = match self.remote_uploads.lock().await.get(ticket) {
+            Some(relay) => relay.clone(),
+            None => return
+        };
+        if let Some(tx) = self.uploads.lock().await.remove(ticket) {
+            self.shared.claim(ticket, &relay, tx);
+        }
+    }
+
+    // the sign in still has to verify, a certificate may be how they do it
+    pub fn is_admin(&self, user: &String) -> bool {
+        self.admins.contains(user) && (self.keys.has_user(user) || self.keys.accepts_certificates())
+    }
+
+    // past MAX_CHALLENGES the one closest to running out makes way for the new one
+    pub async fn issue_admin_challenge(&self, user: &str) -> String {
+        let challenge = AdminChallenge::new(user);
+        let text = challenge.text.clone();
+        let mut challenges = self.admin_challenges.lock().await;
+        challenges.retain(|_, challenge| challenge.is_valid());
+        let outstanding: Vec<&AdminChallenge> = challenges.values().filter(|challenge| challenge.user == user).collect();
+        if outstanding.len() >= MAX_CHALLENGES {
+            if let Some(oldest) = outstanding.iter().min_by_key(|challenge| challenge.expires).map(|challenge| challenge.text.clone()) {
+                challenges.remove(&oldest);
+            }
+        }
+        challenges.insert(text.clone(), challenge);
+        text
+    }
+
+    // challenges are single use, a failed attempt means asking for a new one
+    pub async fn start_admin_session(&self, user: &String, challenge: &String, responses: &Vec<String>) -> Option<AdminSession> {
+        let issued = self.admin_challenges.lock().await.remove(challenge);
+        if !issued.is_some_and(|issued| issued.matches(user)) {
+            debug!("Admin challenge for {} is stale or unknown", user);
+            return None;
+        }
+        if !self.keys.verify_any(user, challenge, responses).await {
+            return None;
+        }
+        let session = AdminSession::new(user);
+        self.admin_sessions.lock().await.insert(session.token.clone(), session.clone());
+        Some(session)
+    }
+
+    // looked through rather than looked up, so the only comparison against a real token is the constant time one
+    pub async fn check_admin_session(&self, token: &str) -> Option<String> {
+        let mut sessions = self.admin_sessions.lock().await;
+        let found = sessions.values().find(|session| session.matches(token)).map(|session| (session.token.clone(), session.is_valid(), session.user.clone()));
+        match found {
+            Some((_, true, user)) => Some(user),
+            Some((token, false, _)) => {
+                sessions.remove(&token);
+                None
+            },
+            None => None
+        }
+    }
+
+    pub fn get_banner(&self) -> Option<&String> {
+        self.banner.as_ref()
+    }
+
+    // a configured agent rule decides first, then a request gets the landing page if it asks for html, otherwise fall back to guessing from the user agent
+    pub fn is_browser(&self, accept: Option<&str>, user_agent: &str) -> bool {
+        if let Some(landing) = self.agents.ruled(user_agent) {
+            return landing;
+        }
+        match accept {
+            Some(accept) if !accept.trim().is_empty() => accept.split(',')
+                .map(|media| media.split(';').next().unwrap_or("").trim())
+                .any(|media| media.eq_ignore_ascii_case("text/html") || media.eq_ignore_ascii_case("application/xhtml+xml")),
+            _ => self.agents.guess(user_agent)
+        }
+    }
+
+    // downloaders can always force a save, but can only ask for inline if the server allows it
+    pub fn resolve_disposition(&self, meta: &FileMetadata, requested: Option<&String>) -> Disposition {
+        match requested.and_then(|r| r.parse::<Disposition>().ok()) {
+            Some(Disposition::Attachment) => Disposition::Attachment,
+            Some(Disposition::Inline) if self.allow_inline_override => Disposition::Inline,
+            _ => meta.get_disposition()
+        }
+    }
+
+    // with the public tier off, only users the relay has keys for (or could get a certificate for) can even ask for a token.
+    // nothing is signed yet, so the token can't be used until it is upgraded
+    pub fn may_create(&self, user: Option<&String>) -> bool {
+        if !self.members_only {
+            return true;
+        }
+        match user {
+            Some(user) => self.keys.has_user(user) || self.keys.accepts_certificates(),
+            None => false
+        }
+    }
+
+    pub fn can_store(&self) -> bool {
+        self.store.is_some()
+    }
+
+    pub fn can_fetch_private(&self) -> bool {
+        self.fetch_private
+    }
+
+    pub async fn set_storage(&self, ticket: &String) -> Option<FileMetadata> {
+        let store = self.store.as_ref()?;
+        let mut files = self.files.lock().await;
+        let meta = files.get_mut(ticket)?;
+        meta.set_storage(store.object_name());
+        self.shared.save(meta);
+        Some(meta.clone())
+    }
+
+    // a receiver signs when they download, with a listed key or a certificate
+    pub fn has_keys_for(&self, user: &String) -> bool {
+        self.keys.has_user(user) || self.keys.accepts_certificates()
+    }
+
+    pub fn hides_upload_form(&self) -> bool {
+        self.hide_upload_form
+    }
+
+    pub fn web_uploader(&self) -> Option<&PathBuf> {
+        self.web_uploader.as_ref()
+    }
+
+    // direct tokens skip the landing page, either because the whole relay is direct or the uploader asked
+    pub fn is_direct(&self, meta: &FileMetadata) -> bool {
+        self.direct_mode || meta.is_direct()
+    }
+
+    pub fn transcodes(&self) -> bool {
+        self.transcode
+    }
+
+    pub fn reflector(&self) -> Option<u16> {
+        self.reflector
+    }
+
+    pub fn public_url(&self) -> &PublicUrl {
+        &self.public_url
+    }
+
+    // every token costs a little memory and compute, so one address can't ask for them endlessly
+    pub async fn allow_new_token(&self, address: &str) -> bool {
+        match &self.token_limit {
+            Some(limit) => limit.allow(&address_key(address)).await,
+            None => true
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    pub fn uptime(&self) -> std::time::Duration {
+        self.started.elapsed()
+    }
+
+    // None without a keyserver, otherwise whether it answered the last time it was asked
+    pub fn keyserver_reachable(&self) -> Option<bool> {
+        self.keys.keyserver_reachable()
+    }
+
+    // anything that currently has an upload or download streaming through the relay
+    pub async fn active_transfers(&self) -> usize {
+        self.files.lock().await.values().filter(|meta| meta.is_transferring()).count()
+    }
+
+    // tokens handed out before a drain can still be uploaded to, so they count as work left until they start or expire
+    pub async fn waiting_uploads(&self) -> usize {
+        self.files.lock().await.values().filter(|meta| meta.get_states().0 == &FileState::NotStarted).count()
+    }
+
+    // once someone has authenticated, their group decides what they get. the first by name, if they are in more than one
+    fn authed_options(&self, user: &String) -> &ServerOptions {
+        match self.groups.iter().find(|(_, group)| group.has_member(user)) {
+            Some((_, group)) => group.options(),
+            None => &self.auth_options
+        }
+    }
+
+    pub fn group_of(&self, user: &String) -> Option<&String> {
+        self.groups.iter().find(|(_, group)| group.has_member(user)).map(|(name, _)| name)
+    }
+
+    pub async fn generate_file_upload(&self, file_name: &str, user: Option<&String>, token_name: Option<&String>) -> Result<FileMetadata, (StatusCode, String)> {
         let mut uploads = self.uploads.lock().await;
         let mut downloads = self.downloads.lock().await;
         let mut meta = self.files.lock().await;
-        let (tx, rx) = channel(self.reg_options.get_cache_size()); // TODO: this should be a whole pool instead of just per-request
-    
         let mut upload = FileMetadata::new(&self.reg_options, user);
+        match token_name {
+            Some(name) => {
+                if !valid_token_name(name) {
+                    return Err((StatusCode::BAD_REQUEST, "Custom tokens are 3 to 64 lowercase letters, numbers, and dashes".to_string()));
+                }
+                if meta.contains_key(name) {
+                    debug!("Custom token {} is already taken", name);
+                    return Err((StatusCode::CONFLICT, format!("The token {name} is already taken")));
+                }
+                upload.set_token_name(name.clone());
+            },
+            None => while meta.contains_key(upload.get_token()) { // word tokens can run into a custom one
+                upload = FileMetadata::new(&self.reg_options, user);
+            }
+        }
+        let (tx, rx) = channel(self.reg_options.get_cache_size()); // TODO: this should be a whole pool instead of just per-request
 
-        upload.file_name = file_name.clone();//.split_off(40);
+        upload.file_name = file_name.to_string();//.split_off(40);
     
         uploads.insert(upload.get_token().clone(), tx);
         downloads.insert(upload.get_token().clone(), rx);
 
-        meta.insert(upload.get_token().clone(), upload.clone());        
-        Some(upload)
+        meta.insert(upload.get_token().clone(), upload.clone());
+        self.shared.save(&upload);
+        self.counters.lock().await.insert(upload.get_token().clone(), Arc::new(TransferCounters::default()));
+        self.log_event(upload.get_token(), TokenEvent::Created { file_name: upload.file_name.clone() }).await;
+        Ok(upload)
+    }
+
+    pub async fn log_event(&self, ticket: &String, event: TokenEvent) {
+        // as fields, so json logs can be filtered by token and phase without picking apart the message
+        let (phase, bytes) = (event.phase(), event.bytes());
+        match &event {
+            TokenEvent::Error { message } => warn!(token = %ticket, phase, bytes, "Transfer error: {}", message),
+            event if event.is_progress() => debug!(token = %ticket, phase, bytes, "Transfer progress"),
+            _ => info!(token = %ticket, phase, bytes, "Token {}", phase.replace('_', " "))
+        }
+        self.events.lock().await.entry(ticket.clone()).or_default().push(LoggedEvent::new(event));
+    }
+
+    pub async fn get_events(&self, ticket: &String) -> Vec<LoggedEvent> {
+        match self.events.lock().await.get(ticket) {
+            Some(events) => events.clone(),
+            None => vec![]
+        }
     }
 
     // this will upgrade the user's file upload if their authentication challenge succeeds
-    pub async fn upgrade(&self, ticket: &String, challenge_responses: &Vec<String>) -> Option<FileMetadata> {
+    pub async fn upgrade(&self, ticket: &String, challenge_responses: &Vec<String>) -> Result<FileMetadata, (StatusCode, String)> {
+        let failed = || (StatusCode::UNAUTHORIZED, "Challenge failed".to_string());
+        let (user, challenge) = match self.files.lock().await.get(ticket).ok_or_else(failed)?.get_challenge_details() {
+            Some((true, _, _)) => return self.files.lock().await.get(ticket).cloned().ok_or_else(failed), // its already upgraded
+            Some((false, user, challenge)) => (user.clone(), challenge.clone()),
+            None => return Err(failed())
+        };
+
+        // verifying can go out to the keyserver, so the files aren't held while it does.
+        // clients sign with every key they have, only one of them (or its certificate) has to be known here
+        if !self.keys.verify_any(&user, &challenge, challenge_responses).await {
+            return Err(failed());
+        }
         let mut meta = self.files.lock().await;
-        let file = meta.get(ticket);
-        match file {
-            Some(file) => {
-                match file.get_challenge_details() {
-                    Some((authenticated, user, challenge)) => {
-                        for challenge_response in challenge_responses {
-                            if authenticated {
-                                // its already upgraded
-                                return Some(file.clone());
-                            }
+        let file = meta.get(ticket).ok_or_else(failed)?.clone();
+        match file.get_challenge_details() {
+            Some((false, _, current)) if *current == challenge => self.promote(ticket, file, user, &mut meta).await,
+            Some((true, _, _)) => Ok(file),
+            _ => Err(failed())
+        }
+    }
 
-                            if self.keys.verify(&user, &challenge, challenge_response) {
-                                // now we need to move everything around and upgrade to authed
-                                // ticket is still the old token
-                                let mut file = file.clone();
-                                file.upgrade(&self.auth_options);
-                                // now we need to move everything around and upgrade to authed
-                                let mut uploads = self.uploads.lock().await;
-                                let mut downloads = self.downloads.lock().await;
-
-                                let (tx, rx) = channel(self.auth_options.get_cache_size());
-                                match uploads.remove(ticket) {
-                                    Some(tik) => {
-                                        // if it has been used, we cannot re-create it!
-                                        if tik.capacity() != self.reg_options.get_cache_size() {
-                                            uploads.insert(file.get_token().clone(), tik);
-                                        } else {
-                                            uploads.insert(file.get_token().clone(), tx);
-                                            downloads.insert(ticket.to_string(), rx); // this will just cause a nice simple move and override the old one
-                                        }
-                                    },
-                                    None => ()
-                                };
-                                match downloads.remove(ticket) {
-                                    Some(tik) => {
-                                        downloads.insert(file.get_token().clone(), tik);
-                                    },
-                                    None => ()
-                                };
-                                match meta.remove(ticket) {
-                                    Some(_) => {
-                                        meta.insert(file.get_token().clone(), file.clone());
-                                    },
-                                    None => ()
-                                };
-
-                                return Some(file);
-                            } else {
-                                return None;
-                            }
-                        }
-                        return None;
-                    },
-                    None => None
+    // the same upgrade for a user who already proved who they are with a client certificate during the tls handshake
+    pub async fn upgrade_certified(&self, ticket: &String, certified_user: &String) -> Result<FileMetadata, (StatusCode, String)> {
+        let mut meta = self.files.lock().await;
+        let file = meta.get(ticket).ok_or((StatusCode::NOT_FOUND, "Token not found".to_string()))?;
+        match file.get_challenge_details() {
+            Some((true, _, _)) => Ok(file.clone()),
+            Some((false, user, _)) if user == certified_user => {
+                let user = user.clone();
+                let file = file.clone();
+                self.promote(ticket, file, user, &mut meta).await
+            },
+            _ => Err((StatusCode::UNAUTHORIZED, format!("The token isn't for {certified_user}")))
+        }
+    }
+
+    // only tokens they authenticated count, anyone can put their name on a new one. so the tier's limit waits until they've proven who they are
+    fn check_held(&self, user: &String, meta: &HashMap<String, FileMetadata>) -> Result<(), (StatusCode, String)> {
+        if let Some(max) = self.authed_options(user).get_max_tokens() {
+            let held = meta.values().filter(|file| matches!(file.get_challenge_details(), Some((true, owner, _)) if owner == user)).count();
+            if held >= max {
+                debug!("{} already holds {} tokens, refusing another", user, held);
+                return Err((StatusCode::TOO_MANY_REQUESTS, format!("{user} already has {held} tokens on this relay, the most allowed at once")));
+            }
+        }
+        Ok(())
+    }
+
+    async fn promote(&self, ticket: &String, mut file: FileMetadata, user: String, meta: &mut HashMap<String, FileMetadata>) -> Result<FileMetadata, (StatusCode, String)> {
+        self.check_held(&user, meta)?;
+        // now we need to move everything around and upgrade to authed
+        // ticket is still the old token
+        let options = self.authed_options(&user);
+        if let Some(group) = self.group_of(&user) {
+            debug!("{} is in group {}, using its options", user, group);
+        }
+        file.upgrade(options);
+        if let Some(asked) = file.get_asked_lifetime() {
+            file.set_lifetime(asked, options.get_max_lifetime());
+        }
+        let mut uploads = self.uploads.lock().await;
+        let mut downloads = self.downloads.lock().await;
+
+        let (tx, rx) = channel(options.get_cache_size());
+        if let Some(tik) = uploads.remove(ticket) {
+            // if it has been used, we cannot re-create it!
+            if tik.capacity() != self.reg_options.get_cache_size() {
+                uploads.insert(file.get_token().clone(), tik);
+            } else {
+                uploads.insert(file.get_token().clone(), tx);
+                downloads.insert(ticket.to_string(), rx); // this will just cause a nice simple move and override the old one
+            }
+        };
+        if let Some(tik) = downloads.remove(ticket) {
+            downloads.insert(file.get_token().clone(), tik);
+        };
+        if meta.remove(ticket).is_some() {
+            meta.insert(file.get_token().clone(), file.clone());
+            self.shared.remove(ticket);
+            self.shared.save(&file);
+        };
+        let mut counters = self.counters.lock().await;
+        if let Some(counter) = counters.remove(ticket) {
+            counters.insert(file.get_token().clone(), counter);
+        }
+        drop(counters);
+        self.stats.lock().await.entry(user.clone()).or_default().add_token();
+        self.stats_changed.store(true, Ordering::Relaxed);
+        let mut events = self.events.lock().await;
+        let mut log = events.remove(ticket).unwrap_or_default();
+        log.push(LoggedEvent::new(TokenEvent::Upgraded { user }));
+        events.insert(file.get_token().clone(), log);
+
+        Ok(file)
+    }
+
+    // signed challenges that aren't tied to a token are "[purpose]:[user]:[unix time]:[nonce]" and only last a few minutes. each one only
+    // works once, so one seen on the way (in a log, or by a proxy) can't be sent again while it's still in its window
+    pub async fn verify_once(&self, purpose: &str, user: &String, challenge: &String, responses: &Vec<String>) -> bool {
+        self.verify_nonce(purpose, user, challenge, responses, true).await
+    }
+
+    // verify_once without using the challenge up, for a look at something (like a HEAD) that the real request will spend it on after
+    pub async fn verify_unused(&self, purpose: &str, user: &String, challenge: &String, responses: &Vec<String>) -> bool {
+        self.verify_nonce(purpose, user, challenge, responses, false).await
+    }
+
+    async fn verify_nonce(&self, purpose: &str, user: &String, challenge: &String, responses: &Vec<String>, spend: bool) -> bool {
+        let timestamp = match challenge.strip_prefix(&format!("{purpose}:{user}:")).and_then(|rest| rest.split_once(':')) {
+            Some((time, nonce)) if nonce.len() >= 16 => match time.parse::<i64>() {
+                Ok(time) => time,
+                Err(_) => return false
+            },
+            _ => return false
+        };
+        if (chrono::Utc::now().timestamp() - timestamp).abs() > 300 {
+            debug!("Challenge for {} is too old", user);
+            return false;
+        }
+        // only remembered once it verifies, otherwise anyone could use up someone else's nonce
+        if !self.keys.verify_any(user, challenge, responses).await {
+            return false;
+        }
+        let mut used = self.used_challenges.lock().await;
+        used.retain(|_, seen| seen.elapsed() < std::time::Duration::from_secs(600));
+        if used.contains_key(challenge) {
+            debug!("Challenge for {} was already used", user);
+            return false;
+        }
+        if spend {
+            used.insert(challenge.clone(), Instant::now());
+        }
+        true
+    }
+
+    // tokens sent to a named receiver need "download/[token]:[receiver]:[unix time]:[nonce]" signed by one of their keys. it ends up in
+    // the download url, so each one only starts one download. spend is false for a HEAD, which leaves it for the GET that follows
+    pub async fn may_download(&self, meta: &FileMetadata, challenge: Option<&String>, signature: Option<&String>, spend: bool) -> bool {
+        let receiver = match meta.get_receiver() {
+            Some(receiver) => receiver,
+            None => return true
+        };
+        let (challenge, signature) = match (challenge, signature) {
+            (Some(challenge), Some(signature)) => (challenge, signature),
+            _ => return false
+        };
+        let signatures: Vec<String> = match serde_json::from_str(signature) {
+            Ok(s) => s,
+            Err(_) => vec![signature.to_string()],
+        };
+        let purpose = format!("download/{}", meta.get_token());
+        match spend {
+            true => self.verify_once(&purpose, receiver, challenge, &signatures).await,
+            false => self.verify_unused(&purpose, receiver, challenge, &signatures).await
+        }
+    }
+
+    pub async fn save_stats(&self) {
+        let path = match &self.stats_path {
+            Some(path) if self.stats_changed.swap(false, Ordering::Relaxed) => path,
+            _ => return
+        };
+        let stats = self.stats.lock().await.clone();
+        if let Err(e) = stats::save(path, &stats).await {
+            error!("{}", e);
+            self.stats_changed.store(true, Ordering::Relaxed); // tried again next time around
+        }
+    }
+
+    pub async fn get_user_stats(&self, user: &String) -> UserStats {
+        self.stats.lock().await.get(user).cloned().unwrap_or_default()
+    }
+
+    pub async fn get_all_user_stats(&self) -> HashMap<String, UserStats> {
+        self.stats.lock().await.clone()
+    }
+
+    pub async fn get_cull_stats(&self) -> CullStats {
+        self.culls.lock().await.clone()
+    }
+
+    // bytes the stored uploads of each tier hold so far, as (public, authenticated). uploads running at the same time can each be given what is left
+    async fn stored_bytes(&self, except: &String) -> (usize, usize) {
+        let files = self.files.lock().await;
+        let counters = self.counters.lock().await;
+        let mut stored = (0, 0);
+        for (ticket, file) in files.iter().filter(|(ticket, file)| *ticket != except && file.is_stored()) {
+            let bytes = match counters.get(ticket) {
+                Some(counters) => counters.uploaded(),
+                None => file.file_size.get_transferred().0
+            };
+            match file.authenticated() {
+                true => stored.1 += bytes,
+                false => stored.0 += bytes
+            }
+        }
+        stored
+    }
+
+    // how much this upload may send, the smallest of the size limit, what is left of the user's day, and (when it's kept) what is left of the tier's storage
+    async fn upload_quota(&self, meta: &FileMetadata, opts: &ServerOptions, stored: (usize, usize)) -> Result<Option<usize>, (StatusCode, String)> {
+        let remaining = match (self.daily_account(meta).await, opts.get_max_bytes_per_day()) {
+            (Some((usage, payer)), Some(max)) => {
+                let used = match usage.lock().await.get(&payer) {
+                    Some((day, used)) if *day == Utc::now().date_naive() => *used,
+                    _ => 0
+                };
+                if used >= max {
+                    debug!("{} has used {} of {} bytes today, refusing the upload", payer, used, max);
+                    return Err((StatusCode::TOO_MANY_REQUESTS, match meta.authenticated() {
+                        true => format!("{payer} has already uploaded the most allowed today"),
+                        false => "This address has already uploaded the most allowed today".to_string()
+                    }));
                 }
+                Some(max - used)
             },
-            None => None,
+            _ => None
+        };
+        let remaining = match (opts.get_store_limit(), meta.is_stored()) {
+            (Some(limit), true) => {
+                let used = match meta.authenticated() {
+                    true => stored.1,
+                    false => stored.0
+                };
+                if used >= limit {
+                    debug!("Stored uploads are holding {} of {} bytes, refusing the upload", used, limit);
+                    return Err((StatusCode::INSUFFICIENT_STORAGE, "The relay has no room left to keep uploads, try again without storing it".to_string()));
+                }
+                Some(remaining.unwrap_or(usize::MAX).min(limit - used))
+            },
+            _ => remaining
+        };
+        Ok(match (opts.get_max_upload_size(), remaining) {
+            (Some(size), Some(remaining)) => Some(size.min(remaining)),
+            (size, remaining) => size.or(remaining)
+        })
+    }
+
+    // who an upload's bytes count against for max_bytes_per_day, the user once they've authenticated, otherwise the address it came from
+    async fn daily_account(&self, meta: &FileMetadata) -> Option<(&Mutex<HashMap<String, (NaiveDate, usize)>>, String)> {
+        match meta.get_challenge_details() {
+            Some((true, user, _)) => Some((&*self.daily_usage, user.clone())),
+            _ => self.payers.lock().await.get(meta.get_token()).map(|address| (&*self.address_usage, address.clone()))
+        }
+    }
+
+    // bytes count against the day whether or not the upload finished
+    async fn charge_daily_usage(&self, meta: &FileMetadata, bytes: usize) {
+        if let Some((usage, payer)) = self.daily_account(meta).await {
+            let today = Utc::now().date_naive();
+            let mut usage = usage.lock().await;
+            let entry = usage.entry(payer).or_insert((today, 0));
+            if entry.0 != today {
+                *entry = (today, 0);
+            }
+            entry.1 += bytes;
+        }
+    }
+
+    // the upload went past what begin_upload allowed it, what made it through still counts
+    pub async fn stop_over_quota(&self, ticket: &String, bytes: usize) {
+        if let Some(meta) = self.inspect_file(ticket).await {
+            self.charge_daily_usage(&meta, bytes).await;
+        }
+        self.abort_upload(ticket, "Upload went over the size, daily, or storage limit of this relay".to_string()).await;
+    }
+
+    // the bytes count against the day for every upload, but only authenticated ones go in the stats, anyone can claim a username otherwise
+    pub async fn record_upload(&self, ticket: &String, bytes: usize, seconds: f64) {
+        let meta = match self.files.lock().await.get(ticket) {
+            Some(meta) => meta.clone(),
+            None => return
+        };
+        self.charge_daily_usage(&meta, bytes).await;
+        if let Some((true, user, _)) = meta.get_challenge_details() {
+            self.stats.lock().await.entry(user.clone()).or_default().add_upload(bytes, seconds, &meta.get_compression());
+            self.stats_changed.store(true, Ordering::Relaxed);
         }
     }
 
+    pub async fn create_bundle(&self, tokens: Vec<String>) -> String {
+        let mut bundles = self.bundles.lock().await;
+        let mut path = self.reg_options.generate_upload_token();
+        while bundles.contains_key(&path) {
+            path = self.reg_options.generate_upload_token();
+        }
+        bundles.insert(path.clone(), tokens);
+        path
+    }
+
+    pub async fn get_bundle(&self, bundle: &String) -> Option<Vec<String>> {
+        self.bundles.lock().await.get(bundle).cloned()
+    }
+
+    // bundles are single use just like the tokens inside them
+    pub async fn take_bundle(&self, bundle: &String) -> Option<Vec<String>> {
+        self.bundles.lock().await.remove(bundle)
+    }
+
     pub async fn get_file_metadata(&self, ticket: &String) -> Option<FileMetadata> {
         trace!("Attempting to get metadata for {}", ticket);
         let mut meta = self.files.lock().await;
@@ -133,32 +846,101 @@ impl AppState {
         match file {
             Some(file) => {
                 trace!("Updating access time for {}", ticket);
+                let share = file.age() > SHARED_ACCESS; // the other relays only need it to know when to cull
                 file.access();
-                Some(file.clone())
+                if share {
+                    self.shared.save(file);
+                }
+                let mut file = file.clone();
+                if let Some(counters) = self.counters.lock().await.get(ticket) {
+                    fold_counters(&mut file, counters);
+                }
+                Some(file)
             },
             None => None,
         }
     }
 
+    // for admins looking at the relay, so unlike get_file_metadata this doesn't count as an access and keep tokens from being culled
+    pub async fn inspect_files(&self) -> Vec<FileMetadata> {
+        let meta = self.files.lock().await;
+        let counters = self.counters.lock().await;
+        let mut files: Vec<FileMetadata> = meta.iter().map(|(ticket, file)| {
+            let mut file = file.clone();
+            if let Some(counters) = counters.get(ticket) {
+                fold_counters(&mut file, counters);
+            }
+            file
+        }).collect();
+        files.sort_by_key(|file| file.get_created());
+        files
+    }
+
+    pub async fn inspect_file(&self, ticket: &String) -> Option<FileMetadata> {
+        let mut file = self.files.lock().await.get(ticket)?.clone();
+        if let Some(counters) = self.counters.lock().await.get(ticket) {
+            fold_counters(&mut file, counters);
+        }
+        Some(file)
+    }
+
     // this gets a bit weird since it uses the FileMetadata as its own thing so it could get messy when the start_upload is triggered but the upload doesnt exist in self here
-    pub async fn begin_upload(&self, ticket: &String, key: &String) -> Result<(Sender<Vec<u8>>, &ServerOptions), (StatusCode, String)> {
+    pub async fn begin_upload(&self, ticket: &String, key: &String, address: &str) -> Result<(Sender<Vec<u8>>, &ServerOptions), (StatusCode, String)> {
+        if self.is_read_only() {
+            return Err((StatusCode::SERVICE_UNAVAILABLE, "This relay is read-only and is not accepting uploads".to_string()))
+        }
+        let stored = match self.store {
+            Some(_) => self.stored_bytes(ticket).await,
+            None => (0, 0)
+        };
         match self.files.lock().await.get_mut(ticket) { // need mut just in case the upload is valid, so we can instantly lock it
             Some(meta) => {
-                if meta.upload_locked() { // cannot allow another upload
-                    Err((StatusCode::CONFLICT,"File is already locked for upload".to_string()))
+                if meta.upload_locked() { // cannot allow another upload, unless it's sending a damaged one again
+                    let resumed = match meta.check_key(key) {
+                        true => self.resume_parked(ticket).await,
+                        false => None
+                    };
+                    resumed.map(|tx| (tx, self.upload_options(meta))).ok_or((StatusCode::CONFLICT,"File is already locked for upload".to_string()))
                 } else if !meta.check_key(key) {
-                    return Err((StatusCode::FORBIDDEN, "File has a different key".to_string()))
+                    Err((StatusCode::FORBIDDEN, "File has a different key".to_string()))
+                } else if self.members_only && !meta.authenticated() {
+                    Err((StatusCode::UNAUTHORIZED, "This relay only accepts uploads from its members, the token must be authenticated first".to_string()))
+                } else if meta.is_named() && !meta.authenticated() { // otherwise anyone could put something up under a name people trust
+                    Err((StatusCode::UNAUTHORIZED, "Custom tokens must be authenticated before they can be uploaded to".to_string()))
                 } else {
                     // okay, we've verified the upload so now we can lock it
                     match self.uploads.lock().await.get(ticket) {
                         Some(tx) => {
-                            let opts = if meta.authenticated() {
-                                &self.auth_options
-                            } else {
-                                &self.reg_options
-                            };
+                            let opts = self.upload_options(meta);
+                            if !meta.authenticated() {
+                                self.payers.lock().await.insert(ticket.clone(), address_key(address));
+                            }
+                            let quota = self.upload_quota(meta, opts, stored).await?;
+                            if let Some(counters) = self.counters.lock().await.get(ticket) {
+                                counters.quota.store(quota.unwrap_or(0), Ordering::Relaxed);
+                            }
                             meta.start_upload(key);
-                            Ok((tx.clone(), opts)) // yay!
+                            self.shared.save(meta);
+                            self.shared.upload_started(ticket);
+                            self.log_event(ticket, TokenEvent::UploadStarted).await;
+                            let tx = match (meta.get_storage(), &self.store, opts.get_spill()) {
+                                (Some(name), Some(store), _) => { // kept for later, nobody is reading the channel
+                                    let key = match SealingKey::generate() {
+                                        Ok(key) => key,
+                                        Err(e) => {
+                                            error!("Could not start storing {}: {}", ticket, e);
+                                            return Err((StatusCode::INTERNAL_SERVER_ERROR, "The upload could not be stored".to_string()));
+                                        }
+                                    };
+                                    let (tx, writing) = store.writer(name.clone(), &key, opts.get_cache_size());
+                                    self.store_keys.lock().await.insert(ticket.clone(), key);
+                                    self.storing.lock().await.insert(ticket.clone(), writing);
+                                    tx
+                                },
+                                (_, _, Some((dir, limit))) => spill_over(tx.clone(), dir, limit, opts.get_cache_size()),
+                                _ => tx.clone()
+                            };
+                            Ok((tx, opts)) // yay!
                         },
                         None => Err((StatusCode::GONE, "Upload does not exist, it is already in progress".to_string()))
                     }
@@ -168,16 +950,143 @@ impl AppState {
         }
     }
 
+    fn upload_options(&self, meta: &FileMetadata) -> &ServerOptions {
+        match meta.get_challenge_details() {
+            Some((true, user, _)) => self.authed_options(user),
+            _ => &self.reg_options
+        }
+    }
+
+    // lets one upload take over from a damaged one. it's the same upload, so it goes on into what the first try was sending to
+    // and nothing that begin_upload did for it (quota, payer, events, storing or spilling) happens twice
+    async fn resume_parked(&self, ticket: &String) -> Option<Sender<Vec<u8>>> {
+        match self.parked.lock().await.get_mut(ticket) {
+            Some(parked) if !parked.resumed => {
+                parked.resumed = true;
+                Some(parked.upload.clone())
+            },
+            _ => None
+        }
+    }
+
+    pub async fn park_upload(&self, ticket: &str, upload: Sender<Vec<u8>>, verifier: Verifier) {
+        let since = Instant::now();
+        self.parked.lock().await.insert(ticket.to_string(), Parked { upload, verifier, since, resumed: false });
+
+        // the downloader can't wait forever for an uploader that isn't coming back
+        let state = self.clone();
+        let ticket = ticket.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(RESUME_WAIT).await;
+            let mut parked = state.parked.lock().await;
+            if parked.get(&ticket).is_some_and(|parked| parked.since == since) {
+                parked.remove(&ticket);
+                drop(parked);
+                state.abort_upload(&ticket, "The upload had a damaged frame and was never sent again".to_string()).await;
+            }
+        });
+    }
+
+    pub async fn take_parked(&self, ticket: &String) -> Option<Parked> {
+        self.parked.lock().await.remove(ticket)
+    }
+
+    pub async fn open_resends(&self, ticket: &str, window: Arc<std::sync::Mutex<ResendWindow>>) {
+        self.resends.lock().await.insert(ticket.to_string(), window);
+    }
+
+    pub async fn resend(&self, ticket: &String, key: &str, offset: u64) -> Option<Bytes> {
+        self.resends.lock().await.get(ticket).and_then(|window| window.lock().unwrap().get(key, offset))
+    }
+
+    // a later download of the same token may have opened its own since
+    pub async fn close_resends(&self, ticket: &String, key: &str) {
+        let mut resends = self.resends.lock().await;
+        if resends.get(ticket).is_some_and(|window| window.lock().unwrap().is(key)) {
+            resends.remove(ticket);
+        }
+    }
+
+    // waits for the store to have the whole upload, after the close chunk has been sent. uploads that aren't being stored are done already
+    pub async fn finish_storing(&self, ticket: &String) -> Result<(), String> {
+        let writing = match self.storing.lock().await.remove(ticket) {
+            Some(writing) => writing,
+            None => return Ok(())
+        };
+        match writing.await {
+            Ok(result) => result,
+            Err(e) => Err(format!("Storing stopped unexpectedly: {}", e))
+        }
+    }
+
+    // every download of a stored upload reads the object from the start, so there's no spool or channel to hand over
+    async fn begin_stored_download(&self, ticket: &String, name: &str, store: &ObjectStore) -> Option<Receiver<Vec<u8>>> {
+        let key = self.store_keys.lock().await.get(ticket).cloned()?;
+        let rx = match store.reader(name, &key, self.reg_options.get_cache_size()).await {
+            Ok(rx) => rx,
+            Err(e) => {
+                error!("Could not read {} back from storage: {}", ticket, e);
+                self.log_event(ticket, TokenEvent::Error { message: "The stored upload could not be read".to_string() }).await;
+                return None;
+            }
+        };
+        let mut files = self.files.lock().await;
+        let meta = files.get_mut(ticket)?;
+        if meta.download_locked() { // someone else got there while the object was being opened
+            return None;
+        }
+        meta.start_download();
+        self.shared.save(meta);
+        self.log_event(ticket, TokenEvent::DownloadStarted).await;
+        Some(rx)
+    }
+
     pub async fn begin_download(&self, ticket: &String) -> Option<Receiver<Vec<u8>>> {
+        let stored = self.files.lock().await.get(ticket).and_then(|meta| meta.get_storage().cloned());
+        if let (Some(name), Some(store)) = (stored, &self.store) {
+            return self.begin_stored_download(ticket, &name, store).await;
+        }
         match self.files.lock().await.get_mut(ticket) { // downloads are kinda weird since they need to be lockable and unlockable, however the lock must consume as this isnt a broadcast
             Some(meta) => {
                 if meta.download_locked() { // cannot allow another download
                     None
+                } else if meta.is_broadcast() {
+                    let mut spools = self.spools.lock().await;
+                    if !spools.contains_key(ticket) { // the first downloader starts spooling the upload
+                        let upload = self.downloads.lock().await.remove(ticket)?;
+                        match Spool::start(upload).await {
+                            Ok(spool) => {
+                                spools.insert(ticket.clone(), (spool, 0));
+                            },
+                            Err(e) => {
+                                error!("Could not start spooling broadcast {}: {}", ticket, e);
+                                return None;
+                            }
+                        }
+                    }
+                    let (spool, active) = spools.get_mut(ticket)?;
+                    *active += 1;
+                    let (rx, reader) = spool.reader();
+                    meta.start_download();
+                    self.shared.save(meta);
+                    self.claim_remote(ticket).await;
+                    self.log_event(ticket, TokenEvent::DownloadStarted).await;
+
+                    let state = self.clone();
+                    let ticket = ticket.clone();
+                    tokio::spawn(async move {
+                        let _ = reader.await;
+                        state.end_broadcast_download(&ticket).await;
+                    });
+                    Some(rx)
                 } else {
                     // okay, we've verified the upload so now we can lock it
                     match self.downloads.lock().await.remove(ticket) {
                         Some(rx) => {
                             meta.start_download();
+                            self.shared.save(meta);
+                            self.claim_remote(ticket).await;
+                            self.log_event(ticket, TokenEvent::DownloadStarted).await;
                             Some(rx) // yay!
                         },
                         None => None
@@ -188,69 +1097,357 @@ impl AppState {
         }
     }
 
-    pub async fn return_download(&self, ticket: &String, stream: Receiver<Vec<u8>>) -> bool {
+    // the downloader went away partway, so the next one picks up where it stopped. stored uploads are read from the start again and have nothing to hand back
+    pub async fn return_download(&self, ticket: &String, stream: Option<Receiver<Vec<u8>>>) -> bool {
         match self.files.lock().await.get_mut(ticket) {
-            Some(meta) => {
-                if meta.download_pausable() {
+            Some(meta) if meta.download_pausable() => {
+                if let Some(stream) = stream {
                     self.downloads.lock().await.insert(ticket.clone(), stream);
-                    meta.pause_download();
-                    true
-                } else {
-                    false
                 }
+                meta.pause_download();
+                self.shared.save(meta);
+                true
             },
-            None => false
+            _ => false
         }
     }
 
-    pub async fn set_metadata(&self, ticket: &String, name: Option<String>, size: Option<usize>, compression: Option<Compression>) -> bool {
+    pub async fn set_metadata(&self, ticket: &String, name: Option<String>, size: Option<usize>, compression: Option<Compression>, disposition: Option<Disposition>) -> bool {
         match self.files.lock().await.get_mut(ticket) { // need mut just in case the upload is valid, so we can instantly lock it
             Some(meta) => {
-                if name.is_some() {
-                    meta.file_name = name.unwrap();
+                if let Some(name) = name {
+                    meta.file_name = name;
+                }
+                if let Some(size) = size {
+                    meta.file_size.set_file_size(size);
                 }
-                if size.is_some() {
-                    meta.file_size.set_file_size(size.unwrap());
+                if let Some(compression) = compression {
+                    meta.set_compression(compression);
                 }
-                if compression.is_some() {
-                    meta.set_compression(compression.unwrap());
+                if let Some(disposition) = disposition {
+                    meta.set_disposition(disposition);
                 }
+                self.shared.save(meta);
                 true
             },
             None => false
         }
     }
 
-    pub async fn increase_upload_download_numbers(&self, ticket: &String, upload: usize, download: usize) -> Option<(usize, usize)> {
-        match self.files.lock().await.get_mut(ticket) { // need mut just in case the upload is valid, so we can instantly lock it
+    // clamped by the tier the token is in. naming a user isn't being them, so until the challenge is signed that's the public tier,
+    // and promote clamps what was asked for again against theirs
+    pub async fn set_lifetime(&self, ticket: &String, lifetime: TimeDelta) -> Option<FileMetadata> {
+        let mut files = self.files.lock().await;
+        let meta = files.get_mut(ticket)?;
+        let max = match meta.get_challenge_details() {
+            Some((true, user, _)) => self.authed_options(user).get_max_lifetime(),
+            _ => self.reg_options.get_max_lifetime()
+        };
+        if lifetime > max {
+            debug!("{} asked to wait {}s, the most allowed is {}s", ticket, lifetime.num_seconds(), max.num_seconds());
+        }
+        meta.set_lifetime(lifetime, max);
+        self.shared.save(meta);
+        Some(meta.clone())
+    }
+
+    pub async fn set_message(&self, ticket: &String, message: String) -> bool {
+        match self.files.lock().await.get_mut(ticket) {
             Some(meta) => {
-                meta.file_size.increase_download(download);
-                meta.file_size.increase_upload(upload);
-                Some((meta.file_size.get_uploaded_size(), meta.file_size.get_download_progress()))
+                meta.set_message(message);
+                self.shared.save(meta);
+                true
             },
-            None => None
+            None => false
+        }
+    }
+
+    pub async fn set_attributes(&self, ticket: &String, attributes: FileAttributes) -> bool {
+        match self.files.lock().await.get_mut(ticket) {
+            Some(meta) => {
+                meta.set_attributes(attributes);
+                self.shared.save(meta);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub async fn set_content_type(&self, ticket: &String, content_type: String) -> bool {
+        match self.files.lock().await.get_mut(ticket) {
+            Some(meta) => {
+                meta.set_content_type(content_type);
+                self.shared.save(meta);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub async fn set_receiver(&self, ticket: &String, receiver: String) -> Option<FileMetadata> {
+        let mut files = self.files.lock().await;
+        let meta = files.get_mut(ticket)?;
+        meta.set_receiver(receiver);
+        self.shared.save(meta);
+        Some(meta.clone())
+    }
+
+    // only before anything has started, once bytes go through the relay it's too late to send them another way
+    pub async fn set_peer(&self, ticket: &String, peer: PeerOffer) -> Option<FileMetadata> {
+        let mut files = self.files.lock().await;
+        let meta = files.get_mut(ticket)?;
+        if meta.upload_locked() || meta.any_download_started() {
+            return None;
+        }
+        meta.set_peer(Some(peer));
+        self.shared.save(meta);
+        Some(meta.clone())
+    }
+
+    // the downloader is connecting from here to punch through, the uploader sees it and connects back at the same time
+    pub async fn answer_peer(&self, ticket: &String, answer: String) -> Option<FileMetadata> {
+        let mut files = self.files.lock().await;
+        let meta = files.get_mut(ticket)?;
+        let mut peer = meta.get_peer()?.clone();
+        if peer.failed || peer.reflected.is_none() || meta.upload_locked() {
+            return None;
+        }
+        peer.answer = Some(answer);
+        meta.set_peer(Some(peer));
+        self.shared.save(meta);
+        Some(meta.clone())
+    }
+
+    // the downloader gave up on the uploader, who sees this and sends it through the relay instead
+    pub async fn fail_peer(&self, ticket: &String) -> Option<FileMetadata> {
+        let mut files = self.files.lock().await;
+        let meta = files.get_mut(ticket)?;
+        let mut peer = meta.get_peer()?.clone();
+        peer.failed = true;
+        meta.set_peer(Some(peer));
+        self.shared.save(meta);
+        Some(meta.clone())
+    }
+
+    // like a direct offer, only before anything has started
+    pub async fn request_delta(&self, ticket: &String) -> Option<FileMetadata> {
+        let mut files = self.files.lock().await;
+        let meta = files.get_mut(ticket)?;
+        if meta.upload_locked() || meta.any_download_started() || meta.get_delta() != Delta::None {
+            return None;
+        }
+        meta.set_delta(Delta::Requested);
+        self.shared.save(meta);
+        Some(meta.clone())
+    }
+
+    // empty checksums are the downloader saying it has nothing to compare against
+    pub async fn offer_delta(&self, ticket: &String, signatures: Vec<u8>) -> bool {
+        let mut files = self.files.lock().await;
+        match files.get_mut(ticket) {
+            Some(meta) if meta.get_delta() == Delta::Requested => {
+                match signatures.is_empty() {
+                    true => meta.set_delta(Delta::Declined),
+                    false => {
+                        meta.set_delta(Delta::Offered);
+                        self.signatures.lock().await.insert(ticket.clone(), signatures);
+                    }
+                }
+                self.shared.save(meta);
+                true
+            },
+            _ => false
+        }
+    }
+
+    // a downloader that never sent checksums (a browser, or curl) gets the whole file, so the uploader stops waiting
+    pub async fn decline_delta(&self, ticket: &String) -> bool {
+        match self.files.lock().await.get_mut(ticket) {
+            Some(meta) if meta.get_delta() == Delta::Requested => {
+                meta.set_delta(Delta::Declined);
+                self.shared.save(meta);
+                true
+            },
+            _ => false
+        }
+    }
+
+    // handed over once, from then on the upload is a delta
+    pub async fn take_delta(&self, ticket: &String) -> Option<Vec<u8>> {
+        let mut files = self.files.lock().await;
+        let meta = files.get_mut(ticket)?;
+        if meta.get_delta() != Delta::Offered {
+            return None;
+        }
+        let signatures = self.signatures.lock().await.remove(ticket)?;
+        meta.set_delta(Delta::Sent);
+        self.shared.save(meta);
+        Some(signatures)
+    }
+
+    pub async fn set_max_downloads(&self, ticket: &String, max: usize) -> bool {
+        match self.files.lock().await.get_mut(ticket) {
+            Some(meta) if !meta.any_download_started() => { // too late once someone has the stream
+                meta.set_max_downloads(max);
+                self.shared.save(meta);
+                true
+            },
+            _ => false
         }
     }
 
+    pub async fn set_manifest(&self, ticket: &String, manifest: Vec<ManifestEntry>) -> bool {
+        match self.files.lock().await.get_mut(ticket) {
+            Some(meta) if !meta.any_download_started() => { // the boundaries can't move under a download
+                meta.set_manifest(manifest);
+                self.shared.save(meta);
+                true
+            },
+            _ => false
+        }
+    }
+
+    pub async fn set_encrypted(&self, ticket: &String, encrypted: bool) -> bool {
+        match self.files.lock().await.get_mut(ticket) {
+            Some(meta) => {
+                meta.set_encrypted(encrypted);
+                self.shared.save(meta);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub async fn set_text(&self, ticket: &String, text: bool) -> bool {
+        match self.files.lock().await.get_mut(ticket) {
+            Some(meta) => {
+                meta.set_text(text);
+                self.shared.save(meta);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub async fn set_direct(&self, ticket: &String, direct: bool) -> bool {
+        match self.files.lock().await.get_mut(ticket) {
+            Some(meta) => {
+                meta.set_direct(direct);
+                self.shared.save(meta);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub async fn set_checksum(&self, ticket: &String, checksum: Checksum) -> bool {
+        match self.files.lock().await.get_mut(ticket) {
+            Some(meta) => {
+                meta.set_checksum(checksum);
+                self.shared.save(meta);
+                true
+            },
+            None => false
+        }
+    }
+
+    // looked up once per transfer so the handlers never need the files lock to count bytes
+    pub async fn get_counters(&self, ticket: &String) -> Option<Arc<TransferCounters>> {
+        self.counters.lock().await.get(ticket).cloned()
+    }
+
+    pub async fn count_upload(&self, ticket: &String, counters: &TransferCounters, bytes: usize) -> usize {
+        let before = counters.uploaded.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(milestone) = crossed_milestone(before, before + bytes) {
+            self.log_event(ticket, TokenEvent::Uploaded { bytes: milestone }).await;
+        }
+        before + bytes
+    }
+
+    pub async fn count_download(&self, ticket: &String, counters: &TransferCounters, bytes: usize) -> usize {
+        let before = counters.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(milestone) = crossed_milestone(before, before + bytes) {
+            self.log_event(ticket, TokenEvent::Downloaded { bytes: milestone }).await;
+        }
+        before + bytes
+    }
+
     pub async fn end(&self, ticket: &String) -> bool {
         let mut meta = self.files.lock().await;
 
         match meta.get_mut(ticket) {
+            Some(meta) if meta.is_broadcast() && meta.get_storage().is_some() => {
+                // there's no spool counting readers, the last download to start ends it
+                if meta.downloads_exhausted() {
+                    meta.end_download();
+                    self.shared.save(meta);
+                }
+                true
+            },
+            Some(meta) if meta.is_broadcast() => true, // each reader ends itself, see end_broadcast_download
             Some(meta) => {
                     meta.end_download();
                     meta.end_upload();
+                    self.shared.save(meta);
                     true
                 },
                 None => false
         }
     }
 
+    // runs when a broadcast downloader's reader stops, finished or not, so a dropped connection frees its spot in the count
+    async fn end_broadcast_download(&self, ticket: &String) {
+        let mut files = self.files.lock().await;
+        let mut spools = self.spools.lock().await;
+        let active = match spools.get_mut(ticket) {
+            Some((_, active)) => {
+                *active = active.saturating_sub(1);
+                *active
+            },
+            None => return // deleted while downloading
+        };
+        if active > 0 {
+            return;
+        }
+        if let Some(meta) = files.get_mut(ticket) {
+            if meta.downloads_remaining() == 0 {
+                debug!("Every download of broadcast {} has been used", ticket);
+                meta.end_download();
+                spools.remove(ticket); // the file goes once nothing is reading it
+            } else {
+                meta.pause_download();
+            }
+            self.shared.save(meta);
+        }
+    }
+
+    pub async fn add_chunked_upload(&self, ticket: &str, upload: ChunkedUpload) -> Arc<Mutex<ChunkedUpload>> {
+        let upload = Arc::new(Mutex::new(upload));
+        self.chunked.lock().await.insert(ticket.to_string(), upload.clone());
+        upload
+    }
+
+    pub async fn get_chunked_upload(&self, ticket: &String) -> Option<Arc<Mutex<ChunkedUpload>>> {
+        self.chunked.lock().await.get(ticket).cloned()
+    }
+
+    pub async fn take_chunked_upload(&self, ticket: &String) -> Option<Arc<Mutex<ChunkedUpload>>> {
+        self.chunked.lock().await.remove(ticket)
+    }
+
+    // drops the stored sender without marking the upload complete, so the downloader sees the stream close early
+    pub async fn abort_upload(&self, ticket: &String, reason: String) {
+        self.uploads.lock().await.remove(ticket);
+        self.log_event(ticket, TokenEvent::Error { message: reason }).await;
+    }
+
     pub async fn end_upload(&self, ticket: &String) -> bool {
         let mut meta = self.files.lock().await;
 
         match meta.get_mut(ticket) {
             Some(meta) => {
                     meta.end_upload();
+                    self.shared.save(meta);
                     let mut up = self.uploads.lock().await;
                     match up.remove(ticket) {
                         Some(t) => {
@@ -266,43 +1463,277 @@ impl AppState {
 
     // this really shouldn't be done unless doing cleanup, otherwise "end" is good enough
     pub async fn delete(&self, ticket: &String) -> bool {
+        let deleted = self.forget(ticket).await;
+        if deleted {
+            self.shared.remove(ticket);
+        }
+        deleted
+    }
+
+    // only on this relay, for a token another relay deleted
+    async fn forget(&self, ticket: &String) -> bool {
         let mut meta = self.files.lock().await;
 
-        if meta.contains_key(ticket) {
-            meta.remove(ticket);
-        } else {
-            return false
+        let removed = match meta.remove(ticket) {
+            Some(removed) => removed,
+            None => return false
+        };
+        if let (Some(name), Some(store)) = (removed.get_storage(), &self.store) {
+            let writing = self.storing.lock().await.remove(ticket);
+            if writing.is_some() || removed.upload_finished() {
+                let (name, store) = (name.clone(), store.clone());
+                tokio::spawn(async move {
+                    // a write still going is stopped first, otherwise it could finish the object after it was deleted
+                    if let Some(writing) = writing {
+                        writing.abort();
+                        if let Ok(Err(_)) = writing.await { // it failed by itself and already took the partial object with it
+                            return;
+                        }
+                    }
+                    if let Err(e) = store.delete(&name).await {
+                        error!("Could not delete {} from storage: {}", name, e);
+                    }
+                });
+            }
         }
         let mut uploads = self.uploads.lock().await;
         let mut downloads = self.downloads.lock().await;
 
        uploads.remove(ticket);
        downloads.remove(ticket);
+       self.events.lock().await.remove(ticket);
+       if let Some(counters) = self.counters.lock().await.remove(ticket) {
+           counters.cancelled.store(true, Ordering::Relaxed); // anything still streaming through the token stops at its next block instead of running to the end
+       }
+       self.parked.lock().await.remove(ticket);
+       self.spools.lock().await.remove(ticket);
+       self.chunked.lock().await.remove(ticket);
+       self.signatures.lock().await.remove(ticket);
+       self.store_keys.lock().await.remove(ticket);
+       self.remote_uploads.lock().await.remove(ticket);
+       self.payers.lock().await.remove(ticket);
 
        true
     }
 
+    // for admins, the token as it was just before it went
+    pub async fn force_delete(&self, ticket: &String) -> Option<FileMetadata> {
+        let file = self.inspect_file(ticket).await?;
+        self.delete(ticket).await;
+        Some(file)
+    }
+
     pub async fn cull(&self) -> usize {
         std::thread::sleep(std::time::Duration::from_secs(10));
         trace!("Trying cull...");
         let meta = self.files.lock().await;
         let to_remove: Vec<String> = meta.keys() // need to deal with auth and not authed!
-            .filter(|id| meta.get(*id).unwrap().age() > match meta.get(*id).unwrap().authenticated() {
-                true => self.auth_options.get_cull_time(),
-                false => self.reg_options.get_cull_time()
-            })
-            .filter(|id| meta.get(*id).unwrap().is_in_waiting_state()) // things that aren't waiting shouldn't be culled
+            .filter(|id| meta.get(*id).unwrap().is_expired(match meta.get(*id).unwrap().get_challenge_details() {
+                Some((true, user, _)) => self.authed_options(user).get_cull_time(),
+                _ => self.reg_options.get_cull_time()
+            }))
+            .filter(|id| meta.get(*id).unwrap().is_in_waiting_state() || meta.get(*id).unwrap().download_finished()) // things that are still transferring shouldn't be culled
             .cloned()
             .collect();
 
         trace!("Found {} items to cull", to_remove.len());
+        self.admin_sessions.lock().await.retain(|_, session| session.is_valid());
+        self.admin_challenges.lock().await.retain(|_, challenge| challenge.is_valid());
+        // bundles go away once anything in them has
+        self.bundles.lock().await.retain(|_, tokens| tokens.iter().all(|t| meta.contains_key(t) && !to_remove.contains(t)));
         drop(meta);
+
+        // a chunked upload only moves while the browser keeps sending, one that is busy with a piece isn't idle
+        let idle: Vec<String> = self.chunked.lock().await.iter()
+            .filter(|(_, upload)| upload.try_lock().map(|upload| upload.is_idle()).unwrap_or(false))
+            .map(|(ticket, _)| ticket.clone())
+            .collect();
+        for ticket in idle {
+            debug!("Chunked upload to {} went idle, dropping it", ticket);
+            self.take_chunked_upload(&ticket).await;
+            self.abort_upload(&ticket, "The web upload stopped sending".to_string()).await;
+        }
+
         // Then remove the IDs in a separate loop
         let rem = to_remove.len();
         for id in to_remove {
             self.delete(&id).await;
             debug!("Culled {}", id);
         }
-        return rem;
+        self.culls.lock().await.record(rem);
+        rem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::shared::LocalTokens;
+
+    // any key works as the CA, it only has to make every username one the relay knows
+    const CA: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKVxVxs5j4glVWs47R+ztZQ4WfDi/N9D26TNoyv9eupl ca";
+
+    fn options(max_lifetime: TimeDelta) -> String {
+        format!("cache_size = 16\nblock_size = 4096\ncull_time = [3600, 0]\ntoken_format = \"{{uuid}}\"\nupload_format = \"{{uuid}}\"\nmax_lifetime = [{}, 0]\n", max_lifetime.num_seconds())
+    }
+
+    // the public tier lets a token wait an hour, alice's group ten days and one token at a time
+    async fn state() -> AppState {
+        state_with(None, options(TimeDelta::hours(1))).await
+    }
+
+    async fn state_with(store: Option<ObjectStore>, public: String) -> AppState {
+        let group: Group = toml::from_str(&format!("members = [\"alice\"]\n[options]\n{}max_tokens = 1\n", options(TimeDelta::days(10)))).unwrap();
+        AppState::new(StateConfig {
+            reg_options: toml::from_str(&public).unwrap(),
+            auth_options: toml::from_str(&options(TimeDelta::days(7))).unwrap(),
+            groups: vec![("longer".to_string(), group)],
+            keyserver: None,
+            keyserver_ttl: std::time::Duration::from_secs(3600),
+            users: vec![],
+            user_ca: vec![CA.to_string()],
+            admins: vec![],
+            read_only: false,
+            banner: None,
+            agents: Agents::new(None, None).unwrap(),
+            allow_inline_override: false,
+            members_only: false,
+            hide_upload_form: false,
+            web_uploader: None,
+            direct_mode: false,
+            transcode: false,
+            public_url: PublicUrl::new(None, false, false),
+            token_limit: None,
+            store,
+            fetch_private: false,
+            stats_path: None,
+            shared: Arc::new(LocalTokens),
+            reflector: None,
+        }).await
+    }
+
+    #[tokio::test]
+    async fn unsigned_claims_get_the_public_lifetime() {
+        let state = state().await;
+        let alice = "alice".to_string();
+        let meta = state.generate_file_upload("notes.txt", Some(&alice), None).await.unwrap();
+        let meta = state.set_lifetime(meta.get_token(), TimeDelta::days(30)).await.unwrap();
+        assert_eq!(*meta.get_expiry().unwrap(), meta.get_created() + TimeDelta::hours(1)); // not the group's 10 days, nobody signed anything
+    }
+
+    #[tokio::test]
+    async fn signing_in_clamps_against_the_users_tier() {
+        let state = state().await;
+        let alice = "alice".to_string();
+        let meta = state.generate_file_upload("notes.txt", Some(&alice), None).await.unwrap();
+        state.set_lifetime(meta.get_token(), TimeDelta::days(30)).await.unwrap();
+        let meta = state.upgrade_certified(meta.get_token(), &alice).await.unwrap();
+        assert_eq!(*meta.get_expiry().unwrap(), meta.get_created() + TimeDelta::days(10));
+    }
+
+    #[tokio::test]
+    async fn a_ca_doesnt_enroll_everyone() {
+        let state = state().await;
+        assert!(!state.keys.has_user(&"mallory".to_string()));
+        assert!(state.keys.accepts_certificates());
+    }
+
+    #[tokio::test]
+    async fn held_tokens_only_count_once_signed_in() {
+        let state = state().await;
+        let alice = "alice".to_string();
+        let first = state.generate_file_upload("notes.txt", Some(&alice), None).await.unwrap();
+        let second = state.generate_file_upload("notes.txt", Some(&alice), None).await.unwrap(); // anyone can ask under her name
+        state.upgrade_certified(first.get_token(), &alice).await.unwrap();
+        let (status, _) = state.upgrade_certified(second.get_token(), &alice).await.unwrap_err();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn a_stored_upload_carries_on_after_a_damaged_frame() {
+        let dir = std::env::temp_dir().join(format!("bytebeam-store-test-{}", uuid::Uuid::new_v4()));
+        let state = state_with(Some(ObjectStore::disk(&dir.to_string_lossy().into_owned()).unwrap()), options(TimeDelta::hours(1))).await;
+        let meta = state.generate_file_upload("notes.txt", None, None).await.unwrap();
+        let (token, key) = (meta.get_token().clone(), meta.get_upload_info().1);
+        state.set_storage(&token).await.unwrap();
+
+        let (upload, _) = state.begin_upload(&token, &key, "127.0.0.1").await.unwrap();
+        upload.send(b"hello ".to_vec()).await.unwrap();
+        state.park_upload(&token, upload.clone(), None).await;
+        drop(upload); // the first try is gone, only the parked sender keeps the object open
+
+        let (upload, _) = state.begin_upload(&token, &key, "127.0.0.1").await.unwrap();
+        let (status, _) = state.begin_upload(&token, &key, "127.0.0.1").await.unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT); // only one upload gets to carry on
+        state.take_parked(&token).await.unwrap();
+        upload.send(b"world".to_vec()).await.unwrap();
+        upload.send(vec![]).await.unwrap();
+        drop(upload);
+        state.finish_storing(&token).await.unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1); // the same object, not a second one started on resume
+
+        let mut download = state.begin_download(&token).await.unwrap();
+        let mut received = vec![];
+        while let Some(chunk) = download.recv().await.filter(|chunk| !chunk.is_empty()) {
+            received.extend(chunk);
+        }
+        assert_eq!(received, b"hello world");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_spilled_upload_carries_on_after_a_damaged_frame() {
+        let dir = std::env::temp_dir().join(format!("bytebeam-spill-test-{}", uuid::Uuid::new_v4()));
+        let state = state_with(None, format!("{}spill_path = {:?}\n", options(TimeDelta::hours(1)), dir)).await;
+        let meta = state.generate_file_upload("notes.txt", None, None).await.unwrap();
+        let (token, key) = (meta.get_token().clone(), meta.get_upload_info().1);
+
+        let (first, _) = state.begin_upload(&token, &key, "127.0.0.1").await.unwrap();
+        first.send(b"hello ".to_vec()).await.unwrap();
+        state.park_upload(&token, first.clone(), None).await;
+
+        let (upload, _) = state.begin_upload(&token, &key, "127.0.0.1").await.unwrap();
+        assert!(upload.same_channel(&first)); // still the one spilling, a second would race the first to the downloader
+        drop(first);
+        state.take_parked(&token).await.unwrap();
+        upload.send(b"world".to_vec()).await.unwrap();
+        upload.send(vec![]).await.unwrap();
+        drop(upload);
+
+        let mut download = state.begin_download(&token).await.unwrap();
+        let mut received = vec![];
+        while let Some(chunk) = download.recv().await.filter(|chunk| !chunk.is_empty()) {
+            received.extend(chunk);
+        }
+        assert_eq!(received, b"hello world");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_token_mid_store_removes_the_object() {
+        let dir = std::env::temp_dir().join(format!("bytebeam-store-test-{}", uuid::Uuid::new_v4()));
+        let state = state_with(Some(ObjectStore::disk(&dir.to_string_lossy().into_owned()).unwrap()), options(TimeDelta::hours(1))).await;
+        let meta = state.generate_file_upload("notes.txt", None, None).await.unwrap();
+        let (token, key) = (meta.get_token().clone(), meta.get_upload_info().1);
+        state.set_storage(&token).await.unwrap();
+
+        let (upload, _) = state.begin_upload(&token, &key, "127.0.0.1").await.unwrap();
+        upload.send(b"hello".to_vec()).await.unwrap();
+        let objects = || std::fs::read_dir(&dir).unwrap().count();
+        while objects() == 0 { // the object is part written once the writer has it open
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let counters = state.get_counters(&token).await.unwrap();
+        assert!(state.delete(&token).await);
+        assert!(counters.is_cancelled()); // the upload stops at its next block
+        for _ in 0..100 {
+            if objects() == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(objects(), 0);
+        assert!(upload.is_closed());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,27 @@
+use axum::{body::Body, extract::{Path, State}, http::{header::CONTENT_TYPE, Response, StatusCode}, response::IntoResponse};
+use tracing::warn;
+
+use super::appstate::AppState;
+
+// the two files `wasm-pack build --target web --out-name bytebeam` makes that the page needs, nothing else in the folder is served
+fn content_type(file: &str) -> Option<&'static str> {
+    match file {
+        "bytebeam.js" => Some("text/javascript"),
+        "bytebeam_bg.wasm" => Some("application/wasm"),
+        _ => None
+    }
+}
+
+pub async fn asset(State(state): State<AppState>, Path(file): Path<String>) -> Response<Body> {
+    let (dir, content_type) = match (state.web_uploader(), content_type(&file)) {
+        (Some(dir), Some(content_type)) => (dir, content_type),
+        _ => return (StatusCode::NOT_FOUND, "Not found").into_response()
+    };
+    match tokio::fs::read(dir.join(&file)).await {
+        Ok(contents) => ([(CONTENT_TYPE, content_type)], contents).into_response(),
+        Err(e) => {
+            warn!("Could not read {} from web_uploader {:?}: {}", file, dir, e);
+            (StatusCode::NOT_FOUND, "Not found").into_response()
+        }
+    }
+}
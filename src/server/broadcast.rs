@@ -0,0 +1,193 @@
+use std::{path::PathBuf, sync::Arc};
+use tokio::{sync::{mpsc::{channel, Receiver}, watch}, task::JoinHandle};
+use tracing::{debug, error, trace};
+use uuid::Uuid;
+
+use super::{sealed::{SealingKey, OVERHEAD}, spoolio::{SpoolReader, SpoolWriter}};
+
+const READER_DEPTH: usize = 16; // chunks each downloader can have waiting, the file holds the rest
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SpoolState {
+    Writing(u64), // bytes written to the file so far, sealed
+    Complete(u64),
+    Failed,
+}
+
+// a broadcast upload is written to disk once and every downloader reads it back at their own pace
+#[derive(Debug)]
+pub struct Spool {
+    path: PathBuf,
+    key: SealingKey,
+    state: watch::Receiver<SpoolState>,
+}
+
+impl Spool {
+    // takes over the upload's channel, so the upload only moves as fast as the disk instead of the slowest downloader
+    pub async fn start(mut upload: Receiver<Vec<u8>>) -> std::io::Result<Arc<Self>> {
+        let path = std::env::temp_dir().join(format!("bytebeam-broadcast-{}", Uuid::new_v4()));
+        let mut file = SpoolWriter::create(&path).await?;
+        let key = SealingKey::generate()?;
+        let mut sealer = key.sealer();
+        let (progress, state) = watch::channel(SpoolState::Writing(0));
+        debug!("Spooling broadcast upload to {:?}", path);
+
+        tokio::spawn(async move {
+            let mut written: u64 = 0;
+            loop {
+                match upload.recv().await {
+                    Some(data) if data.is_empty() => { // the close signal
+                        progress.send_replace(SpoolState::Complete(written));
+                        return;
+                    },
+                    Some(data) => {
+                        let result = match sealer.seal(&data) {
+                            Ok(record) => file.write(record).await,
+                            Err(e) => Err(e)
+                        };
+                        if let Err(e) = result {
+                            error!("Could not write to the broadcast spool: {}", e);
+                            progress.send_replace(SpoolState::Failed);
+                            return;
+                        }
+                        written += (data.len() + OVERHEAD) as u64;
+                        progress.send_replace(SpoolState::Writing(written));
+                    },
+                    None => {
+                        debug!("Broadcast upload dropped after {} bytes", written);
+                        progress.send_replace(SpoolState::Failed);
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Arc::new(Spool { path, key, state }))
+    }
+
+    // hands out a channel that behaves like the upload's own, ending with an empty chunk or closing early if the upload failed
+    pub fn reader(self: &Arc<Self>) -> (Receiver<Vec<u8>>, JoinHandle<()>) {
+        let (tx, rx) = channel(READER_DEPTH);
+        let spool = self.clone();
+        let handle = tokio::spawn(async move {
+            let mut state = spool.state.clone();
+            let mut file = match SpoolReader::open(&spool.path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Could not open the broadcast spool: {}", e);
+                    return;
+                }
+            };
+            let mut opener = spool.key.opener();
+            let mut offset: u64 = 0;
+            loop {
+                let current = *state.borrow_and_update();
+                let (available, complete) = match current {
+                    SpoolState::Writing(written) => (written, false),
+                    SpoolState::Complete(written) => (written, true),
+                    SpoolState::Failed => return,
+                };
+
+                // the writer only counts whole records, so everything up to what it has written can be opened
+                while offset < available {
+                    let chunk = match opener.read(&mut file).await {
+                        Ok(Some(chunk)) => chunk,
+                        Ok(None) => {
+                            error!("The broadcast spool ended before what was written to it");
+                            return;
+                        },
+                        Err(e) => {
+                            error!("Could not read the broadcast spool: {}", e);
+                            return;
+                        }
+                    };
+                    offset += (chunk.len() + OVERHEAD) as u64;
+                    if tx.send(chunk).await.is_err() {
+                        trace!("Broadcast downloader went away at {} bytes", offset);
+                        return;
+                    }
+                }
+
+                if complete {
+                    let _ = tx.send(vec![]).await;
+                    return;
+                }
+                if state.changed().await.is_err() {
+                    return; // the writer is gone without finishing
+                }
+            }
+        });
+        (rx, handle)
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        debug!("Removing broadcast spool {:?}", self.path);
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            error!("Could not remove broadcast spool {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn chunks() -> Vec<Vec<u8>> {
+        (0..48u8).map(|i| vec![i; 700 + i as usize * 3]).collect()
+    }
+
+    async fn read_all(mut reader: Receiver<Vec<u8>>, pause: Option<Duration>) -> Vec<Vec<u8>> {
+        let mut received = vec![];
+        while let Some(chunk) = reader.recv().await {
+            if chunk.is_empty() {
+                return received;
+            }
+            received.push(chunk);
+            if let Some(pause) = pause {
+                tokio::time::sleep(pause).await;
+            }
+        }
+        panic!("the reader closed without the close chunk after {} chunks", received.len());
+    }
+
+    #[tokio::test]
+    async fn slow_and_fast_readers_both_get_everything_in_order() {
+        let (upload, rx) = channel(4);
+        let spool = Spool::start(rx).await.unwrap();
+        let (fast, _) = spool.reader();
+        let (slow, _) = spool.reader();
+        let fast = tokio::spawn(read_all(fast, None));
+        let slow = tokio::spawn(read_all(slow, Some(Duration::from_millis(2))));
+        for chunk in chunks() {
+            upload.send(chunk).await.unwrap(); // never waits on the slow reader, only the disk
+        }
+        upload.send(vec![]).await.unwrap();
+        assert_eq!(fast.await.unwrap(), chunks());
+        assert_eq!(slow.await.unwrap(), chunks());
+
+        // someone who turns up once it's all written reads it from the start
+        let (late, _) = spool.reader();
+        assert_eq!(read_all(late, None).await, chunks());
+
+        let path = spool.path.clone();
+        drop(spool);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn a_dropped_upload_closes_readers_without_the_close_chunk() {
+        let (upload, rx) = channel(4);
+        let spool = Spool::start(rx).await.unwrap();
+        let (mut reader, handle) = spool.reader();
+        upload.send(b"partial".to_vec()).await.unwrap();
+        drop(upload);
+        // what was written may or may not make it out first, but it never looks finished
+        while let Some(chunk) = reader.recv().await {
+            assert_eq!(chunk, b"partial".to_vec());
+        }
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,565 @@
+use std::collections::HashMap;
+use async_stream::stream;
+use axum::{body::Body, extract::{Path, State}, http::{HeaderValue, Response, StatusCode}, response::IntoResponse, Extension, Form, Json};
+use bytes::Bytes;
+use chrono::{Datelike, Timelike, Utc};
+use maud::{html, Markup};
+use reqwest::header::{CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS};
+use serde::Serialize;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, error, info};
+
+use crate::utils::{compression::Compression, digest::DigestWorker, metadata::ManifestEntry};
+use super::{appstate::AppState, eventlog::TokenEvent, forwarded::Requester, server::{downloadable, make_upload, safe_file_name, DownloadGuard}, tls::ClientIdentity};
+
+#[derive(Serialize, Debug)]
+pub struct BundleInfo {
+    path: String,
+    files: usize,
+}
+
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+// a zip that can be written front to back without knowing any sizes ahead of time.
+// every entry is stored (the uploads are often compressed already) with zip64 data descriptors
+struct ZipStream {
+    offset: u64,
+    entries: Vec<ZipEntry>,
+    dos_time: u16,
+    dos_date: u16,
+}
+
+impl ZipStream {
+    fn new() -> Self {
+        let now = Utc::now();
+        ZipStream {
+            offset: 0,
+            entries: vec![],
+            dos_time: ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16,
+            dos_date: (((now.year().max(1980) - 1980) as u32) << 9 | (now.month() << 5) | now.day()) as u16,
+        }
+    }
+
+    fn start_entry(&mut self, name: &str) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        out.extend_from_slice(&45u16.to_le_bytes()); // zip64
+        out.extend_from_slice(&0x0808u16.to_le_bytes()); // data descriptor + utf8 names
+        out.extend_from_slice(&0u16.to_le_bytes()); // stored
+        out.extend_from_slice(&self.dos_time.to_le_bytes());
+        out.extend_from_slice(&self.dos_date.to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes()); // crc comes in the descriptor
+        out.extend_from_slice(&u32::MAX.to_le_bytes());
+        out.extend_from_slice(&u32::MAX.to_le_bytes());
+        out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        out.extend_from_slice(&20u16.to_le_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(&0x0001u16.to_le_bytes());
+        out.extend_from_slice(&16u16.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+
+        self.entries.push(ZipEntry {
+            name: name.to_string(),
+            crc: 0,
+            size: 0,
+            offset: self.offset,
+        });
+        self.offset += out.len() as u64;
+        out
+    }
+
+    fn finish_entry(&mut self, crc: u32, size: u64) -> Vec<u8> {
+        self.offset += size;
+        let entry = self.entries.last_mut().expect("Finished a zip entry that was never started");
+        entry.crc = crc;
+        entry.size = size;
+
+        let mut out = vec![];
+        out.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        out.extend_from_slice(&crc.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        self.offset += out.len() as u64;
+        out
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let mut out = vec![];
+        for entry in &self.entries {
+            out.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            out.extend_from_slice(&(45u16 | (3 << 8)).to_le_bytes()); // made by unix
+            out.extend_from_slice(&45u16.to_le_bytes());
+            out.extend_from_slice(&0x0808u16.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&self.dos_time.to_le_bytes());
+            out.extend_from_slice(&self.dos_date.to_le_bytes());
+            out.extend_from_slice(&entry.crc.to_le_bytes());
+            out.extend_from_slice(&u32::MAX.to_le_bytes());
+            out.extend_from_slice(&u32::MAX.to_le_bytes());
+            out.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&28u16.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes()); // comment
+            out.extend_from_slice(&0u16.to_le_bytes()); // disk
+            out.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+            out.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+            out.extend_from_slice(&u32::MAX.to_le_bytes());
+            out.extend_from_slice(entry.name.as_bytes());
+            out.extend_from_slice(&0x0001u16.to_le_bytes());
+            out.extend_from_slice(&24u16.to_le_bytes());
+            out.extend_from_slice(&entry.size.to_le_bytes());
+            out.extend_from_slice(&entry.size.to_le_bytes());
+            out.extend_from_slice(&entry.offset.to_le_bytes());
+        }
+
+        let directory_offset = self.offset;
+        let directory_size = out.len() as u64;
+        let zip64_end_offset = directory_offset + directory_size;
+        let count = self.entries.len() as u64;
+
+        out.extend_from_slice(&0x06064b50u32.to_le_bytes());
+        out.extend_from_slice(&44u64.to_le_bytes());
+        out.extend_from_slice(&(45u16 | (3 << 8)).to_le_bytes());
+        out.extend_from_slice(&45u16.to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&directory_size.to_le_bytes());
+        out.extend_from_slice(&directory_offset.to_le_bytes());
+
+        out.extend_from_slice(&0x07064b50u32.to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&zip64_end_offset.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+
+        out.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&u16::MAX.to_le_bytes());
+        out.extend_from_slice(&u16::MAX.to_le_bytes());
+        out.extend_from_slice(&u32::MAX.to_le_bytes());
+        out.extend_from_slice(&u32::MAX.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+}
+
+// compressed uploads are stored as-is, so the name needs to say so
+fn entry_name(file_name: &str, compression: &Compression, taken: &mut Vec<String>) -> String {
+    let base = safe_file_name(file_name).unwrap_or("file".to_string());
+    let name = match compression.extension() {
+        Some(extension) => format!("{base}.{extension}"),
+        None => base.to_string(),
+    };
+
+    let mut unique = name.clone();
+    let mut n = 1;
+    while taken.contains(&unique) {
+        unique = format!("{n}-{name}");
+        n += 1;
+    }
+    taken.push(unique.clone());
+    unique
+}
+
+// pulls exactly `size` bytes of an upload, keeping whatever is left over for the next file
+async fn next_part<S>(input: &mut S, pending: &mut Bytes, remaining: u64) -> Result<Bytes, String> where S: Stream<Item = Result<Bytes, String>> + Unpin {
+    while pending.is_empty() {
+        *pending = match input.next().await {
+            Some(chunk) => chunk?,
+            None => return Err("Upload ended before all of its files were sent".to_string())
+        };
+    }
+    Ok(pending.split_to(remaining.min(pending.len() as u64) as usize))
+}
+
+// a multi-file upload arrives as its files back to back, this turns it into a zip with one entry per file
+pub fn zip_manifest<S>(manifest: Vec<ManifestEntry>, mut input: S) -> impl Stream<Item = Result<Bytes, String>> where S: Stream<Item = Result<Bytes, String>> + Unpin {
+    stream! {
+        let mut zip = ZipStream::new();
+        let mut taken = vec![];
+        let mut pending = Bytes::new();
+        for file in manifest {
+            yield Ok(Bytes::from(zip.start_entry(&entry_name(&file.name, &Compression::None, &mut taken))));
+            let hasher = DigestWorker::new(crc32fast::Hasher::new());
+            let mut remaining = file.size;
+            while remaining > 0 {
+                let part = match next_part(&mut input, &mut pending, remaining).await {
+                    Ok(part) => part,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                remaining -= part.len() as u64;
+                hasher.update(part.clone()).await;
+                yield Ok(part);
+            }
+            let crc = match hasher.finish().await {
+                Some(crc) => crc,
+                None => {
+                    yield Err(format!("Could not checksum {}", file.name));
+                    return;
+                }
+            };
+            yield Ok(Bytes::from(zip.finish_entry(crc, file.size)));
+        }
+
+        // the upload still has to be read to the end so the download is marked complete
+        let mut extra = pending.len();
+        while let Some(chunk) = input.next().await {
+            match chunk {
+                Ok(chunk) => extra += chunk.len(),
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+        if extra > 0 {
+            debug!("Upload had {} bytes more than its manifest listed, they were left out of the zip", extra);
+        }
+        yield Ok(Bytes::from(zip.finish()));
+    }
+}
+
+// one file out of a multi-file upload. The earlier files are read and skipped, so this only makes sense on a broadcast spool
+pub fn slice_manifest<S>(manifest: &[ManifestEntry], index: usize, mut input: S) -> impl Stream<Item = Result<Bytes, String>> where S: Stream<Item = Result<Bytes, String>> + Unpin {
+    let mut skip: u64 = manifest.iter().take(index).map(|file| file.size).sum();
+    let mut remaining = manifest.get(index).map(|file| file.size).unwrap_or(0);
+    stream! {
+        let mut pending = Bytes::new();
+        while skip > 0 {
+            match next_part(&mut input, &mut pending, skip).await {
+                Ok(part) => skip -= part.len() as u64,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+        while remaining > 0 {
+            match next_part(&mut input, &mut pending, remaining).await {
+                Ok(part) => {
+                    remaining -= part.len() as u64;
+                    yield Ok(part);
+                },
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// takes tokens=["token/key", ...] (a single token/key is also fine) and hands back one link for all of them
+pub async fn make_bundle(State(state): State<AppState>, requester: Requester, identity: Option<Extension<ClientIdentity>>, Form(params): Form<HashMap<String, String>>) -> Response<Body> {
+    let tokens = match params.get("tokens") {
+        Some(tokens) => tokens.clone(),
+        None => { // without tokens this is someone uploading a file called "bundle"
+            return make_upload(State(state), Path("bundle".to_string()), requester, identity, Form(params)).await.into_response();
+        }
+    };
+
+    match create_bundle(&state, &tokens).await {
+        Ok(info) => Json(info).into_response(),
+        Err(e) => e.into_response()
+    }
+}
+
+async fn create_bundle(state: &AppState, tokens: &String) -> Result<BundleInfo, (StatusCode, Markup)> {
+    let pairs: Vec<String> = match serde_json::from_str(tokens) {
+        Ok(pairs) => pairs,
+        Err(_) => vec![tokens.to_string()],
+    };
+
+    if pairs.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, html! {"No tokens to bundle"}));
+    }
+
+    let mut owned = vec![];
+    for pair in pairs {
+        // ownership is proven the same way as uploading, with the upload key
+        let (token, key) = match pair.trim_matches('/').split_once('/') {
+            Some(tk) => tk,
+            None => return Err((StatusCode::BAD_REQUEST, html! {"Tokens must be given as token/key"})),
+        };
+        let meta = match state.get_file_metadata(&token.to_string()).await {
+            Some(meta) => meta,
+            None => return Err((StatusCode::NOT_FOUND, html! {"Token " (token) " was not found"})),
+        };
+        if !meta.check_key(&key.to_string()) {
+            return Err((StatusCode::FORBIDDEN, html! {"Token " (token) " has a different key"}));
+        }
+        if meta.get_receiver().is_some() { // the bundle link would skip the receiver's challenge
+            return Err((StatusCode::FORBIDDEN, html! {"Token " (token) " can only be downloaded by its receiver"}));
+        }
+        if meta.downloads_exhausted() {
+            return Err((StatusCode::GONE, html! {"Token " (token) " has already been downloaded"}));
+        }
+        if meta.download_locked() {
+            return Err((StatusCode::CONFLICT, html! {"Token " (token) " is already being downloaded"}));
+        }
+        if !owned.contains(&token.to_string()) {
+            owned.push(token.to_string());
+        }
+    }
+
+    let files = owned.len();
+    let path = state.create_bundle(owned).await;
+    info!("Created bundle {} with {} files", path, files);
+    Ok(BundleInfo { path, files })
+}
+
+pub async fn download_bundle(State(state): State<AppState>, Path(bundle): Path<String>) -> Result<impl IntoResponse, (StatusCode, Markup)> {
+    let tokens = match state.get_bundle(&bundle).await {
+        Some(tokens) => tokens,
+        None => return Err((StatusCode::NOT_FOUND, html! {"Bundle not found"})),
+    };
+    // all of them are checked before any is started, so one that's gone doesn't leave the others used up for a broken zip
+    for token in &tokens {
+        match state.get_file_metadata(token).await {
+            Some(meta) => downloadable(&meta).map_err(|(status, _)| (status, html! {"Token " (token) " in this bundle can't be downloaded"}))?,
+            None => return Err((StatusCode::GONE, html! {"Token " (token) " in this bundle no longer exists"}))
+        }
+    }
+    if state.take_bundle(&bundle).await.is_none() { // another request got there first
+        return Err((StatusCode::NOT_FOUND, html! {"Bundle not found"}));
+    }
+
+    let disposition = format!("attachment; filename=\"{bundle}.zip\"");
+    let s = stream! {
+        let mut zip = ZipStream::new();
+        let mut taken = vec![];
+        for token in tokens {
+            let meta = match state.get_file_metadata(&token).await {
+                Some(meta) => meta,
+                None => {
+                    error!("Bundled token {} expired before it could be sent", token);
+                    yield Err(format!("Token {token} no longer exists"));
+                    return;
+                }
+            };
+            // an entry cut off by the zip being dropped goes back like any other download, so the token can still be fetched on its own
+            let (mut download, counters) = match (state.begin_download(&token).await, state.get_counters(&token).await) {
+                (Some(dl), Some(counters)) => (DownloadGuard::new(&state, &token, dl, &meta), counters),
+                _ => {
+                    error!("Bundled token {} could not be downloaded", token);
+                    yield Err(format!("Token {token} could not be downloaded"));
+                    return;
+                }
+            };
+
+            debug!("Adding {} to bundle {}", token, bundle);
+            yield Ok(Bytes::from(zip.start_entry(&entry_name(&meta.file_name, &meta.get_compression(), &mut taken))));
+
+            let hasher = DigestWorker::new(crc32fast::Hasher::new());
+            let mut size: u64 = 0;
+            loop {
+                match download.recv().await {
+                    Some(data) => {
+                        if data.is_empty() {
+                            break;
+                        }
+                        let data = Bytes::from(data);
+                        hasher.update(data.clone()).await;
+                        size += data.len() as u64;
+                        state.count_download(&token, &counters, data.len()).await;
+                        if counters.is_cancelled() {
+                            info!("Bundle download of {} stopped, the token was deleted", token);
+                            download.finish();
+                            yield Err(format!("{token} was deleted"));
+                            return;
+                        }
+                        yield Ok(data);
+                    },
+                    None => {
+                        download.finish();
+                        state.log_event(&token, TokenEvent::Error { message: "Upload stream closed during a bundle download".to_string() }).await;
+                        yield Err(format!("Upload for {token} was dropped"));
+                        return;
+                    }
+                }
+            }
+
+            download.finish();
+            state.log_event(&token, TokenEvent::DownloadComplete { bytes: counters.downloaded() }).await;
+            state.end(&token).await;
+            let crc = match hasher.finish().await {
+                Some(crc) => crc,
+                None => {
+                    yield Err(format!("Could not checksum {token}"));
+                    return;
+                }
+            };
+            yield Ok(Bytes::from(zip.finish_entry(crc, size)));
+        }
+        yield Ok(Bytes::from(zip.finish()));
+        info!("Bundle {} complete", bundle);
+    };
+
+    let response = Response::new(Body::from_stream(s));
+    let (mut parts, body) = response.into_parts();
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/zip"));
+    parts.headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")); // same as any other download
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        parts.headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    Ok(Response::from_parts(parts, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(zip: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(zip[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(zip: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(zip[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(zip: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(zip[at..at + 8].try_into().unwrap())
+    }
+
+    // walks the archive the way an unzipper would, from the end records to the central directory to each entry,
+    // checking every record agrees with the others, and gives back each entry's name and contents
+    fn read_zip(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(zip, end), 0x06054b50);
+        assert_eq!(u16_at(zip, end + 10), u16::MAX); // the real count is in the zip64 record
+        assert_eq!(u32_at(zip, end + 16), u32::MAX);
+
+        let locator = end - 20;
+        assert_eq!(u32_at(zip, locator), 0x07064b50);
+        let zip64_end = u64_at(zip, locator + 8) as usize;
+        assert_eq!(u32_at(zip, zip64_end), 0x06064b50);
+        assert_eq!(u64_at(zip, zip64_end + 4), 44);
+        let count = u64_at(zip, zip64_end + 32) as usize;
+        let directory_size = u64_at(zip, zip64_end + 40) as usize;
+        let directory = u64_at(zip, zip64_end + 48) as usize;
+        assert_eq!(directory + directory_size, zip64_end);
+
+        let mut entries = vec![];
+        let mut at = directory;
+        for _ in 0..count {
+            assert_eq!(u32_at(zip, at), 0x02014b50);
+            assert_eq!(u16_at(zip, at + 10), 0); // stored
+            let crc = u32_at(zip, at + 16);
+            assert_eq!(u32_at(zip, at + 20), u32::MAX);
+            let (name_length, extra_length, comment_length) = (u16_at(zip, at + 28) as usize, u16_at(zip, at + 30) as usize, u16_at(zip, at + 32) as usize);
+            let name = String::from_utf8(zip[at + 46..at + 46 + name_length].to_vec()).unwrap();
+            let extra = at + 46 + name_length;
+            assert_eq!(u16_at(zip, extra), 0x0001);
+            assert_eq!(u16_at(zip, extra + 2), 24);
+            let size = u64_at(zip, extra + 4);
+            assert_eq!(u64_at(zip, extra + 12), size);
+            let offset = u64_at(zip, extra + 20) as usize;
+
+            assert_eq!(u32_at(zip, offset), 0x04034b50);
+            assert_eq!(u16_at(zip, offset + 6), 0x0808);
+            assert_eq!(u16_at(zip, offset + 26) as usize, name_length);
+            assert_eq!(&zip[offset + 30..offset + 30 + name_length], name.as_bytes());
+            let data = offset + 30 + name_length + u16_at(zip, offset + 28) as usize;
+            let contents = zip[data..data + size as usize].to_vec();
+            assert_eq!(crc32fast::hash(&contents), crc);
+
+            let descriptor = data + size as usize;
+            assert_eq!(u32_at(zip, descriptor), 0x08074b50);
+            assert_eq!(u32_at(zip, descriptor + 4), crc);
+            assert_eq!(u64_at(zip, descriptor + 8), size);
+            assert_eq!(u64_at(zip, descriptor + 16), size);
+
+            entries.push((name, contents));
+            at += 46 + name_length + extra_length + comment_length;
+        }
+        assert_eq!(at, zip64_end);
+        entries
+    }
+
+    fn entry(name: &str, size: u64) -> ManifestEntry {
+        ManifestEntry { name: name.to_string(), size, attributes: None, link: None }
+    }
+
+    // the same bytes, cut up differently from how the files divide them
+    fn pieces(data: &[u8], size: usize) -> impl Stream<Item = Result<Bytes, String>> + Unpin {
+        tokio_stream::iter(data.chunks(size).map(|piece| Ok(Bytes::copy_from_slice(piece))).collect::<Vec<_>>())
+    }
+
+    async fn collect<S>(stream: S) -> Result<Vec<u8>, String> where S: Stream<Item = Result<Bytes, String>> {
+        let mut stream = Box::pin(stream);
+        let mut out = vec![];
+        while let Some(bytes) = stream.next().await {
+            out.extend_from_slice(&bytes?);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn writes_entries_an_unzipper_can_find() {
+        let first = b"hello".to_vec();
+        let second: Vec<u8> = (0..5000u32).map(|n| n as u8).collect();
+        let mut zip = ZipStream::new();
+        let mut out = vec![];
+        for (name, contents) in [("a.txt", &first), ("b.bin", &second)] {
+            out.extend(zip.start_entry(name));
+            out.extend_from_slice(contents);
+            out.extend(zip.finish_entry(crc32fast::hash(contents), contents.len() as u64));
+        }
+        out.extend(zip.finish());
+
+        assert_eq!(read_zip(&out), vec![("a.txt".to_string(), first), ("b.bin".to_string(), second)]);
+    }
+
+    #[test]
+    fn writes_an_empty_archive() {
+        assert!(read_zip(&ZipStream::new().finish()).is_empty());
+    }
+
+    #[test]
+    fn names_entries_safely_and_once() {
+        let mut taken = vec![];
+        assert_eq!(entry_name("../../etc/passwd", &Compression::None, &mut taken), "passwd");
+        assert_eq!(entry_name("C:\\Users\\me\\notes.txt", &Compression::None, &mut taken), "notes.txt");
+        assert_eq!(entry_name("notes.txt", &Compression::None, &mut taken), "1-notes.txt");
+        assert_eq!(entry_name("notes.txt", &Compression::None, &mut taken), "2-notes.txt");
+        assert_eq!(entry_name("log", &Compression::Zstd, &mut taken), "log.zst");
+        assert_eq!(entry_name("..", &Compression::None, &mut taken), "file");
+    }
+
+    #[tokio::test]
+    async fn zips_a_manifest_whatever_the_pieces() {
+        let data: Vec<u8> = (0..3000u32).map(|n| (n % 251) as u8).collect();
+        let manifest = vec![entry("one", 1000), entry("empty", 0), entry("two", 2000)];
+        let zip = collect(zip_manifest(manifest, pieces(&data, 333))).await.unwrap();
+
+        let entries = read_zip(&zip);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0], ("one".to_string(), data[..1000].to_vec()));
+        assert_eq!(entries[1], ("empty".to_string(), vec![]));
+        assert_eq!(entries[2], ("two".to_string(), data[1000..].to_vec()));
+    }
+
+    #[tokio::test]
+    async fn fails_a_manifest_the_upload_falls_short_of() {
+        let manifest = vec![entry("one", 1000)];
+        assert!(collect(zip_manifest(manifest, pieces(&[0; 600], 100))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn slices_one_file_out() {
+        let data: Vec<u8> = (0..300u32).map(|n| n as u8).collect();
+        let manifest = vec![entry("one", 100), entry("two", 150), entry("three", 50)];
+        assert_eq!(collect(slice_manifest(&manifest, 1, pieces(&data, 64))).await.unwrap(), data[100..250].to_vec());
+        assert_eq!(collect(slice_manifest(&manifest, 2, pieces(&data, 7))).await.unwrap(), data[250..].to_vec());
+    }
+}
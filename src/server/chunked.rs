@@ -0,0 +1,213 @@
+use std::{collections::HashMap, pin::Pin, sync::Arc, time::{Duration, Instant}};
+use axum::{body::Body, extract::{Path, Query, State}, http::{Response, StatusCode}, response::IntoResponse, Json};
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::sync::mpsc::Sender;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, error, info, trace};
+
+use crate::utils::{chunked::{piece_crc, ChunkReceipt, CHUNK_SIZE}, compression::Compression};
+
+use super::{appstate::{AppState, TransferCounters}, eventlog::TokenEvent, forwarded::Requester, throttle::Throttle};
+
+// a browser that hasn't sent anything in this long has gone away, and its downloader shouldn't wait on it forever
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+// a piece with a crc is held until all of it is in, so it has to be about the size the uploaders send
+const MAX_CHECKED_PIECE: usize = 4 * CHUNK_SIZE;
+
+type PieceStream = Pin<Box<dyn Stream<Item = Result<Bytes, axum::Error>> + Send>>;
+
+// an upload sent as a series of PUTs, kept between requests so each one carries on where the last stopped
+#[derive(Debug)]
+pub struct ChunkedUpload {
+    upload: Sender<Vec<u8>>,
+    counters: Arc<TransferCounters>,
+    throttle: Option<Throttle>,
+    block_size: usize,
+    received: usize,
+    started: Instant,
+    last_chunk: Instant,
+}
+
+impl ChunkedUpload {
+    pub fn is_idle(&self) -> bool {
+        self.last_chunk.elapsed() > IDLE_TIMEOUT
+    }
+
+    // full blocks go out as they fill up, the rest only once the request is over
+    async fn send(&mut self, buffer: &mut BytesMut, flush: bool) -> Result<(), ()> {
+        while buffer.len() >= self.block_size || (flush && !buffer.is_empty()) {
+            let block = buffer.split_to(self.block_size.min(buffer.len())).to_vec();
+            let size = block.len();
+            if self.upload.send(block).await.is_err() {
+                return Err(());
+            }
+            if let Some(throttle) = &mut self.throttle {
+                throttle.take(size).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+// the whole piece when it came with a crc that matches, a piece that doesn't match is turned away before any of it goes to the downloader
+async fn checked_piece(body: Body, crc: &str, received: usize) -> Result<PieceStream, Response<Body>> {
+    let mut data = body.into_data_stream();
+    let mut piece = BytesMut::new();
+    while let Some(bytes) = data.next().await {
+        match bytes {
+            Ok(bytes) if piece.len() + bytes.len() > MAX_CHECKED_PIECE => return Err((StatusCode::PAYLOAD_TOO_LARGE, "Pieces with a crc can't be bigger than 4MiB").into_response()),
+            Ok(bytes) => piece.put(bytes),
+            Err(e) => { // half a piece can't be checked, so none of it is kept
+                debug!("Checked piece ended early: {}", e);
+                return Err(Json(ChunkReceipt { received }).into_response());
+            }
+        }
+    }
+    if piece_crc(&piece) != crc.to_lowercase() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(ChunkReceipt { received })).into_response());
+    }
+    Ok(Box::pin(tokio_stream::once(Ok(piece.freeze()))))
+}
+
+// PUT /{token}/{key}?offset=N, with last=true on the final piece (see utils::chunked::piece_query). anything before what the relay already has is skipped,
+// so a piece can always be sent again if its response was lost. with crc=[crc32 in hex] the piece is checked first, and a damaged one gets a 422
+// with the same receipt so it can be sent again
+pub async fn upload_chunk(State(state): State<AppState>, Path((token, key)): Path<(String, String)>, requester: Requester, Query(params): Query<HashMap<String, String>>, body: Body) -> Response<Body> {
+    let offset = match params.get("offset").map(|offset| offset.parse::<usize>()) {
+        Some(Ok(offset)) => offset,
+        _ => return (StatusCode::BAD_REQUEST, "Each piece needs the offset it starts at").into_response()
+    };
+    let last = params.get("last").map(|last| last == "true").unwrap_or(false);
+
+    let meta = match state.get_file_metadata(&token).await {
+        Some(meta) => meta,
+        None => return (StatusCode::NOT_FOUND, "Upload ticket does not exist").into_response()
+    };
+
+    let chunked = match state.get_chunked_upload(&token).await {
+        Some(_) if !meta.check_key(&key) => return (StatusCode::FORBIDDEN, "File has a different key").into_response(),
+        Some(chunked) => chunked,
+        None if meta.upload_finished() && meta.check_key(&key) => return "Done! The upload is already complete".into_response(), // the last response was lost
+        None if offset == 0 => {
+            let (upload, options) = match state.begin_upload(&token, &key, &requester.address).await {
+                Ok(upload) => upload,
+                Err(e) => return e.into_response()
+            };
+            let counters = match state.get_counters(&token).await {
+                Some(counters) => counters,
+                None => {
+                    error!("Upload has no byte counters, was it deleted?");
+                    return (StatusCode::GONE, "Upload no longer exists").into_response();
+                }
+            };
+            // the web uploader may have compressed the file first, which the first piece says like the form's field would
+            let size = params.get("size").and_then(|size| size.parse::<usize>().ok());
+            let compression = params.get("compression").and_then(|compression| compression.parse::<Compression>().ok());
+            if size.is_some() || compression.is_some() {
+                state.set_metadata(&token, None, size, compression, None).await;
+            }
+            debug!("Starting chunked upload for {}", token);
+            state.add_chunked_upload(&token, ChunkedUpload {
+                upload,
+                counters,
+                throttle: options.get_throttle(),
+                block_size: options.get_block_size().max(1),
+                received: 0,
+                started: Instant::now(),
+                last_chunk: Instant::now(),
+            }).await
+        },
+        None => return (StatusCode::NOT_FOUND, "There is no chunked upload in progress, start again from offset 0").into_response()
+    };
+
+    // one piece at a time, a retry that overlaps one still being read waits for it
+    let mut chunked = chunked.lock().await;
+    if offset > chunked.received {
+        return (StatusCode::CONFLICT, Json(ChunkReceipt { received: chunked.received })).into_response();
+    }
+
+    let mut data: PieceStream = match params.get("crc") {
+        Some(crc) => match checked_piece(body, crc, chunked.received).await {
+            Ok(data) => data,
+            Err(response) => {
+                chunked.last_chunk = Instant::now();
+                debug!("Piece for {} at {} was turned away", token, offset);
+                return response;
+            }
+        },
+        None => Box::pin(body.into_data_stream())
+    };
+    let mut skip = chunked.received - offset;
+    let mut buffer = BytesMut::new();
+    let mut broken = false;
+    while let Some(piece) = data.next().await {
+        let mut piece = match piece {
+            Ok(piece) => piece,
+            Err(e) => { // whatever did arrive is kept, the browser sends the rest again
+                debug!("Piece for {} ended early: {}", token, e);
+                broken = true;
+                break;
+            }
+        };
+        if skip > 0 {
+            let n = skip.min(piece.len());
+            piece = piece.slice(n..);
+            skip -= n;
+        }
+        if piece.is_empty() {
+            continue;
+        }
+        state.count_upload(&token, &chunked.counters, piece.len()).await;
+        if chunked.counters.is_cancelled() {
+            info!("Chunked upload to {} stopped, the token was deleted", token);
+            return (StatusCode::GONE, "Upload no longer exists").into_response();
+        }
+        if chunked.counters.over_quota() {
+            info!("Chunked upload to {} stopped, it went over its quota", token);
+            state.take_chunked_upload(&token).await;
+            state.stop_over_quota(&token, chunked.counters.uploaded()).await;
+            return (StatusCode::PAYLOAD_TOO_LARGE, "Upload went over the size, daily, or storage limit of this relay").into_response();
+        }
+        chunked.received += piece.len();
+        buffer.put(piece);
+        if chunked.send(&mut buffer, false).await.is_err() {
+            break;
+        }
+    }
+    chunked.last_chunk = Instant::now();
+
+    if chunked.send(&mut buffer, true).await.is_err() || chunked.upload.is_closed() {
+        error!("Downloader went away during the chunked upload to {}", token);
+        state.take_chunked_upload(&token).await;
+        state.abort_upload(&token, "Downloader went away during the upload".to_string()).await;
+        return (StatusCode::GONE, "The download was dropped, the upload can't continue").into_response();
+    }
+    trace!("Chunked upload for {} has {} bytes", token, chunked.received);
+
+    if !last || broken {
+        return Json(ChunkReceipt { received: chunked.received }).into_response();
+    }
+
+    // without the close signal the downloader sees the upload as dropped instead of complete
+    if let Err(e) = chunked.upload.send(vec![]).await {
+        error!("Failed to send close signal: {:?}", e);
+    }
+    let final_bytes = chunked.counters.uploaded();
+    state.take_chunked_upload(&token).await;
+    if let Err(e) = state.finish_storing(&token).await {
+        error!("Could not store the upload to {}: {}", token, e);
+        state.abort_upload(&token, format!("Could not store the upload: {}", e)).await;
+        return (StatusCode::BAD_GATEWAY, "The relay could not store the upload").into_response();
+    }
+    state.log_event(&token, TokenEvent::UploadComplete { bytes: final_bytes }).await;
+    state.record_upload(&token, final_bytes, chunked.started.elapsed().as_secs_f64()).await;
+
+    info!("Sent file with size {} to token {} in pieces", final_bytes, &token);
+    match state.end_upload(&token).await {
+        true => format!("Done! Sent {} bytes", final_bytes).into_response(),
+        false => {
+            error!("Had an issue marking the download as ended");
+            format!("Done! Sent {} bytes, however the upload failed to be marked as complete", final_bytes).into_response()
+        }
+    }
+}
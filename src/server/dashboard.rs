@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, HashMap};
+use axum::{extract::State, http::{header::SET_COOKIE, HeaderMap, StatusCode}, response::{IntoResponse, Redirect, Response}, Form};
+use bytesize::ByteSize;
+use chrono::Utc;
+use maud::{html, Markup, DOCTYPE};
+use tracing::{debug, info, warn};
+
+use crate::utils::metadata::{FileMetadata, FileState};
+use super::{admin::{admin_user, authorize, SESSION_COOKIE, SESSION_LIFETIME}, appstate::AppState, eventlog::LoggedEvent, routes, stats::CullStats};
+
+fn page(state: &AppState, title: &str, refresh: bool, body: Markup) -> Markup {
+    html! {
+        (DOCTYPE);
+        html {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1.0";
+                @if refresh {
+                    meta http-equiv="refresh" content="10";
+                }
+                title {"ByteBeam " (title)}
+            }
+            body {
+                h1 {"ByteBeam " (title)}
+                @if let Some(banner) = state.get_banner() {
+                    p { i {(banner)} }
+                }
+                (body)
+            }
+        }
+    }
+}
+
+fn sign_in_form(state: &AppState, error: Option<&str>) -> Markup {
+    page(state, "Admin", false, html! {
+        @if let Some(error) = error {
+            p { b {(error)} }
+        }
+        form method="POST" action=(state.public_url().link(routes::ADMIN_SIGN_IN)) {
+            label for="user" {"Admin user "}
+            input id="user" name="user" type="text" required;
+            input type="submit" value="Get a challenge";
+        }
+    })
+}
+
+// the browser can't sign anything itself, so the challenge is signed with ssh-keygen and pasted back in
+fn challenge_form(state: &AppState, user: &String, challenge: &String) -> Markup {
+    page(state, "Admin", false, html! {
+        p {"Sign this challenge with your ssh key within a minute, then paste the signature below."}
+        pre { "printf '%s' '" (challenge) "' | ssh-keygen -Y sign -n bytebeam -f ~/.ssh/id_ed25519" }
+        form method="POST" action=(state.public_url().link(routes::ADMIN_SIGN_IN)) {
+            input name="user" type="hidden" value=(user);
+            input name="challenge" type="hidden" value=(challenge);
+            textarea name="signature" rows="8" cols="72" placeholder="-----BEGIN SSH SIGNATURE-----" required {}
+            br;
+            input type="submit" value="Sign in";
+        }
+    })
+}
+
+// bytes per second since the phase started, for transfers still going
+fn rate(events: &[LoggedEvent], phase: &str, bytes: usize) -> Option<u64> {
+    let started = events.iter().rev().find(|logged| logged.event().phase() == phase)?.time();
+    let seconds = (Utc::now() - started).num_milliseconds() as f64 / 1000.0;
+    match seconds > 0.0 {
+        true => Some((bytes as f64 / seconds) as u64),
+        false => None
+    }
+}
+
+fn human(bytes: u64) -> String {
+    ByteSize(bytes).to_string_as(true)
+}
+
+// a transfer with how fast each side has been going, rates are only known while that side is streaming
+struct Transfer {
+    file: FileMetadata,
+    upload_rate: Option<u64>,
+    download_rate: Option<u64>,
+}
+
+async fn transfers(state: &AppState, files: &[FileMetadata]) -> Vec<Transfer> {
+    let mut transfers = vec![];
+    for file in files.iter().filter(|file| file.is_transferring()) {
+        let events = state.get_events(file.get_token()).await;
+        let (uploading, downloading) = file.get_states();
+        let (uploaded, downloaded) = file.file_size.get_transferred();
+        transfers.push(Transfer {
+            file: file.clone(),
+            upload_rate: (uploading == &FileState::InProgress).then(|| rate(&events, "upload_started", uploaded)).flatten(),
+            download_rate: (downloading == &FileState::InProgress).then(|| rate(&events, "download_started", downloaded)).flatten(),
+        });
+    }
+    transfers
+}
+
+fn dashboard_page(state: &AppState, admin: &String, files: &[FileMetadata], transfers: &[Transfer], culls: &CullStats, usage: &BTreeMap<String, (usize, usize, usize, usize)>) -> Markup {
+    let throughput: u64 = transfers.iter().filter_map(|transfer| transfer.upload_rate).sum();
+    let waiting = files.iter().filter(|file| file.get_states().0 == &FileState::NotStarted).count();
+    page(state, "Admin", true, html! {
+        p { "Signed in as " code {(admin)} ". This page refreshes every 10 seconds." }
+        @if state.is_read_only() {
+            p { b {"The relay is read-only, uploads are refused."} }
+        } @else if state.is_draining() {
+            p { b {"The relay is draining, no new uploads are accepted."} }
+        }
+
+        h2 {"Relay"}
+        ul {
+            li { "Tokens held: " (files.len()) }
+            li { "Waiting for an upload: " (waiting) }
+            li { "Transferring: " (transfers.len()) }
+            li { "Upload throughput: " (human(throughput)) "/s" }
+        }
+
+        h2 {"Active transfers"}
+        @if transfers.is_empty() {
+            p {"Nothing is transferring right now."}
+        } @else {
+            table {
+                tr { th {"Token"} th {"File"} th {"User"} th {"Upload"} th {"Download"} th {} }
+                @for transfer in transfers {
+                    @let (upload, download) = transfer.file.get_states();
+                    @let (uploaded, downloaded) = transfer.file.file_size.get_transferred();
+                    tr {
+                        td { code {(transfer.file.get_token())} }
+                        td {(transfer.file.file_name)}
+                        td {
+                            @match transfer.file.get_challenge_details() {
+                                Some((true, user, _)) => (user),
+                                _ => "-"
+                            }
+                        }
+                        td {
+                            (upload.describe()) " " (human(uploaded as u64))
+                            @if let Some(rate) = transfer.upload_rate { " at " (human(rate)) "/s" }
+                        }
+                        td {
+                            (download.describe()) " " (human(downloaded as u64))
+                            @if let Some(rate) = transfer.download_rate { " at " (human(rate)) "/s" }
+                        }
+                        td {
+                            form method="POST" action=(state.public_url().link(routes::ADMIN_KILL)) {
+                                input name="token" type="hidden" value=(transfer.file.get_token());
+                                input type="submit" value="Kill";
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        h2 {"Culling"}
+        (cull_summary(culls))
+
+        h2 {"Users"}
+        @if usage.is_empty() {
+            p {"No authenticated users yet."}
+        } @else {
+            table {
+                tr { th {"User"} th {"Holding"} th {"Tokens created"} th {"Uploads"} th {"Uploaded"} }
+                @for (user, (holding, tokens, uploads, bytes)) in usage {
+                    tr {
+                        td { code {(user)} }
+                        td {(holding)}
+                        td {(tokens)}
+                        td {(uploads)}
+                        td {(human(*bytes as u64))}
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn cull_summary(culls: &CullStats) -> Markup {
+    html! {
+        ul {
+            li { "Runs: " (culls.runs()) }
+            li { "Tokens culled: " (culls.culled()) }
+            @match culls.last_run() {
+                Some(last) => li { "Last run: " ((Utc::now() - last).num_seconds()) " seconds ago, culled " (culls.last_culled()) },
+                None => li {"The cull loop hasn't run yet."}
+            }
+        }
+    }
+}
+
+pub async fn dashboard(State(state): State<AppState>, headers: HeaderMap) -> Markup {
+    let admin = match admin_user(&state, &headers).await {
+        Some(admin) => admin,
+        None => return sign_in_form(&state, None)
+    };
+
+    let files = state.inspect_files().await;
+    let transfers = transfers(&state, &files).await;
+
+    // what each user is holding now next to what they've done since the relay started
+    let mut usage: BTreeMap<String, (usize, usize, usize, usize)> = BTreeMap::new();
+    for (user, stats) in state.get_all_user_stats().await {
+        usage.insert(user, (0, stats.tokens(), stats.uploads(), stats.bytes()));
+    }
+    for file in &files {
+        if let Some((true, user, _)) = file.get_challenge_details() {
+            usage.entry(user.clone()).or_default().0 += 1;
+        }
+    }
+
+    dashboard_page(&state, &admin, &files, &transfers, &state.get_cull_stats().await, &usage)
+}
+
+// POST user for a challenge, then user, challenge, and signature to get the session cookie
+pub async fn sign_in(State(state): State<AppState>, headers: HeaderMap, Form(params): Form<HashMap<String, String>>) -> Response {
+    let user = match params.get("user").map(|user| user.trim().to_string()) {
+        Some(user) if !user.is_empty() => user,
+        _ => return (StatusCode::BAD_REQUEST, sign_in_form(&state, Some("A user is needed to sign in"))).into_response()
+    };
+    if !state.is_admin(&user) {
+        warn!("Admin dashboard sign in attempted for {}, who is not an admin", user);
+        return (StatusCode::UNAUTHORIZED, sign_in_form(&state, Some("That user is not an admin of this relay"))).into_response();
+    }
+
+    let (challenge, signature) = match (params.get("challenge"), params.get("signature")) {
+        (Some(challenge), Some(signature)) => (challenge, signature.replace("\r\n", "\n")), // browsers send textareas with windows line endings
+        _ => {
+            let challenge = state.issue_admin_challenge(&user).await;
+            return challenge_form(&state, &user, &challenge).into_response();
+        }
+    };
+
+    match state.start_admin_session(&user, challenge, &vec![signature]).await {
+        Some(session) => {
+            info!("Admin dashboard session started for {}", user);
+            let secure = match state.public_url().secure(&headers) {
+                true => "; Secure",
+                false => ""
+            };
+            let cookie = format!("{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Strict{}", SESSION_COOKIE, session.token, state.public_url().link(routes::ADMIN), SESSION_LIFETIME * 60, secure);
+            ([(SET_COOKIE, cookie)], Redirect::to(&state.public_url().link(routes::ADMIN))).into_response()
+        },
+        None => {
+            debug!("Admin dashboard challenge for {} failed verification", user);
+            (StatusCode::UNAUTHORIZED, sign_in_form(&state, Some("The signature didn't match, or the challenge ran out. Try again."))).into_response()
+        }
+    }
+}
+
+// the kill buttons, the same as deleting the token through the API
+pub async fn kill(State(state): State<AppState>, headers: HeaderMap, Form(params): Form<HashMap<String, String>>) -> Result<Redirect, (StatusCode, Markup)> {
+    let admin = authorize(&state, &headers).await?;
+    let token = match params.get("token") {
+        Some(token) => token,
+        None => return Err((StatusCode::BAD_REQUEST, html! {"token is required"}))
+    };
+    match state.force_delete(token).await {
+        Some(_) => info!("Token {} killed from the dashboard by {}", token, admin),
+        None => debug!("Token {} was already gone when {} killed it", token, admin)
+    }
+    Ok(Redirect::to(&state.public_url().link(routes::ADMIN)))
+}
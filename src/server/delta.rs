@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use axum::{body::Bytes, extract::{Path, Query, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}, Form};
+use maud::{html, Markup};
+use tracing::debug;
+
+use crate::utils::metadata::Delta;
+
+use super::{appstate::AppState, eventlog::TokenEvent, forwarded::Requester, server::download};
+
+// a 100GB image's checksums come to about 20MB, anything past this isn't checksums
+const MAX_SIGNATURES: usize = 64 * 1024 * 1024;
+
+// the relay passes the downloader's block checksums to the uploader and carries the delta back, so it sees a checksum of
+// every block of the old copy and the new file's changed bytes, just not the blocks both sides already have
+// POST key from the uploader to ask for them, PUT them from the downloader, GET with the key for the uploader to take them
+pub async fn request(State(state): State<AppState>, Path(token): Path<String>, Form(params): Form<HashMap<String, String>>) -> Result<StatusCode, (StatusCode, Markup)> {
+    let meta = match state.get_file_metadata(&token).await {
+        Some(meta) => meta,
+        None => return Err((StatusCode::NOT_FOUND, html! {"File not found"}))
+    };
+    if !params.get("key").is_some_and(|key| meta.check_key(key)) {
+        return Err((StatusCode::FORBIDDEN, html! {"File has a different key"}));
+    }
+    // a delta is against one downloader's copy, and a stored upload is sent before anyone has said what they have
+    if meta.is_broadcast() || meta.is_stored() {
+        return Err((StatusCode::CONFLICT, html! {"Broadcast and stored uploads are always sent whole"}));
+    }
+    match state.request_delta(&token).await {
+        Some(_) => {
+            debug!("Uploader of {} is waiting for the downloader's block checksums", token);
+            Ok(StatusCode::NO_CONTENT)
+        },
+        None => Err((StatusCode::CONFLICT, html! {"The transfer has already started, or a delta was already asked for"}))
+    }
+}
+
+pub async fn offer(State(state): State<AppState>, Path(token): Path<String>, body: Bytes) -> Result<StatusCode, (StatusCode, Markup)> {
+    if body.len() > MAX_SIGNATURES {
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, html! {"Too many block checksums"}));
+    }
+    let declined = body.is_empty();
+    let bytes = body.len();
+    match state.offer_delta(&token, body.to_vec()).await {
+        true => {
+            match declined {
+                true => state.log_event(&token, TokenEvent::DeltaDeclined).await,
+                false => state.log_event(&token, TokenEvent::DeltaOffered { bytes }).await
+            }
+            Ok(StatusCode::NO_CONTENT)
+        },
+        false => Err((StatusCode::CONFLICT, html! {"The uploader isn't waiting for block checksums"}))
+    }
+}
+
+// 202 while the downloader hasn't answered, 204 once it has nothing to compare against
+pub async fn take(State(state): State<AppState>, Path(token): Path<String>, requester: Requester, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Result<Response, (StatusCode, Markup)> {
+    let key = match params.get("key") {
+        Some(key) => key.clone(),
+        None => { // without a key this is just a download of a file named "delta"
+            return Ok(download(State(state), Path((token, "delta".to_string())), requester, headers, Query(params)).await.into_response());
+        }
+    };
+    let meta = match state.get_file_metadata(&token).await {
+        Some(meta) => meta,
+        None => return Err((StatusCode::NOT_FOUND, html! {"File not found"}))
+    };
+    if !meta.check_key(&key) {
+        return Err((StatusCode::FORBIDDEN, html! {"File has a different key"}));
+    }
+    match meta.get_delta() {
+        Delta::Requested => Ok(StatusCode::ACCEPTED.into_response()),
+        Delta::Declined => Ok(StatusCode::NO_CONTENT.into_response()),
+        _ => match state.take_delta(&token).await {
+            Some(signatures) => Ok(signatures.into_response()),
+            None => Err((StatusCode::CONFLICT, html! {"There are no block checksums to take"}))
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+// everything that happens to a token, so a failed transfer can be pieced back together
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TokenEvent {
+    Created { file_name: String },
+    Upgraded { user: String },
+    UploadStarted,
+    Uploaded { bytes: usize },
+    UploadComplete { bytes: usize },
+    DownloadStarted,
+    Downloaded { bytes: usize },
+    DownloadComplete { bytes: usize },
+    DownloadPaused { bytes: usize },
+    PeerOffered { addresses: usize },
+    PeerFailed,
+    DeltaOffered { bytes: usize },
+    DeltaDeclined,
+    Fetching { host: String },
+    Error { message: String },
+}
+
+impl TokenEvent {
+    // the same names as the serialized event, so log lines and /log entries can be matched up
+    pub fn phase(&self) -> &'static str {
+        match self {
+            TokenEvent::Created { .. } => "created",
+            TokenEvent::Upgraded { .. } => "upgraded",
+            TokenEvent::UploadStarted => "upload_started",
+            TokenEvent::Uploaded { .. } => "uploaded",
+            TokenEvent::UploadComplete { .. } => "upload_complete",
+            TokenEvent::DownloadStarted => "download_started",
+            TokenEvent::Downloaded { .. } => "downloaded",
+            TokenEvent::DownloadComplete { .. } => "download_complete",
+            TokenEvent::DownloadPaused { .. } => "download_paused",
+            TokenEvent::PeerOffered { .. } => "peer_offered",
+            TokenEvent::PeerFailed => "peer_failed",
+            TokenEvent::DeltaOffered { .. } => "delta_offered",
+            TokenEvent::DeltaDeclined => "delta_declined",
+            TokenEvent::Fetching { .. } => "fetching",
+            TokenEvent::Error { .. } => "error",
+        }
+    }
+
+    pub fn bytes(&self) -> Option<usize> {
+        match self {
+            TokenEvent::Uploaded { bytes } | TokenEvent::UploadComplete { bytes } | TokenEvent::Downloaded { bytes } | TokenEvent::DownloadComplete { bytes } | TokenEvent::DownloadPaused { bytes } => Some(*bytes),
+            _ => None
+        }
+    }
+
+    // progress milestones come often enough on big transfers to be left out at the default level
+    pub fn is_progress(&self) -> bool {
+        matches!(self, TokenEvent::Uploaded { .. } | TokenEvent::Downloaded { .. })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LoggedEvent {
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    event: TokenEvent,
+}
+
+impl LoggedEvent {
+    pub fn new(event: TokenEvent) -> Self {
+        LoggedEvent {
+            time: Utc::now(),
+            event
+        }
+    }
+
+    pub fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+
+    pub fn event(&self) -> &TokenEvent {
+        &self.event
+    }
+}
+
+// relayed bytes are logged each time they cross a power of two starting at 1MiB, so big transfers don't flood the log
+pub fn crossed_milestone(before: usize, after: usize) -> Option<usize> {
+    let mut milestone = 1024 * 1024;
+    let mut crossed = None;
+    while milestone <= after {
+        if milestone > before {
+            crossed = Some(milestone);
+        }
+        milestone = milestone.checked_mul(2)?;
+    }
+    crossed
+}
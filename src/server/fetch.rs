@@ -0,0 +1,213 @@
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr, SocketAddr}, time::{Duration, Instant}};
+use axum::{extract::{Path, Query, State}, http::{HeaderMap, StatusCode}, response::IntoResponse, Form, Json};
+use maud::{html, Markup};
+use reqwest::{header::LOCATION, redirect::Policy, Response};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::utils::metadata::FileMetadata;
+
+use super::{appstate::AppState, eventlog::TokenEvent, forwarded::Requester, server::{download, safe_file_name, send_body}};
+
+const MAX_REDIRECTS: usize = 5; // release downloads usually go through one or two on their way to a cdn
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(60); // nothing coming through the body for this long is a dead source, not a slow downloader
+
+// POST key and url (and optionally file-name and message) to have the relay download the file and send it on itself.
+// it answers as soon as the url has, the rest goes to the downloader like any other upload
+pub async fn fetch(State(state): State<AppState>, Path(token): Path<String>, requester: Requester, Form(params): Form<HashMap<String, String>>) -> Result<(StatusCode, Json<FileMetadata>), (StatusCode, Markup)> {
+    let meta = match state.get_file_metadata(&token).await {
+        Some(meta) => meta,
+        None => return Err((StatusCode::NOT_FOUND, html! {"File not found"}))
+    };
+    let key = match params.get("key") {
+        Some(key) => key,
+        None => return Err((StatusCode::BAD_REQUEST, html! {"key is required"}))
+    };
+    if !meta.check_key(key) {
+        return Err((StatusCode::FORBIDDEN, html! {"File has a different key"}));
+    }
+    // otherwise anyone could use the relay to download things for them, or to poke at whatever it can reach
+    if !meta.authenticated() {
+        return Err((StatusCode::UNAUTHORIZED, html! {"Only authenticated uploads can have the relay fetch a url"}));
+    }
+    if meta.upload_locked() {
+        return Err((StatusCode::CONFLICT, html! {"File is already locked for upload"}));
+    }
+
+    let url = match params.get("url").map(|url| Url::parse(url.trim())) {
+        Some(Ok(url)) if url.scheme() == "http" || url.scheme() == "https" => url,
+        Some(Ok(_)) => return Err((StatusCode::BAD_REQUEST, html! {"Only http and https urls can be fetched"})),
+        Some(Err(e)) => return Err((StatusCode::BAD_REQUEST, html! {"Invalid url: " (e)})),
+        None => return Err((StatusCode::BAD_REQUEST, html! {"url is required"}))
+    };
+
+    let response = match open(url, state.can_fetch_private()).await {
+        Ok(response) => response,
+        Err(e) => {
+            debug!("Could not fetch for {}: {}", token, e);
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, html! {"Could not fetch the url: " (e)})); // not a 502, clients retry those as a proxy hiccup
+        }
+    };
+    if !response.status().is_success() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, html! {"The url answered " (response.status())}));
+    }
+
+    let (upload, upload_options) = match state.begin_upload(&token, key, &requester.address).await {
+        Ok(res) => res,
+        Err((status, message)) => return Err((status, html! {(message)}))
+    };
+    let block_size = upload_options.get_block_size();
+    let throttle = upload_options.get_throttle();
+    info!("Upload to {} started by {}, fetching {}", token, requester.address, response.url());
+
+    // the url only names the file when nothing better was asked for
+    let file_name = params.get("file-name").and_then(|name| safe_file_name(name))
+        .or(response.url().path_segments().and_then(|mut segments| segments.next_back()).and_then(|name| urlencoding::decode(name).ok()).and_then(|name| safe_file_name(&name)));
+    state.set_metadata(&token, file_name, response.content_length().map(|size| size as usize), None, None).await;
+    if let Some(message) = params.get("message").map(|message| message.trim()).filter(|message| !message.is_empty()) {
+        state.set_message(&token, message.to_string()).await;
+    }
+    state.log_event(&token, TokenEvent::Fetching { host: response.url().host_str().unwrap_or_default().to_string() }).await;
+
+    let meta = state.get_file_metadata(&token).await.unwrap_or(meta);
+    let fetching = state.clone();
+    tokio::spawn(async move {
+        let body = response.bytes_stream();
+        let result = send_body(&fetching, &token, upload, block_size, throttle, Instant::now(), None, body).await;
+        debug!("Fetch for {} finished with {}", token, result.status());
+    });
+    Ok((StatusCode::ACCEPTED, Json(meta)))
+}
+
+// fetches are only ever POSTed, so a GET here is a download of a file named "fetch"
+pub async fn download_named(State(state): State<AppState>, Path(token): Path<String>, requester: Requester, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> axum::response::Response {
+    download(State(state), Path((token, "fetch".to_string())), requester, headers, Query(params)).await.into_response()
+}
+
+// redirects are followed by hand so every hop gets the same check, and the connection goes to the address that was checked
+async fn open(mut url: Url, private: bool) -> Result<Response, String> {
+    for _ in 0..=MAX_REDIRECTS {
+        let host = url.host_str().ok_or("the url has no host")?.to_string();
+        let port = url.port_or_known_default().ok_or("the url has no port")?;
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_matches(|c| c == '[' || c == ']'), port)).await
+            .map_err(|e| format!("could not look up {}: {}", host, e))?
+            .collect();
+        if addresses.is_empty() || !(private || addresses.iter().all(|address| is_public(address.ip()))) {
+            warn!("Refusing to fetch {}, it isn't a public address", host);
+            return Err(format!("{} isn't a public address", host));
+        }
+
+        // a proxy from the relay's environment would look the host up again itself, past the check
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .redirect(Policy::none())
+            .resolve_to_addrs(&host, &addresses)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .read_timeout(READ_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let response = client.get(url.clone()).send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_redirection() {
+            return Ok(response);
+        }
+        let location = response.headers().get(LOCATION).and_then(|location| location.to_str().ok()).ok_or("a redirect with nowhere to go")?;
+        url = url.join(location).map_err(|e| format!("a redirect to an invalid url: {}", e))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err("a redirect away from http".to_string());
+        }
+        debug!("Fetch redirected to {}", url);
+    }
+    Err("too many redirects".to_string())
+}
+
+// loopback, private, and link local addresses are the relay's own network, which nobody outside should get at through it
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_broadcast() || ip.is_multicast()
+            || ip.octets()[0] == 0 // "this network", which linux connects to as if it were the relay itself
+            || ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64 // carrier grade nat
+            || ip.octets()[0] == 198 && ip.octets()[1] & 0xfe == 18 // benchmarking, which some networks hand out internally
+            || ip.octets()[0] >= 240), // reserved, and the broadcast address at the end of it
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            match (ip.to_ipv4_mapped(), segments[0]) {
+                (Some(mapped), _) => is_public(IpAddr::V4(mapped)),
+                (None, 0x2002) => is_public(IpAddr::V4(Ipv4Addr::from(((segments[1] as u32) << 16) | segments[2] as u32))), // 6to4 ends up at the ipv4 address inside it
+                (None, _) => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                    || segments[..6] == [0; 6] // ipv4 compatible, some stacks still reach the ipv4 address on the end
+                    || segments[0] & 0xfe00 == 0xfc00 // unique local
+                    || segments[0] & 0xffc0 == 0xfe80 // link local
+                    || segments[0] & 0xffc0 == 0xfec0 // site local, deprecated but still routed inside some networks
+                    || segments[..2] == [0x2001, 0] // teredo, which tunnels to an ipv4 address that can't be checked from here
+                    || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]) // nat64, a gateway turns it into whatever ipv4 address is on the end
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn this_network_is_not_public() {
+        assert!(!public("0.0.0.0"));
+        assert!(!public("0.1.2.3"));
+        assert!(!public("::ffff:0.1.2.3"));
+        assert!(public("1.0.0.1"));
+    }
+
+    #[test]
+    fn benchmarking_is_not_public() {
+        assert!(!public("198.18.0.1"));
+        assert!(!public("198.19.255.254"));
+        assert!(public("198.17.255.255"));
+        assert!(public("198.20.0.1"));
+    }
+
+    #[test]
+    fn nat64_is_not_public() {
+        assert!(!public("64:ff9b::7f00:1")); // 127.0.0.1 on the other side of the gateway
+        assert!(!public("64:ff9b::808:808"));
+        assert!(public("2606:4700:4700::1111"));
+    }
+
+    #[test]
+    fn ipv4_compatible_is_not_public() {
+        assert!(!public("::7f00:1"));
+        assert!(!public("::8.8.8.8"));
+    }
+
+    #[test]
+    fn six_to_four_is_as_public_as_what_it_wraps() {
+        assert!(!public("2002:7f00:1::1")); // 127.0.0.1
+        assert!(!public("2002:c0a8:101::1")); // 192.168.1.1
+        assert!(public("2002:808:808::1")); // 8.8.8.8
+    }
+
+    #[test]
+    fn teredo_is_not_public() {
+        assert!(!public("2001::1"));
+        assert!(!public("2001:0:4136:e378:8000:63bf:3fff:fdd2"));
+        assert!(public("2001:4860:4860::8888")); // only 2001::/32, the rest of 2001 is ordinary addresses
+    }
+
+    #[test]
+    fn site_local_is_not_public() {
+        assert!(!public("fec0::1"));
+        assert!(!public("feff::1"));
+    }
+
+    #[test]
+    fn reserved_ipv4_is_not_public() {
+        assert!(!public("240.0.0.1"));
+        assert!(!public("255.255.255.254"));
+        assert!(!public("::ffff:250.1.2.3"));
+        assert!(public("223.255.255.254"));
+    }
+}
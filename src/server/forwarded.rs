@@ -0,0 +1,111 @@
+use std::{convert::Infallible, net::SocketAddr};
+use axum::{extract::{ConnectInfo, FromRequestParts}, http::{header::HOST, request::Parts, HeaderMap}};
+
+use super::appstate::AppState;
+
+// how the relay looks from outside, which behind a reverse proxy isn't the address it listens on
+#[derive(Debug, Clone)]
+pub struct PublicUrl {
+    base_path: String, // empty, or like "/beam" without a trailing slash
+    trust_forwarded: bool,
+    https: bool,
+}
+
+impl PublicUrl {
+    pub fn new(base_path: Option<String>, trust_forwarded: bool, https: bool) -> Self {
+        let base_path = match base_path.as_deref().map(|path| path.trim_matches('/')) {
+            Some(path) if !path.is_empty() => format!("/{path}"),
+            _ => String::new()
+        };
+        PublicUrl { base_path, trust_forwarded, https }
+    }
+
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    // a path on the relay as the browser has to ask for it
+    pub fn link(&self, path: &str) -> String {
+        format!("{}{}", self.base_path, path)
+    }
+
+    // whether the browser reached the relay over https, directly or through a trusted proxy, so cookies can be kept off plain http
+    pub fn secure(&self, headers: &HeaderMap) -> bool {
+        self.https || self.forwarded(headers, "x-forwarded-proto") == Some("https")
+    }
+
+    // only the trusted proxy's entry counts, a proxy that appends leaves whatever the client sent in front of it
+    fn forwarded<'a>(&self, headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+        if !self.trust_forwarded {
+            return None;
+        }
+        headers.get(name).and_then(|value| value.to_str().ok()).and_then(|value| value.rsplit(',').next()).map(|value| value.trim())
+    }
+
+    // the scheme and host the user reached the relay with
+    fn origin(&self, parts: &Parts) -> String {
+        let scheme = self.forwarded(&parts.headers, "x-forwarded-proto").unwrap_or(match self.https {
+            true => "https",
+            false => "http"
+        });
+        let host = self.forwarded(&parts.headers, "x-forwarded-host")
+            .or(parts.headers.get(HOST).and_then(|host| host.to_str().ok()))
+            .or(parts.uri.authority().map(|authority| authority.as_str())) // http/2 has no Host header
+            .unwrap_or("localhost");
+        format!("{scheme}://{host}")
+    }
+
+    // the last X-Forwarded-For entry is the one the trusted proxy added, earlier ones came from the client
+    fn address(&self, parts: &Parts) -> String {
+        let forwarded = match self.trust_forwarded {
+            true => parts.headers.get("x-forwarded-for").and_then(|value| value.to_str().ok()).and_then(|value| value.rsplit(',').next()).map(|value| value.trim().to_string()),
+            false => None
+        };
+        match (forwarded, parts.extensions.get::<ConnectInfo<SocketAddr>>()) {
+            (Some(address), _) => address,
+            (None, Some(ConnectInfo(address))) => address.ip().to_string(),
+            (None, None) => "unknown".to_string()
+        }
+    }
+}
+
+// who is asking, and where they think the relay is
+pub struct Requester {
+    pub origin: String,
+    pub address: String,
+}
+
+impl Requester {
+    // the full url of a path on the relay, for when it's shown to be copied somewhere else
+    pub fn url(&self, state: &AppState, path: &str) -> String {
+        format!("{}{}", self.origin, state.public_url().link(path))
+    }
+}
+
+impl FromRequestParts<AppState> for Requester {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let public_url = state.public_url();
+        Ok(Requester { origin: public_url.origin(parts), address: public_url.address(parts) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    #[test]
+    fn the_proxys_own_entries_win() {
+        let (parts, _) = Request::builder()
+            .header("x-forwarded-proto", "https, http")
+            .header("x-forwarded-host", "evil.example, beam.example")
+            .header("x-forwarded-for", "10.0.0.1, 192.0.2.7")
+            .body(()).unwrap().into_parts();
+        let public_url = PublicUrl::new(None, true, false);
+        assert_eq!(public_url.origin(&parts), "http://beam.example");
+        assert_eq!(public_url.address(&parts), "192.0.2.7");
+        assert!(!public_url.secure(&parts.headers));
+    }
+}
@@ -0,0 +1,216 @@
+use std::{collections::VecDeque, fmt, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use axum::{body::Body, http::{HeaderMap, HeaderValue, Response, StatusCode}, response::IntoResponse};
+use bytes::{BufMut, Bytes, BytesMut};
+use reqwest::header::CONTENT_LENGTH;
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::utils::{checksum::{Checksum, Hasher}, digest::DigestWorker, frames::{frame, Deframer, Frame, FRAMES, FRAMES_HEADER, MAX_FRAME, RESEND_HEADER, RESUME_HEADER}};
+
+use super::appstate::AppState;
+
+// how much of a download is kept to send again, far more than is ever between the relay and the downloader
+const RESEND_WINDOW: usize = 8 * 1024 * 1024;
+// the downloader may still be checking the last frames once the relay has sent them
+const RESEND_LINGER: Duration = Duration::from_secs(60);
+// how long the downloader is kept waiting for the rest of a damaged upload
+pub const RESUME_WAIT: Duration = Duration::from_secs(60);
+
+pub type Verifier = Option<(Checksum, DigestWorker<Hasher>)>;
+
+// the last frames of a download, by where they start
+#[derive(Debug)]
+pub struct ResendWindow {
+    key: String,
+    sent: VecDeque<(u64, Bytes)>,
+    offset: u64,
+    kept: usize,
+}
+
+impl ResendWindow {
+    fn push(&mut self, data: Bytes) {
+        self.kept += data.len();
+        let offset = self.offset;
+        self.offset += data.len() as u64;
+        self.sent.push_back((offset, data));
+        while self.kept > RESEND_WINDOW {
+            match self.sent.pop_front() {
+                Some((_, dropped)) => self.kept -= dropped.len(),
+                None => break
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str, offset: u64) -> Option<Bytes> {
+        if key != self.key {
+            return None;
+        }
+        self.sent.iter().find(|(start, _)| *start == offset).map(|(_, data)| data.clone())
+    }
+
+    pub fn is(&self, key: &str) -> bool {
+        self.key == key
+    }
+}
+
+// held by the download as it streams, the frames stay around for a bit after it's done with them
+pub struct Resends {
+    state: AppState,
+    token: String,
+    window: Arc<Mutex<ResendWindow>>,
+    key: String,
+}
+
+impl Resends {
+    fn frame(&self, data: Vec<u8>) -> Bytes {
+        let data = Bytes::from(data);
+        let mut framed = BytesMut::with_capacity(data.len() + 8);
+        let mut window = self.window.lock().unwrap();
+        for start in (0..data.len()).step_by(MAX_FRAME) {
+            let piece = data.slice(start..(start + MAX_FRAME).min(data.len()));
+            framed.put(frame(&piece));
+            window.push(piece);
+        }
+        framed.freeze()
+    }
+
+    pub fn key(&self) -> &String {
+        &self.key
+    }
+}
+
+impl Drop for Resends {
+    fn drop(&mut self) {
+        let (state, token, key) = (self.state.clone(), self.token.clone(), self.key.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(RESEND_LINGER).await;
+            state.close_resends(&token, &key).await;
+        });
+    }
+}
+
+// only a downloader that asked gets frames. beam down undoes compression itself, so the frames go around the compressed bytes
+pub async fn resends(state: &AppState, token: &str, headers: &HeaderMap) -> Option<Resends> {
+    if headers.get(FRAMES_HEADER).is_none_or(|frames| frames != FRAMES) {
+        return None;
+    }
+    let key = Uuid::new_v4().simple().to_string();
+    let window = Arc::new(Mutex::new(ResendWindow { key: key.clone(), sent: VecDeque::new(), offset: 0, kept: 0 }));
+    state.open_resends(token, window.clone()).await;
+    Some(Resends { state: state.clone(), token: token.to_string(), window, key })
+}
+
+// the data as it goes out to the downloader
+pub fn framed(resends: &Option<Resends>, data: Vec<u8>) -> Bytes {
+    match resends {
+        Some(resends) => resends.frame(data),
+        None => Bytes::from(data)
+    }
+}
+
+// a framed download is longer than the file, and says what to ask for when a frame comes through damaged
+pub fn headers(resends: Option<&String>, headers: &mut HeaderMap) {
+    if let Some(key) = resends.and_then(|key| HeaderValue::from_str(key).ok()) {
+        headers.remove(CONTENT_LENGTH);
+        headers.insert(FRAMES_HEADER, HeaderValue::from_static(FRAMES));
+        headers.insert(RESEND_HEADER, key);
+    }
+}
+
+// GET /{token}/{name}?resend=[key]&offset=[where the damaged frame started], the frame as it was first sent
+pub async fn resend(state: &AppState, token: &String, key: &str, offset: Option<&String>) -> Response<Body> {
+    let offset = match offset.and_then(|offset| offset.parse::<u64>().ok()) {
+        Some(offset) => offset,
+        None => return (StatusCode::BAD_REQUEST, "Say which frame with offset").into_response()
+    };
+    match state.resend(token, key, offset).await {
+        Some(data) => {
+            debug!("Sending the frame at {} of {} again", offset, token);
+            frame(&data).into_response()
+        },
+        None => (StatusCode::GONE, "That part of the download is no longer kept").into_response()
+    }
+}
+
+// what a damaged upload leaves behind for when the uploader sends the rest
+pub struct Parked {
+    pub upload: Sender<Vec<u8>>, // what the upload was going into, a store or spill file is carried on rather than started again
+    pub verifier: Verifier,
+    pub since: Instant,
+    pub resumed: bool, // an upload has taken it over, so nothing else can
+}
+
+impl fmt::Debug for Parked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Parked").field("since", &self.since).field("resumed", &self.resumed).finish()
+    }
+}
+
+// the upload as it comes in, less whatever the relay already had from an earlier try
+pub struct Incoming {
+    deframer: Option<Deframer>,
+    skip: usize,
+    damaged: bool,
+}
+
+impl Incoming {
+    // everything up to a damaged frame, which stops it
+    pub fn data(&mut self, chunk: Bytes) -> Bytes {
+        let chunk = match &mut self.deframer {
+            Some(deframer) => {
+                deframer.push(&chunk);
+                let mut data = BytesMut::new();
+                for frame in deframer.by_ref() {
+                    match frame {
+                        Frame::Data(frame) => data.put(frame),
+                        Frame::Damaged(_) => {
+                            self.damaged = true;
+                            break;
+                        }
+                    }
+                }
+                data.freeze()
+            },
+            None => chunk
+        };
+        let skipped = self.skip.min(chunk.len());
+        self.skip -= skipped;
+        chunk.slice(skipped..)
+    }
+
+    pub fn is_damaged(&self) -> bool {
+        self.damaged
+    }
+
+    // a frame cut short at the end is as damaged as any other
+    pub fn finish(&mut self) -> bool {
+        !self.damaged && self.deframer.as_mut().is_none_or(|deframer| deframer.finish())
+    }
+}
+
+// an upload that comes after a damaged one carries on from what the downloader already has, anything before that is dropped
+pub async fn resume(state: &AppState, token: &String, verifier: Verifier, framed: bool, from: usize, received: usize) -> Result<(Verifier, Incoming), Response<Body>> {
+    match state.take_parked(token).await {
+        Some(parked) if from <= received => {
+            debug!("Upload to {} carries on from {}, {} of it is already there", token, from, received);
+            Ok((parked.verifier, Incoming { deframer: framed.then(Deframer::default), skip: received - from, damaged: false }))
+        },
+        Some(_) => {
+            state.abort_upload(token, format!("The upload was sent again from {} but the relay only had {}", from, received)).await;
+            Err((StatusCode::CONFLICT, [(RESUME_HEADER, received.to_string())], "The upload was sent again from past what the relay has").into_response())
+        },
+        None => Ok((verifier, Incoming { deframer: framed.then(Deframer::default), skip: 0, damaged: false }))
+    }
+}
+
+// a damaged frame never reaches the downloader. everything before it goes on, and the downloader waits for the uploader to send the rest again
+pub async fn park(state: &AppState, token: &String, upload: &Sender<Vec<u8>>, buffer: BytesMut, verifier: Verifier, received: usize) -> Response<Body> {
+    if !buffer.is_empty() && upload.send(buffer.to_vec()).await.is_err() { // an empty one would end the download
+        state.abort_upload(token, "Downloader went away during the upload".to_string()).await;
+        return "Failed to send a chunk... upload may have failed".into_response();
+    }
+    warn!("Upload to {} had a damaged frame after {} bytes, waiting for it to be sent again", token, received);
+    state.park_upload(token, upload.clone(), verifier).await;
+    (StatusCode::UNPROCESSABLE_ENTITY, [(RESUME_HEADER, received.to_string())], format!("A frame was damaged on the way, send the upload again from {}", received)).into_response()
+}
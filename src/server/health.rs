@@ -0,0 +1,51 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::{appstate::AppState, routes};
+
+// what load balancers and uptime monitors get, without having to make a token to find out
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Health {
+    status: &'static str, // "ok", or for /readyz why the relay shouldn't be sent new transfers
+    version: &'static str,
+    uptime: u64, // seconds
+    active_transfers: usize,
+    draining: bool,
+    read_only: bool,
+    keyserver: Option<bool>, // null without a keyserver or before it was first asked, otherwise whether it answered last time
+}
+
+async fn health(state: &AppState, status: &'static str) -> Health {
+    Health {
+        status,
+        version: env!("CARGO_PKG_VERSION"),
+        uptime: state.uptime().as_secs(),
+        active_transfers: state.active_transfers().await,
+        draining: state.is_draining(),
+        read_only: state.is_read_only(),
+        keyserver: state.keyserver_reachable(),
+    }
+}
+
+// the relay is up and answering, a monitor restarting it for anything else would cut off the transfers still going
+#[utoipa::path(get, path = routes::HEALTHZ, tag = "relay", responses((status = 200, body = Health)))]
+pub async fn healthz(State(state): State<AppState>) -> Json<Health> {
+    Json(health(&state, "ok").await)
+}
+
+// 503 while draining or while the keyserver can't be reached, so a balancer sends new transfers to another relay.
+// read-only relays stay ready, they still serve downloads
+#[utoipa::path(get, path = routes::READYZ, tag = "relay", responses((status = 200, body = Health), (status = 503, description = "Draining, or the keyserver is down", body = Health)))]
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Health>) {
+    let status = match (state.is_draining(), state.keyserver_reachable()) {
+        (true, _) => "draining",
+        (false, Some(false)) => "keyserver unreachable",
+        (false, _) => "ok"
+    };
+    let code = match status {
+        "ok" => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(health(&state, status).await))
+}
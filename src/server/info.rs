@@ -0,0 +1,36 @@
+use axum::{extract::State, Json};
+
+use crate::utils::{compression::Compression, info::ServerInfo};
+
+use super::{appstate::AppState, routes, server::MAX_BODY_SIZE};
+
+#[utoipa::path(get, path = routes::INFO, tag = "relay", responses((status = 200, body = ServerInfo)))]
+pub async fn info(State(state): State<AppState>) -> Json<ServerInfo> {
+    // always there, whatever the config says
+    let mut features: Vec<&str> = vec!["bundle", "chunked", "delta", "events", "fetch", "healthz", "peer", "receiver", "snippets", "token-name", "websocket"];
+    if state.can_store() {
+        features.push("store");
+    }
+    if state.transcodes() {
+        features.push("transcode");
+    }
+    if state.web_uploader().is_some() {
+        features.push("web-uploader");
+    }
+    if state.is_read_only() {
+        features.push("read-only");
+    }
+    if state.is_draining() {
+        features.push("draining");
+    }
+    if state.reflector().is_some() {
+        features.push("punch");
+    }
+    Json(ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        compression: vec![Compression::Gzip, Compression::Deflate, Compression::Brotli, Compression::Lz4, Compression::Zstd],
+        max_body_size: MAX_BODY_SIZE,
+        features: features.into_iter().map(String::from).collect(),
+        reflector: state.reflector(),
+    })
+}
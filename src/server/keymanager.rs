@@ -1,54 +1,145 @@
-use std::collections::HashMap;
-use ssh_key::{PublicKey, SshSig};
+use std::{collections::HashMap, sync::{atomic::{AtomicU8, Ordering}, Arc, Mutex, RwLock}, time::{Duration, Instant}};
+use ssh_key::{certificate::CertType, Algorithm, Certificate, Fingerprint, HashAlg, PublicKey, SshSig};
 use tracing::{debug, error, warn};
 
+const SK_TRAILER: usize = 5; // a security key's signature is followed by its flags and a counter
+const SK_USER_PRESENT: u8 = 0x01;
+const SIGNATURE_END: &str = "-----END SSH SIGNATURE-----"; // a certificate for the key can follow it
+pub const DEFAULT_KEYSERVER_TTL: Duration = Duration::from_secs(60 * 60);
+const MIN_REFETCH: Duration = Duration::from_secs(60); // a signature nothing verifies asks the keyserver again, but not more often than this
+const KEYSERVER_TIMEOUT: Duration = Duration::from_secs(10);
+// how the last request to the keyserver went, for the health checks
+const KEYSERVER_UNKNOWN: u8 = 0;
+const KEYSERVER_UP: u8 = 1;
+const KEYSERVER_DOWN: u8 = 2;
+
 // this handles all signing operations
 #[derive(Debug, Clone)]
 pub struct KeyManager {
     keyserver: Option<String>, // for example. github does https://github.com/username.keys
-    users: HashMap<String, Vec<PublicKey>> // allowed users, and all of their keys. If no keyserver, this comes from a config
+    users: Arc<RwLock<HashMap<String, Vec<PublicKey>>>>, // allowed users, and all of their keys. If no keyserver, this comes from a config
+    fetched: Arc<Mutex<HashMap<String, Instant>>>, // users whose keys come from the keyserver, and when it was last asked for them
+    authorities: Vec<Fingerprint>, // ssh CAs whose user certificates are trusted for their principals
+    reachable: Arc<AtomicU8>, // one of the KEYSERVER_ states
 }
 
 impl KeyManager {
-    pub async fn new_checking_keyserver(keyserver: Option<String>, users: Vec<String>) -> Self {
+    pub async fn new_checking_keyserver(keyserver: Option<String>, users: Vec<String>, user_ca: Vec<String>, ttl: Duration) -> Self {
         let mut km = KeyManager {
             keyserver,
-            users: HashMap::new(),
+            users: Arc::new(RwLock::new(HashMap::new())),
+            fetched: Arc::new(Mutex::new(HashMap::new())),
+            authorities: vec![],
+            reachable: Arc::new(AtomicU8::new(KEYSERVER_UNKNOWN)),
         };
 
+        for ca in user_ca {
+            match PublicKey::from_openssh(&ca) {
+                Ok(key) => {
+                    debug!("Trusting user certificates from CA {}", key.fingerprint(HashAlg::Sha256));
+                    km.authorities.push(key.fingerprint(HashAlg::Sha256));
+                },
+                Err(e) => error!("Could not parse user CA key {}: {:?}", ca, e)
+            }
+        }
+
         // we need to see if "users" is a list of SSH keys or simply just a list of usernames which we ask the keyserver for
         // users can exist as SSH keys, using the keyserver by no means says you cannot also have hardcoded user keys
         for user in users {
             match PublicKey::from_openssh(&user) {
                 Ok(key) => {
                     debug!("User provided has SSH key {}", key.fingerprint(Default::default()));
-                    km.users.insert(user.clone(), vec![key]);
+                    km.users.write().unwrap().insert(user.clone(), vec![key]);
                 },
                 Err(_) => {
                     // ssh_key::authorized_keys
                     // if we can't parse the key, it's probably a username and we need to ask the keyserver for their keys
+                    if km.keyserver.is_none() {
+                        error!("{} isn't an ssh key, and there's no keyserver to look them up with", user);
+                        continue;
+                    }
                     debug!("Getting {}'s keys from keyserver", user);
-                    let response = km.get_keys_from_keyserver(&user).await;
-                    if let Some(key_response) = response {
-                        km.users.insert(user.clone(), key_response);
-                    } else {
+                    km.fetched.lock().unwrap().insert(user.clone(), Instant::now());
+                    if !km.fetch_user(&user).await {
                         error!("Failed to get keyserver keys!");
                     }
                 },
             }
         }
 
+        // keys people add to (or remove from) the keyserver show up without a restart
+        if !km.fetched.lock().unwrap().is_empty() {
+            let refreshing = km.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(ttl);
+                interval.tick().await; // the first tick is right away, and they were all just fetched
+                loop {
+                    interval.tick().await;
+                    refreshing.refresh().await;
+                }
+            });
+        }
+
         km
     }
 
-    async fn get_keys_from_keyserver(&self, name: &String) -> Option<Vec<PublicKey>> {
-        if self.keyserver.is_none() {
-            return None;
+    async fn refresh(&self) {
+        let names: Vec<String> = {
+            let mut fetched = self.fetched.lock().unwrap();
+            fetched.values_mut().for_each(|last| *last = Instant::now());
+            fetched.keys().cloned().collect()
+        };
+        debug!("Refreshing keys for {} users from the keyserver", names.len());
+        for name in names {
+            self.fetch_user(&name).await;
         }
-        let ks = self.keyserver.as_ref().unwrap();
+    }
+
+    // a keyserver that is down or answers with an error keeps the keys already known, it shouldn't lock everyone out
+    async fn fetch_user(&self, name: &String) -> bool {
+        match self.get_keys_from_keyserver(name).await {
+            Some(keys) => {
+                self.users.write().unwrap().insert(name.clone(), keys);
+                true
+            },
+            None => {
+                warn!("Could not get {}'s keys from the keyserver, keeping any already known", name);
+                false
+            }
+        }
+    }
+
+    // only users configured by name come from the keyserver, so a signature for anyone else can't make the relay go asking
+    async fn fetch_on_miss(&self, name: &String) -> bool {
+        {
+            let mut fetched = self.fetched.lock().unwrap();
+            match fetched.get_mut(name) {
+                Some(last) if last.elapsed() >= MIN_REFETCH => *last = Instant::now(),
+                _ => return false
+            }
+        }
+        debug!("None of {}'s known keys verified, asking the keyserver again", name);
+        self.fetch_user(name).await
+    }
+
+    async fn get_keys_from_keyserver(&self, name: &String) -> Option<Vec<PublicKey>> {
+        let ks = self.keyserver.as_ref()?;
         let url = ks.replace("{}", name);
         debug!("Checking key server at {} for user {}", url, name);
-        return match reqwest::get(url).await {
+        let client = match reqwest::Client::builder().timeout(KEYSERVER_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Could not build the keyserver client: {:?}", e);
+                return None;
+            }
+        };
+        let response = client.get(url).send().await;
+        // a user the keyserver doesn't know still means it answered, only not getting through at all counts as down
+        self.reachable.store(match &response {
+            Ok(response) if !response.status().is_server_error() => KEYSERVER_UP,
+            _ => KEYSERVER_DOWN
+        }, Ordering::Relaxed);
+        match response {
             Ok(response) => {
                 if response.status().is_success() {
                     let keys_str = match response.text().await {
@@ -75,16 +166,43 @@ impl KeyManager {
                 error!("Could not get data from keyserver: {:?}", e);
                 None
             }
-        };
+        }
     }
 
-    pub fn verify(&self, name: &String, challenge: &String, response: &String) -> bool {
-        let user_keys = match self.users.get(name) {
-            Some(keys) => keys,
-            None => return false,
+    // None when there's no keyserver, or it hasn't been needed yet
+    pub fn keyserver_reachable(&self) -> Option<bool> {
+        match (&self.keyserver, self.reachable.load(Ordering::Relaxed)) {
+            (None, _) | (_, KEYSERVER_UNKNOWN) => None,
+            (_, state) => Some(state == KEYSERVER_UP)
+        }
+    }
+
+    // users with keys listed in the config or fetched from the keyserver
+    pub fn has_user(&self, name: &String) -> bool {
+        self.users.read().unwrap().contains_key(name)
+    }
+
+    // with a CA, anyone could turn up with a certificate naming them, so nobody can be ruled out ahead of time. only verify says who they are
+    pub fn accepts_certificates(&self) -> bool {
+        !self.authorities.is_empty()
+    }
+
+    pub async fn verify_any(&self, name: &String, challenge: &String, responses: &Vec<String>) -> bool {
+        for response in responses {
+            if self.verify(name, challenge, response).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub async fn verify(&self, name: &String, challenge: &String, response: &str) -> bool {
+        let (armored, certificate) = match response.find(SIGNATURE_END) {
+            Some(end) => response.split_at(end + SIGNATURE_END.len()),
+            None => (response, "")
         };
 
-        let signature = match response.parse::<SshSig>() {
+        let signature = match armored.parse::<SshSig>() {
             Ok(s) => s,
             Err(e) => {
                 error!("Failed to parse SSH challenge: {:?}", e);
@@ -92,13 +210,84 @@ impl KeyManager {
             },
         };
 
+        // a certificate the relay doesn't trust still leaves the key itself, which could be listed for them
+        if !certificate.trim().is_empty() && self.verify_certified(name, challenge, &signature, certificate.trim()) {
+            return true;
+        }
+
+        // the key might have been added to the keyserver since it was last asked
+        self.verify_known(name, challenge, &signature) || (self.fetch_on_miss(name).await && self.verify_known(name, challenge, &signature))
+    }
+
+    fn verify_known(&self, name: &String, challenge: &String, signature: &SshSig) -> bool {
+        let users = self.users.read().unwrap();
+        let user_keys = match users.get(name) {
+            Some(keys) => keys,
+            None => return false,
+        };
+
         for key in user_keys {
-            match key.verify("bytebeam", challenge.as_bytes(), &signature) {
+            match key.verify("bytebeam", challenge.as_bytes(), signature) {
+                Ok(_) if !user_present(key, signature) => warn!("{} signed with a security key without touching it, not accepting that", name),
                 Ok(_) => return true, // we only need it to succeed once!
                 Err(e) => debug!("Failed to verify SSH key: {:?}", e)
             }
         }
 
-        return false;
+        false
     }
-}
\ No newline at end of file
+
+    fn verify_certified(&self, name: &String, challenge: &String, signature: &SshSig, certificate: &str) -> bool {
+        if self.authorities.is_empty() {
+            debug!("{} sent a certificate, but no user_ca is trusted", name);
+            return false;
+        }
+        let certificate = match Certificate::from_openssh(certificate) {
+            Ok(certificate) => certificate,
+            Err(e) => {
+                debug!("Failed to parse certificate from {}: {:?}", name, e);
+                return false;
+            }
+        };
+        if let Err(e) = certificate.validate(&self.authorities) {
+            debug!("Certificate {} for {} isn't from a trusted CA or isn't valid right now: {:?}", certificate.key_id(), name, e);
+            return false;
+        }
+        // validate leaves the rest to us, it has to be a user certificate naming them and without restrictions the relay can't enforce
+        if certificate.cert_type() != CertType::User || !certificate.valid_principals().contains(name) {
+            debug!("Certificate {} isn't a user certificate for {}", certificate.key_id(), name);
+            return false;
+        }
+        if !certificate.critical_options().is_empty() {
+            warn!("Certificate {} for {} has critical options, which the relay can't enforce, not accepting it", certificate.key_id(), name);
+            return false;
+        }
+
+        let key = PublicKey::from(certificate.public_key().clone());
+        match key.verify("bytebeam", challenge.as_bytes(), signature) {
+            Ok(_) if !user_present(&key, signature) => {
+                warn!("{} signed with a security key without touching it, not accepting that", name);
+                false
+            },
+            Ok(_) => {
+                debug!("{} signed in with certificate {}", name, certificate.key_id());
+                true
+            },
+            Err(e) => {
+                debug!("Failed to verify certified key: {:?}", e);
+                false
+            }
+        }
+    }
+}
+
+// like sshd, a security key only counts when it was touched for this signature, being plugged in isn't enough
+fn user_present(key: &PublicKey, signature: &SshSig) -> bool {
+    match key.algorithm() {
+        Algorithm::SkEd25519 | Algorithm::SkEcdsaSha2NistP256 => {
+            let bytes = signature.signature().as_bytes();
+            bytes.len() >= SK_TRAILER && bytes[bytes.len() - SK_TRAILER] & SK_USER_PRESENT != 0
+        },
+        _ => true
+    }
+}
@@ -0,0 +1,78 @@
+// follows the token's status events so the landing page shows the upload as it happens
+const live = document.getElementById("live");
+const status = document.getElementById("status");
+const progress = document.getElementById("progress");
+const size = document.getElementById("size");
+
+function bytes(n) {
+    const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let unit = 0;
+    while (n >= 1024 && unit < units.length - 1) {
+        n /= 1024;
+        unit++;
+    }
+    return n.toFixed(unit ? 1 : 0) + " " + units[unit];
+}
+
+const events = new EventSource(live.dataset.events);
+
+events.addEventListener("status", (e) => {
+    const meta = JSON.parse(e.data);
+    const sizes = meta.file_size;
+    // compressed uploads only have a size once they are done
+    const total = sizes.file_size_trustworthy && sizes.file_size ? sizes.file_size : (sizes.upload_complete ? sizes.uploaded_size : null);
+
+    if (total) {
+        progress.max = total;
+        progress.value = Math.min(sizes.uploaded_size, total);
+        size.textContent = bytes(total);
+    } else {
+        progress.removeAttribute("value"); // shown as busy instead of a made up percentage
+    }
+
+    if (meta.download === "Complete") {
+        status.textContent = "This file has already been downloaded.";
+        progress.hidden = true;
+        events.close();
+    } else if (meta.download === "InProgress") {
+        status.textContent = "The download has started.";
+    } else if (meta.upload === "NotStarted") {
+        status.textContent = "Waiting for the sender to start the upload. The download can be started now and will begin once they do.";
+    } else if (meta.upload === "InProgress") {
+        status.textContent = "The sender is uploading, " + bytes(sizes.uploaded_size) + (total ? " of " + bytes(total) : "") + " so far. Ready to download.";
+    } else {
+        status.textContent = "The upload is complete. Ready to download.";
+    }
+});
+
+events.addEventListener("cancelled", () => {
+    status.textContent = "This file is no longer available.";
+    progress.hidden = true;
+    events.close();
+});
+
+// snippets are shown on the page instead of downloaded, fetching them here is the download
+const text = document.getElementById("text");
+if (text) {
+    const content = document.getElementById("text-content");
+    const copy = document.getElementById("copy-text");
+    const textStatus = document.getElementById("text-status");
+
+    fetch(text.dataset.source)
+        .then((response) => response.ok ? response.text() : Promise.reject(new Error(response.status + " " + response.statusText)))
+        .then((body) => {
+            content.textContent = body;
+            content.hidden = false;
+            copy.hidden = false;
+            textStatus.hidden = true;
+        })
+        .catch((e) => {
+            textStatus.textContent = "Could not load the text: " + e.message;
+        });
+
+    copy.addEventListener("click", () => {
+        navigator.clipboard.writeText(content.textContent)
+            .then(() => { copy.textContent = "Copied"; })
+            .catch(() => { copy.textContent = "Could not copy, select the text instead"; });
+    });
+}
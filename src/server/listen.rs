@@ -0,0 +1,40 @@
+use std::net::{SocketAddr, TcpListener};
+use serde::Deserialize;
+use socket2::{Domain, Socket, Type};
+
+// one address, or several like ["0.0.0.0:3000", "[::]:3000"] that are all served by the same relay
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Listen {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Listen {
+    // LISTEN can't be a list, so a single address can also separate several with commas
+    pub fn addresses(&self) -> Vec<String> {
+        let addresses = match self {
+            Listen::One(address) => vec![address.clone()],
+            Listen::Many(addresses) => addresses.clone()
+        };
+        addresses.iter()
+            .flat_map(|address| address.split(','))
+            .map(|address| address.trim().to_string())
+            .filter(|address| !address.is_empty())
+            .collect()
+    }
+}
+
+// ipv6 sockets only take ipv6, otherwise [::] grabs ipv4 too and 0.0.0.0 on the same port can't be bound next to it
+pub fn bind(address: &str) -> std::io::Result<TcpListener> {
+    let address: SocketAddr = address.parse().map_err(|e| std::io::Error::other(format!("{} is not an address to listen on: {}", address, e)))?;
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
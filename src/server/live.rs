@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use async_stream::stream;
+use axum::{body::Body, extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Path, Query, State}, http::{Response, StatusCode}, response::IntoResponse};
+use maud::html;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, trace};
+
+use crate::utils::{metadata::FileMetadata, status::StatusUpdate};
+use super::appstate::AppState;
+
+const TICK: tokio::time::Duration = tokio::time::Duration::from_millis(500);
+
+// follows a token until it is downloaded or gone. reading the metadata keeps the token alive, the same as the status polls did
+pub fn updates(state: AppState, token: String, with_events: bool) -> impl Stream<Item = StatusUpdate> {
+    stream! {
+        let mut seen = 0;
+        let mut last: Option<FileMetadata> = None;
+        loop {
+            let meta = match state.get_file_metadata(&token).await {
+                Some(meta) => meta,
+                None => {
+                    yield StatusUpdate::Cancelled { reason: "The token was deleted or has expired".to_string() };
+                    return;
+                }
+            };
+
+            if with_events {
+                let events = state.get_events(&token).await;
+                for event in events.iter().skip(seen) {
+                    yield StatusUpdate::Event { event: serde_json::to_value(event).unwrap_or_default() };
+                }
+                seen = events.len();
+            }
+
+            if !last.as_ref().map(|last| last.same_progress(&meta)).unwrap_or(false) {
+                yield StatusUpdate::Status { metadata: Box::new(meta.redact()) };
+                if meta.download_finished() {
+                    return;
+                }
+                last = Some(meta);
+            }
+            tokio::time::sleep(TICK).await;
+        }
+    }
+}
+
+// the key works like it does for the log, without it only the redacted status is sent
+pub async fn websocket(State(state): State<AppState>, Path(token): Path<String>, Query(params): Query<HashMap<String, String>>, ws: WebSocketUpgrade) -> Response<Body> {
+    let meta = match state.get_file_metadata(&token).await {
+        Some(meta) => meta,
+        None => return (StatusCode::NOT_FOUND, html! {"File not found"}).into_response()
+    };
+    let with_events = params.get("key").map(|key| meta.check_key(key)).unwrap_or(false);
+    debug!("Following {} over a websocket", token);
+    ws.on_upgrade(move |socket| follow(socket, state, token, with_events))
+}
+
+async fn follow(mut socket: WebSocket, state: AppState, token: String, with_events: bool) {
+    let mut updates = Box::pin(updates(state, token.clone(), with_events));
+    loop {
+        tokio::select! {
+            update = updates.next() => {
+                let update = match update {
+                    Some(update) => update,
+                    None => break
+                };
+                let text = serde_json::to_string(&update).unwrap_or_default();
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    trace!("Websocket for {} went away", token);
+                    return;
+                }
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    trace!("Websocket for {} closed", token);
+                    return;
+                },
+                _ => () // nothing is read from the client yet, pings are answered by axum
+            }
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
@@ -1,48 +1,113 @@
+use std::collections::HashMap;
 use serde::Deserialize;
+use chrono::TimeDelta;
 use clap::Args;
-use serveropts::ServerOptions;
+use serveropts::{Group, ServerOptions};
 use tracing::warn;
+mod acme;
 mod appstate;
+mod admin;
+mod agents;
+mod assets;
+mod broadcast;
+mod bundle;
+mod chunked;
+mod dashboard;
+mod delta;
+mod eventlog;
+mod frames;
+mod fetch;
+mod forwarded;
+mod health;
+mod info;
+mod listen;
+mod live;
+mod onion;
+mod openapi;
+mod peer;
+#[cfg(feature = "redis")]
+mod pubsub;
+mod reflector;
+mod routes;
+mod sealed;
+pub mod stats;
+#[allow(clippy::module_inception)] // server::server is the relay itself, renaming it would break the library's paths
 pub mod server;
 pub mod serveropts;
+mod shared;
+mod spill;
+mod spoolio;
+mod storage;
+mod throttle;
+mod tls;
+mod transcode;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
 pub mod keymanager;
 
+pub use server::router;
+
 #[derive(Args, Deserialize, Debug)]
 pub struct ServerArgs {
-    /// the address to listen on
+    /// the address to listen on, several can be separated by commas
     #[arg(long, value_name = "ADDRESS", env="LISTEN")]
     listen: Option<String>,
 
     #[arg(long, value_name = "KEYSERVER", env="KEYSERVER")]
     keyserver: Option<String>,
+
+    /// Refuse new uploads while still serving existing ones for download
+    #[arg(long, env="READ_ONLY")]
+    read_only: bool,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct ServerConfig {
-    listen: Option<String>,
+    listen: Option<listen::Listen>,
     public_options: Option<ServerOptions>,
     authenticated_options: Option<ServerOptions>,
     keyserver: Option<String>,
-    users: Vec<String>
+    users: Vec<String>,
+    admins: Option<Vec<String>>, // users (from users or the keyserver) who can sign in to the /admin routes, if unset they are disabled
+    read_only: Option<bool>,
+    banner: Option<String>, // short announcement shown on landing pages and sent to clients
+    browser_agents: Option<Vec<String>>, // deprecated, user_agents.browsers replaces these prefixes
+    user_agents: Option<agents::AgentRules>, // regexes for agents that always get the landing page or the file, whatever they accept
+    allow_inline_override: Option<bool>, // lets downloaders ask for ?disposition=inline even when the uploader did not
+    members_only: Option<bool>, // turns off the public tier, only configured users can create and use tokens
+    hide_upload_form: Option<bool>, // reverse uploads only show the curl instructions, not the browser form
+    web_uploader: Option<String>, // the wasm-pack build of the browser uploader, upload pages use it to compress before sending
+    direct_mode: Option<bool>, // GET on a token always streams the file, for relays whose links are embedded elsewhere
+    transcode: Option<bool>, // recompress (or decompress) uploads for downloaders that can't read their compression, on unless set to false
+    onion: Option<onion::OnionConfig>, // also publish the relay as a tor onion service
+    tls_cert: Option<String>, // PEM certificate chain, with tls_key the relay serves https itself instead of behind a proxy
+    tls_key: Option<String>,
+    http_redirect: Option<String>, // a plain http address that sends everyone to the https one
+    base_path: Option<String>, // the prefix a reverse proxy serves the relay under, like "/beam", every route and link gets it
+    trust_forwarded: Option<bool>, // take X-Forwarded-For, -Proto, and -Host from the reverse proxy in front, only safe when nothing else can reach the relay
+    client_ca: Option<Vec<String>>, // PEM CA certificates whose client certificates sign users in instead of an ssh challenge, the common name is the user
+    acme: Option<acme::AcmeConfig>, // gets and renews certificates automatically instead of tls_cert and tls_key
+    token_rate: Option<usize>, // new tokens each client address (or ipv6 /64) can ask for per token_rate_window, unlimited if unset
+    token_rate_window: Option<TimeDelta>, // defaults to a minute
+    storage: Option<storage::StorageConfig>, // an s3 bucket that uploads can ask to be kept in, so the sender doesn't have to wait for the download
+    store_path: Option<String>, // a folder on the relay to keep them in instead of a bucket
+    fetch_private: Option<bool>, // lets POST /{token}/fetch reach loopback and private addresses, only for relays that nobody untrusted can authenticate to
+    user_ca: Option<Vec<String>>, // ssh CA public keys, a key they certified signs in as any of the certificate's principals without being listed in users
+    keyserver_ttl: Option<TimeDelta>, // how often users' keys are fetched from the keyserver again, an hour if unset
+    groups: Option<HashMap<String, Group>>, // members and the options they get instead of authenticated_options
+    stats_path: Option<String>, // a json file each user's stats are saved to, so they outlive restarts. in memory only if unset
+    shared: Option<shared::SharedConfig>, // redis that several relays behind one load balancer share their tokens through
+    peer_reflector: Option<String>, // a plain tcp address that tells direct mode peers the address and port their NAT gives them, so they can punch through to each other
 }
 
 impl ServerConfig {
-    pub fn default() -> Self {
-        ServerConfig {
-            listen: None,
-            public_options: None,
-            authenticated_options: None,
-            keyserver: None,
-            users: Vec::new()
-        }
-    }
     pub fn apply_args(&mut self, args: ServerArgs) {
        self.listen = Some(match args.listen {
-            Some(l) => l,
+            Some(l) => listen::Listen::One(l),
             None => match &self.listen {
                 None => {
                     warn!("Server not provided. Using default!");
-                    "0.0.0.0:3000".to_string()
+                    listen::Listen::One("0.0.0.0:3000".to_string())
                 },
                 Some(k) => k.clone()
             }
@@ -58,5 +123,9 @@ impl ServerConfig {
                 }
             }
         };
+
+        if args.read_only {
+            self.read_only = Some(true);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,161 @@
+use std::{net::SocketAddr, path::PathBuf};
+use serde::Deserialize;
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::TcpStream};
+use tracing::{debug, error, info, warn};
+
+// publishes the relay as a .onion service through a running tor's control port
+#[derive(Deserialize, Debug, Clone)]
+pub struct OnionConfig {
+    control: Option<String>, // tor's control port, defaults to 127.0.0.1:9051
+    password: Option<String>, // for HashedControlPassword, otherwise cookie or no authentication is used
+    key_file: Option<String>, // keeps the same .onion address across restarts, created on first run
+    port: Option<u16>, // the port on the .onion address, defaults to 80
+}
+
+struct ControlPort {
+    reader: BufReader<TcpStream>,
+}
+
+impl ControlPort {
+    async fn connect(address: &str) -> std::io::Result<Self> {
+        Ok(ControlPort { reader: BufReader::new(TcpStream::connect(address).await?) })
+    }
+
+    // sends a command and returns the reply lines, without the status codes, if it succeeded
+    async fn command(&mut self, command: &str) -> Result<Vec<String>, String> {
+        self.reader.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await.map_err(|e| e.to_string())?;
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+                return Err("tor closed the control connection".to_string());
+            }
+            let line = line.trim_end();
+            if line.len() < 4 {
+                return Err(format!("Unexpected reply from tor: {}", line));
+            }
+            let (code, rest) = line.split_at(3);
+            if code != "250" {
+                return Err(line.to_string());
+            }
+            lines.push(rest[1..].to_string());
+            if rest.starts_with(' ') { // "250 " ends the reply, "250-" continues it
+                return Ok(lines);
+            }
+        }
+    }
+
+    async fn authenticate(&mut self, password: Option<&String>) -> Result<(), String> {
+        if let Some(password) = password {
+            self.command(&format!("AUTHENTICATE \"{}\"", password.replace('\\', "\\\\").replace('"', "\\\""))).await?;
+            return Ok(());
+        }
+
+        let info = self.command("PROTOCOLINFO 1").await?;
+        let cookie_file = info.iter()
+            .find_map(|line| line.split("COOKIEFILE=\"").nth(1))
+            .and_then(|rest| rest.split('"').next());
+
+        match cookie_file {
+            Some(path) => {
+                let cookie = tokio::fs::read(path).await.map_err(|e| format!("Could not read tor's cookie at {}: {}", path, e))?;
+                let hex: String = cookie.iter().map(|b| format!("{:02x}", b)).collect();
+                self.command(&format!("AUTHENTICATE {}", hex)).await?;
+            },
+            None => {
+                self.command("AUTHENTICATE").await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// tor forwards to this, and it can't forward to 0.0.0.0
+fn local_target(listen: &str) -> String {
+    match listen.parse::<SocketAddr>() {
+        Ok(mut address) if address.ip().is_unspecified() => {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+                SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+            });
+            address.to_string()
+        },
+        _ => listen.to_string()
+    }
+}
+
+// it's the service's identity, so only the relay's user should be able to read it
+fn save_key(path: &PathBuf, key: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(key.as_bytes())
+}
+
+// the service only lives as long as the control connection, so this keeps it open until the server stops
+pub async fn publish(config: OnionConfig, listen: &str) {
+    let control = config.control.unwrap_or("127.0.0.1:9051".to_string());
+    let mut port = match ControlPort::connect(&control).await {
+        Ok(port) => port,
+        Err(e) => {
+            error!("Could not reach tor's control port at {}: {}. The onion service is disabled", control, e);
+            return;
+        }
+    };
+
+    if let Err(e) = port.authenticate(config.password.as_ref()).await {
+        error!("Could not authenticate with tor: {}. The onion service is disabled", e);
+        return;
+    }
+
+    let key_file = config.key_file.map(|f| PathBuf::from(shellexpand::tilde(&f).into_owned()));
+    let key = match &key_file {
+        Some(path) if path.exists() => match tokio::fs::read_to_string(path).await {
+            Ok(key) => key.trim().to_string(),
+            Err(e) => {
+                error!("Could not read onion key from {:?}: {}. The onion service is disabled", path, e);
+                return;
+            }
+        },
+        _ => "NEW:ED25519-V3".to_string()
+    };
+
+    let target = local_target(listen);
+    let virtual_port = config.port.unwrap_or(80);
+    debug!("Asking tor to forward port {} to {}", virtual_port, target);
+    let reply = match port.command(&format!("ADD_ONION {} Port={},{}", key, virtual_port, target)).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            error!("Tor refused to add the onion service: {}", e);
+            return;
+        }
+    };
+
+    let service = reply.iter().find_map(|line| line.strip_prefix("ServiceID="));
+    match service {
+        Some(service) => info!("Relay is available at http://{}.onion{}", service, match virtual_port {
+            80 => String::new(),
+            p => format!(":{}", p)
+        }),
+        None => warn!("Tor added the onion service but did not say its address"),
+    }
+
+    if let (Some(path), Some(new_key)) = (&key_file, reply.iter().find_map(|line| line.strip_prefix("PrivateKey="))) {
+        match save_key(path, new_key) {
+            Ok(_) => info!("Saved the onion key to {:?}, the address will stay the same next time", path),
+            Err(e) => warn!("Could not save the onion key to {:?}: {}. The address will change on restart", path, e),
+        }
+    }
+
+    // nothing else is sent, reading just notices if tor goes away
+    let mut line = String::new();
+    while let Ok(n) = port.reader.read_line(&mut line).await {
+        if n == 0 {
+            break;
+        }
+        line.clear();
+    }
+    warn!("Lost the connection to tor, the onion service is gone");
+}
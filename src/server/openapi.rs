@@ -0,0 +1,91 @@
+use axum::Json;
+use serde::Deserialize;
+use utoipa::{openapi::security::{Http, HttpAuthScheme, SecurityScheme}, Modify, OpenApi, ToSchema};
+
+use crate::utils::{checksum::{Checksum, ChecksumAlgorithm}, compression::Compression, info::ServerInfo, metadata::{Delta, Disposition, FileAttributes, FileMetadata, FileSize, FileState, ManifestEntry, PeerOffer}, stats::{RelayStats, StatsReport}};
+
+use super::{admin::{self, AdminSession, ChallengeResponse, RelayStatus, TokenDetails}, eventlog::{LoggedEvent, TokenEvent}, health::{self, Health}, info, server, stats};
+
+// the handlers read these as plain forms, so they're only written out here for the spec
+#[allow(dead_code)]
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct CreateForm {
+    user: Option<String>, // a user the relay has keys for, the token has to be signed for before it gets their options
+    file_size: Option<usize>,
+    expires: Option<i64>, // seconds, up to the tier's max_lifetime
+    receiver: Option<String>, // only this user can download
+    token_name: Option<String>, // picks the token instead of a generated one, along with name_challenge and name_signature
+    name_challenge: Option<String>, // "token-name/[name]:[user]:[unix time]:[nonce]"
+    name_signature: Option<String>, // a JSON list of armored signatures of name_challenge, not needed with a client certificate
+    store: Option<bool>, // keep the upload on the relay until it's downloaded
+    max_downloads: Option<usize>,
+    challenge: Option<String>, // on an existing token, a JSON list of armored signatures of its challenge. without it the path is a file name
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct UploadForm {
+    file_size: Option<usize>, // before compression, only trusted without it
+    compression: Option<String>, // none, gzip, deflate, br, or zstd
+    disposition: Option<String>, // inline or attachment
+    file_name: Option<String>,
+    max_downloads: Option<usize>,
+    encrypted: Option<bool>,
+    text: Option<bool>,
+    direct: Option<bool>,
+    message: Option<String>,
+    manifest: Option<String>, // JSON list of ManifestEntry for several files back to back
+    attributes: Option<String>, // JSON FileAttributes, the mode and modification time to put back on download
+    content_type: Option<String>, // the file's MIME type, otherwise it's looked up from the name
+    checksum: Option<String>, // [algorithm]:[hex digest], has to come before the file
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>, // always last
+}
+
+// the same challenge and signature fields as /admin/session, signed for "stats:[user]:[unix time]"
+#[allow(dead_code)]
+#[derive(Deserialize, ToSchema)]
+pub struct SignedForm {
+    user: String,
+    challenge: String,
+    signature: String, // armored, or a JSON list of them
+}
+
+struct AdminAuth;
+
+impl Modify for AdminAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme("admin_session", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "ByteBeam", description = "Stream a file from one machine to another through a relay. Every path is under the relay's base_path when it has one."),
+    paths(
+        server::make_upload, server::get_download, server::remove_file, server::download, server::head_download, server::upload, server::put_upload, server::token_log,
+        info::info, stats::my_stats, health::healthz, health::readyz,
+        admin::get_challenge, admin::create_session, admin::get_status, admin::set_drain, admin::set_read_only,
+        admin::list_tokens, admin::get_token, admin::delete_token, admin::get_stats
+    ),
+    components(schemas(
+        FileMetadata, FileSize, FileState, Disposition, Compression, Checksum, ChecksumAlgorithm, ManifestEntry, FileAttributes, Delta, PeerOffer,
+        ServerInfo, StatsReport, RelayStats, RelayStatus, TokenDetails, AdminSession, ChallengeResponse, LoggedEvent, TokenEvent, Health,
+        CreateForm, UploadForm, SignedForm
+    )),
+    modifiers(&AdminAuth),
+    tags(
+        (name = "transfer", description = "Making tokens, uploading, and downloading"),
+        (name = "relay", description = "What the relay is and how it's doing"),
+        (name = "admin", description = "Looking after the relay, with a session from /admin/session")
+    )
+)]
+pub struct ApiDoc;
+
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
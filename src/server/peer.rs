@@ -0,0 +1,109 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}};
+use axum::{body::Body, extract::{Path, Query, State}, http::{HeaderMap, Response, StatusCode}, response::IntoResponse, Form, Json};
+use maud::{html, Markup};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::utils::metadata::{FileMetadata, PeerOffer};
+
+use super::{appstate::AppState, eventlog::TokenEvent, forwarded::Requester, server::download};
+
+const MAX_PEER_ADDRESSES: usize = 8; // from the uploader, the one the relay sees is added after
+
+// the relay only introduces the two sides here, the file itself never comes through it unless the downloader gives up
+// POST key and port (and any addresses the uploader knows it has, and the one the reflector saw) to offer. the downloader POSTs
+// answer=[address:port] when it's about to punch through to the uploader, or failed=true to fall back
+pub async fn peer(State(state): State<AppState>, Path(token): Path<String>, requester: Requester, Form(params): Form<HashMap<String, String>>) -> Result<Json<FileMetadata>, (StatusCode, Markup)> {
+    let meta = match state.get_file_metadata(&token).await {
+        Some(meta) => meta,
+        None => return Err((StatusCode::NOT_FOUND, html! {"File not found"}))
+    };
+
+    let key = match params.get("key") {
+        Some(key) => key,
+        None if params.get("failed").map(|failed| failed == "true").unwrap_or(false) => {
+            let meta = match state.fail_peer(&token).await {
+                Some(meta) => meta,
+                None => return Err((StatusCode::CONFLICT, html! {"This upload isn't being offered directly"}))
+            };
+            debug!("Downloader of {} couldn't reach the uploader, falling back to the relay", token);
+            state.log_event(&token, TokenEvent::PeerFailed).await;
+            return Ok(Json(meta.redact()));
+        },
+        None if params.contains_key("answer") => {
+            let answer = match params.get("answer").and_then(|answer| answer.trim().parse::<SocketAddr>().ok()) {
+                Some(answer) => answer,
+                None => return Err((StatusCode::BAD_REQUEST, html! {"answer has to be an address and port"}))
+            };
+            let meta = match state.answer_peer(&token, answer.to_string()).await {
+                Some(meta) => meta,
+                None => return Err((StatusCode::CONFLICT, html! {"This upload isn't waiting to be punched through to"}))
+            };
+            debug!("Downloader of {} is punching through from {}", token, answer);
+            return Ok(Json(meta.redact()));
+        },
+        None => return Err((StatusCode::BAD_REQUEST, html! {"key, answer, or failed is required"}))
+    };
+    if !meta.check_key(key) {
+        return Err((StatusCode::FORBIDDEN, html! {"File has a different key"}));
+    }
+
+    // each of these relies on the relay seeing the download, which a direct one goes around
+    if meta.get_receiver().is_some() {
+        return Err((StatusCode::CONFLICT, html! {"This link is locked to a receiver, who has to sign for it through the relay"}));
+    }
+    if meta.is_broadcast() || meta.is_stored() {
+        return Err((StatusCode::CONFLICT, html! {"Broadcast and stored uploads can only go through the relay"}));
+    }
+
+    let port: u16 = match params.get("port").and_then(|port| port.parse().ok()) {
+        Some(port) if port > 0 => port,
+        _ => return Err((StatusCode::BAD_REQUEST, html! {"A port to connect to is required"}))
+    };
+
+    // what the uploader sees of itself works on its own network, what the relay sees works from outside it (unless there's a NAT in the way)
+    let mut addresses: Vec<String> = params.get("addresses").map(|addresses| addresses.split(',')
+        .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, port).to_string())
+        .take(MAX_PEER_ADDRESSES)
+        .collect()).unwrap_or_default();
+    if let Ok(ip) = requester.address.parse::<IpAddr>() {
+        let seen = SocketAddr::new(ip, port).to_string();
+        if !addresses.contains(&seen) {
+            addresses.push(seen);
+        }
+    }
+    if addresses.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, html! {"No addresses the downloader could connect to"}));
+    }
+
+    // only worth anything with a reflector to have seen it, the NAT picks the port rather than the uploader
+    let reflected = match params.get("reflected").map(|reflected| reflected.trim().parse::<SocketAddr>()) {
+        Some(Ok(reflected)) if state.reflector().is_some() => Some(reflected.to_string()),
+        Some(Ok(_)) => return Err((StatusCode::BAD_REQUEST, html! {"This relay has no reflector to punch through NATs with"})),
+        Some(Err(_)) => return Err((StatusCode::BAD_REQUEST, html! {"reflected has to be an address and port"})),
+        None => None
+    };
+
+    let offer = PeerOffer {
+        addresses,
+        secret: Uuid::new_v4().simple().to_string(),
+        failed: false,
+        reflected,
+        answer: None
+    };
+    let count = offer.addresses.len();
+    match state.set_peer(&token, offer).await {
+        Some(meta) => {
+            debug!("Uploader of {} is offering it directly on {} addresses", token, count);
+            state.log_event(&token, TokenEvent::PeerOffered { addresses: count }).await;
+            Ok(Json(meta))
+        },
+        None => Err((StatusCode::CONFLICT, html! {"The transfer has already started through the relay"}))
+    }
+}
+
+// offers are only ever POSTed, so a GET here is a download of a file named "peer"
+pub async fn download_named(State(state): State<AppState>, Path(token): Path<String>, requester: Requester, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Response<Body> {
+    download(State(state), Path((token, "peer".to_string())), requester, headers, Query(params)).await.into_response()
+}
@@ -0,0 +1,271 @@
+use std::{collections::HashMap, fmt, sync::Mutex, time::Duration};
+use redis::{aio::MultiplexedConnection, AsyncCommands, Client, RedisResult};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedSender};
+use tokio_stream::StreamExt;
+use tracing::{debug, error, trace, warn};
+use uuid::Uuid;
+
+use crate::utils::metadata::FileMetadata;
+
+use super::shared::{Change, Pending, SharedConfig, TokenStore};
+
+const WINDOW: u64 = 64; // chunks a forwarded upload can get ahead of what the claiming relay has passed on
+const ACK_EVERY: u64 = 16;
+const ACK_TIMEOUT: Duration = Duration::from_secs(120); // a claiming relay this quiet has gone away
+const GONE: &str = "gone"; // acked instead of a count when the download stopped
+
+// the first byte of every message on a transfer's channel
+const DATA: u8 = 0; // a chunk, an empty one is the upload's close signal like on any other channel
+const DROPPED: u8 = 1; // the upload stopped without finishing
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Notice {
+    Saved { meta: Box<FileMetadata> },
+    Removed { ticket: String },
+    UploadStarted { ticket: String },
+    Claimed { ticket: String, from: String },
+}
+
+// every notice says which relay sent it, so each can skip its own
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope {
+    relay: String,
+    #[serde(flatten)]
+    notice: Notice,
+}
+
+// tokens are kept in one hash so a relay that starts later can load them, and every change is published on one channel.
+// transfers between two relays get a channel of their own
+pub struct RedisTokens {
+    relay: String, // made up at start, only used to tell the relays apart
+    prefix: String,
+    client: Client,
+    connection: MultiplexedConnection,
+    outbox: UnboundedSender<Notice>,
+    changes: Mutex<Option<Receiver<Change>>>,
+}
+
+impl fmt::Debug for RedisTokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RedisTokens({}, {})", self.relay, self.prefix)
+    }
+}
+
+impl RedisTokens {
+    pub async fn connect(config: SharedConfig) -> Result<Self, String> {
+        let client = Client::open(config.redis.as_str()).map_err(|e| format!("Bad redis address {}: {}", config.redis, e))?;
+        let connection = client.get_multiplexed_async_connection().await.map_err(|e| format!("Could not connect to redis: {}", e))?;
+        let prefix = config.prefix.unwrap_or("bytebeam:".to_string());
+        let relay = Uuid::new_v4().to_string();
+        let notices = format!("{prefix}notices");
+
+        let mut pubsub = client.get_async_pubsub().await.map_err(|e| format!("Could not connect to redis: {}", e))?;
+        pubsub.subscribe(&notices).await.map_err(|e| format!("Could not subscribe to {}: {}", notices, e))?;
+        let (changes, received) = channel(256);
+        let me = relay.clone();
+        tokio::spawn(async move {
+            let mut messages = pubsub.into_on_message();
+            while let Some(message) = messages.next().await {
+                let envelope = match message.get_payload::<String>().ok().and_then(|payload| serde_json::from_str::<Envelope>(&payload).ok()) {
+                    Some(envelope) if envelope.relay != me => envelope,
+                    Some(_) => continue,
+                    None => {
+                        warn!("Ignoring a notice from another relay that could not be read");
+                        continue;
+                    }
+                };
+                let change = match envelope.notice {
+                    Notice::Saved { meta } => Change::Saved(meta),
+                    Notice::Removed { ticket } => Change::Removed(ticket),
+                    Notice::UploadStarted { ticket } => Change::UploadStarted { ticket, relay: envelope.relay },
+                    Notice::Claimed { ticket, from } if from == me => Change::Claimed(ticket),
+                    Notice::Claimed { .. } => continue
+                };
+                if changes.send(change).await.is_err() {
+                    return;
+                }
+            }
+            error!("Lost the connection to redis, changes from the other relays won't be seen");
+        });
+
+        let (outbox, mut pending) = unbounded_channel::<Notice>();
+        let (mut writer, tokens, me) = (connection.clone(), format!("{prefix}tokens"), relay.clone());
+        tokio::spawn(async move {
+            while let Some(notice) = pending.recv().await {
+                let stored: RedisResult<()> = match &notice {
+                    Notice::Saved { meta } => writer.hset(&tokens, meta.get_token(), serde_json::to_string(meta).unwrap_or_default()).await,
+                    Notice::Removed { ticket } => writer.hdel(&tokens, ticket).await,
+                    _ => Ok(())
+                };
+                let envelope = serde_json::to_string(&Envelope { relay: me.clone(), notice }).unwrap_or_default();
+                let published: RedisResult<()> = writer.publish(&notices, envelope).await;
+                if let Err(e) = stored.and(published) {
+                    warn!("Could not share a token change with the other relays: {}", e);
+                }
+            }
+        });
+
+        debug!("Sharing tokens through redis as relay {}", relay);
+        Ok(RedisTokens { relay, prefix, client, connection, outbox, changes: Mutex::new(Some(received)) })
+    }
+
+    fn send(&self, notice: Notice) {
+        let _ = self.outbox.send(notice); // only fails once the runtime is going away
+    }
+}
+
+impl TokenStore for RedisTokens {
+    fn load(&self) -> Pending<'_, Vec<FileMetadata>> {
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            let tokens: HashMap<String, String> = match connection.hgetall(format!("{}tokens", self.prefix)).await {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    error!("Could not load the other relays' tokens: {}", e);
+                    return vec![];
+                }
+            };
+            debug!("Loaded {} tokens from the other relays", tokens.len());
+            tokens.values().filter_map(|meta| serde_json::from_str(meta).ok()).collect()
+        })
+    }
+
+    fn save(&self, meta: &FileMetadata) {
+        self.send(Notice::Saved { meta: Box::new(meta.clone()) });
+    }
+
+    fn remove(&self, ticket: &str) {
+        self.send(Notice::Removed { ticket: ticket.to_string() });
+    }
+
+    fn upload_started(&self, ticket: &str) {
+        self.send(Notice::UploadStarted { ticket: ticket.to_string() });
+    }
+
+    fn changes(&self) -> Option<Receiver<Change>> {
+        self.changes.lock().unwrap().take()
+    }
+
+    // subscribes before asking, so nothing the other relay sends back can be missed
+    fn claim(&self, ticket: &str, relay: &str, into: Sender<Vec<u8>>) {
+        let (client, mut connection, prefix, ticket) = (self.client.clone(), self.connection.clone(), self.prefix.clone(), ticket.to_string());
+        let claim = Envelope { relay: self.relay.clone(), notice: Notice::Claimed { ticket: ticket.clone(), from: relay.to_string() } };
+        tokio::spawn(async move {
+            let mut pubsub = match client.get_async_pubsub().await {
+                Ok(pubsub) => pubsub,
+                Err(e) => {
+                    error!("Could not connect to redis to claim {}: {}", ticket, e);
+                    return;
+                }
+            };
+            if let Err(e) = pubsub.subscribe(format!("{prefix}bytes:{ticket}")).await {
+                error!("Could not subscribe to the upload of {}: {}", ticket, e);
+                return;
+            }
+            let claimed: RedisResult<()> = connection.publish(format!("{prefix}notices"), serde_json::to_string(&claim).unwrap_or_default()).await;
+            if let Err(e) = claimed {
+                error!("Could not claim the upload of {}: {}", ticket, e);
+                return;
+            }
+            debug!("Claimed the upload of {} from another relay", ticket);
+
+            let acks = format!("{prefix}acks:{ticket}");
+            let mut messages = pubsub.into_on_message();
+            let mut received = 0u64;
+            loop {
+                let message = tokio::select! {
+                    message = messages.next() => message,
+                    _ = into.closed() => None // the download, or the whole token, went away here
+                };
+                let payload: Vec<u8> = match message {
+                    Some(message) => message.get_payload().unwrap_or_default(),
+                    None => {
+                        let _: RedisResult<()> = connection.publish(&acks, GONE).await;
+                        return;
+                    }
+                };
+                match payload.split_first() {
+                    Some((&DATA, chunk)) => {
+                        let close = chunk.is_empty();
+                        if into.send(chunk.to_vec()).await.is_err() {
+                            let _: RedisResult<()> = connection.publish(&acks, GONE).await;
+                            return;
+                        }
+                        if close {
+                            trace!("Upload of {} finished on the other relay", ticket);
+                            return;
+                        }
+                        received += 1;
+                        if received.is_multiple_of(ACK_EVERY) {
+                            let _: RedisResult<()> = connection.publish(&acks, received.to_string()).await;
+                        }
+                    },
+                    _ => {
+                        debug!("Upload of {} stopped on the other relay", ticket);
+                        return; // dropping the sender without the close chunk tells the download it was cut off
+                    }
+                }
+            }
+        });
+    }
+
+    fn forward(&self, ticket: &str, mut from: Receiver<Vec<u8>>) {
+        let (client, mut connection, prefix, ticket) = (self.client.clone(), self.connection.clone(), self.prefix.clone(), ticket.to_string());
+        tokio::spawn(async move {
+            let mut pubsub = match client.get_async_pubsub().await {
+                Ok(pubsub) => pubsub,
+                Err(e) => {
+                    error!("Could not connect to redis to forward {}: {}", ticket, e);
+                    return;
+                }
+            };
+            if let Err(e) = pubsub.subscribe(format!("{prefix}acks:{ticket}")).await {
+                error!("Could not subscribe to the acks for {}: {}", ticket, e);
+                return;
+            }
+            debug!("Forwarding the upload of {} to another relay", ticket);
+
+            let bytes = format!("{prefix}bytes:{ticket}");
+            let mut acks = pubsub.into_on_message();
+            let (mut sent, mut acked) = (0u64, 0u64);
+            loop {
+                // pub/sub doesn't wait for anyone, so the upload only gets a window ahead of the download
+                while sent >= acked + WINDOW {
+                    match tokio::time::timeout(ACK_TIMEOUT, acks.next()).await {
+                        Ok(Some(message)) => match message.get_payload::<String>().ok().and_then(|ack| ack.parse::<u64>().ok()) {
+                            Some(count) => acked = acked.max(count),
+                            None => {
+                                debug!("The download of {} on the other relay went away", ticket);
+                                return;
+                            }
+                        },
+                        _ => {
+                            warn!("The relay downloading {} stopped answering", ticket);
+                            return;
+                        }
+                    }
+                }
+                let (message, last) = match from.recv().await {
+                    Some(chunk) => {
+                        let mut message = Vec::with_capacity(chunk.len() + 1);
+                        message.push(DATA);
+                        message.extend_from_slice(&chunk);
+                        (message, chunk.is_empty())
+                    },
+                    None => (vec![DROPPED], true)
+                };
+                let published: RedisResult<()> = connection.publish(&bytes, message).await;
+                if let Err(e) = published {
+                    error!("Could not forward the upload of {}: {}", ticket, e);
+                    return;
+                }
+                if last {
+                    return;
+                }
+                sent += 1;
+            }
+        });
+    }
+}
@@ -0,0 +1,31 @@
+use std::{net::SocketAddr, time::Duration};
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+use tracing::{debug, error, info, trace};
+
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// what STUN's binding request does, over tcp and without the framing: whoever connects is told the address and port it came from,
+// then the connection is closed. direct mode connects from the port it's listening on, so this is the port its NAT maps that one to
+pub async fn reflect(listener: TcpListener) {
+    match listener.local_addr() {
+        Ok(address) => info!("Telling direct mode peers their addresses on {}", address),
+        Err(e) => debug!("The reflector has no local address: {}", e)
+    }
+    loop {
+        let (mut socket, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("The reflector stopped accepting connections: {}", e);
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            let answer = format!("{}\n", SocketAddr::new(address.ip().to_canonical(), address.port()));
+            match tokio::time::timeout(ANSWER_TIMEOUT, async { socket.write_all(answer.as_bytes()).await?; socket.shutdown().await }).await {
+                Ok(Ok(())) => trace!("Told {} its address", address),
+                Ok(Err(e)) => debug!("Could not tell {} its address: {}", address, e),
+                Err(_) => debug!("Timed out telling {} its address", address)
+            }
+        });
+    }
+}
@@ -0,0 +1,39 @@
+// every path the relay serves, the router and the OpenAPI document both use these so they can't drift apart
+pub const INDEX: &str = "/";
+pub const INFO: &str = "/api/info";
+pub const OPENAPI: &str = "/api/openapi.json";
+pub const MY_STATS: &str = "/api/v1/me/stats";
+pub const HEALTHZ: &str = "/healthz";
+pub const READYZ: &str = "/readyz";
+pub const ASSETS: &str = "/assets/{file}";
+pub const WEB_UPLOADER: &str = "/assets/bytebeam.js";
+pub const BUNDLE: &str = "/bundle";
+pub const BUNDLE_DOWNLOAD: &str = "/bundle/{bundle}";
+pub const ADMIN: &str = "/admin";
+pub const ADMIN_SIGN_IN: &str = "/admin/sign-in";
+pub const ADMIN_KILL: &str = "/admin/kill";
+pub const ADMIN_CHALLENGE: &str = "/admin/challenge";
+pub const ADMIN_SESSION: &str = "/admin/session";
+pub const ADMIN_STATUS: &str = "/admin/status";
+pub const ADMIN_DRAIN: &str = "/admin/drain";
+pub const ADMIN_READ_ONLY: &str = "/admin/read-only";
+pub const ADMIN_TOKENS: &str = "/admin/tokens";
+pub const ADMIN_TOKEN: &str = "/admin/tokens/{token}";
+pub const ADMIN_STATS: &str = "/admin/stats";
+pub const WEBSOCKET: &str = "/ws/{token}";
+pub const TOKEN: &str = "/{token}";
+pub const TOKEN_LOG: &str = "/{token}/log";
+pub const TOKEN_EVENTS: &str = "/{token}/events";
+pub const TOKEN_PEER: &str = "/{token}/peer";
+pub const TOKEN_FETCH: &str = "/{token}/fetch";
+pub const TOKEN_DELTA: &str = "/{token}/delta";
+pub const TOKEN_PATH: &str = "/{token}/{path}"; // the download under a file name, or the upload with the key in its place
+
+// the path for one token, for links and redirects the relay hands out. the token and path should already be url encoded
+pub fn for_token(route: &str, token: &str) -> String {
+    route.replace("{token}", token)
+}
+
+pub fn for_token_path(route: &str, token: &str, path: &str) -> String {
+    for_token(route, token).replace("{path}", path)
+}
@@ -0,0 +1,198 @@
+use std::{fmt, io, sync::Arc};
+use ring::{aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, MAX_TAG_LEN, NONCE_LEN}, rand::{SecureRandom, SystemRandom}};
+
+use super::spoolio::SpoolReader;
+
+const HEADER: usize = 4; // each record is the sealed chunk's length as a u32, then the sealed chunk
+pub const OVERHEAD: usize = HEADER + MAX_TAG_LEN; // how much bigger a chunk is once it's on disk
+pub const MAX_SEALED: usize = 64 * 1024 * 1024; // the biggest chunk that gets sealed, block_size is checked against it at startup
+
+// anything the relay writes to disk or a bucket is sealed with a key that only ever lives in its memory, so a copy of the
+// spool folder or the bucket (a backup, a seized disk) is no use on its own. a restart loses the keys, but it loses the tokens they belong to too
+#[derive(Clone)]
+pub struct SealingKey(Arc<LessSafeKey>);
+
+impl fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SealingKey(..)")
+    }
+}
+
+impl SealingKey {
+    pub fn generate() -> io::Result<Self> {
+        let mut bytes = [0u8; 32];
+        SystemRandom::new().fill(&mut bytes).map_err(|_| io::Error::other("Could not generate a key for data at rest"))?;
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &bytes).map_err(|_| io::Error::other("Could not set up the key for data at rest"))?;
+        Ok(SealingKey(Arc::new(LessSafeKey::new(key))))
+    }
+
+    // every record sealed with this gets the next counter as its nonce, so there should only ever be one sealer per key
+    pub fn sealer(&self) -> Sealer {
+        Sealer { key: self.clone(), counter: 0 }
+    }
+
+    // records have to be opened in the order they were sealed, readers of the same file each get their own
+    pub fn opener(&self) -> Opener {
+        Opener { key: self.clone(), counter: 0, pending: Vec::new() }
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+pub struct Sealer {
+    key: SealingKey,
+    counter: u64,
+}
+
+impl Sealer {
+    // the whole record, header and all, ready to be written
+    pub fn seal(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        if chunk.len() > MAX_SEALED {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("A {} byte chunk is too big to seal", chunk.len())));
+        }
+        let mut record = Vec::with_capacity(chunk.len() + OVERHEAD);
+        record.extend_from_slice(&[0; HEADER]);
+        record.extend_from_slice(chunk);
+        let mut sealed = record.split_off(HEADER);
+        self.key.0.seal_in_place_append_tag(nonce(self.counter), Aad::empty(), &mut sealed).map_err(|_| io::Error::other("Could not seal a chunk"))?;
+        self.counter += 1;
+        record[..HEADER].copy_from_slice(&(sealed.len() as u32).to_be_bytes());
+        record.extend_from_slice(&sealed);
+        Ok(record)
+    }
+}
+
+pub struct Opener {
+    key: SealingKey,
+    counter: u64,
+    pending: Vec<u8>, // part of a record from a stream that hasn't all arrived yet
+}
+
+// a header is read before anything checks it, so a damaged one mustn't get a huge buffer allocated for it
+fn record_length(header: [u8; HEADER]) -> io::Result<usize> {
+    match u32::from_be_bytes(header) as usize {
+        length if length <= MAX_SEALED + MAX_TAG_LEN => Ok(length),
+        length => Err(io::Error::new(io::ErrorKind::InvalidData, format!("A record at rest claims to be {length} bytes, more than is ever sealed")))
+    }
+}
+
+impl Opener {
+    // one sealed chunk, without its header
+    fn open(&mut self, mut sealed: Vec<u8>) -> io::Result<Vec<u8>> {
+        let opened = self.key.0.open_in_place(nonce(self.counter), Aad::empty(), &mut sealed)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "A chunk at rest did not open, it was changed or is out of order"))?
+            .len();
+        sealed.truncate(opened);
+        self.counter += 1;
+        Ok(sealed)
+    }
+
+    // for records coming in whatever pieces a stream gives them in, returns every one that is whole now
+    pub fn push(&mut self, bytes: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        self.pending.extend_from_slice(bytes);
+        let mut opened = vec![];
+        while self.pending.len() >= HEADER {
+            let length = record_length(self.pending[..HEADER].try_into().expect("the header is 4 bytes"))?;
+            if self.pending.len() < HEADER + length {
+                break;
+            }
+            let sealed = self.pending[HEADER..HEADER + length].to_vec();
+            self.pending.drain(..HEADER + length);
+            opened.push(self.open(sealed)?);
+        }
+        Ok(opened)
+    }
+
+    // anything left over at the end of a stream is a record that was cut off
+    pub fn is_finished(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // the next record of a file, None once there are no more
+    pub async fn read(&mut self, reader: &mut SpoolReader) -> io::Result<Option<Vec<u8>>> {
+        let mut header = [0u8; HEADER];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e)
+        }
+        let mut sealed = vec![0; record_length(header)?];
+        reader.read_exact(&mut sealed).await?;
+        self.open(sealed).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(sealer: &mut Sealer, chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        chunks.iter().map(|chunk| sealer.seal(chunk).unwrap()).collect()
+    }
+
+    fn kind(result: io::Result<Vec<Vec<u8>>>) -> io::ErrorKind {
+        result.expect_err("the records should not have opened").kind()
+    }
+
+    #[test]
+    fn records_open_in_order_whatever_they_arrive_in() {
+        let key = SealingKey::generate().unwrap();
+        let sealed = records(&mut key.sealer(), &[b"hello", b"", b" world"]).concat();
+        assert_eq!(key.opener().push(&sealed).unwrap(), vec![b"hello".to_vec(), vec![], b" world".to_vec()]);
+
+        let mut opener = key.opener();
+        let mut opened = vec![];
+        for piece in sealed.chunks(3) {
+            opened.extend(opener.push(piece).unwrap());
+        }
+        assert_eq!(opened, vec![b"hello".to_vec(), vec![], b" world".to_vec()]);
+        assert!(opener.is_finished());
+
+        let mut opener = key.opener();
+        opener.push(&sealed[..sealed.len() - 1]).unwrap();
+        assert!(!opener.is_finished()); // cut off
+    }
+
+    #[test]
+    fn a_changed_byte_doesnt_open() {
+        let key = SealingKey::generate().unwrap();
+        let mut sealed = records(&mut key.sealer(), &[b"first", b"second"]).concat();
+        sealed[HEADER + 2] ^= 0x01;
+        assert_eq!(kind(key.opener().push(&sealed)), io::ErrorKind::InvalidData);
+        assert_eq!(kind(SealingKey::generate().unwrap().opener().push(&records(&mut key.sealer(), &[b"first"]).concat())), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn records_out_of_order_dont_open() {
+        let key = SealingKey::generate().unwrap();
+        let sealed = records(&mut key.sealer(), &[b"first", b"second"]);
+        let mut opener = key.opener();
+        assert_eq!(kind(opener.push(&sealed[1])), io::ErrorKind::InvalidData);
+        assert_eq!(kind(key.opener().push(&[sealed[1].clone(), sealed[0].clone()].concat())), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn lengths_past_the_biggest_record_are_refused() {
+        let key = SealingKey::generate().unwrap();
+        assert_eq!(key.sealer().seal(&vec![0; MAX_SEALED + 1]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(key.opener().push(&u32::MAX.to_be_bytes())), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn records_read_back_from_a_file() {
+        let key = SealingKey::generate().unwrap();
+        let path = std::env::temp_dir().join(format!("bytebeam-sealed-test-{}", uuid::Uuid::new_v4()));
+        let mut sealer = key.sealer();
+        std::fs::write(&path, [sealer.seal(b"first").unwrap(), sealer.seal(b"second").unwrap(), u32::MAX.to_be_bytes().to_vec()].concat()).unwrap();
+        let mut reader = SpoolReader::open(&path).await.unwrap();
+        let mut opener = key.opener();
+        assert_eq!(opener.read(&mut reader).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(opener.read(&mut reader).await.unwrap(), Some(b"second".to_vec()));
+        assert_eq!(opener.read(&mut reader).await.unwrap_err().kind(), io::ErrorKind::InvalidData); // never allocated
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,72 +1,442 @@
-use std::{collections::HashMap, sync::{atomic::{AtomicUsize, Ordering}, Arc}};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
 use anyhow::Result;
+use axum_server::tls_rustls::RustlsConfig;
 use async_stream::stream;
-use axum::{body::Body, extract::{DefaultBodyLimit, Multipart, Path, Query, State}, http::{HeaderMap, HeaderName, HeaderValue, Response, StatusCode}, response::{IntoResponse, Redirect}, routing::{delete, get, post}, Form, Json, Router};
+use axum::{body::Body, extract::{DefaultBodyLimit, Multipart, Path, Query, State}, http::{HeaderMap, HeaderName, HeaderValue, Response, StatusCode}, response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Redirect}, routing::{delete, get, head, post, put, MethodRouter}, Extension, Form, Json, Router};
 use chrono::{Duration, TimeDelta};
-use maud::{html, Markup};
-use bytes::{BytesMut, BufMut};
-use reqwest::header::{CONTENT_ENCODING, CONTENT_LENGTH};
+use maud::{html, Markup, PreEscaped};
+use bytes::{Bytes, BytesMut, BufMut};
+use bytesize::ByteSize;
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_SECURITY_POLICY, CONTENT_TYPE, VARY, X_CONTENT_TYPE_OPTIONS};
 use tracing::{debug, error, info, trace, warn};
-use crate::{server::appstate::AppState, utils::{compression::Compression, metadata::FileMetadata}};
+use crate::{server::appstate::{AppState, StateConfig}, utils::{checksum::Checksum, compression::Compression, digest::DigestWorker, frames::FRAMES, metadata::{Delta, Disposition, FileAttributes, FileMetadata, FileState, ManifestEntry, MANIFEST_HEADER, MAX_MANIFEST_FILES}, status::StatusUpdate}};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_stream::{Stream, StreamExt};
 use tower_http::set_header::SetResponseHeaderLayer;
 use std::str::FromStr;
 
-use super::{serveropts::ServerOptions, ServerConfig};
+use super::{acme, admin, agents::Agents, assets, bundle, chunked, dashboard, delta, fetch, frames, health, info, keymanager::DEFAULT_KEYSERVER_TTL, listen, live, onion, openapi::{self, CreateForm, UploadForm}, peer, reflector, routes, sealed::MAX_SEALED, shared, stats, tls::{self, ClientIdentity}, transcode, eventlog::{LoggedEvent, TokenEvent}, forwarded::{PublicUrl, Requester}, serveropts::{Group, ServerOptions}, storage::ObjectStore, throttle::{SlidingWindow, Throttle}, ServerConfig};
 
 
 
-pub async fn server(config: ServerConfig) -> Result<()> {
-    let address = config.listen.expect("No server listen address defined");
+pub const MAX_BODY_SIZE: u64 = 1024*1024*1024*100; // bytes in one request, uploads past it are cut off
 
-    let public_config = match config.public_options {
+/// Everything the relay serves, for running it inside another service instead of with `beam server`.
+/// Its config can come from TOML like `[server]` in the config file, or start from `ServerConfig::default()`.
+/// Client addresses come from the connection, so serve it with `into_make_service_with_connect_info::<SocketAddr>()`.
+pub async fn router(config: ServerConfig) -> Result<Router> {
+    let mut public_config = match config.public_options {
         Some(public_options) => public_options,
         None => {
             warn!("Public config is not defined... Using defaults!");
             // limit of 4kbps to long UUID tokens
-            ServerOptions::new(1, 4096, Duration::hours(1), "{uuid}".to_string(), "{uuid}".to_string(), Some(TimeDelta::seconds(1)), None)
+            ServerOptions::new(1, 4096, Duration::hours(1), "{uuid}".to_string(), "{uuid}".to_string(), Some(4096))
         },
     };
 
-    let authed_config = match config.authenticated_options {
+    let mut authed_config = match config.authenticated_options {
         Some(authenticated_options) => authenticated_options,
         None => {
             warn!("Authenticated config is not defined... Using defaults!");
-            ServerOptions::new((1024 * 1024 * 1024) / 4096, 4096, Duration::hours(1), "{number}-{word}-{word}-{word}".to_string(), "{number}-{word}-{word}-{word}".to_string(), None, None)
+            ServerOptions::new((1024 * 1024 * 1024) / 4096, 4096, Duration::hours(1), "{number}-{word}-{word}-{word}".to_string(), "{number}-{word}-{word}-{word}".to_string(), None)
+        },
+    };
+
+    // sorted so a user in more than one group always gets the same one
+    let mut groups: Vec<(String, Group)> = config.groups.unwrap_or_default().into_iter().collect();
+    groups.sort_by(|a, b| a.0.cmp(&b.0));
+    for (i, (name, group)) in groups.iter().enumerate() {
+        for member in group.members() {
+            if let Some((first, _)) = groups[..i].iter().find(|(_, earlier)| earlier.has_member(member)) {
+                warn!("{} is in both {} and {}, only the options of {} apply", member, first, name, first);
+            }
+        }
+    }
+
+    for options in [&mut public_config, &mut authed_config].into_iter().chain(groups.iter_mut().map(|(_, group)| group.options_mut())) {
+        if let Err(e) = options.prepare_tokens() {
+            error!("{}", e);
+            return Err(anyhow::anyhow!("bad token settings"));
+        }
+        if options.get_block_size() > MAX_SEALED {
+            error!("block_size {} is bigger than the {} bytes a chunk can be", options.get_block_size(), MAX_SEALED);
+            return Err(anyhow::anyhow!("bad block_size"));
+        }
+        options.prepare_throttle();
+        if options.uses_packet_delay() {
+            warn!("packet_delay is deprecated, use rate_limit = {} (bytes per second) instead", options.get_rate_limit().unwrap_or(0));
+        }
+    }
+
+    let store = match (config.storage, config.store_path) {
+        (Some(_), Some(_)) => {
+            error!("Both storage and store_path are set, uploads can only be kept in one of them");
+            return Err(anyhow::anyhow!("bad storage settings"));
+        },
+        (Some(storage), None) => Some(ObjectStore::new(storage)),
+        (None, Some(path)) => Some(ObjectStore::disk(&path)),
+        (None, None) => None
+    };
+    let store = match store {
+        Some(Ok(store)) => Some(store),
+        Some(Err(e)) => {
+            error!("{}", e);
+            return Err(anyhow::anyhow!("bad storage settings"));
         },
+        None => None
+    };
+
+    let shared = match shared::connect(config.shared).await {
+        Ok(shared) => shared,
+        Err(e) => {
+            error!("{}", e);
+            return Err(anyhow::anyhow!("bad shared settings"));
+        }
     };
 
-    let state = AppState::new(public_config, authed_config, config.keyserver, config.users).await;
+    let admins = config.admins.unwrap_or_default();
+    if admins.is_empty() {
+        debug!("No admins defined, admin routes are disabled");
+    }
+
+    let agents = match Agents::new(config.user_agents, config.browser_agents) {
+        Ok(agents) => agents,
+        Err(e) => {
+            error!("{}", e);
+            return Err(anyhow::anyhow!("bad user agent rules"));
+        }
+    };
+
+    let state = AppState::new(StateConfig {
+        reg_options: public_config,
+        auth_options: authed_config,
+        groups,
+        keyserver: config.keyserver,
+        keyserver_ttl: config.keyserver_ttl.and_then(|ttl| ttl.to_std().ok()).unwrap_or(DEFAULT_KEYSERVER_TTL),
+        users: config.users,
+        user_ca: config.user_ca.unwrap_or_default(),
+        admins,
+        read_only: config.read_only.unwrap_or(false),
+        banner: config.banner,
+        agents,
+        allow_inline_override: config.allow_inline_override.unwrap_or(false),
+        members_only: config.members_only.unwrap_or(false),
+        hide_upload_form: config.hide_upload_form.unwrap_or(false),
+        web_uploader: config.web_uploader.map(PathBuf::from),
+        direct_mode: config.direct_mode.unwrap_or(false),
+        transcode: config.transcode.unwrap_or(true),
+        public_url: PublicUrl::new(config.base_path, config.trust_forwarded.unwrap_or(false), config.tls_cert.is_some() || config.acme.is_some()),
+        token_limit: config.token_rate.map(|rate| SlidingWindow::new(rate, config.token_rate_window.and_then(|window| window.to_std().ok()).unwrap_or(std::time::Duration::from_secs(60)))),
+        store,
+        fetch_private: config.fetch_private.unwrap_or(false),
+        stats_path: config.stats_path.map(|path| PathBuf::from(shellexpand::tilde(&path).into_owned())),
+        shared,
+        reflector: config.peer_reflector.as_ref().and_then(|address| address.parse::<SocketAddr>().ok()).map(|address| address.port())
+    }).await;
+    let base_path = state.public_url().base_path().to_string();
 
 
-    info!("Starting server listening on {}", address);
     let app = Router::new()
-        .route("/", get(index))
-        .route("/{token}", get(get_download)) // redirects to download of direct file name
-        .route("/{token}", delete(remove_file))
-        .route("/{token}/{path}", get(download)) // download using certain filename, gets confused with upload path though
-        .route("/{token}", post(make_upload)) // generates a new upload for a certain filename
-        .route("/{token}/{path}", post(upload)) // allows upload to a given token and key, only upload generator determines file name
-        .with_state(state)
-        .layer(DefaultBodyLimit::max(1024*1024*1024*100))
+        .route(routes::INDEX, get(index))
+        .route(routes::BUNDLE, post(bundle::make_bundle)) // combines several owned tokens into one zip download
+        .route(routes::BUNDLE_DOWNLOAD, get(bundle::download_bundle))
+        .route(routes::INFO, get(info::info)) // version, codecs, and features, for clients to adapt to
+        .route(routes::OPENAPI, get(openapi::openapi)) // the routes below for third-party clients
+        .route(routes::MY_STATS, post(stats::my_stats))
+        .route(routes::HEALTHZ, get(health::healthz)) // liveness for monitors, always 200 while the relay answers
+        .route(routes::READYZ, get(health::readyz)) // 503 while draining or the keyserver is down
+        .route(routes::ASSETS, get(assets::asset)) // the browser uploader, when web_uploader is set
+        .route(routes::ADMIN, get(dashboard::dashboard))
+        .route(routes::ADMIN_SIGN_IN, post(dashboard::sign_in))
+        .route(routes::ADMIN_KILL, post(dashboard::kill))
+        .route(routes::ADMIN_CHALLENGE, post(admin::get_challenge))
+        .route(routes::ADMIN_SESSION, post(admin::create_session))
+        .route(routes::ADMIN_STATUS, get(admin::get_status))
+        .route(routes::ADMIN_DRAIN, post(admin::set_drain))
+        .route(routes::ADMIN_READ_ONLY, post(admin::set_read_only))
+        .route(routes::ADMIN_TOKENS, get(admin::list_tokens))
+        .route(routes::ADMIN_TOKEN, get(admin::get_token).delete(admin::delete_token))
+        .route(routes::ADMIN_STATS, get(admin::get_stats))
+        .route(routes::WEBSOCKET, get(live::websocket)) // pushes status changes instead of clients polling ?status=true
+        .route(routes::TOKEN, get(get_download)) // redirects to download of direct file name
+        .route(routes::TOKEN, delete(remove_file))
+        .route(routes::TOKEN_LOG, get(token_log).merge(head_named("log"))) // event timeline for whoever holds the upload key
+        .route(routes::TOKEN_EVENTS, get(token_events).merge(head_named("events"))) // server-sent status updates for the landing page
+        .route(routes::TOKEN_PEER, post(peer::peer).get(peer::download_named).merge(head_named("peer"))) // the uploader offering to send directly, or the downloader giving up on that
+        .route(routes::TOKEN_FETCH, post(fetch::fetch).get(fetch::download_named).merge(head_named("fetch"))) // the relay downloads the file from a url and uploads it itself
+        .route(routes::TOKEN_DELTA, post(delta::request).put(delta::offer).get(delta::take).merge(head_named("delta"))) // block checksums from the downloader to the uploader, so only changes are sent
+        .route(routes::TOKEN_PATH, get(download).head(head_download)) // download using certain filename, gets confused with upload path though
+        .route(routes::TOKEN, post(make_upload)) // generates a new upload for a certain filename
+        .route(routes::TOKEN_PATH, post(upload)) // allows upload to a given token and key, only upload generator determines file name
+        .route(routes::TOKEN_PATH, put(put_upload)) // the whole file as the request body (curl -T), or in pieces with ?offset= like the web page sends it
+        .with_state(state);
+    let app = match base_path.as_str() {
+        "" => app,
+        base_path => { // the prefix's own index is only matched without the slash
+            let index = base_path.to_string();
+            Router::new()
+                .route(&format!("{base_path}/"), get(move || async move { Redirect::permanent(&index) }))
+                .nest(base_path, app)
+        }
+    };
+    Ok(app
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE as usize))
         .layer(SetResponseHeaderLayer::if_not_present(
             HeaderName::from_static("server"),
             HeaderValue::from_str(&format!("ByteBeam/{}", env!("CARGO_PKG_VERSION")))
                 .unwrap(),
-        ));
+        )))
+}
+
+// the routes that share their path with a file name would otherwise answer HEAD with their GET, which can start a download (pausing it
+// or using up a broadcast slot when it's dropped) or take what it's there to hand over. a HEAD only ever looks at the file by that name
+fn head_named(name: &'static str) -> MethodRouter<AppState> {
+    head(move |state: State<AppState>, Path(token): Path<String>, headers: HeaderMap, params: Query<HashMap<String, String>>| {
+        head_download(state, Path((token, name.to_string())), headers, params)
+    })
+}
 
-    let listener = tokio::net::TcpListener::bind(address).await.expect("Could not listen to port");
-    axum::serve(listener, app).await?;
+pub async fn server(config: ServerConfig) -> Result<()> {
+    let addresses = config.listen.as_ref().expect("No server listen address defined").addresses();
+    let address = match addresses.first() {
+        Some(address) => address.clone(), // the one the onion service and the https redirect point at
+        None => {
+            error!("listen has no addresses in it");
+            return Err(anyhow::anyhow!("no listen address"));
+        }
+    };
+
+    let app = router(config.clone()).await?;
+    info!("Starting server listening on {}", addresses.join(", "));
+
+    let client_ca = config.client_ca.unwrap_or_default();
+    let acme = match (config.acme, &config.tls_cert) {
+        (Some(_), Some(_)) => {
+            error!("acme and tls_cert can't both be used, acme provides the certificate itself");
+            return Err(anyhow::anyhow!("conflicting tls config"));
+        },
+        (Some(acme_config), None) => match acme::Acme::new(acme_config, client_ca.clone()) {
+            Ok(acme) => Some(acme),
+            Err(e) => {
+                error!("Could not set up acme: {}", e);
+                return Err(anyhow::anyhow!(e));
+            }
+        },
+        (None, _) => None
+    };
+
+    // the CA checks the domain over plain http on port 80, so acme always needs the redirect listener
+    let http_redirect = match (config.http_redirect, &acme) {
+        (None, Some(_)) => Some("0.0.0.0:80".to_string()),
+        (http_redirect, _) => http_redirect
+    };
+    let challenges = acme.as_ref().map(|acme| acme.challenges()).unwrap_or_default();
+    let https_port = address.rsplit_once(':').and_then(|(_, port)| port.parse().ok()).unwrap_or(443);
+    if let (Some(redirect), true) = (&http_redirect, acme.is_some() || config.tls_cert.is_some()) {
+        tokio::spawn(tls::redirect_http(redirect.clone(), https_port, challenges));
+    }
+
+    let tls = match (&config.tls_cert, &config.tls_key, &acme) {
+        (_, _, Some(acme)) => match acme.server_config().await {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                error!("Could not get a certificate: {}", e);
+                return Err(anyhow::anyhow!(e));
+            }
+        },
+        (Some(cert), Some(key), None) => match tls::server_config(cert, key, &client_ca) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                error!("Could not set up https: {}", e);
+                return Err(anyhow::anyhow!(e));
+            }
+        },
+        (None, None, None) => None,
+        _ => {
+            error!("tls_cert and tls_key have to be set together");
+            return Err(anyhow::anyhow!("incomplete tls config"));
+        }
+    };
+
+    if let Some(onion_config) = config.onion {
+        if tls.is_some() {
+            warn!("The onion service forwards to the https listener, so it has to be visited with https://");
+        }
+        let address = address.clone();
+        tokio::spawn(async move { onion::publish(onion_config, &address).await });
+    }
+
+    // everything is bound before anything is served, so one bad address doesn't leave the relay half up
+    let reflector = match &config.peer_reflector {
+        Some(address) => match listen::bind(address).and_then(tokio::net::TcpListener::from_std) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("Could not listen on {} for peer_reflector: {}", address, e);
+                return Err(e.into());
+            }
+        },
+        None => None
+    };
+    let mut listeners = vec![];
+    for address in &addresses {
+        match listen::bind(address) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                error!("Could not listen on {}: {}", address, e);
+                return Err(e.into());
+            }
+        }
+    }
+
+    if let Some(reflector) = reflector {
+        tokio::spawn(reflector::reflect(reflector));
+    }
+
+    let mut servers = tokio::task::JoinSet::new();
+    match tls {
+        Some(tls) => {
+            let tls = RustlsConfig::from_config(Arc::new(tls));
+            if let Some(acme) = acme {
+                tokio::spawn(acme.keep_renewed(tls.clone()));
+            }
+            for listener in listeners {
+                servers.spawn(tls::serve(listener, app.clone(), tls.clone()));
+            }
+        },
+        None => {
+            if http_redirect.is_some() {
+                warn!("http_redirect is only used when serving https with tls_cert and tls_key or acme");
+            }
+            if !client_ca.is_empty() {
+                warn!("client_ca is only used when serving https with tls_cert and tls_key or acme");
+            }
+            for listener in listeners {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let app = app.clone();
+                servers.spawn(async move { axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await });
+            }
+        }
+    }
+
+    // the relay goes down with any of its listeners
+    if let Some(result) = servers.join_next().await {
+        result??;
+    }
 
     Ok(())
 }
 
-async fn index() -> &'static str { // this should be a landing page for the project to the github and such
-    "If you were sent a link here, it probably doesn't exist anymore."
+async fn index(State(state): State<AppState>) -> String { // this should be a landing page for the project to the github and such
+    let status = if state.is_read_only() {
+        "This relay is read-only. Existing files can still be downloaded until they expire, but no new uploads are being accepted."
+    } else if state.is_draining() {
+        "This relay is draining for maintenance. Transfers in progress will finish, but no new uploads are being accepted."
+    } else {
+        "If you were sent a link here, it probably doesn't exist anymore."
+    };
+
+    match state.get_banner() {
+        Some(banner) => format!("{}\n\n{}", banner, status),
+        None => status.to_string()
+    }
+}
+
+// browsers don't agree on how to read non-ascii names, so send both a plain fallback and the RFC 5987 encoded name
+fn content_disposition(file_name: &str, disposition: &Disposition) -> String {
+    let fallback: String = file_name.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, fallback, urlencoding::encode(file_name))
+}
+
+// by extension only, the bytes going through are usually compressed or encrypted
+fn guess_content_type(file_name: &str) -> Option<String> {
+    mime_guess::from_path(file_name).first().map(|mime| mime.essence_str().to_string())
+}
+
+// types a browser only displays. anything else opened inline (html, svg, xml, scripts) would run as the relay's own origin
+fn shows_safely(content_type: Option<&str>) -> bool {
+    let essence = match content_type {
+        Some(content_type) => content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase(),
+        None => return false
+    };
+    match essence.split_once('/') {
+        Some(("image", subtype)) => !subtype.contains("svg"),
+        Some(("video", _)) | Some(("audio", _)) => true,
+        _ => essence == "application/pdf" || essence == "text/plain"
+    }
+}
+
+// the uploader (or the link) asking for inline is only honored for types that can't script anything
+fn disposition_for(state: &AppState, meta: &FileMetadata, params: &HashMap<String, String>, content_type: Option<&str>) -> Disposition {
+    match state.resolve_disposition(meta, params.get("disposition")) {
+        Disposition::Inline if !shows_safely(content_type) => Disposition::Attachment,
+        disposition => disposition
+    }
 }
 
-async fn download(State(state): State<AppState>, Path((token, path)): Path<(String, String)>) -> Result<impl IntoResponse, (StatusCode, Markup)> {
+// names from the outside can end up as download names, but they should never be a path
+pub fn safe_file_name(name: &str) -> Option<String> {
+    Some(name.rsplit(['/', '\\']).next().unwrap_or("").trim().to_string())
+        .filter(|name| !name.is_empty() && name != "." && name != "..")
+}
+
+// the filename query can override the uploaded name
+fn requested_file_name(params: &HashMap<String, String>) -> Option<String> {
+    params.get("filename").and_then(|name| safe_file_name(name))
+}
+
+// owns the download channel while it streams, if the response is dropped before it finishes (the browser went away) the rest goes back for a retry
+pub struct DownloadGuard {
+    state: AppState,
+    token: String,
+    download: Option<Receiver<Vec<u8>>>,
+    resumable: bool, // broadcast readers free their own spot when dropped, there's nothing to hand back
+    stored: bool,
+}
+
+impl DownloadGuard {
+    pub fn new(state: &AppState, token: &str, download: Receiver<Vec<u8>>, meta: &FileMetadata) -> Self {
+        DownloadGuard { state: state.clone(), token: token.to_string(), download: Some(download), resumable: !meta.is_broadcast(), stored: meta.is_stored() }
+    }
+
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.download.as_mut()?.recv().await
+    }
+
+    // finished or failed for good, nothing should be handed back
+    pub fn finish(&mut self) {
+        self.download = None;
+    }
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        let download = match self.download.take() {
+            Some(download) if self.resumable => download,
+            _ => return
+        };
+        let (state, token) = (self.state.clone(), self.token.clone());
+        let download = (!self.stored).then_some(download); // dropping it stops reading the stored file
+        tokio::spawn(async move {
+            if state.return_download(&token, download).await {
+                let bytes = state.get_counters(&token).await.map(|counters| counters.downloaded()).unwrap_or(0);
+                state.log_event(&token, TokenEvent::DownloadPaused { bytes }).await;
+                info!("Download of {} was dropped partway, it can be tried again", token);
+            }
+        });
+    }
+}
+
+#[utoipa::path(get, path = routes::TOKEN_PATH, tag = "transfer",
+    params(("token" = String, Path), ("path" = String, Path, description = "Any file name, or the upload key for the upload page"), ("raw" = Option<bool>, Query, description = "Several files back to back with their manifest in a header, instead of zipped"), ("challenge" = Option<String>, Query), ("signature" = Option<String>, Query, description = "For tokens locked to a receiver")),
+    responses((status = 200, description = "The file as it streams through the relay", body = Vec<u8>, content_type = "application/octet-stream"), (status = 403, description = "Locked to a receiver who didn't sign for it"), (status = 404, description = "No such token"), (status = 409, description = "Already being downloaded, or a stored upload that isn't all there yet")))]
+pub async fn download(State(state): State<AppState>, Path((token, path)): Path<(String, String)>, requester: Requester, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Result<impl IntoResponse, (StatusCode, Markup)> {
     // we could check the path, but its quite honestly not needed and the user should be able to do what they want
     debug!("Attempting download to {token}/{path}");
+    if let Some(key) = params.get("resend") { // a frame of the download came through damaged
+        return Ok(frames::resend(&state, &token, key, params.get("offset")).await);
+    }
     let meta = match state.get_file_metadata(&token).await {
         Some(meta) => meta,
         None => {
@@ -89,109 +459,347 @@ async fn download(State(state): State<AppState>, Path((token, path)): Path<(Stri
                 }
                 body {
                     h1 {"ByteBeam File Upload"}
+                    @if let Some(banner) = state.get_banner() {
+                        p { i {(banner)} }
+                    }
+                    @if state.is_read_only() {
+                        p { b {"This relay is currently read-only, uploads will be refused."} }
+                    } @else if state.is_draining() {
+                        p { b {"This relay is draining for maintenance. Uploads that have already been started will finish."} }
+                    }
                     p { "You can only begin an upload once, if the upload fails you will need to ask for a new upload link"}
-                    form method="POST" action=(format!("/{token}/{path}")) enctype="multipart/form-data" {
-                        input name="file" type="file";
-                        input type="submit" value="Upload";
+                    @if !state.hides_upload_form() {
+                        div id="upload" data-target=(state.public_url().link(&routes::for_token_path(routes::TOKEN_PATH, &urlencoding::encode(&token), &urlencoding::encode(&path)))) data-uploader=[state.web_uploader().map(|_| state.public_url().link(routes::WEB_UPLOADER))] hidden {
+                            select id="compression" hidden { // only once the web uploader has loaded, upload.js can't compress
+                                option value="none" { "No compression" }
+                                option value="zstd" { "zstd" }
+                                option value="br" { "brotli" }
+                                option value="gzip" { "gzip" }
+                                option value="lz4" { "lz4" }
+                            }
+                            label id="drop" for="file" style="display: block; padding: 3em; border: 2px dashed gray; text-align: center; cursor: pointer" {
+                                "Drop a file here, or click to choose one"
+                            }
+                            input id="file" type="file" hidden;
+                            progress id="upload-progress" hidden {}
+                            p id="upload-status" {}
+                        }
+                        noscript { // the pieces are sent by the script, without it the whole file goes in one request
+                            form method="POST" action=(state.public_url().link(&routes::for_token_path(routes::TOKEN_PATH, &token, &path))) enctype="multipart/form-data" {
+                                input name="file" type="file";
+                                input type="submit" value="Upload";
+                            }
+                        }
+                        script { (PreEscaped(include_str!("upload.js"))) }
                     }
                     p {"You can also upload the file using curl"}
-                    tt {"curl -F 'file=@/path/to/file' http://this-url/and/path" }
+                    tt {"curl -T /path/to/file " (requester.url(&state, &routes::for_token_path(routes::TOKEN_PATH, &token, &path))) }
                     // now we need to do the form. There should maybe be a JS progress bar or something...
                 }
             }
             }.into_response());
     }
 
-    if meta.download_locked() {
-        if meta.download_finished() {
-            return Err((StatusCode::GONE, html! {"File already downloaded"}));
-        }
-        return Err((StatusCode::CONFLICT, html! {"File being downloaded"}));
+    if !state.may_download(&meta, params.get("challenge"), params.get("signature"), true).await {
+        debug!("Refusing download of {token}, the receiver's challenge was missing or failed");
+        return Err((StatusCode::UNAUTHORIZED, html! {"This file can only be downloaded by the user it was sent to"}));
+    }
+
+    downloadable(&meta)?;
+    let (part, zip) = requested_part(&meta, &params)?;
+
+    // whoever downloads without having sent checksums can't rebuild a delta, so the uploader sends it whole
+    if state.decline_delta(&token).await {
+        debug!("Download of {} started without block checksums, the uploader will send all of it", token);
+        state.log_event(&token, TokenEvent::DeltaDeclined).await;
     }
 
-    let mut download = match state.begin_download(&token).await {
+    // a live upload picks up where the dropped download stopped, so only a stored one is whole again
+    let resumed = meta.get_states().1 == &FileState::Paused && !meta.is_broadcast() && !meta.is_stored();
+    let download = match state.begin_download(&token).await {
         Some(dl) => dl,
         None => {
             error!("File is unlocked however the stream could not be obtained");
             return Err((StatusCode::INTERNAL_SERVER_ERROR, html! {"Internal Server Error"})) // this file should be freed!
         }
     };
+    info!("Download of {} started by {}", token, requester.address);
 
-    let bytes_counter = Arc::new(AtomicUsize::new(0));
-    let bytes_counter_clone = bytes_counter.clone();
-
-    // Spawn a separate tokio task to handle the updates
-    let update_handle = {
-        let state = state.clone();
-        let token = token.clone();
-        tokio::spawn(async move {
-            let mut updown = (0, 0);
-            
-            loop {
-                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                
-                let bytes = bytes_counter.swap(0, Ordering::Relaxed);
-                if bytes > 0 {
-                    updown = match state.increase_upload_download_numbers(&token, 0, bytes).await {
-                        Some((uploaded, downloaded)) => (uploaded, downloaded),
-                        None => {
-                            warn!("Failed to get upload/download numbers");
-                            updown
-                        }
-                    };
-                }
-            }
-        })
+    let counters = match state.get_counters(&token).await {
+        Some(counters) => counters,
+        None => {
+            error!("File is downloading however it has no byte counters");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, html! {"Internal Server Error"}))
+        }
     };
 
+    let compression = meta.get_compression();
+    let converted = conversion(&state, &meta, &headers);
+
+    // a zip, one file out of several, or a transcoded download is made from the upload, so only the upload as it was sent is framed
+    let resends = match zip || part.is_some() || converted.is_some() {
+        true => None,
+        false => frames::resends(&state, &token, &headers).await
+    };
+    let resend_key = resends.as_ref().map(|resends| resends.key().clone());
+    // with a content length the response can end on the last byte, before the close signal is ever read
+    let expected = match resumed {
+        true => None,
+        false => meta.file_size.get_content_length()
+    };
+    // worked out before the stream takes the state
+    let mut response_headers = download_headers(&state, &meta, &params, part, zip, converted.clone(), expected);
+    frames::headers(resend_key.as_ref(), &mut response_headers);
+    let mut download = DownloadGuard::new(&state, &token, download, &meta);
     let s = stream! {
+        let mut sent = 0;
         loop {
             let data = download.recv().await;
             match data {
                 Some(data) => {
-                    bytes_counter_clone.fetch_add(data.len(), Ordering::Relaxed);
+                    state.count_download(&token, &counters, data.len()).await;
+                    if counters.is_cancelled() {
+                        info!("Download of {} stopped, the token was deleted", token);
+                        download.finish();
+                        yield Err("Token was deleted".to_string());
+                        return;
+                    }
                     if data.is_empty() {
                         debug!("No bytes remaining to read");
-                        state.end(&token).await;
                         break;
                     }
-                    yield Ok(data);
+                    sent += data.len();
+                    if expected.is_some_and(|expected| expected > 0 && sent >= expected) {
+                        download.finish();
+                        state.log_event(&token, TokenEvent::DownloadComplete { bytes: counters.downloaded() }).await;
+                        state.end(&token).await;
+                        info!("Download complete for {}", token);
+                        yield Ok(frames::framed(&resends, data));
+                        return;
+                    }
+                    yield Ok(frames::framed(&resends, data));
                 },
                 None => {
-                    yield Err(format!("Download possibly dropped?"));
+                    download.finish();
+                    state.log_event(&token, TokenEvent::Error { message: "Upload stream closed before the download finished".to_string() }).await;
+                    yield Err("Download possibly dropped?".to_string());
                     break;
                 }
             }
         }
-        // the download is complete
-        let final_bytes = bytes_counter_clone.load(Ordering::Relaxed);
-        state.increase_upload_download_numbers(&token, 0, final_bytes).await;
+        download.finish();
+        state.log_event(&token, TokenEvent::DownloadComplete { bytes: counters.downloaded() }).await;
         state.end(&token).await;
-        update_handle.abort();
         info!("Download complete for {}", token);
     };
 
-    let body = Body::from_stream(s);
+    let body = match (meta.get_manifest(), part) {
+        (Some(manifest), Some(index)) => Body::from_stream(bundle::slice_manifest(manifest, index, Box::pin(s))),
+        (Some(manifest), None) if zip => Body::from_stream(bundle::zip_manifest(manifest.clone(), Box::pin(s))),
+        _ => match &converted {
+            Some(to) => Body::from_stream(transcode::transcode(Box::pin(s), compression.clone(), to.clone())),
+            None => Body::from_stream(s)
+        }
+    };
+
+    let mut response = Response::new(body);
+    *response.headers_mut() = response_headers;
+    Ok(response)
+}
+
+// the same headers a GET would get, without starting the download. link previews and download managers look before they download,
+// and a GET answering HEAD would use up the one download to do it
+#[utoipa::path(head, path = routes::TOKEN_PATH, tag = "transfer",
+    params(("token" = String, Path), ("path" = String, Path, description = "Any file name, or the upload key for the upload page"), ("raw" = Option<bool>, Query), ("challenge" = Option<String>, Query), ("signature" = Option<String>, Query, description = "For tokens locked to a receiver")),
+    responses((status = 200, description = "The headers the download would have"), (status = 401, description = "Locked to a receiver who didn't sign for it"), (status = 404, description = "No such token"), (status = 409, description = "Already being downloaded, or a stored upload that isn't all there yet"), (status = 410, description = "Already downloaded")))]
+pub async fn head_download(State(state): State<AppState>, Path((token, path)): Path<(String, String)>, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Result<Response<Body>, (StatusCode, Markup)> {
+    let meta = match state.get_file_metadata(&token).await {
+        Some(meta) => meta,
+        None => return Err((StatusCode::NOT_FOUND, html! {"File not found"}))
+    };
+    if meta.check_key(&path) { // the upload page
+        return Ok(([(CONTENT_TYPE, "text/html; charset=utf-8")], Body::empty()).into_response());
+    }
+    if !state.may_download(&meta, params.get("challenge"), params.get("signature"), false).await {
+        return Err((StatusCode::UNAUTHORIZED, html! {"This file can only be downloaded by the user it was sent to"}));
+    }
+    downloadable(&meta)?;
+    let (part, zip) = requested_part(&meta, &params)?;
+
+    // a dropped download that will carry on doesn't know how much is left
+    let resumed = meta.get_states().1 == &FileState::Paused && !meta.is_broadcast() && !meta.is_stored();
+    let expected = match resumed {
+        true => None,
+        false => meta.file_size.get_content_length()
+    };
+    let converted = conversion(&state, &meta, &headers);
+    let mut response = Response::new(Body::empty());
+    *response.headers_mut() = download_headers(&state, &meta, &params, part, zip, converted, expected);
+    Ok(response)
+}
+
+pub fn downloadable(meta: &FileMetadata) -> Result<(), (StatusCode, Markup)> {
+    if meta.download_locked() {
+        if meta.downloads_exhausted() {
+            return Err((StatusCode::GONE, html! {"File already downloaded"}));
+        }
+        return Err((StatusCode::CONFLICT, html! {"File being downloaded"}));
+    }
+    if meta.is_stored() && !meta.upload_finished() {
+        return Err((StatusCode::CONFLICT, html! {"This file is still being uploaded to the relay, it can be downloaded once the upload finishes"}));
+    }
+    Ok(())
+}
+
+// multi-file uploads come out as a zip, as one of their files, or as sent for beam down to split up itself
+fn requested_part(meta: &FileMetadata, params: &HashMap<String, String>) -> Result<(Option<usize>, bool), (StatusCode, Markup)> {
+    let raw = params.get("raw").map(|raw| raw == "true").unwrap_or(false);
+    let part = match (meta.get_manifest(), params.get("file")) {
+        (Some(manifest), Some(index)) => match index.parse::<usize>() {
+            Ok(index) if index < manifest.len() && meta.has_file_links() => Some(index),
+            Ok(index) if index < manifest.len() => return Err((StatusCode::BAD_REQUEST, html! {"Files can only be downloaded one at a time when the sender allows more than one download"})),
+            _ => return Err((StatusCode::BAD_REQUEST, html! {"There is no such file in this upload"}))
+        },
+        _ => None
+    };
+    Ok((part, meta.can_split() && part.is_none() && !raw))
+}
+
+// a compressed upload is useless to a browser that can't read its compression, so it gets converted on the way out
+fn conversion(state: &AppState, meta: &FileMetadata, headers: &HeaderMap) -> Option<Compression> {
+    let compression = meta.get_compression();
+    match compression != Compression::None && !meta.is_encrypted() && state.transcodes() {
+        true => transcode::target(headers.get(ACCEPT_ENCODING).and_then(|v| v.to_str().ok()), &compression),
+        false => None
+    }
+}
 
-    let response = Response::new(body);
-    let (mut parts, body) = response.into_parts();
+// the type of the file inside, Content-Encoding says how it's wrapped. an encrypted one is only ever age to anything on the way
+fn served_content_type(meta: &FileMetadata, part: Option<usize>, zip: bool) -> Option<String> {
+    match (meta.get_manifest(), part) {
+        _ if zip => Some("application/zip".to_string()),
+        _ if meta.shows_text() => Some("text/plain; charset=utf-8".to_string()), // snippets are always utf-8, which a browser opening one shouldn't have to guess
+        _ if meta.is_encrypted() => None,
+        (Some(manifest), Some(index)) => guess_content_type(&manifest[index].name),
+        (Some(_), None) => None, // several files back to back aren't any one type
+        (None, _) => meta.get_content_type().cloned()
+    }
+}
 
-    if let Some(content_length) = meta.file_size.get_content_length() {
+// everything about a download but its body
+fn download_headers(state: &AppState, meta: &FileMetadata, params: &HashMap<String, String>, part: Option<usize>, zip: bool, converted: Option<Compression>, expected: Option<usize>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let (content_length, default_name) = match (meta.get_manifest(), part) {
+        (Some(manifest), Some(index)) => (Some(manifest[index].size as usize), manifest[index].name.clone()),
+        (Some(_), None) if zip => (None, match meta.file_name.ends_with(".zip") {
+            true => meta.file_name.clone(),
+            false => format!("{}.zip", meta.file_name)
+        }),
+        _ => match &converted {
+            Some(_) => (None, meta.file_name.clone()),
+            None => (expected, meta.file_name.clone())
+        }
+    };
+
+    if let Some(content_length) = content_length {
         debug!("Writing content length as {}", content_length);
-        parts.headers.insert(CONTENT_LENGTH, content_length.into());
+        headers.insert(CONTENT_LENGTH, content_length.into());
+    }
+
+    if let (false, false, Some(manifest), None) = (zip, meta.shows_text(), meta.get_manifest(), part) {
+        // header values have to be ascii, so the names are percent encoded
+        match HeaderValue::from_str(&urlencoding::encode(&serde_json::to_string(manifest).unwrap_or_default())) {
+            Ok(manifest) => {
+                headers.insert(MANIFEST_HEADER, manifest);
+            },
+            Err(e) => warn!("Could not write the manifest header: {:?}", e)
+        }
+    }
+
+    let content_type = served_content_type(meta, part, zip);
+    let mut file_name = requested_file_name(params).unwrap_or(default_name);
+    if meta.is_encrypted() { // so whoever saves it knows what to open it with
+        file_name += ".age";
+    }
+    let disposition = disposition_for(state, meta, params, content_type.as_deref());
+    match HeaderValue::from_str(&content_disposition(&file_name, &disposition)) {
+        Ok(disposition) => {
+            headers.insert(CONTENT_DISPOSITION, disposition);
+        },
+        Err(e) => warn!("Could not write content disposition for {}: {:?}", file_name, e)
+    }
+
+    // the browser goes by the type the relay gives, and anything that could script is opened without the relay's origin
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    if !shows_safely(content_type.as_deref()) {
+        headers.insert(CONTENT_SECURITY_POLICY, HeaderValue::from_static("sandbox"));
     }
+    let content_type = content_type.and_then(|content_type| HeaderValue::from_str(&content_type).ok());
+    headers.insert(CONTENT_TYPE, content_type.unwrap_or(HeaderValue::from_static("application/octet-stream")));
 
-    if meta.get_compression() != Compression::None {
-        debug!("Writing compression as {:?}", meta.get_compression());
-        parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_str(meta.get_compression().to_string().as_str()).unwrap());
+    // the compression is inside the encryption, so nothing on the way can undo it
+    let compression = meta.get_compression();
+    let transcoding = compression != Compression::None && !meta.is_encrypted() && state.transcodes();
+    let encoding = converted.unwrap_or(compression);
+    if encoding != Compression::None && !meta.is_encrypted() {
+        debug!("Writing compression as {:?}", encoding);
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_str(encoding.to_string().as_str()).unwrap());
     };
+    if transcoding { // caches in between shouldn't hand one requester's encoding to another
+        headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    headers
+}
+
+// only the holder of the upload key (the uploader, or the creator of a reverse upload) can read the log
+#[utoipa::path(get, path = routes::TOKEN_LOG, tag = "transfer",
+    params(("token" = String, Path), ("key" = String, Query, description = "The upload key")),
+    responses((status = 200, description = "Everything that happened to the token", body = Vec<LoggedEvent>), (status = 403, description = "Wrong key"), (status = 404, description = "No such token")))]
+pub async fn token_log(State(state): State<AppState>, Path(token): Path<String>, requester: Requester, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Response<Body> {
+    let key = match params.get("key") {
+        Some(key) => key.clone(),
+        None => { // without a key this is just a download of a file named "log"
+            return download(State(state), Path((token, "log".to_string())), requester, headers, Query(params)).await.into_response();
+        }
+    };
+
+    let meta = match state.get_file_metadata(&token).await {
+        Some(meta) => meta,
+        None => return (StatusCode::NOT_FOUND, html! {"File not found"}).into_response()
+    };
+
+    if !meta.check_key(&key) {
+        return (StatusCode::FORBIDDEN, html! {"File has a different key"}).into_response();
+    }
 
-    Ok(Response::from_parts(parts, body))
+    let events: Vec<LoggedEvent> = state.get_events(&token).await;
+    Json(events).into_response()
+}
+
+// EventSource always asks for text/event-stream, anything else is a download of a file named "events"
+async fn token_events(State(state): State<AppState>, Path(token): Path<String>, requester: Requester, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Response<Body> {
+    let wants_events = headers.get("Accept").and_then(|a| a.to_str().ok()).map(|accept| accept.contains("text/event-stream")).unwrap_or(false);
+    if !wants_events {
+        return download(State(state), Path((token, "events".to_string())), requester, headers, Query(params)).await.into_response();
+    }
 
-    // on fail, return the downloader
+    if state.get_file_metadata(&token).await.is_none() {
+        return (StatusCode::NOT_FOUND, html! {"File not found"}).into_response();
+    }
+
+    let updates = live::updates(state, token, false).map(|update| match update {
+        StatusUpdate::Status { metadata } => Event::default().event("status").json_data(metadata),
+        StatusUpdate::Event { event } => Event::default().event("event").json_data(event),
+        StatusUpdate::Cancelled { reason } => Ok(Event::default().event("cancelled").data(reason)),
+    });
+    Sse::new(updates).keep_alive(KeepAlive::default()).into_response()
 }
 
-async fn get_download(State(state): State<AppState>, Path(token): Path<String>, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Result<impl IntoResponse, (StatusCode, Markup)> {
+#[utoipa::path(get, path = routes::TOKEN, tag = "transfer",
+    params(("token" = String, Path), ("status" = Option<bool>, Query, description = "Only the redacted metadata, which also keeps the token alive")),
+    responses((status = 200, description = "With ?status=true the metadata, otherwise the landing page for browsers", body = FileMetadata), (status = 303, description = "On to the file under its name"), (status = 404, description = "No such token"), (status = 410, description = "Its downloads are used up")))]
+pub async fn get_download(State(state): State<AppState>, Path(token): Path<String>, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Result<impl IntoResponse, (StatusCode, Markup)> {
     debug!("Attempting download check to {token}");
     let meta = match state.get_file_metadata(&token).await {
         Some(meta) => meta,
@@ -200,21 +808,9 @@ async fn get_download(State(state): State<AppState>, Path(token): Path<String>,
         }
     };
 
-    let return_metadata: bool = match params.get("status") {
-        Some(m_str) => match m_str.parse() {
-            Ok(q) => q,
-            Err(_) => false
-        },
-        None => false
-    };
+    let return_metadata = params.get("status").is_some_and(|m_str| m_str.parse().unwrap_or_default());
 
-    let stream_metadata: bool = match params.get("stream") {
-        Some(m_str) => match m_str.parse() {
-            Ok(q) => q,
-            Err(_) => false
-        },
-        None => false
-    };
+    let stream_metadata = params.get("stream").is_some_and(|m_str| m_str.parse().unwrap_or_default());
 
     if stream_metadata {
         let s =  stream! {
@@ -248,8 +844,13 @@ async fn get_download(State(state): State<AppState>, Path(token): Path<String>,
         return Ok(Json(meta.redact()).into_response());
     }
 
+    let direct = state.is_direct(&meta);
+
     if meta.download_locked() {
-        if meta.download_finished() {
+        if direct { // there is nothing to stream, and no page to explain why
+            return Err((StatusCode::NOT_FOUND, html! {}));
+        }
+        if meta.downloads_exhausted() {
             return Err((StatusCode::GONE, html! {"File already downloaded"}));
         }
         return Err((StatusCode::CONFLICT, html! {"File being downloaded"}));
@@ -259,21 +860,24 @@ async fn get_download(State(state): State<AppState>, Path(token): Path<String>,
 
     let user_agent = headers.get("User-Agent");
 
-    let query_download: bool = match params.get("download") {
-        Some(query_download) => match query_download.parse() {
-            Ok(query_download) => query_download,
-            Err(_) => false
-        },
-        None => false
-    };
+    let query_download = params.get("download").is_some_and(|query_download| query_download.parse().unwrap_or_default());
     let agent = match user_agent {
-        Some(user_agent) => user_agent.to_str().unwrap(),
+        Some(user_agent) => user_agent.to_str().unwrap_or(""),
         None => ""
     };
+    let accept = headers.get("Accept").and_then(|a| a.to_str().ok());
 
-    if (agent.starts_with("Mozilla") || agent.starts_with("WhatsApp")) && !query_download {
+    if state.is_browser(accept, agent) && !query_download && !direct {
         debug!("User agent is web ({}), sending landing", agent);
         let file_size_string = meta.file_size.get_file_string();
+        let disposition = disposition_for(&state, &meta, &params, served_content_type(&meta, None, meta.can_split()).as_deref());
+        let mut download_link = "?download=true".to_string();
+        if let Some(name) = requested_file_name(&params) {
+            download_link += &format!("&filename={}", urlencoding::encode(&name));
+        }
+        if let Some(requested) = params.get("disposition") {
+            download_link += &format!("&disposition={}", urlencoding::encode(requested));
+        }
         return Err((StatusCode::from_u16(200).unwrap(),
         html! { // this could be prettier, although it's not meant to be too complex
         // some simple CSS down the line may be helpful
@@ -288,15 +892,79 @@ async fn get_download(State(state): State<AppState>, Path(token): Path<String>,
                 }
                 body {
                     h1 {"ByteBeam File Download"}
-                    p { "This download can only be started once. If it fails, you will need to ask the sender to re-upload"}
+                    @if let Some(banner) = state.get_banner() {
+                        p { i {(banner)} }
+                    }
+                    @if state.is_draining() {
+                        p { b {"This relay is draining for maintenance. This download is still available."} }
+                    }
+                    @if let Some(message) = meta.get_message() {
+                        p { "Message from the sender: " b {(message)} }
+                    }
+                    @if let Some(receiver) = meta.get_receiver() {
+                        p { b {"This file was sent to " code {(receiver)} "."} " Only they can download it, with " code {"beam down"} " and one of their ssh keys." }
+                    }
+                    @if meta.is_encrypted() {
+                        p { b {"This file is end-to-end encrypted."} " Downloading it here gives you the encrypted " code {".age"} " file. Use " code {"beam down"} " or " code {"age --decrypt"} " with the passphrase or key from the sender to open it." }
+                    }
+                    @if let Some(files) = meta.get_manifest() {
+                        p { (files.len()) " files were sent together:" }
+                        ul {
+                            @for (index, file) in files.iter().enumerate() {
+                                li {
+                                    @if meta.has_file_links() {
+                                        a href=(state.public_url().link(&format!("{}?file={index}", routes::for_token_path(routes::TOKEN_PATH, &token, &urlencoding::encode(&file.name))))) download {(file.name)}
+                                    } @else {
+                                        (file.name)
+                                    }
+                                    " (" (ByteSize(file.size).to_string_as(true)) ")"
+                                }
+                            }
+                        }
+                        @if meta.has_file_links() {
+                            p { "Each file link, and the zip of all of them, counts as one of the " (meta.downloads_remaining()) " downloads left." }
+                        } @else if meta.can_split() {
+                            p { "They can only be downloaded once, all together." }
+                        } @else {
+                            p { "The sender compressed or encrypted these files together, so they can only be split back up with " code {"beam down"} "." }
+                        }
+                    } @else if meta.shows_text() && !meta.is_broadcast() {
+                        p { "This text can only be shown once, so copy it before leaving the page."}
+                    } @else {
+                        p { "This download can only be started once. If it fails, you will need to ask the sender to re-upload"}
+                    }
+                    div id="live" data-events=(state.public_url().link(&routes::for_token(routes::TOKEN_EVENTS, &urlencoding::encode(&token)))) {
+                        p id="status" { // the script keeps this up to date, this is what shows without it
+                            @if meta.upload_locked() {"The sender is uploading. Ready to download."} @else {"Waiting for the sender to start the upload."}
+                        }
+                        progress id="progress" {}
+                    }
                     ul {
                         li {"File name: " (&meta.file_name)}
-                        li {"Uncompressed file size: " (&file_size_string)}
+                        li {"Uncompressed file size: " span id="size" {(&file_size_string)}}
                         li {"Compression: " (&meta.get_compression().to_string())}
+                        li {"Opens as: " @if disposition == Disposition::Inline {"shown in the browser"} @else {"saved as a file"}}
+                    }
+                    @if meta.get_receiver().is_some() {
+                        // the browser has no key to sign with
+                    } @else if meta.shows_text() {
+                        // reading it is the download, the script does it as soon as the page opens
+                        div id="text" data-source=(download_link) {
+                            p id="text-status" {"Loading the text..."}
+                            pre id="text-content" style="white-space: pre-wrap; border: 1px solid gray; padding: 1em" hidden {}
+                            button id="copy-text" type="button" hidden {"Copy"}
+                            noscript { a href = (download_link) {"Click here to open the text"} }
+                        }
+                    } @else if meta.can_split() {
+                        a href = (download_link) download {"Click here to download all of them as a zip"}
+                    } @else if disposition == Disposition::Inline {
+                        a href = (download_link) {"Click here to open the file"}
+                    } @else {
+                        a href = (download_link) download {"Click here to start the download"}
                     }
-                    a href = "?download=true" download {"Click here to start the download"}
                     br;
                     i {"You may also download using curl or wget using this same url"} // should we give example commands?
+                    script { (PreEscaped(include_str!("landing.js"))) }
                 }
             }
         }
@@ -305,76 +973,197 @@ async fn get_download(State(state): State<AppState>, Path(token): Path<String>,
 
     // nothing is locked so we can just redirect
 
-    debug!("Redirecting download to {token}/{}", meta.file_name);
-    Ok(Redirect::temporary(format!("/{token}/{}", meta.file_name).as_str()).into_response())
+    // overrides are carried through as the download route reads them from the query
+    let mut query = vec![];
+    let name = match requested_file_name(&params) {
+        Some(name) => {
+            query.push(format!("filename={}", urlencoding::encode(&name)));
+            name
+        },
+        None => meta.file_name.clone()
+    };
+    if let Some(requested) = params.get("disposition") {
+        query.push(format!("disposition={}", urlencoding::encode(requested)));
+    }
+    for carried in ["raw", "file", "challenge", "signature"] {
+        if let Some(value) = params.get(carried) {
+            query.push(format!("{carried}={}", urlencoding::encode(value)));
+        }
+    }
+    let redirect = routes::for_token_path(routes::TOKEN_PATH, &token, &urlencoding::encode(&name));
+    let redirect = match query.is_empty() {
+        true => redirect,
+        false => format!("{redirect}?{}", query.join("&"))
+    };
+    let redirect = state.public_url().link(&redirect);
+    debug!("Redirecting download to {redirect}");
+    Ok(Redirect::temporary(redirect.as_str()).into_response())
 
 }
 
 // this will return a lock/link to do the upload to
 #[axum::debug_handler]
-async fn make_upload(State(state): State<AppState>, Path(path): Path<String>, Form(params): Form<HashMap<String, String>>) -> Result<Json<FileMetadata>, (StatusCode, Markup)> {
+#[utoipa::path(post, path = routes::TOKEN, tag = "transfer",
+    params(("token" = String, Path, description = "File name for a new token, or an existing token to sign for along with its challenge")),
+    request_body(content = CreateForm, content_type = "application/x-www-form-urlencoded"),
+    responses((status = 200, description = "The new token with its upload key, and a challenge to sign", body = FileMetadata), (status = 403, description = "Members only, or a challenge that didn't verify"), (status = 409, description = "The token name is taken"), (status = 429, description = "Too many tokens"), (status = 503, description = "Draining or read-only")))]
+pub async fn make_upload(State(state): State<AppState>, Path(path): Path<String>, requester: Requester, identity: Option<Extension<ClientIdentity>>, Form(params): Form<HashMap<String, String>>) -> Result<Json<FileMetadata>, (StatusCode, Markup)> {
     // new: anyone can call for an upload token, however it will be limited unless authenticated
-    // rate limits may be good to add here, collisions are highly unlikely with uuids, however dealing with this takes compute!
+    // collisions are highly unlikely with uuids, however dealing with this takes compute, so token_rate limits each address
 
-    // this effectively has two paths, of "path" is a token, this is an upgrade 
-    match state.get_file_metadata(&path).await {
-        Some(_) => { // we have to do an upgrade
+    // this effectively has two paths, if "path" is a token and a challenge was signed for it, this is an upgrade.
+    // otherwise it's a file name, even one that happens to match a token, so `beam up notes` works next to a token called notes
+    match (state.get_file_metadata(&path).await, params.contains_key("challenge")) {
+        (Some(_), true) => { // we have to do an upgrade
             let challenge = match params.get("challenge") {
                 Some(challenge) => challenge,
                 None => return Err((StatusCode::BAD_REQUEST, html! {"Missing challenge parameter"})),
             };
 
             // allows JSON but also will allow single entry
-            let tests: Vec<String> = match serde_json::from_str(&challenge) {
+            let tests: Vec<String> = match serde_json::from_str(challenge) {
                 Ok(tests) => tests,
                 Err(_) => vec![challenge.to_string()],
             };
 
-            let resp = match state.upgrade(&path, &tests).await {
-                Some(metadata) => {
+            let mut resp = match state.upgrade(&path, &tests).await {
+                Ok(metadata) => {
                     debug!("Challenge passed. New metadata: {:?}", metadata);
                     metadata
                 },
-                None => return Err((StatusCode::UNAUTHORIZED, html! {"Challenge failed"})),
+                Err((status, message)) => return Err((status, html! {(message)})),
             };
 
+            resp.banner = state.get_banner().cloned();
+            resp.frames = Some(FRAMES.to_string());
             Ok(Json(resp))
         },
-        None => { // we are doing a new upload
+        _ => { // we are doing a new upload
+            if state.is_draining() {
+                debug!("Refusing new upload for {path}, relay is draining");
+                return Err((StatusCode::SERVICE_UNAVAILABLE, html! {"This relay is draining for maintenance and is not accepting new uploads"}));
+            }
+            if state.is_read_only() {
+                debug!("Refusing new upload for {path}, relay is read-only");
+                return Err((StatusCode::SERVICE_UNAVAILABLE, html! {"This relay is read-only and is not accepting new uploads"}));
+            }
+            if !state.allow_new_token(&requester.address).await {
+                debug!("Refusing new upload for {path}, {} is asking for tokens too quickly", requester.address);
+                return Err((StatusCode::TOO_MANY_REQUESTS, html! {"Too many new uploads from this address, try again later"}));
+            }
             let username = params.get("user");
             debug!("{:?}", username);
-            match state.generate_file_upload(&path, username).await {
-                    Some(file_metadata) => {
+            // a client certificate for the same user stands in for the ssh challenge, the relay's CA already vouches for them
+            let certified = match (identity, username) {
+                (Some(Extension(ClientIdentity(Some(certified)))), Some(username)) => certified == *username,
+                _ => false
+            };
+            if !certified && !state.may_create(username) {
+                debug!("Refusing new upload for {path}, {:?} is not a member", username);
+                return Err((StatusCode::UNAUTHORIZED, html! {"This relay only accepts uploads from its members"}));
+            }
+            // tokens minted ahead of time ask to wait this many seconds for their upload
+            let lifetime = match params.get("expires").map(|e| e.parse::<i64>().ok().and_then(TimeDelta::try_seconds)) {
+                Some(Some(lifetime)) if lifetime > TimeDelta::zero() => Some(lifetime),
+                Some(_) => return Err((StatusCode::BAD_REQUEST, html! {"expires must be a positive number of seconds"})),
+                None => None
+            };
+            // only this user can download, so it has to be someone the relay can check signatures for
+            let receiver = match params.get("receiver").map(|r| r.trim().to_string()) {
+                Some(receiver) if state.has_keys_for(&receiver) => Some(receiver),
+                Some(receiver) => return Err((StatusCode::BAD_REQUEST, html! {"This relay has no keys for the receiver " (receiver)})),
+                None => None
+            };
+            // picking the token is for users who prove who they are up front, signing "token-name/[name]:[user]:[unix time]:[nonce]",
+            // otherwise anyone could hold a name until it's culled by asking for it under someone else's username
+            let token_name = params.get("token-name");
+            if let Some(name) = token_name {
+                let signed = match (username, params.get("name-challenge"), params.get("name-signature")) {
+                    (Some(username), Some(challenge), Some(signature)) => {
+                        let signatures: Vec<String> = serde_json::from_str(signature).unwrap_or_else(|_| vec![signature.to_string()]);
+                        state.verify_once(&format!("token-name/{name}"), username, challenge, &signatures).await
+                    },
+                    _ => false
+                };
+                if !certified && !signed {
+                    debug!("Refusing custom token for {path}, {:?} didn't authenticate", username);
+                    return Err((StatusCode::FORBIDDEN, html! {"Custom tokens are only for users who sign for them or present a client certificate"}));
+                }
+            }
+            let store = params.get("store").map(|store| store == "true").unwrap_or(false);
+            if store && !state.can_store() {
+                return Err((StatusCode::BAD_REQUEST, html! {"This relay has no storage to keep uploads in, both sides need to be online"}));
+            }
+            // the path is the file name, and %2F gets through the router as a slash
+            let file_name = safe_file_name(&path).unwrap_or("file".to_string());
+            match state.generate_file_upload(&file_name, username, token_name).await {
+                    Ok(mut file_metadata) => {
                         debug!("Generated upload token for {path}");
+                        if let (true, Some(username)) = (certified, username) {
+                            match state.upgrade_certified(file_metadata.get_token(), username).await {
+                                Ok(upgraded) => file_metadata = upgraded,
+                                Err((StatusCode::TOO_MANY_REQUESTS, message)) => return Err((StatusCode::TOO_MANY_REQUESTS, html! {(message)})),
+                                Err((_, message)) => warn!("Could not upgrade {path} with its client certificate: {message}")
+                            }
+                        }
+                        if let Some(lifetime) = lifetime {
+                            if let Some(updated) = state.set_lifetime(file_metadata.get_token(), lifetime).await {
+                                file_metadata = updated;
+                            }
+                        }
+                        if store {
+                            if let Some(updated) = state.set_storage(file_metadata.get_token()).await {
+                                file_metadata = updated;
+                            }
+                        }
+                        if let Some(receiver) = receiver {
+                            if let Some(updated) = state.set_receiver(file_metadata.get_token(), receiver).await {
+                                file_metadata = updated;
+                            }
+                        }
+                        if let Some(max) = params.get("max-downloads").and_then(|m| m.parse::<usize>().ok()) {
+                            state.set_max_downloads(file_metadata.get_token(), max).await;
+                            file_metadata = state.get_file_metadata(file_metadata.get_token()).await.unwrap_or(file_metadata);
+                        }
+                        file_metadata.banner = state.get_banner().cloned();
+                        file_metadata.frames = Some(FRAMES.to_string());
                         // we may also want to allow options to be included in the upload
                         Ok(Json(file_metadata))
                     },
-                    None => {
-                        debug!("Failed to generate lock token for {path}. User likely did not use main token");
-                        Err((StatusCode::UNAUTHORIZED, html! {"Unauthorized" }))
+                    Err((status, message)) => {
+                        debug!("Failed to generate lock token for {path}: {message}");
+                        Err((status, html! {(message)}))
                     }
                 }
         }
     }
 }
 
-async fn upload(State(state): State<AppState>, Path((token, key)): Path<(String, String)>, mut multipart: Multipart) -> impl IntoResponse { // "path" is actually the key
+#[utoipa::path(post, path = routes::TOKEN_PATH, tag = "transfer",
+    params(("token" = String, Path), ("path" = String, Path, description = "The upload key")),
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses((status = 200, description = "Uploaded, once the downloader (or storage) has all of it"), (status = 403, description = "Wrong key"), (status = 409, description = "Already uploading"), (status = 413, description = "Past the tier's max_upload_size"), (status = 507, description = "The tier's store_limit is full")))]
+pub async fn upload(State(state): State<AppState>, Path((token, key)): Path<(String, String)>, requester: Requester, mut multipart: Multipart) -> impl IntoResponse { // "path" is actually the key
     
-    let (upload, upload_options) = match state.begin_upload(&token, &key).await {
+    let (upload, upload_options) = match state.begin_upload(&token, &key, &requester.address).await {
         Ok(res) => res,
         Err(e) => {
             return e.into_response();
         }
     };
+    info!("Upload to {} started by {}", token, requester.address);
 
+    let started = std::time::Instant::now();
     let block_size = upload_options.get_block_size();
-    let delay_time = upload_options.get_delay_time();
+    let throttle = upload_options.get_throttle();
 
-    trace!("Starting upload for {} with a delay size of {:?}", token, delay_time);
+    trace!("Starting upload for {} with a rate limit of {:?} and {:?} in total", token, upload_options.get_rate_limit(), upload_options.get_total_rate_limit());
 
+    let mut framed = false;
+    let mut resume_from = 0;
     // now we just need to allow the upload!
     while let Ok(field_raw) = multipart.next_field().await {
-        let mut field = match field_raw {
+        let field = match field_raw {
             Some(field) => field,
             None => {
                 error!("Form data incorrect, did the stream end early?");
@@ -388,7 +1177,7 @@ async fn upload(State(state): State<AppState>, Path((token, key)): Path<(String,
             debug!("User is attempting set size");
             let content = field.text().await.unwrap();
             // DONT unwrap the parse here!
-            state.set_metadata(&token, None, Some(content.parse::<usize>().unwrap()), None).await;
+            state.set_metadata(&token, None, Some(content.parse::<usize>().unwrap()), None, None).await;
             debug!("User set file size {}", content);
             continue;
         }
@@ -398,100 +1187,345 @@ async fn upload(State(state): State<AppState>, Path((token, key)): Path<(String,
             let content = field.text().await.unwrap();
             // DONT unwrap the parse here!
             // does it matter?
-            state.set_metadata(&token, None, None, Some(Compression::from_str(content.as_str()).unwrap())).await;
+            state.set_metadata(&token, None, None, Some(Compression::from_str(content.as_str()).unwrap()), None).await;
             debug!("User set compression {}", content);
             continue;
         }
 
-        // now get upload things
-        info!("Upload to path {} had receiver... sending", name);
+        if name == "frames" {
+            framed = field.text().await.unwrap_or_default() == FRAMES;
+            continue;
+        }
 
-        let mut buffer = BytesMut::new();
-        let bytes_counter = Arc::new(AtomicUsize::new(0));
-        let bytes_counter_clone = bytes_counter.clone();
-
-        // Spawn a separate tokio task to handle the updates
-            let update_handle = {
-            let state = state.clone();
-            let token = token.clone();
-            tokio::spawn(async move {
-                let mut updown = (0, 0);
-                
-                loop {
-                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                    
-                    let bytes = bytes_counter.swap(0, Ordering::Relaxed);
-                    if bytes > 0 {
-                        updown = match state.increase_upload_download_numbers(&token, bytes, 0).await {
-                            Some((uploaded, downloaded)) => (uploaded, downloaded),
-                            None => {
-                                warn!("Failed to get upload/download numbers");
-                                updown
-                            }
-                        };
-                    }
-                }
-            })
-        };
+        if name == "resume-from" { // where this try starts, after a damaged frame stopped the last one
+            resume_from = field.text().await.unwrap_or_default().parse::<usize>().unwrap_or(0);
+            continue;
+        }
 
-        while let Some(chunk) = field.chunk().await.unwrap() {
-            bytes_counter_clone.fetch_add(chunk.len(), Ordering::Relaxed);
-            buffer.put(chunk);
-
-            while buffer.len() >= block_size {
-                let chunk_data = buffer.split_to(block_size).to_vec();
-                match upload.send(chunk_data).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Failed to send chunk: {:?}. Upload ended prematurely?", e);
-                        return "Failed to send a chunk... upload may have failed".into_response();
+        if name == "disposition" {
+            let content = field.text().await.unwrap_or_default();
+            match Disposition::from_str(content.as_str()) {
+                Ok(disposition) => {
+                    state.set_metadata(&token, None, None, None, Some(disposition)).await;
+                    debug!("User set disposition {}", content);
+                },
+                Err(e) => warn!("Ignoring disposition from upload: {}", e)
+            }
+            continue;
+        }
+
+        if name == "file-name" {
+            let content = field.text().await.unwrap_or_default();
+            if let Some(renamed) = safe_file_name(&content) {
+                debug!("User renamed upload to {}", renamed);
+                state.set_metadata(&token, Some(renamed), None, None, None).await;
+            }
+            continue;
+        }
+
+        if name == "max-downloads" {
+            let content = field.text().await.unwrap_or_default();
+            match content.parse::<usize>() {
+                Ok(max) => if !state.set_max_downloads(&token, max).await {
+                    warn!("Could not make {} a broadcast, a download already started", token);
+                },
+                Err(e) => warn!("Ignoring max-downloads from upload: {}", e)
+            }
+            continue;
+        }
+
+        if name == "message" {
+            let content = field.text().await.unwrap_or_default();
+            if !content.trim().is_empty() {
+                state.set_message(&token, content.trim().to_string()).await;
+            }
+            continue;
+        }
+
+        if name == "attributes" {
+            let content = field.text().await.unwrap_or_default();
+            match serde_json::from_str::<FileAttributes>(&content) {
+                Ok(attributes) => {
+                    state.set_attributes(&token, attributes).await;
+                },
+                Err(e) => warn!("Ignoring attributes from upload: {}", e)
+            }
+            continue;
+        }
+
+        if name == "manifest" {
+            let content = field.text().await.unwrap_or_default();
+            match serde_json::from_str::<Vec<ManifestEntry>>(&content) {
+                Ok(manifest) if manifest.is_empty() || manifest.len() > MAX_MANIFEST_FILES => warn!("Ignoring manifest with {} files, it has to have 1 to {}", manifest.len(), MAX_MANIFEST_FILES),
+                Ok(manifest) => {
+                    // the names end up in zips and download names, so they can't be paths
+                    let manifest = manifest.into_iter().map(|file| ManifestEntry {
+                        name: safe_file_name(&file.name).unwrap_or("file".to_string()),
+                        size: file.size,
+                        attributes: file.attributes,
+                        link: file.link
+                    }).collect();
+                    if !state.set_manifest(&token, manifest).await {
+                        warn!("Could not set the manifest for {}, a download already started", token);
                     }
-                }
+                },
+                Err(e) => warn!("Ignoring manifest from upload: {}", e)
+            }
+            continue;
+        }
 
+        if name == "encrypted" {
+            let content = field.text().await.unwrap_or_default();
+            state.set_encrypted(&token, content == "true").await;
+            continue;
+        }
 
-                if upload.is_closed() {
-                    error!("Upload failed");
-                    return "Upload failed".into_response();
-                }
-                // we dont need to delay or try to if it doesnt exist
-                if let Some(delay) = delay_time {
-                    let std_duration = std::time::Duration::from_millis(delay.num_milliseconds() as u64); // micro/nano may be a better idea
-                    tokio::time::sleep(std_duration).await;
-                }
+        if name == "text" {
+            let content = field.text().await.unwrap_or_default();
+            state.set_text(&token, content == "true").await;
+            continue;
+        }
+
+        if name == "direct" {
+            let content = field.text().await.unwrap_or_default();
+            state.set_direct(&token, content == "true").await;
+            continue;
+        }
+
+        if name == "content-type" {
+            let content = field.text().await.unwrap_or_default();
+            match content.parse::<mime_guess::Mime>() {
+                Ok(content_type) => {
+                    state.set_content_type(&token, content_type.essence_str().to_string()).await;
+                },
+                Err(e) => warn!("Ignoring content type from upload: {}", e)
             }
+            continue;
         }
 
-        match upload.send(buffer.to_vec()).await {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to send final chunk: {:?}", e);
+        if name == "checksum" {
+            let content = field.text().await.unwrap_or_default();
+            match Checksum::from_str(content.as_str()) {
+                Ok(checksum) => {
+                    state.set_checksum(&token, checksum).await;
+                    debug!("User set checksum {}", content);
+                },
+                Err(e) => warn!("Ignoring checksum from upload: {}", e)
             }
+            continue;
+        }
+
+        // now get upload things
+        info!("Upload to path {} had receiver... sending", name);
+        return send_body(&state, &token, upload, block_size, throttle, started, framed.then_some(resume_from), field).await;
+    }
+    "An error occured (form has incomplete fields)".to_string().into_response()
+}
+
+// a PUT with an offset is one piece of a chunked upload, without one the body is the whole file
+#[utoipa::path(put, path = routes::TOKEN_PATH, tag = "transfer",
+    params(("token" = String, Path), ("path" = String, Path, description = "The upload key"), ("offset" = Option<u64>, Query, description = "Where this piece goes, for uploads sent in pieces"), ("last" = Option<bool>, Query, description = "Set on the final piece")),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses((status = 200, description = "Uploaded, or for a piece how much the relay has"), (status = 403, description = "Wrong key"), (status = 409, description = "Already uploading")))]
+pub async fn put_upload(State(state): State<AppState>, Path((token, key)): Path<(String, String)>, requester: Requester, Query(params): Query<HashMap<String, String>>, headers: HeaderMap, body: Body) -> Response<Body> {
+    if params.contains_key("offset") {
+        return chunked::upload_chunk(State(state), Path((token, key)), requester, Query(params), body).await;
+    }
+
+    let (upload, upload_options) = match state.begin_upload(&token, &key, &requester.address).await {
+        Ok(res) => res,
+        Err(e) => return e.into_response()
+    };
+    let block_size = upload_options.get_block_size();
+    let throttle = upload_options.get_throttle();
+    info!("Upload to {} started by {} as a raw body", token, requester.address);
+
+    // curl sends the length of the file, which is as good as the file-size field of a form
+    if let Some(size) = headers.get(CONTENT_LENGTH).and_then(|size| size.to_str().ok()).and_then(|size| size.parse::<usize>().ok()) {
+        state.set_metadata(&token, None, Some(size), None, None).await;
+    }
+    send_body(&state, &token, upload, block_size, throttle, std::time::Instant::now(), None, body.into_data_stream()).await
+}
+
+// everything after the upload is locked: the body goes out to the downloader in blocks, checked against the quota and checksum on the way.
+// shared by the form upload and anything else that has the file as a stream
+// a framed upload says where it carries on from, anything else is taken as it comes
+#[allow(clippy::too_many_arguments)]
+pub async fn send_body<S, E>(state: &AppState, token: &String, upload: Sender<Vec<u8>>, block_size: usize, mut throttle: Option<Throttle>, started: std::time::Instant, framed_from: Option<usize>, mut body: S) -> Response<Body>
+where S: Stream<Item = Result<Bytes, E>> + Unpin, E: std::fmt::Display {
+    let meta = state.get_file_metadata(token).await;
+
+    // an upload that didn't say what it is is looked up by its name, once the name can't change anymore
+    if let Some(content_type) = meta.as_ref().filter(|meta| meta.get_content_type().is_none() && meta.get_manifest().is_none()).and_then(|meta| guess_content_type(&meta.file_name)) {
+        state.set_content_type(token, content_type).await;
+    }
+
+    // compressed, encrypted or delta uploads can't be checked here, the downloader checks them after decoding
+    let verifier = match meta {
+        Some(meta) if meta.get_compression() == Compression::None && !meta.is_encrypted() && meta.get_delta() != Delta::Sent => meta.get_checksum()
+            .and_then(|checksum| checksum.algorithm.hasher().map(|hasher| (checksum, DigestWorker::new(hasher)))),
+        _ => None
+    };
+
+    let mut buffer = BytesMut::new();
+    let counters = match state.get_counters(token).await {
+        Some(counters) => counters,
+        None => {
+            error!("Upload has no byte counters, was it deleted?");
+            return (StatusCode::GONE, "Upload no longer exists").into_response();
         }
+    };
+    let (verifier, mut incoming) = match frames::resume(state, token, verifier, framed_from.is_some(), framed_from.unwrap_or(0), counters.uploaded()).await {
+        Ok(resumed) => resumed,
+        Err(response) => return response
+    };
 
-        match upload.send(vec![]).await {
-            Ok(_) => (),
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
             Err(e) => {
-                error!("Failed to send close signal: {:?}", e);
+                error!("Upload to {} ended early: {}", token, e);
+                state.abort_upload(token, format!("The upload ended early: {}", e)).await;
+                return (StatusCode::BAD_REQUEST, "The upload ended before all of it was sent").into_response();
             }
+        };
+        let chunk = incoming.data(chunk);
+        state.count_upload(token, &counters, chunk.len()).await;
+        if counters.is_cancelled() {
+            info!("Upload to {} stopped, the token was deleted", token);
+            return (StatusCode::GONE, "Upload no longer exists").into_response();
+        }
+        if counters.over_quota() {
+            info!("Upload to {} stopped, it went over its quota", token);
+            state.stop_over_quota(token, counters.uploaded()).await;
+            return (StatusCode::PAYLOAD_TOO_LARGE, "Upload went over the size, daily, or storage limit of this relay").into_response();
+        }
+        if let Some((_, worker)) = &verifier {
+            worker.update(chunk.clone()).await;
         }
+        buffer.put(chunk);
 
-        let final_bytes = bytes_counter_clone.load(Ordering::Relaxed);
-        state.increase_upload_download_numbers(&token, 0, final_bytes).await;
-        state.end(&token).await;
-        update_handle.abort();
+        while buffer.len() >= block_size {
+            let chunk_data = buffer.split_to(block_size).to_vec();
+            match upload.send(chunk_data).await {
+                Ok(_) => (),
+                Err(e) => {
+                    error!("Failed to send chunk: {:?}. Upload ended prematurely?", e);
+                    state.log_event(token, TokenEvent::Error { message: "Downloader went away during the upload".to_string() }).await;
+                    return "Failed to send a chunk... upload may have failed".into_response();
+                }
+            }
+
+
+            if upload.is_closed() {
+                error!("Upload failed");
+                state.log_event(token, TokenEvent::Error { message: "Upload channel closed".to_string() }).await;
+                return "Upload failed".into_response();
+            }
+            if let Some(throttle) = &mut throttle {
+                throttle.take(block_size).await;
+            }
+        }
+        if incoming.is_damaged() {
+            return frames::park(state, token, &upload, buffer, verifier, counters.uploaded()).await;
+        }
+    }
+    if !incoming.finish() {
+        return frames::park(state, token, &upload, buffer, verifier, counters.uploaded()).await;
+    }
+
+    match upload.send(buffer.to_vec()).await {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Failed to send final chunk: {:?}", e);
+        }
+    }
+
+    // without the close signal the downloader sees the upload as dropped instead of complete
+    if let Some((expected, worker)) = verifier {
+        let actual = worker.finish().await.unwrap_or_default();
+        if actual != expected.digest {
+            error!("Checksum mismatch for {}: expected {}, got {}", token, expected, actual);
+            state.abort_upload(token, format!("Checksum mismatch, expected {} but got {}:{}", expected, expected.algorithm, actual)).await;
+            return (StatusCode::UNPROCESSABLE_ENTITY, format!("Checksum mismatch: expected {}, got {}:{}", expected, expected.algorithm, actual)).into_response();
+        }
+        debug!("Checksum verified for {}", token);
+    }
 
-        info!("Sent file with size {} to token {}", final_bytes, &token);
-        // now we can mark upload as complete
-        if state.end_upload(&token).await {
-            return format!("Done! Sent {} bytes", final_bytes).into_response();
-        } else { // this shouldn't really happen?
-            error!("Had an issue marking the download as ended");
-            return format!("Done! Sent {} bytes, however the upload failed to be marked as complete", final_bytes).into_response();
+    match upload.send(vec![]).await {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Failed to send close signal: {:?}", e);
         }
     }
-    return format!("An error occured (form has incomplete fields)").into_response();
+
+    if let Err(e) = state.finish_storing(token).await {
+        error!("Could not store the upload to {}: {}", token, e);
+        state.abort_upload(token, format!("Could not store the upload: {}", e)).await;
+        return (StatusCode::BAD_GATEWAY, "The relay could not store the upload").into_response();
+    }
+
+    let final_bytes = counters.uploaded();
+    state.log_event(token, TokenEvent::UploadComplete { bytes: final_bytes }).await;
+    state.record_upload(token, final_bytes, started.elapsed().as_secs_f64()).await;
+
+    info!("Sent file with size {} to token {}", final_bytes, token);
+    // now we can mark upload as complete
+    if state.end_upload(token).await {
+        format!("Done! Sent {} bytes", final_bytes).into_response()
+    } else { // this shouldn't really happen?
+        error!("Had an issue marking the download as ended");
+        format!("Done! Sent {} bytes, however the upload failed to be marked as complete", final_bytes).into_response()
+    }
 }
 
-async fn remove_file(State(state): State<AppState>, Path(token): Path<String>) { // "path" is actually the key
+#[utoipa::path(delete, path = routes::TOKEN, tag = "transfer", params(("token" = String, Path)), responses((status = 200, description = "Gone, or was never there")))]
+pub async fn remove_file(State(state): State<AppState>, Path(token): Path<String>) { // "path" is actually the key
     state.delete(&token).await;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 5987's attr-char, everything else in the extended value has to be percent encoded
+    fn is_attr_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || "!#$&+-.^_`|~".contains(c)
+    }
+
+    #[test]
+    fn sends_plain_names_both_ways() {
+        assert_eq!(content_disposition("report.pdf", &Disposition::Attachment), "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf");
+        assert_eq!(content_disposition("cat.png", &Disposition::Inline), "inline; filename=\"cat.png\"; filename*=UTF-8''cat.png");
+    }
+
+    #[test]
+    fn encodes_names_browsers_disagree_on() {
+        assert_eq!(content_disposition("résumé 2024.pdf", &Disposition::Attachment),
+            "attachment; filename=\"r_sum_ 2024.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%202024.pdf");
+        assert_eq!(content_disposition("写真.jpg", &Disposition::Attachment),
+            "attachment; filename=\"__.jpg\"; filename*=UTF-8''%E5%86%99%E7%9C%9F.jpg");
+    }
+
+    #[test]
+    fn names_cant_break_out_of_the_header() {
+        for name in ["a\"b.txt", "a\\b.txt", "a\r\nSet-Cookie: x=1", "a;b=c'd.txt", "100%.txt", "tab\there"] {
+            let header = content_disposition(name, &Disposition::Attachment);
+            let (plain, extended) = header.split_once("; filename*=UTF-8''").unwrap();
+            let fallback = plain.strip_prefix("attachment; filename=\"").and_then(|rest| rest.strip_suffix('"')).unwrap();
+            assert!(fallback.chars().all(|c| c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\'), "{:?}", fallback);
+            assert!(extended.split('%').skip(1).all(|escape| escape.len() >= 2 && escape[..2].chars().all(|c| c.is_ascii_hexdigit())), "{:?}", extended);
+            assert!(extended.chars().all(|c| c == '%' || is_attr_char(c)), "{:?}", extended);
+            assert_eq!(urlencoding::decode(extended).unwrap(), name);
+            assert!(HeaderValue::from_str(&header).is_ok());
+        }
+    }
+
+    #[test]
+    fn keeps_only_the_last_part_of_a_name() {
+        assert_eq!(safe_file_name("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(safe_file_name("..\\..\\boot.ini").as_deref(), Some("boot.ini"));
+        assert_eq!(safe_file_name(" notes.txt ").as_deref(), Some("notes.txt"));
+        assert_eq!(safe_file_name("dir/"), None);
+        assert_eq!(safe_file_name(".."), None);
+    }
+}
@@ -1,32 +1,94 @@
+use std::{path::PathBuf, sync::Arc};
 use chrono::TimeDelta;
 use serde::Deserialize;
 use rand::Rng;
 use uuid::Uuid;
 
+use tokio::sync::Mutex;
+
+use crate::utils::bucket::TokenBucket;
+
+use super::throttle::Throttle;
+
+const HEX: &str = "0123456789abcdef";
+const BASE58: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz"; // no 0, O, I, or l to mix up
+const ALPHABETS: [(&str, &str); 2] = [("hex", HEX), ("base58", BASE58)];
+const MAX_PLACEHOLDER_LENGTH: usize = 128;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerOptions {
     cache_size: usize, // max size for each upload to be cached
     block_size: usize, // size of each chunk in bytes. if this is set to 0, uploads will be blocked
     cull_time: TimeDelta, // time after which an upload is removed from cache when considered stale
-    token_format: String, // This is for the path of downloads. Normally {number}-{word}-{word}-{word}. options are {number}, {word}, {uuid}
+    token_format: String, // This is for the path of downloads. Normally {number}-{word}-{word}-{word}. options are {number}, {word}, {uuid}, {hex:N}, and {base58:N}
     upload_format: String, // same as above.
-    size_update_time: TimeDelta,
-    packet_delay: Option<TimeDelta> // time to limit between each packet
+    rate_limit: Option<usize>, // bytes per second for each upload, unlimited if unset or 0
+    burst_size: Option<usize>, // bytes that can go out at full speed before the rate limit applies, defaults to one second's worth
+    total_rate_limit: Option<usize>, // bytes per second shared by every upload using these options at once, unlimited if unset or 0
+    packet_delay: Option<TimeDelta>, // old fixed delay between each block, only read to work out an equivalent rate_limit
+    spill_path: Option<String>, // folder to overflow to when the downloader falls behind, otherwise the upload waits for it
+    spill_limit: Option<usize>, // most bytes each upload can have waiting on disk, unlimited if unset
+    max_tokens: Option<usize>, // most tokens one user can hold at a time, unlimited if unset
+    max_upload_size: Option<usize>, // bytes in any one upload, unlimited if unset
+    max_bytes_per_day: Option<usize>, // bytes one user (or for public uploads, one address) can upload each day (UTC), unlimited if unset
+    store_limit: Option<usize>, // most bytes uploads kept for later can take up in storage at once, unlimited if unset
+    max_lifetime: Option<TimeDelta>, // longest a token can ask to wait with expires, so nothing is parked on the relay forever. defaults to a week
+    wordlist: Option<String>, // file with a word on each line for {word}, the built in wordle list otherwise
+    number_range: Option<(u64, u64)>, // {number} is picked from the first up to but not including the second, defaults to [0, 100]
+    #[serde(skip)]
+    words: Option<Arc<Vec<String>>>, // the wordlist once it has been read
+    #[serde(skip)]
+    total_throttle: Option<Arc<Mutex<TokenBucket>>> // the bucket for total_rate_limit, shared by every clone of these options
+}
+
+// users who share a set of options, so a policy is written once for all of them instead of per user
+#[derive(Debug, Clone, Deserialize)]
+pub struct Group {
+    members: Vec<String>, // usernames, or keys for users listed by key
+    options: ServerOptions // used instead of authenticated_options once a member authenticates
+}
+
+impl Group {
+    pub fn has_member(&self, user: &String) -> bool {
+        self.members.contains(user)
+    }
+
+    pub fn members(&self) -> &Vec<String> {
+        &self.members
+    }
+
+    pub fn options(&self) -> &ServerOptions {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut ServerOptions {
+        &mut self.options
+    }
 }
 
 impl ServerOptions {
-    pub fn new(cache_size: usize, block_size: usize, cull_time: TimeDelta, token_format: String, upload_format: String, packet_delay: Option<TimeDelta>, size_update_time: Option<TimeDelta>) -> Self {
+    pub fn new(cache_size: usize, block_size: usize, cull_time: TimeDelta, token_format: String, upload_format: String, rate_limit: Option<usize>) -> Self {
         ServerOptions {
             cache_size,
             block_size,
             cull_time,
             token_format,
             upload_format,
-            packet_delay,
-            size_update_time: match size_update_time {
-                Some(t) => t,
-                None => TimeDelta::new(1, 0).unwrap(),
-            },
+            rate_limit,
+            burst_size: None,
+            total_rate_limit: None,
+            packet_delay: None,
+            spill_path: None,
+            spill_limit: None,
+            max_tokens: None,
+            max_upload_size: None,
+            max_bytes_per_day: None,
+            store_limit: None,
+            max_lifetime: None,
+            wordlist: None,
+            number_range: None,
+            words: None,
+            total_throttle: None,
         }
     }
 
@@ -42,20 +104,107 @@ impl ServerOptions {
         self.cull_time
     }
 
-    pub fn get_delay_time(&self) -> Option<TimeDelta> {
-        self.packet_delay
+    pub fn get_rate_limit(&self) -> Option<usize> {
+        match (self.rate_limit, self.packet_delay) {
+            (Some(rate), _) => Some(rate).filter(|rate| *rate > 0), // like total_rate_limit, a bucket that never refills would wait forever
+            (None, Some(delay)) if delay > TimeDelta::zero() => {
+                // one block per delay is the same average bandwidth the delay used to give
+                let rate = (self.block_size as f64 / (delay.num_microseconds().unwrap_or(i64::MAX) as f64 / 1_000_000.0)) as usize;
+                Some(rate.max(1))
+            },
+            _ => None
+        }
+    }
+
+    pub fn get_spill(&self) -> Option<(PathBuf, Option<usize>)> {
+        self.spill_path.as_ref().map(|path| (PathBuf::from(shellexpand::tilde(path).into_owned()), self.spill_limit))
+    }
+
+    pub fn get_max_tokens(&self) -> Option<usize> {
+        self.max_tokens
+    }
+
+    pub fn get_max_upload_size(&self) -> Option<usize> {
+        self.max_upload_size
+    }
+
+    pub fn get_max_bytes_per_day(&self) -> Option<usize> {
+        self.max_bytes_per_day
     }
 
-    fn generate_token(format: &String) -> String {
+    pub fn get_store_limit(&self) -> Option<usize> {
+        self.store_limit
+    }
+
+    pub fn get_max_lifetime(&self) -> TimeDelta {
+        self.max_lifetime.unwrap_or(TimeDelta::days(7))
+    }
+
+    pub fn uses_packet_delay(&self) -> bool {
+        self.rate_limit.is_none() && self.packet_delay.is_some()
+    }
+
+    // a fresh bucket for each upload, so one transfer can't use up another's burst, along with the total they all share
+    pub fn get_throttle(&self) -> Option<Throttle> {
+        let own = self.get_rate_limit().map(|rate| TokenBucket::new(rate, self.burst_size.unwrap_or(rate).max(self.block_size)));
+        Throttle::new(own, self.total_throttle.clone())
+    }
+
+    pub fn get_total_rate_limit(&self) -> Option<usize> {
+        self.total_rate_limit
+    }
+
+    // made once at startup, the clones in each request then all draw from the same bucket
+    pub fn prepare_throttle(&mut self) {
+        self.total_throttle = self.total_rate_limit.filter(|rate| *rate > 0).map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate, rate.max(self.block_size)))));
+    }
+
+    // reads the wordlist and checks both formats, so a typo is caught at startup instead of in every link
+    pub fn prepare_tokens(&mut self) -> Result<(), String> {
+        if let Some(path) = &self.wordlist {
+            let path = shellexpand::tilde(path).into_owned();
+            let contents = std::fs::read_to_string(&path).map_err(|e| format!("Could not read wordlist {path}: {e}"))?;
+            let words: Vec<String> = contents.lines().map(|word| word.trim().to_string()).filter(|word| !word.is_empty()).collect();
+            if words.is_empty() {
+                return Err(format!("Wordlist {path} has no words in it"));
+            }
+            self.words = Some(Arc::new(words));
+        }
+        if let Some((low, high)) = self.number_range {
+            if low >= high {
+                return Err(format!("number_range [{low}, {high}] is empty, the second number has to be bigger"));
+            }
+        }
+        for format in [&self.token_format, &self.upload_format] {
+            for (name, _) in ALPHABETS {
+                let tag = format!("{{{name}:");
+                let mut rest = format.as_str();
+                while let Some(start) = rest.find(&tag) {
+                    rest = &rest[start + tag.len()..];
+                    match rest.find('}').map(|end| rest[..end].parse::<usize>()) {
+                        Some(Ok(length)) if length > 0 && length <= MAX_PLACEHOLDER_LENGTH => (),
+                        _ => return Err(format!("{format} has a bad {{{name}:N}}, N has to be a length from 1 to {MAX_PLACEHOLDER_LENGTH}"))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn generate_token(&self, format: &str) -> String {
         // we need to see how many of each we need
         let mut rng = rand::rng();
         let words_raw = include_str!("../../wordlist.txt").trim(); // via https://gist.githubusercontent.com/dracos/dd0668f281e685bad51479e5acaadb93/raw/6bfa15d263d6d5b63840a8e5b64e04b382fdb079/valid-wordle-words.txt
         // now split by newlines
-        let words = words_raw.split('\n').collect::<Vec<&str>>();
+        let words = match &self.words {
+            Some(words) => words.iter().map(|word| word.as_str()).collect::<Vec<&str>>(),
+            None => words_raw.split('\n').collect::<Vec<&str>>()
+        };
+        let (low, high) = self.number_range.unwrap_or((0, 100));
 
-        let mut output = format.clone();
+        let mut output = format.to_string();
         while output.contains("{number}") {
-            let number = rng.random_range(0..100);
+            let number = rng.random_range(low..high);
             output = output.replacen("{number}", &number.to_string(), 1);
         }
 
@@ -69,16 +218,56 @@ impl ServerOptions {
             output = output.replacen("{uuid}", &uuid, 1);
         }
 
+        // lengths were checked by prepare_tokens, anything else is left as written
+        for (name, alphabet) in ALPHABETS {
+            let tag = format!("{{{name}:");
+            let alphabet: Vec<char> = alphabet.chars().collect();
+            let mut from = 0;
+            while let Some(start) = output[from..].find(&tag).map(|start| start + from) {
+                let end = match output[start..].find('}') {
+                    Some(end) => start + end,
+                    None => break
+                };
+                let length = match output[start + tag.len()..end].parse::<usize>() {
+                    Ok(length) if length <= MAX_PLACEHOLDER_LENGTH => length,
+                    _ => {
+                        from = end;
+                        continue
+                    }
+                };
+                let random: String = (0..length).map(|_| alphabet[rng.random_range(0..alphabet.len())]).collect();
+                output.replace_range(start..=end, &random);
+                from = start + random.len();
+            }
+        }
+
         output
     }
 
     pub fn generate_upload_token(&self) -> String {
-        return Self::generate_token(&self.token_format)
+        self.generate_token(&self.token_format)
     }
 
     pub fn generate_key_token(&self) -> String {
-        return Self::generate_token(&self.upload_format)
+        self.generate_token(&self.upload_format)
     }
 
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(extra: &str) -> ServerOptions {
+        toml::from_str(&format!("cache_size = 16\nblock_size = 4096\ncull_time = [3600, 0]\ntoken_format = \"{{uuid}}\"\nupload_format = \"{{uuid}}\"\n{}", extra)).unwrap()
+    }
+
+    #[test]
+    fn a_rate_of_zero_is_unlimited() {
+        let mut opts = options("rate_limit = 0\ntotal_rate_limit = 0\n");
+        opts.prepare_throttle();
+        assert_eq!(opts.get_rate_limit(), None);
+        assert!(opts.get_throttle().is_none());
+    }
+}
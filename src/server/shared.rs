@@ -0,0 +1,76 @@
+use std::{fmt, future::Future, pin::Pin, sync::Arc};
+use serde::Deserialize;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::utils::metadata::FileMetadata;
+
+pub type Pending<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// several relays behind one load balancer, sharing their tokens through redis
+#[derive(Deserialize, Debug, Clone)]
+#[cfg_attr(not(feature = "redis"), allow(dead_code))] // still read from the config, to say the feature is missing
+pub struct SharedConfig {
+    pub redis: String, // like redis://127.0.0.1:6379/0
+    pub prefix: Option<String>, // put in front of every key and channel, defaults to "bytebeam:"
+}
+
+// what another relay did to a token
+#[derive(Debug)]
+#[cfg_attr(not(feature = "redis"), allow(dead_code))] // only the redis backend sees other relays
+pub enum Change {
+    Saved(Box<FileMetadata>), // made or changed it
+    Removed(String),
+    UploadStarted { ticket: String, relay: String }, // is taking its upload, the bytes can be claimed from it
+    Claimed(String), // has the download of an upload this relay is taking, and wants the bytes
+}
+
+// where tokens live beyond this relay. every relay keeps its own copy of each token to answer from, the store carries
+// changes between them and the bytes of a transfer whose upload and download reached different relays
+pub trait TokenStore: Send + Sync + fmt::Debug {
+    // the tokens the other relays already have, for a relay that just started
+    fn load(&self) -> Pending<'_, Vec<FileMetadata>>;
+    // these go out in the order they were made, without waiting on the store
+    fn save(&self, meta: &FileMetadata);
+    fn remove(&self, ticket: &str);
+    fn upload_started(&self, ticket: &str);
+    // what the other relays do from now on, only the first caller gets it. None when there are no other relays
+    fn changes(&self) -> Option<Receiver<Change>>;
+    // the upload `relay` is taking, sent into this relay's own channel for the token
+    fn claim(&self, ticket: &str, relay: &str, into: Sender<Vec<u8>>);
+    // answers a claim with the upload this relay is taking
+    fn forward(&self, ticket: &str, from: Receiver<Vec<u8>>);
+}
+
+// a relay on its own, its tokens are only ever in its memory
+#[derive(Debug)]
+pub struct LocalTokens;
+
+impl TokenStore for LocalTokens {
+    fn load(&self) -> Pending<'_, Vec<FileMetadata>> {
+        Box::pin(async { vec![] })
+    }
+
+    fn save(&self, _meta: &FileMetadata) {}
+
+    fn remove(&self, _ticket: &str) {}
+
+    fn upload_started(&self, _ticket: &str) {}
+
+    fn changes(&self) -> Option<Receiver<Change>> {
+        None
+    }
+
+    fn claim(&self, _ticket: &str, _relay: &str, _into: Sender<Vec<u8>>) {}
+
+    fn forward(&self, _ticket: &str, _from: Receiver<Vec<u8>>) {}
+}
+
+pub async fn connect(config: Option<SharedConfig>) -> Result<Arc<dyn TokenStore>, String> {
+    match config {
+        None => Ok(Arc::new(LocalTokens)),
+        #[cfg(feature = "redis")]
+        Some(config) => Ok(Arc::new(super::pubsub::RedisTokens::connect(config).await?)),
+        #[cfg(not(feature = "redis"))]
+        Some(_) => Err("shared is set, but this relay was built without the redis feature".to_string())
+    }
+}
@@ -0,0 +1,189 @@
+use std::{collections::VecDeque, io, path::{Path, PathBuf}};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tracing::{debug, error, trace};
+use uuid::Uuid;
+
+use super::{sealed::{Opener, Sealer, SealingKey}, spoolio::{SpoolReader, SpoolWriter}};
+
+// sits between the upload and the download channel, once the channel is full chunks go to a file until the downloader catches up
+struct SpillFile {
+    path: PathBuf,
+    writer: SpoolWriter,
+    reader: SpoolReader,
+    sealer: Sealer, // the nonces keep counting up when the file starts over, so none are used twice
+    opener: Opener,
+    chunks: VecDeque<usize>, // sizes of what is waiting in the file, in order
+    bytes: usize,
+}
+
+impl SpillFile {
+    async fn create(dir: &Path) -> std::io::Result<Self> {
+        let path = dir.join(format!("bytebeam-spill-{}", Uuid::new_v4()));
+        let writer = SpoolWriter::create(&path).await?;
+        let reader = SpoolReader::open(&path).await?;
+        let key = SealingKey::generate()?;
+        Ok(SpillFile { path, writer, reader, sealer: key.sealer(), opener: key.opener(), chunks: VecDeque::new(), bytes: 0 })
+    }
+
+    async fn push(&mut self, chunk: Vec<u8>) -> std::io::Result<()> {
+        self.writer.write(self.sealer.seal(&chunk)?).await?;
+        self.bytes += chunk.len();
+        self.chunks.push_back(chunk.len());
+        Ok(())
+    }
+
+    async fn pop(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let size = match self.chunks.pop_front() {
+            Some(size) => size,
+            None => return Ok(None)
+        };
+        let chunk = self.opener.read(&mut self.reader).await?.ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, "The spill file ended early"))?;
+        self.bytes -= size;
+        if self.chunks.is_empty() { // caught up, so the file can start over instead of growing for the whole transfer
+            self.writer.truncate().await?;
+            self.reader.rewind().await?;
+        }
+        Ok(Some(chunk))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            error!("Could not remove spill file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// returns the sender the upload should use instead of the download channel
+pub fn spill_over(download: Sender<Vec<u8>>, dir: PathBuf, limit: Option<usize>, depth: usize) -> Sender<Vec<u8>> {
+    let (tx, rx) = channel(depth.max(1));
+    tokio::spawn(async move {
+        if let Err(e) = run(rx, download, dir, limit).await {
+            error!("Spilling to disk failed, the transfer is dropped: {}", e);
+        }
+    });
+    tx
+}
+
+async fn run(mut upload: Receiver<Vec<u8>>, download: Sender<Vec<u8>>, dir: PathBuf, limit: Option<usize>) -> std::io::Result<()> {
+    let mut spill: Option<SpillFile> = None;
+    let mut upload_done = false;
+
+    loop {
+        let waiting = spill.as_ref().map(|s| !s.chunks.is_empty()).unwrap_or(false);
+        if upload_done && !waiting {
+            return Ok(()); // dropping the download sender here is what the downloader waits for after the close signal
+        }
+
+        tokio::select! {
+            biased;
+            permit = download.reserve(), if waiting => {
+                let permit = match permit {
+                    Ok(permit) => permit,
+                    Err(_) => return Ok(()) // the downloader went away, the upload finds out from its own sender
+                };
+                if let Some(chunk) = spill.as_mut().unwrap().pop().await? {
+                    permit.send(chunk);
+                }
+            },
+            chunk = upload.recv(), if !upload_done => {
+                let chunk = match chunk {
+                    Some(chunk) => chunk,
+                    None => {
+                        upload_done = true;
+                        continue;
+                    }
+                };
+
+                // order matters, so nothing skips ahead of chunks that are already on disk
+                let chunk = match waiting {
+                    true => chunk,
+                    false => match download.try_send(chunk) {
+                        Ok(_) => continue,
+                        Err(TrySendError::Closed(_)) => return Ok(()),
+                        Err(TrySendError::Full(chunk)) => chunk,
+                    }
+                };
+
+                let file = match &mut spill {
+                    Some(file) => file,
+                    None => {
+                        let file = SpillFile::create(&dir).await?;
+                        debug!("Download channel is full, spilling to {:?}", file.path);
+                        spill.insert(file)
+                    }
+                };
+
+                if limit.map(|limit| file.bytes + chunk.len() > limit).unwrap_or(false) {
+                    // over the limit the upload waits for the downloader again, like it would without spilling
+                    trace!("Spill limit reached, draining {} bytes first", file.bytes);
+                    while let Some(spilled) = file.pop().await? {
+                        if download.send(spilled).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    if download.send(chunk).await.is_err() {
+                        return Ok(());
+                    }
+                } else {
+                    file.push(chunk).await?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks() -> Vec<Vec<u8>> {
+        (0..64u8).map(|i| vec![i; 1000 + i as usize]).collect()
+    }
+
+    async fn drain(download: &mut Receiver<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut received = vec![];
+        while let Some(chunk) = download.recv().await {
+            received.push(chunk);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn a_full_channel_spills_and_comes_back_in_order() {
+        let dir = std::env::temp_dir().join(format!("bytebeam-spill-test-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let (download_tx, mut download) = channel(2);
+        let upload = spill_over(download_tx, dir.clone(), None, 1);
+        // nobody is downloading yet, so all but the first couple have to go to disk for the upload to get through
+        for chunk in chunks() {
+            upload.send(chunk).await.unwrap();
+        }
+        drop(upload);
+        assert_eq!(drain(&mut download).await, chunks());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0); // the spill file goes once it's done
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_limit_makes_the_upload_wait_without_losing_anything() {
+        let dir = std::env::temp_dir().join(format!("bytebeam-spill-test-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let (download_tx, mut download) = channel(2);
+        let upload = spill_over(download_tx, dir.clone(), Some(4000), 1);
+        let sending = tokio::spawn(async move {
+            for chunk in chunks() {
+                upload.send(chunk).await.unwrap();
+            }
+        });
+        let mut received = vec![];
+        while let Some(chunk) = download.recv().await {
+            received.push(chunk);
+            tokio::task::yield_now().await; // a slow downloader
+        }
+        sending.await.unwrap();
+        assert_eq!(received, chunks());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+use std::{io, path::Path};
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+use tokio::{fs::{File, OpenOptions}, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}};
+
+// the relay's own files (broadcast spools, spill files and stored uploads), through io_uring when built with the uring feature on linux
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub use super::uring::{SpoolReader, SpoolWriter};
+
+// only the relay's own user can read what it writes out, and nothing already at the path (a file, or a link someone put
+// where the next one would go in a shared temp folder) is ever written through
+pub fn private_options() -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+}
+
+// appends whole records, each one can be read back as soon as write returns
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+pub struct SpoolWriter(File);
+
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+impl SpoolWriter {
+    pub async fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self(OpenOptions::from(private_options()).open(path).await?))
+    }
+
+    pub async fn write(&mut self, record: Vec<u8>) -> io::Result<()> {
+        self.0.write_all(&record).await?;
+        self.0.flush().await
+    }
+
+    // empties the file and starts writing at the beginning again
+    pub async fn truncate(&mut self) -> io::Result<()> {
+        self.0.set_len(0).await?;
+        self.0.rewind().await.map(|_| ())
+    }
+}
+
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+pub struct SpoolReader(File);
+
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+impl SpoolReader {
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self(File::open(path).await?))
+    }
+
+    // UnexpectedEof when the file ends first
+    pub async fn read_exact(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        self.0.read_exact(buffer).await.map(|_| ())
+    }
+
+    pub async fn rewind(&mut self) -> io::Result<()> {
+        self.0.rewind().await.map(|_| ())
+    }
+}
@@ -0,0 +1,148 @@
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf};
+use axum::{extract::State, http::StatusCode, Form, Json};
+use chrono::{DateTime, Utc};
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::utils::{compression::Compression, stats::StatsReport};
+use super::{appstate::AppState, openapi::SignedForm, routes};
+
+// running totals for one authenticated user, saved to stats_path if there is one so they outlive restarts
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UserStats {
+    tokens: usize,
+    uploads: usize,
+    bytes: usize,
+    seconds: f64,
+    bytes_by_month: BTreeMap<String, usize>,
+    compression: HashMap<String, usize>,
+}
+
+impl UserStats {
+    pub fn add_token(&mut self) {
+        self.tokens += 1;
+    }
+
+    pub fn add_upload(&mut self, bytes: usize, seconds: f64, compression: &Compression) {
+        self.uploads += 1;
+        self.bytes += bytes;
+        self.seconds += seconds;
+        *self.bytes_by_month.entry(Utc::now().format("%Y-%m").to_string()).or_default() += bytes;
+        *self.compression.entry(compression.to_string()).or_default() += 1;
+    }
+
+    pub fn tokens(&self) -> usize {
+        self.tokens
+    }
+
+    pub fn uploads(&self) -> usize {
+        self.uploads
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn report(&self, user: &str) -> StatsReport {
+        StatsReport {
+            user: user.to_string(),
+            tokens: self.tokens,
+            uploads: self.uploads,
+            bytes: self.bytes,
+            bytes_by_month: self.bytes_by_month.clone(),
+            average_speed: match self.seconds > 0.0 {
+                true => Some((self.bytes as f64 / self.seconds) as u64),
+                false => None
+            },
+            top_compression: self.compression.iter()
+                .max_by_key(|(_, count)| **count)
+                .map(|(compression, _)| compression.clone()),
+        }
+    }
+}
+
+// a missing file is a relay that hasn't saved any yet, a broken one is started over rather than keeping the relay down
+pub fn load(path: &PathBuf) -> HashMap<String, UserStats> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            warn!("Could not read user stats from {:?}, starting them over: {}", path, e);
+            return HashMap::new();
+        }
+    };
+    match serde_json::from_str(&contents) {
+        Ok(stats) => stats,
+        Err(e) => {
+            warn!("User stats in {:?} could not be parsed, starting them over: {}", path, e);
+            HashMap::new()
+        }
+    }
+}
+
+// written next to the old file and moved over it, so a crash partway through never leaves half of them
+pub async fn save(path: &PathBuf, stats: &HashMap<String, UserStats>) -> Result<(), String> {
+    let json = serde_json::to_vec(stats).map_err(|e| format!("Could not serialize user stats: {}", e))?;
+    let partial = path.with_extension("partial");
+    tokio::fs::write(&partial, json).await.map_err(|e| format!("Could not write {:?}: {}", partial, e))?;
+    tokio::fs::rename(&partial, path).await.map_err(|e| format!("Could not replace {:?}: {}", path, e))
+}
+
+// what the cull loop has done since the server started
+#[derive(Debug, Clone, Default)]
+pub struct CullStats {
+    runs: usize,
+    culled: usize,
+    last_run: Option<DateTime<Utc>>,
+    last_culled: usize,
+}
+
+impl CullStats {
+    pub fn record(&mut self, culled: usize) {
+        self.runs += 1;
+        self.culled += culled;
+        self.last_run = Some(Utc::now());
+        self.last_culled = culled;
+    }
+
+    pub fn runs(&self) -> usize {
+        self.runs
+    }
+
+    pub fn culled(&self) -> usize {
+        self.culled
+    }
+
+    pub fn last_run(&self) -> Option<DateTime<Utc>> {
+        self.last_run
+    }
+
+    pub fn last_culled(&self) -> usize {
+        self.last_culled
+    }
+}
+
+// the caller proves who they are by signing "stats:[user]:[unix time]:[nonce]", each of which only works once
+#[utoipa::path(post, path = routes::MY_STATS, tag = "relay",
+    request_body(content = SignedForm, content_type = "application/x-www-form-urlencoded"),
+    responses((status = 200, body = StatsReport), (status = 401, description = "The signature didn't verify")))]
+pub async fn my_stats(State(state): State<AppState>, Form(params): Form<HashMap<String, String>>) -> Result<Json<StatsReport>, (StatusCode, Markup)> {
+    let (user, challenge, signatures) = match (params.get("user"), params.get("challenge"), params.get("signature")) {
+        (Some(user), Some(challenge), Some(signature)) => (user, challenge, signature),
+        _ => return Err((StatusCode::BAD_REQUEST, html! {"user, challenge, and signature are required"})),
+    };
+
+    let signatures: Vec<String> = match serde_json::from_str(signatures) {
+        Ok(s) => s,
+        Err(_) => vec![signatures.to_string()],
+    };
+
+    if !state.verify_once("stats", user, challenge, &signatures).await {
+        debug!("Stats request for {} failed verification", user);
+        return Err((StatusCode::UNAUTHORIZED, html! {"Challenge failed"}));
+    }
+
+    let stats = state.get_user_stats(user).await;
+    Ok(Json(stats.report(user)))
+}
@@ -0,0 +1,112 @@
+use std::{collections::{HashMap, VecDeque}, net::{IpAddr, Ipv6Addr}, sync::Arc, time::{Duration, Instant}};
+use tokio::sync::Mutex;
+
+use crate::utils::bucket::TokenBucket;
+
+// what an upload waits on, its own bucket and the one every upload with the same options shares
+#[derive(Debug)]
+pub struct Throttle {
+    own: Option<TokenBucket>,
+    shared: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl Throttle {
+    pub fn new(own: Option<TokenBucket>, shared: Option<Arc<Mutex<TokenBucket>>>) -> Option<Self> {
+        match own.is_some() || shared.is_some() {
+            true => Some(Throttle { own, shared }),
+            false => None
+        }
+    }
+
+    pub async fn take(&mut self, bytes: usize) {
+        if let Some(own) = &mut self.own {
+            own.take(bytes).await;
+        }
+        // the lock is held through the wait, so uploads queue up for the shared rate instead of all sleeping and bursting together
+        if let Some(shared) = &self.shared {
+            shared.lock().await.take(bytes).await;
+        }
+    }
+}
+
+// what a client address is counted as. one ipv6 client usually has a whole /64 to pick addresses from, so it's keyed by that
+pub fn address_key(address: &str) -> String {
+    match address.parse::<IpAddr>().map(|ip| ip.to_canonical()) {
+        Ok(IpAddr::V6(ip)) => {
+            let segments = ip.segments();
+            let prefix = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], 0, 0, 0, 0);
+            format!("{prefix}/64")
+        },
+        Ok(ip) => ip.to_string(),
+        Err(_) => address.to_string() // not an address at all, like a unix socket, so it's its own key
+    }
+}
+
+// at most `limit` hits for each key in any `window`, counted from the hits themselves so there is no burst at a boundary
+#[derive(Debug)]
+pub struct SlidingWindow {
+    limit: usize,
+    window: Duration,
+    hits: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl SlidingWindow {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        SlidingWindow {
+            limit,
+            window,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // a refused hit isn't counted, so someone retrying in a loop gets back in once the window moves on
+    pub async fn allow(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().await;
+        let recent = hits.entry(key.to_string()).or_default();
+        while recent.front().is_some_and(|hit| now.duration_since(*hit) >= self.window) {
+            recent.pop_front();
+        }
+        if recent.len() >= self.limit {
+            return false;
+        }
+        recent.push_back(now);
+        true
+    }
+
+    // forgets keys that haven't been seen for a whole window
+    pub async fn prune(&self) {
+        let now = Instant::now();
+        self.hits.lock().await.retain(|_, recent| recent.back().is_some_and(|hit| now.duration_since(*hit) < self.window));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_is_keyed_by_its_64() {
+        assert_eq!(address_key("2001:db8:1:2:aaaa::1"), "2001:db8:1:2::/64");
+        assert_eq!(address_key("2001:db8:1:2:bbbb:cccc:dddd:eeee"), address_key("2001:db8:1:2::1"));
+        assert_ne!(address_key("2001:db8:1:2::1"), address_key("2001:db8:1:3::1"));
+    }
+
+    #[test]
+    fn ipv4_is_keyed_by_its_address() {
+        assert_eq!(address_key("192.0.2.7"), "192.0.2.7");
+        assert_eq!(address_key("::ffff:192.0.2.7"), "192.0.2.7"); // from a dual stack listener
+        assert_eq!(address_key("unknown"), "unknown");
+    }
+
+    #[tokio::test]
+    async fn refuses_past_the_limit_until_the_window_moves() {
+        let window = SlidingWindow::new(2, Duration::from_millis(50));
+        assert!(window.allow("a").await);
+        assert!(window.allow("a").await);
+        assert!(!window.allow("a").await);
+        assert!(window.allow("b").await);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(window.allow("a").await);
+    }
+}
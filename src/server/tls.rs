@@ -0,0 +1,147 @@
+use std::{fs::File, future::Future, io::BufReader, net::{SocketAddr, TcpListener}, pin::Pin, sync::Arc};
+use axum::{extract::Request, http::{header::HOST, StatusCode}, response::{IntoResponse, Redirect}, routing::get, Router};
+use axum_server::{accept::Accept, tls_rustls::{RustlsAcceptor, RustlsConfig}};
+use rustls::{crypto::ring, pki_types::{CertificateDer, PrivateKeyDer}, server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tower_http::add_extension::AddExtension;
+use tracing::{debug, error, info, trace};
+
+use super::acme::{self, Challenges};
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let path = shellexpand::tilde(path).into_owned();
+    let file = File::open(&path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>().map_err(|e| format!("Could not read {}: {}", path, e))?;
+    match certs.is_empty() {
+        true => Err(format!("{} has no certificates in it", path)),
+        false => Ok(certs)
+    }
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let path = shellexpand::tilde(path).into_owned();
+    let file = File::open(&path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(format!("{} has no private key in it", path)),
+        Err(e) => Err(format!("Could not read {}: {}", path, e))
+    }
+}
+
+// trusts client certificates signed by these CAs, but still lets everyone else connect to use the relay without one
+fn client_verifier(client_ca: &[String], provider: Arc<rustls::crypto::CryptoProvider>) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>, String> {
+    let mut roots = RootCertStore::empty();
+    for path in client_ca {
+        for cert in read_certs(path)? {
+            roots.add(cert).map_err(|e| format!("{} is not a usable CA certificate: {}", path, e))?;
+        }
+    }
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .allow_unauthenticated()
+        .build()
+        .map_err(|e| format!("Could not set up client certificates: {}", e))
+}
+
+// serves https itself, for relays without a reverse proxy in front of them
+pub fn server_config(cert: &str, key: &str, client_ca: &[String]) -> Result<ServerConfig, String> {
+    let certs = read_certs(cert)?;
+    let key = read_key(key)?;
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match client_ca.is_empty() {
+        true => builder.with_no_client_auth(),
+        false => builder.with_client_cert_verifier(client_verifier(client_ca, provider)?)
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("The certificate and key don't work together: {}", e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+// the user a verified client certificate belongs to, taken from its common name. None for anyone who didn't present one
+#[derive(Clone, Debug, Default)]
+pub struct ClientIdentity(pub Option<String>);
+
+fn identify(certs: Option<&[CertificateDer<'static>]>) -> ClientIdentity {
+    let cert = match certs.and_then(|certs| certs.first()) {
+        Some(cert) => cert,
+        None => return ClientIdentity::default()
+    };
+    let user = x509_parser::parse_x509_certificate(cert.as_ref()).ok()
+        .and_then(|(_, cert)| cert.subject().iter_common_name().next().and_then(|name| name.as_str().ok()).map(|name| name.to_string()));
+    match &user {
+        Some(user) => trace!("Connection presented a client certificate for {}", user),
+        None => debug!("Client certificate has no common name to take a user from")
+    }
+    ClientIdentity(user)
+}
+
+// the handshake is over by the time a request is handled, so the certificate is attached to the whole connection here
+#[derive(Clone)]
+struct IdentifyingAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl<I, S> Accept<I, S> for IdentifyingAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = <RustlsAcceptor as Accept<I, S>>::Stream;
+    type Service = AddExtension<S, ClientIdentity>;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let identity = identify(stream.get_ref().1.peer_certificates());
+            Ok((stream, AddExtension::new(service, identity)))
+        })
+    }
+}
+
+// the config can be reloaded while serving, which is how renewed certificates get swapped in
+pub async fn serve(listener: TcpListener, app: Router, config: RustlsConfig) -> std::io::Result<()> {
+    info!("Serving https on {}", listener.local_addr()?);
+    axum_server::from_tcp(listener)
+        .acceptor(IdentifyingAcceptor { inner: RustlsAcceptor::new(config) })
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+}
+
+// everything on the plain listener goes to the same path over https, on the port the relay really listens on,
+// except for ACME challenges which the CA only asks for over http
+pub async fn redirect_http(listen: String, https_port: u16, challenges: Challenges) {
+    let app = Router::new()
+        .route("/.well-known/acme-challenge/{token}", get(acme::answer_challenge))
+        .with_state(challenges)
+        .fallback(move |request: Request| async move {
+            let host = match request.headers().get(HOST).and_then(|host| host.to_str().ok()) {
+                Some(host) => host.rsplit_once(':').filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit())).map(|(host, _)| host).unwrap_or(host).to_string(),
+                None => return (StatusCode::BAD_REQUEST, "A Host header is needed to be redirected to https").into_response()
+            };
+            let port = match https_port {
+                443 => String::new(),
+                port => format!(":{port}")
+            };
+            let path = request.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+            debug!("Redirecting {}{} to https", host, path);
+            Redirect::permanent(&format!("https://{host}{port}{path}")).into_response()
+        });
+
+    let listener = match tokio::net::TcpListener::bind(&listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not listen on {} for the https redirect: {}", listen, e);
+            return;
+        }
+    };
+    info!("Redirecting http on {} to https", listen);
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("The https redirect stopped: {}", e);
+    }
+}
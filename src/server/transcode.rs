@@ -0,0 +1,102 @@
+use std::io::{self, Read, Write};
+use async_stream::stream;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, error};
+
+use crate::utils::compression::{decoder, ChannelReader, Compression};
+
+const PIPELINE_DEPTH: usize = 8;
+const READ_SIZE: usize = 64 * 1024;
+
+// how much the requester wants a coding, anything listed with q=0 (or not listed without a *) is refused
+fn quality(accept_encoding: &str, coding: &str) -> f32 {
+    let mut wildcard = None;
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let q = params.find_map(|param| param.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok())).unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+// what the upload has to become for this requester, None to send it as it is.
+// no Accept-Encoding at all means anything goes, so curl and beam down still get the upload as it was sent
+pub fn target(accept_encoding: Option<&str>, compression: &Compression) -> Option<Compression> {
+    let accept_encoding = accept_encoding?;
+    if *compression == Compression::None || quality(accept_encoding, &compression.to_string()) > 0.0 {
+        return None;
+    }
+    // gzip is cheap to make on the fly and every browser reads it, otherwise the file goes out plain
+    match quality(accept_encoding, "gzip") > 0.0 {
+        true => Some(Compression::Gzip),
+        false => Some(Compression::None)
+    }
+}
+
+fn run(chunks: mpsc::Receiver<Result<Bytes, io::Error>>, from: &Compression, to: &Compression, out: &mpsc::Sender<Result<Bytes, String>>) -> io::Result<()> {
+    let mut reader = decoder(from, ChannelReader::new(chunks))?;
+    let mut encoder = match to {
+        Compression::Gzip => Some(GzEncoder::new(Vec::new(), flate2::Compression::fast())),
+        _ => None
+    };
+    let mut buffer = vec![0; READ_SIZE];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        let data = match n {
+            0 => match encoder.take() {
+                Some(encoder) => encoder.finish()?,
+                None => return Ok(())
+            },
+            n => match &mut encoder {
+                Some(encoder) => {
+                    encoder.write_all(&buffer[..n])?;
+                    std::mem::take(encoder.get_mut()) // whatever gzip has finished so far
+                },
+                None => buffer[..n].to_vec()
+            }
+        };
+        if !data.is_empty() && out.blocking_send(Ok(Bytes::from(data))).is_err() {
+            return Ok(()); // the downloader went away
+        }
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+// undoes the upload's compression on a blocking thread, and compresses it again if the requester asked for something else
+pub fn transcode<S>(mut input: S, from: Compression, to: Compression) -> impl Stream<Item = Result<Bytes, String>> where S: Stream<Item = Result<Bytes, String>> + Unpin + Send + 'static {
+    debug!("Transcoding download from {} to {}", from, to);
+    let (raw_tx, raw_rx) = mpsc::channel::<Result<Bytes, io::Error>>(PIPELINE_DEPTH);
+    let (out_tx, mut out_rx) = mpsc::channel::<Result<Bytes, String>>(PIPELINE_DEPTH);
+
+    tokio::spawn(async move {
+        while let Some(chunk) = input.next().await {
+            if raw_tx.send(chunk.map_err(io::Error::other)).await.is_err() {
+                return; // the transcoder went away, it has already reported why
+            }
+        }
+    });
+
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = run(raw_rx, &from, &to, &out_tx) {
+            error!("Could not transcode the {} upload: {}", from, e);
+            let _ = out_tx.blocking_send(Err(e.to_string()));
+        }
+    });
+
+    stream! {
+        while let Some(chunk) = out_rx.recv().await {
+            yield chunk;
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// sends the chosen file in pieces, so a dropped connection only costs the piece it was on
+const CHUNK_SIZE = 1024 * 1024;
+const RETRIES = 5;
+
+const upload = document.getElementById("upload");
+const drop = document.getElementById("drop");
+const input = document.getElementById("file");
+const progress = document.getElementById("upload-progress");
+const status = document.getElementById("upload-status");
+const compression = document.getElementById("compression");
+upload.hidden = false;
+
+// relays with web_uploader set serve the wasm build, which sends the same pieces but can compress them first
+async function loadUploader() {
+    if (!upload.dataset.uploader) {
+        return null;
+    }
+    try {
+        const module = await import(upload.dataset.uploader);
+        await module.default();
+        compression.hidden = false;
+        return module;
+    } catch (e) {
+        console.log("Web uploader unavailable, sending the file as it is", e);
+        return null;
+    }
+}
+const uploader = loadUploader();
+
+const sleep = (ms) => new Promise((resolve) => setTimeout(resolve, ms));
+
+// each piece says its crc32 so the relay can turn away one that was changed on the way
+const CRC_TABLE = Array.from({ length: 256 }, (_, n) => {
+    let c = n;
+    for (let k = 0; k < 8; k++) {
+        c = c & 1 ? 0xedb88320 ^ (c >>> 1) : c >>> 1;
+    }
+    return c >>> 0;
+});
+function crc32(bytes) {
+    let crc = 0xffffffff;
+    for (const byte of bytes) {
+        crc = CRC_TABLE[(crc ^ byte) & 0xff] ^ (crc >>> 8);
+    }
+    return ((crc ^ 0xffffffff) >>> 0).toString(16).padStart(8, "0");
+}
+
+// network errors and 5xx are worth another try, anything else is the relay saying no
+async function sendPiece(url, piece) {
+    for (let attempt = 0; ; attempt++) {
+        try {
+            const response = await fetch(url, { method: "PUT", body: piece });
+            if (response.status < 500) {
+                return response;
+            }
+        } catch (e) {
+            console.log("Piece failed", e);
+        }
+        if (attempt >= RETRIES) {
+            return null;
+        }
+        status.textContent = "Connection trouble, retrying...";
+        await sleep(1000 * 2 ** attempt);
+    }
+}
+
+async function send(file) {
+    drop.hidden = true;
+    compression.disabled = true;
+    progress.hidden = false;
+    progress.max = file.size || 1;
+    progress.value = 0;
+
+    const module = await uploader;
+    if (module) {
+        try {
+            status.textContent = await module.upload(file, upload.dataset.target, compression.value, (sent, total) => {
+                progress.value = sent;
+                status.textContent = Math.floor(100 * sent / (total || 1)) + "% uploaded";
+            });
+        } catch (e) {
+            status.textContent = String(e);
+        }
+        return;
+    }
+
+    let offset = 0;
+    let damaged = 0; // times in a row the relay got the current piece changed
+    for (;;) {
+        const last = offset + CHUNK_SIZE >= file.size;
+        const piece = new Uint8Array(await file.slice(offset, offset + CHUNK_SIZE).arrayBuffer());
+        const params = new URLSearchParams({ offset, crc: crc32(piece) });
+        if (offset === 0) {
+            params.set("size", file.size);
+        }
+        if (last) {
+            params.set("last", "true");
+        }
+
+        const response = await sendPiece(upload.dataset.target + "?" + params, piece);
+        if (response === null) {
+            status.textContent = "The upload failed, the relay could not be reached.";
+            return;
+        }
+        const text = await response.text();
+        let receipt = null;
+        try {
+            receipt = JSON.parse(text);
+        } catch (e) {
+            receipt = null;
+        }
+
+        if (response.status === 409 && receipt) { // the relay has a different amount than we thought, carry on from there
+            offset = receipt.received;
+            continue;
+        }
+        if (response.status === 422 && receipt && damaged++ < RETRIES) { // the piece didn't match its crc, none of it was kept so it goes again
+            offset = receipt.received;
+            continue;
+        }
+        if (!response.ok) {
+            status.textContent = "The upload failed: " + text;
+            return;
+        }
+        if (receipt === null) { // the final piece answers with the relay's summary instead
+            progress.value = progress.max;
+            status.textContent = text;
+            return;
+        }
+        damaged = 0;
+        offset = receipt.received;
+        progress.value = offset;
+        status.textContent = Math.floor(100 * offset / (file.size || 1)) + "% uploaded";
+    }
+}
+
+function choose(files) {
+    if (files.length !== 1) {
+        status.textContent = "Only one file can be uploaded here.";
+        return;
+    }
+    send(files[0]);
+}
+
+input.addEventListener("change", () => choose(input.files));
+drop.addEventListener("dragover", (e) => e.preventDefault());
+drop.addEventListener("drop", (e) => {
+    e.preventDefault();
+    choose(e.dataTransfer.files);
+});
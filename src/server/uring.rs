@@ -0,0 +1,145 @@
+use std::{io, path::{Path, PathBuf}, thread};
+use tokio::sync::{mpsc, oneshot};
+
+use super::spoolio::private_options;
+
+// io_uring needs its own runtime, so every open file gets a thread running one and the main runtime sends it what to do.
+// positions are kept on this side, the thread only ever reads and writes at the offsets it's given
+const URING_QUEUE: usize = 8;
+
+enum Op {
+    Read(u64, usize, oneshot::Sender<io::Result<Vec<u8>>>), // at most this many bytes, fewer at the end of the file
+    Write(u64, Vec<u8>, oneshot::Sender<io::Result<()>>),
+    Truncate(oneshot::Sender<io::Result<()>>),
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "io_uring file thread stopped")
+}
+
+struct Ring(mpsc::Sender<Op>);
+
+impl Ring {
+    async fn start(path: PathBuf, create: bool) -> io::Result<Self> {
+        let (sender, mut receiver) = mpsc::channel::<Op>(URING_QUEUE);
+        let (opened_tx, opened) = oneshot::channel();
+        thread::spawn(move || {
+            tokio_uring::start(async move {
+                let std_file = match create {
+                    true => private_options().open(&path),
+                    false => std::fs::File::open(&path),
+                };
+                // truncating isn't something io_uring does, the std handle does it instead
+                let (file, truncater) = match std_file.and_then(|file| Ok((file.try_clone()?, file))) {
+                    Ok((file, truncater)) => {
+                        let _ = opened_tx.send(Ok(()));
+                        (tokio_uring::fs::File::from_std(file), truncater)
+                    },
+                    Err(e) => {
+                        let _ = opened_tx.send(Err(e));
+                        return;
+                    }
+                };
+                while let Some(op) = receiver.recv().await {
+                    match op {
+                        Op::Read(position, length, reply) => {
+                            let (res, mut buffer) = file.read_at(Vec::with_capacity(length), position).await;
+                            let _ = reply.send(res.map(|read| {
+                                buffer.truncate(read);
+                                buffer
+                            }));
+                        },
+                        Op::Write(position, record, reply) => {
+                            let _ = reply.send(write_all_at(&file, record, position).await);
+                        },
+                        Op::Truncate(reply) => {
+                            let _ = reply.send(truncater.set_len(0));
+                        }
+                    }
+                }
+                let _ = file.close().await;
+            });
+        });
+        match opened.await {
+            Ok(Ok(())) => Ok(Ring(sender)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(stopped()),
+        }
+    }
+
+    async fn run<T>(&self, op: impl FnOnce(oneshot::Sender<io::Result<T>>) -> Op) -> io::Result<T> {
+        let (reply, result) = oneshot::channel();
+        self.0.send(op(reply)).await.map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+}
+
+// short writes hand the buffer back, so keep going with what is left of it
+async fn write_all_at(file: &tokio_uring::fs::File, record: Vec<u8>, mut position: u64) -> io::Result<()> {
+    let mut record = bytes::Bytes::from(record);
+    while !record.is_empty() {
+        let (res, written) = file.write_at(record, position).await;
+        let n = res?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "io_uring wrote nothing"));
+        }
+        position += n as u64;
+        record = written.slice(n..);
+    }
+    Ok(())
+}
+
+pub struct SpoolWriter {
+    ring: Ring,
+    position: u64,
+}
+
+impl SpoolWriter {
+    pub async fn create(path: &Path) -> io::Result<Self> {
+        Ok(SpoolWriter { ring: Ring::start(path.to_path_buf(), true).await?, position: 0 })
+    }
+
+    pub async fn write(&mut self, record: Vec<u8>) -> io::Result<()> {
+        let length = record.len() as u64;
+        self.ring.run(|reply| Op::Write(self.position, record, reply)).await?;
+        self.position += length;
+        Ok(())
+    }
+
+    pub async fn truncate(&mut self) -> io::Result<()> {
+        self.ring.run(Op::Truncate).await?;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+pub struct SpoolReader {
+    ring: Ring,
+    position: u64,
+}
+
+impl SpoolReader {
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        Ok(SpoolReader { ring: Ring::start(path.to_path_buf(), false).await?, position: 0 })
+    }
+
+    pub async fn read_exact(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let position = self.position;
+            let read = self.ring.run(|reply| Op::Read(position, buffer.len() - filled, reply)).await?;
+            if read.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The file ended early"));
+            }
+            buffer[filled..filled + read.len()].copy_from_slice(&read);
+            filled += read.len();
+            self.position += read.len() as u64;
+        }
+        Ok(())
+    }
+
+    pub async fn rewind(&mut self) -> io::Result<()> {
+        self.position = 0;
+        Ok(())
+    }
+}
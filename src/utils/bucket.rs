@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+// a token bucket: bytes go out as fast as they come while the bucket has tokens, then at the refill rate.
+// the relay's rate limits and beam's --limit-rate both wait on one of these
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64, // bytes per second
+    burst: f64, // most bytes that can go out at once after being idle
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: usize, burst: usize) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64, // a fresh transfer starts with a full bucket
+            refilled: Instant::now(),
+        }
+    }
+
+    // holding a second's worth, so a stall can't be made up for with a burst at full speed afterwards
+    pub fn per_second(rate: u64) -> Self {
+        TokenBucket::new(rate as usize, rate as usize)
+    }
+
+    pub async fn take(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * self.rate).min(self.burst);
+        self.refilled = now;
+
+        // a block bigger than the bucket still goes out, it just leaves the bucket in debt
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // how long taking each of these in turn waits, all together
+    async fn timed(bucket: &mut TokenBucket, takes: &[usize]) -> Duration {
+        let started = Instant::now();
+        for &bytes in takes {
+            bucket.take(bytes).await;
+        }
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    async fn starts_full() {
+        let mut bucket = TokenBucket::new(100_000, 10_000);
+        assert!(timed(&mut bucket, &[4_000, 6_000]).await < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn waits_off_a_debt_at_the_rate() {
+        let mut bucket = TokenBucket::new(100_000, 10_000);
+        let waited = timed(&mut bucket, &[30_000]).await; // 20,000 past the burst
+        assert!(waited >= Duration::from_millis(190) && waited < Duration::from_millis(600), "{:?}", waited);
+        let waited = timed(&mut bucket, &[10_000]).await; // the debt was paid by waiting, so nothing is left over
+        assert!(waited >= Duration::from_millis(90), "{:?}", waited);
+    }
+
+    #[tokio::test]
+    async fn idling_only_fills_up_to_the_burst() {
+        let mut bucket = TokenBucket::new(100_000, 10_000);
+        bucket.take(10_000).await;
+        tokio::time::sleep(Duration::from_millis(300)).await; // would be 30,000 without the cap
+        assert!(timed(&mut bucket, &[10_000]).await < Duration::from_millis(50));
+        let waited = timed(&mut bucket, &[10_000]).await;
+        assert!(waited >= Duration::from_millis(90), "{:?}", waited);
+    }
+
+    #[tokio::test]
+    async fn holds_to_the_rate_over_many_takes() {
+        let mut bucket = TokenBucket::per_second(200_000);
+        let waited = timed(&mut bucket, &[20_000; 20]).await; // 400,000 bytes, a second of it covered by the full bucket
+        assert!(waited >= Duration::from_millis(950) && waited < Duration::from_millis(1500), "{:?}", waited);
+    }
+}
@@ -0,0 +1,123 @@
+use std::{fmt, io::Read, path::PathBuf, str::FromStr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+use super::digest::ChunkDigest;
+
+// the integrity hash covers the original file, so it is the same no matter what compression was used on the wire
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub enum ChecksumAlgorithm {
+    None,
+    Sha256,
+    Sha512,
+    #[default]
+    Blake3, // much faster than sha2 on large files
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumAlgorithm::None => write!(f, "none"),
+            ChecksumAlgorithm::Sha256 => write!(f, "sha256"),
+            ChecksumAlgorithm::Sha512 => write!(f, "sha512"),
+            ChecksumAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(ChecksumAlgorithm::None),
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "sha512" => Ok(ChecksumAlgorithm::Sha512),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            _ => Err(format!("Unknown checksum algorithm: {}", s)),
+        }
+    }
+}
+
+impl ChecksumAlgorithm {
+    pub fn hasher(&self) -> Option<Hasher> {
+        match self {
+            ChecksumAlgorithm::None => None,
+            ChecksumAlgorithm::Sha256 => Some(Hasher::Sha256(Sha256::new())),
+            ChecksumAlgorithm::Sha512 => Some(Hasher::Sha512(Sha512::new())),
+            ChecksumAlgorithm::Blake3 => Some(Hasher::Blake3(Box::new(blake3::Hasher::new()))),
+        }
+    }
+}
+
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ChunkDigest for Hasher {
+    type Output = String;
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => Digest::update(h, data),
+            Hasher::Sha512(h) => Digest::update(h, data),
+            Hasher::Blake3(h) => { h.update(data); },
+        }
+    }
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(h) => ChunkDigest::finalize(h),
+            Hasher::Sha512(h) => format!("{:x}", Digest::finalize(h)),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+// sent and stored as "[algorithm]:[hex digest]"
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: String,
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.digest)
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, digest) = s.split_once(':').ok_or(format!("Checksum {} is not [algorithm]:[digest]", s))?;
+        let algorithm = ChecksumAlgorithm::from_str(algorithm)?;
+        if algorithm == ChecksumAlgorithm::None || digest.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Invalid checksum: {}", s));
+        }
+        Ok(Checksum { algorithm, digest: digest.to_ascii_lowercase() })
+    }
+}
+
+// hashes a whole file off the reactor, before it is sent
+pub async fn checksum_file(path: PathBuf, algorithm: ChecksumAlgorithm) -> std::io::Result<Option<Checksum>> {
+    let mut hasher = match algorithm.hasher() {
+        Some(hasher) => hasher,
+        None => return Ok(None),
+    };
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut buffer = vec![0; 1024 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(Some(Checksum { algorithm, digest: hasher.finalize() }))
+    }).await?
+}
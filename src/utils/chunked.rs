@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use super::compression::Compression;
+
+// how much the web uploader puts in each PUT, a dropped connection only costs this much
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+// the relay's answer to each piece, to a piece that doesn't start where it got to (409), and to one that arrived damaged (422)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkReceipt {
+    pub received: usize,
+}
+
+// PUT /{token}/{key}?offset=N&crc=C, the first piece also says how big the file is and how it was compressed
+pub fn piece_query(offset: usize, size: usize, compression: &Compression, last: bool, piece: &[u8]) -> String {
+    let mut query = format!("offset={}&crc={}", offset, piece_crc(piece));
+    if offset == 0 {
+        query.push_str(&format!("&size={}", size));
+        if *compression != Compression::None {
+            query.push_str(&format!("&compression={}", compression));
+        }
+    }
+    if last {
+        query.push_str("&last=true");
+    }
+    query
+}
+
+// the CRC32 of a piece as hex, so the relay can tell it changed on the way instead of passing it on
+pub fn piece_crc(piece: &[u8]) -> String {
+    format!("{:08x}", crc32fast::hash(piece))
+}
+
+// what has been read (and compressed) but not yet confirmed by the relay, so a piece can be sent again
+// from wherever the relay got to without reading the file twice
+#[derive(Debug, Default)]
+pub struct Outbox {
+    start: usize, // the relay's offset of the first byte still held
+    pending: Vec<u8>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    // the offset just past everything pushed so far
+    pub fn end(&self) -> usize {
+        self.start + self.pending.len()
+    }
+
+    // up to max bytes starting at offset, none if the relay already confirmed them
+    pub fn piece(&self, offset: usize, max: usize) -> Option<&[u8]> {
+        if offset < self.start || offset > self.end() {
+            return None;
+        }
+        let from = offset - self.start;
+        Some(&self.pending[from..(from + max).min(self.pending.len())])
+    }
+
+    // drops everything before the relay's receipt
+    pub fn confirm(&mut self, received: usize) {
+        let n = received.saturating_sub(self.start).min(self.pending.len());
+        self.pending.drain(..n);
+        self.start += n;
+    }
+}
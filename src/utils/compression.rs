@@ -1,15 +1,21 @@
-use std::{fmt, str::FromStr};
+use std::{fmt, io::{self, Read, Write}, str::FromStr};
+use bytes::Bytes;
+use flate2::write::{DeflateEncoder, GzEncoder};
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 
 // Reqwest supports various forms of compression, however doing it ourselves allows for more types,
 // and allows for more control over the compression process
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
 pub enum Compression {
+    #[default]
     None,
     Brotli,
     Deflate, // flate2
     Gzip, // flate2
+    Lz4, // frames, barely compresses but keeps up with a LAN on one core
     Zstd,
 }
 
@@ -20,11 +26,26 @@ impl fmt::Display for Compression {
             Compression::Gzip => write!(f, "gzip"),
             Compression::Deflate => write!(f, "deflate"),
             Compression::Brotli => write!(f, "br"),
+            Compression::Lz4 => write!(f, "lz4"),
             Compression::Zstd => write!(f, "zstd"),
         }
     }
 }
 
+impl Compression {
+    // what gets added to a file name when it is saved still compressed
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Deflate => Some("deflate"),
+            Compression::Brotli => Some("br"),
+            Compression::Lz4 => Some("lz4"),
+            Compression::Zstd => Some("zst"),
+        }
+    }
+}
+
 impl FromStr for Compression {
     type Err = String;
 
@@ -34,14 +55,119 @@ impl FromStr for Compression {
             "gzip" => Ok(Compression::Gzip),
             "deflate" => Ok(Compression::Deflate),
             "br" => Ok(Compression::Brotli),
+            "lz4" => Ok(Compression::Lz4),
             "zstd" => Ok(Compression::Zstd),
             _ => Err(format!("Unknown compression type: {}", s)),
         }
     }
 }
 
-impl Default for Compression {
-    fn default() -> Self {
-        Compression::None
+// lets a blocking decoder read the download as it arrives, network errors come out as read errors instead of an early end
+pub struct ChannelReader {
+    chunks: mpsc::Receiver<Result<Bytes, io::Error>>,
+    current: Bytes,
+}
+
+impl ChannelReader {
+    pub fn new(chunks: mpsc::Receiver<Result<Bytes, io::Error>>) -> Self {
+        ChannelReader { chunks, current: Bytes::new() }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0)
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current.split_to(n));
+        Ok(n)
+    }
+}
+
+// used by the downloader after decrypting, and by the relay for requesters that can't read the upload's compression
+pub fn decoder<R: Read + Send + 'static>(compression: &Compression, reader: R) -> io::Result<Box<dyn Read + Send>> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+        Compression::Deflate => Box::new(flate2::read::DeflateDecoder::new(reader)),
+        Compression::Brotli => Box::new(brotli::Decompressor::new(reader, 1024*16)),
+        Compression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+    })
+}
+
+// 128 MiB, as far back as long distance matching looks. decoders accept up to this without being told to
+const LONG_WINDOW_LOG: u32 = 27;
+
+// extra work zstd can put into a big upload, the other compressions ignore it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZstdTuning {
+    pub long: bool, // finds repeats much further apart, like the same file twice in an archive
+    pub threads: u32, // 0 compresses on the calling thread
+}
+
+// compresses a chunk at a time, shared by beam up and the web uploader so they produce the same streams
+pub enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(DeflateEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>), // boxed, it keeps its whole window inline
+    Lz4(lz4_flex::frame::FrameEncoder<Vec<u8>>),
+    Zstd(zstd::stream::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    pub fn new(compression: &Compression) -> std::io::Result<Option<Self>> {
+        Self::tuned(compression, ZstdTuning::default())
+    }
+
+    pub fn tuned(compression: &Compression, tuning: ZstdTuning) -> std::io::Result<Option<Self>> {
+        Ok(match compression {
+            Compression::None => None,
+            Compression::Gzip => Some(Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default()))),
+            Compression::Deflate => Some(Encoder::Deflate(DeflateEncoder::new(Vec::new(), flate2::Compression::default()))),
+            Compression::Brotli => Some(Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 1024*16, 7, 0)))),
+            Compression::Lz4 => Some(Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(Vec::new()))),
+            Compression::Zstd => {
+                let mut encoder = zstd::stream::Encoder::new(Vec::new(), 3)?;
+                if tuning.long {
+                    encoder.long_distance_matching(true)?;
+                    encoder.window_log(LONG_WINDOW_LOG)?;
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                if tuning.threads > 0 {
+                    encoder.multithread(tuning.threads)?;
+                }
+                Some(Encoder::Zstd(encoder))
+            },
+        })
     }
-}
\ No newline at end of file
+
+    // compresses a chunk and hands back whatever the encoder has produced so far
+    pub fn write(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        let buffer = match self {
+            Encoder::Gzip(e) => { e.write_all(chunk)?; e.get_mut() },
+            Encoder::Deflate(e) => { e.write_all(chunk)?; e.get_mut() },
+            Encoder::Brotli(e) => { e.write_all(chunk)?; e.get_mut() },
+            Encoder::Lz4(e) => { e.write_all(chunk)?; e.get_mut() },
+            Encoder::Zstd(e) => { e.write_all(chunk)?; e.get_mut() },
+        };
+        Ok(std::mem::take(buffer))
+    }
+
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+            Encoder::Brotli(mut e) => {
+                e.flush()?;
+                Ok(e.into_inner())
+            },
+            Encoder::Lz4(e) => e.finish().map_err(io::Error::from),
+            Encoder::Zstd(e) => e.finish(),
+        }
+    }
+}
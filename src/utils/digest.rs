@@ -0,0 +1,76 @@
+use bytes::Bytes;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::error;
+
+// chunks waiting to be digested before the transfer has to wait for the worker
+const DIGEST_QUEUE: usize = 16;
+
+// anything that can be fed chunks and produce a result at the end, checksums now and ciphers later
+pub trait ChunkDigest: Send + 'static {
+    type Output: Send + 'static;
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self) -> Self::Output;
+}
+
+impl ChunkDigest for crc32fast::Hasher {
+    type Output = u32;
+    fn update(&mut self, data: &[u8]) {
+        crc32fast::Hasher::update(self, data);
+    }
+    fn finalize(self) -> u32 {
+        crc32fast::Hasher::finalize(self)
+    }
+}
+
+impl ChunkDigest for sha2::Sha256 {
+    type Output = String;
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(self, data);
+    }
+    fn finalize(self) -> String {
+        format!("{:x}", sha2::Digest::finalize(self))
+    }
+}
+
+// runs the digest on a blocking thread so the reactor only ever hands over (cheaply cloned) chunks
+pub struct DigestWorker<D: ChunkDigest> {
+    sender: mpsc::Sender<Bytes>,
+    handle: JoinHandle<D::Output>,
+}
+
+impl<D: ChunkDigest> DigestWorker<D> {
+    pub fn new(mut digest: D) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Bytes>(DIGEST_QUEUE);
+        let handle = tokio::task::spawn_blocking(move || {
+            while let Some(chunk) = receiver.blocking_recv() {
+                digest.update(&chunk);
+            }
+            digest.finalize()
+        });
+        Self { sender, handle }
+    }
+
+    pub async fn update(&self, chunk: Bytes) {
+        if self.sender.send(chunk).await.is_err() {
+            error!("Digest worker stopped early");
+        }
+    }
+
+    pub async fn finish(self) -> Option<D::Output> {
+        drop(self.sender);
+        match self.handle.await {
+            Ok(output) => Some(output),
+            Err(e) => {
+                error!("Digest worker failed: {:?}", e);
+                None
+            }
+        }
+    }
+
+    // for data that is already all in memory
+    pub async fn digest(digest: D, data: Bytes) -> Option<D::Output> {
+        let worker = Self::new(digest);
+        worker.update(data).await;
+        worker.finish().await
+    }
+}
@@ -0,0 +1,175 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+// a download asks for frames with this header and the relay answers with it when it sends them, uploads say so with a "frames" form field
+pub const FRAMES_HEADER: &str = "x-beam-frames";
+pub const FRAMES: &str = "crc32";
+// names the download's frames when asking for one again, so only the downloader can
+pub const RESEND_HEADER: &str = "x-beam-resend";
+// how much of the upload the relay has when a damaged frame stopped it
+pub const RESUME_HEADER: &str = "x-beam-resume-from";
+// the most one frame carries, a length past this can only be a damaged header
+pub const MAX_FRAME: usize = 1024 * 1024;
+const HEADER: usize = 8;
+
+// [length: u32][crc32 of the length and the data: u32][data], both big endian.
+// the length is under the crc too, so a damaged header is caught the same way damaged data is
+pub fn frame(data: &[u8]) -> Bytes {
+    let mut framed = BytesMut::with_capacity(data.len() + HEADER * (data.len() / MAX_FRAME + 1));
+    for piece in data.chunks(MAX_FRAME) {
+        let length = (piece.len() as u32).to_be_bytes();
+        framed.put_slice(&length);
+        framed.put_u32(crc(&length, piece));
+        framed.put_slice(piece);
+    }
+    framed.freeze()
+}
+
+fn crc(length: &[u8], data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(length);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Data(Bytes),
+    Damaged(u64), // where the frame that didn't check out starts, counted in unframed bytes
+}
+
+// turns the framed bytes back into data as they arrive. once a frame is damaged nothing after it can be trusted
+// to start where it says, so it stays stuck there until it's told how long the frame really was
+#[derive(Debug, Default)]
+pub struct Deframer {
+    buffer: BytesMut,
+    offset: u64,
+    skip: usize, // framed bytes of a replaced frame that haven't arrived yet
+    damaged: bool,
+}
+
+impl Deframer {
+    pub fn push(&mut self, data: &[u8]) {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        self.buffer.put_slice(&data[skipped..]);
+    }
+
+    // the damaged frame came again some other way and held this much, so it's dropped from the stream and reading carries on after it
+    pub fn replaced(&mut self, length: usize) {
+        let framed = HEADER + length;
+        let dropped = framed.min(self.buffer.len());
+        self.buffer.advance(dropped);
+        self.skip = framed - dropped;
+        self.offset += length as u64;
+        self.damaged = false;
+    }
+
+    // the stream ended, anything still held is a frame that was cut short or said it was longer than it was
+    pub fn finish(&mut self) -> bool {
+        if !self.buffer.is_empty() {
+            self.damaged = true;
+        }
+        !self.damaged
+    }
+}
+
+impl Iterator for Deframer {
+    type Item = Frame;
+
+    // the next whole frame, none until there is one
+    fn next(&mut self) -> Option<Frame> {
+        if self.damaged {
+            return Some(Frame::Damaged(self.offset));
+        }
+        if self.buffer.len() < HEADER {
+            return None;
+        }
+        let length = u32::from_be_bytes([self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]]) as usize;
+        if length > MAX_FRAME {
+            self.damaged = true;
+            return Some(Frame::Damaged(self.offset));
+        }
+        if self.buffer.len() < HEADER + length {
+            return None;
+        }
+        let expected = u32::from_be_bytes([self.buffer[4], self.buffer[5], self.buffer[6], self.buffer[7]]);
+        if crc(&self.buffer[..4], &self.buffer[HEADER..HEADER + length]) != expected {
+            self.damaged = true;
+            return Some(Frame::Damaged(self.offset));
+        }
+        let mut data = self.buffer.split_to(HEADER + length);
+        data.advance(HEADER);
+        self.offset += length as u64;
+        Some(Frame::Data(data.freeze()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deframe(deframer: &mut Deframer) -> Vec<Frame> {
+        std::iter::from_fn(|| deframer.next()).take_while(|frame| matches!(frame, Frame::Data(_))).collect()
+    }
+
+    #[test]
+    fn frames_come_back_out_whatever_they_arrive_in() {
+        let framed = [frame(b"hello"), frame(b""), frame(b" world")].concat(); // nothing isn't worth a frame
+        let mut deframer = Deframer::default();
+        for byte in framed.chunks(3) {
+            deframer.push(byte);
+        }
+        assert_eq!(deframe(&mut deframer), vec![Frame::Data(Bytes::from("hello")), Frame::Data(Bytes::from(" world"))]);
+        assert!(deframer.finish());
+    }
+
+    #[test]
+    fn damaged_data_stops_at_its_frame() {
+        let mut framed = [frame(b"first"), frame(b"second"), frame(b"third")].concat();
+        framed[HEADER * 2 + 5 + 1] ^= 0x10; // inside "second"
+        let mut deframer = Deframer::default();
+        deframer.push(&framed);
+        assert_eq!(deframer.next(), Some(Frame::Data(Bytes::from("first"))));
+        assert_eq!(deframer.next(), Some(Frame::Damaged(5)));
+        assert_eq!(deframer.next(), Some(Frame::Damaged(5)));
+        deframer.replaced(6);
+        assert_eq!(deframer.next(), Some(Frame::Data(Bytes::from("third"))));
+        assert!(deframer.finish());
+    }
+
+    #[test]
+    fn damaged_lengths_are_caught_and_skipped_past() {
+        let mut framed = [frame(b"first"), frame(b"second"), frame(b"third")].concat();
+        framed[HEADER + 5 + 3] = 2; // "second" now claims to be 2 bytes long
+        let mut deframer = Deframer::default();
+        deframer.push(&framed);
+        assert_eq!(deframer.next(), Some(Frame::Data(Bytes::from("first"))));
+        assert_eq!(deframer.next(), Some(Frame::Damaged(5)));
+        deframer.replaced(6);
+        assert_eq!(deframer.next(), Some(Frame::Data(Bytes::from("third"))));
+
+        // or longer than the rest of the stream, which only shows once it ends
+        let mut framed = [frame(b"first"), frame(b"second")].concat();
+        framed[HEADER + 5 + 2] = 1;
+        let mut deframer = Deframer::default();
+        deframer.push(&framed);
+        assert_eq!(deframer.next(), Some(Frame::Data(Bytes::from("first"))));
+        assert_eq!(deframer.next(), None);
+        assert!(!deframer.finish());
+        assert_eq!(deframer.next(), Some(Frame::Damaged(5)));
+    }
+
+    #[test]
+    fn a_replaced_frame_can_still_be_on_its_way() {
+        let framed = [frame(b"first"), frame(b"second")].concat();
+        let mut deframer = Deframer::default();
+        deframer.push(&framed[..HEADER + 5 + 3]);
+        assert_eq!(deframer.next(), Some(Frame::Data(Bytes::from("first"))));
+        deframer.damaged = true; // as if what arrived of the header was bad
+        deframer.replaced(6);
+        deframer.push(&framed[HEADER + 5 + 3..]);
+        deframer.push(&frame(b"third"));
+        assert_eq!(deframer.next(), Some(Frame::Data(Bytes::from("third"))));
+        assert!(deframer.finish());
+    }
+}
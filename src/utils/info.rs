@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+use super::compression::Compression;
+
+// what GET /api/info says the relay can do, so clients can adapt before asking for a token instead of finding out after
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct ServerInfo {
+    pub version: String,
+    pub compression: Vec<Compression>, // what the relay can read to transcode for downloaders, uploads in anything else are passed through untouched
+    pub max_body_size: u64, // bytes in one request
+    pub features: Vec<String>, // like "store" or "fetch", only what this relay has turned on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reflector: Option<u16>, // a plain tcp port on the relay's host that answers with the address and port it sees, for direct mode to punch through NATs
+}
+
+impl ServerInfo {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    pub fn accepts(&self, compression: &Compression) -> bool {
+        *compression == Compression::None || self.compression.contains(compression)
+    }
+}
@@ -1,6 +1,7 @@
+use std::{fmt, str::FromStr};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use super::compression::Compression;
+use super::{checksum::Checksum, compression::Compression};
 #[cfg(feature = "server")]
 use tracing::warn;
 #[cfg(feature = "server")]
@@ -10,7 +11,15 @@ use chrono::Duration;
 #[cfg(feature = "server")]
 use crate::server::serveropts::ServerOptions;
 
+#[cfg(feature = "server")]
+const MAX_MESSAGE_LENGTH: usize = 500;
+#[cfg(feature = "server")]
+const MAX_BROADCAST_DOWNLOADS: usize = 64;
+#[cfg(feature = "server")]
+pub const MAX_MANIFEST_FILES: usize = 256;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
 pub enum FileState {
     NotStarted,
     InProgress,
@@ -18,11 +27,145 @@ pub enum FileState {
     Complete
 }
 
+impl FileState {
+    // for people looking over the relay, the variant names read like code
+    pub fn describe(&self) -> &'static str {
+        match self {
+            FileState::NotStarted => "waiting",
+            FileState::InProgress => "streaming",
+            FileState::Paused => "paused",
+            FileState::Complete => "done"
+        }
+    }
+}
+
+// how browsers should treat the download, either showing it in the tab or saving it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub enum Disposition {
+    Inline,
+    #[default]
+    Attachment
+}
+
+impl fmt::Display for Disposition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Disposition::Inline => write!(f, "inline"),
+            Disposition::Attachment => write!(f, "attachment"),
+        }
+    }
+}
+
+impl FromStr for Disposition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "inline" => Ok(Disposition::Inline),
+            "attachment" => Ok(Disposition::Attachment),
+            _ => Err(format!("Unknown disposition: {}", s)),
+        }
+    }
+}
+
+// snippets bigger than this are only flagged as text, they're downloaded like any other file
+pub const MAX_TEXT_SIZE: usize = 1024 * 1024;
+
+// carries the manifest when a multi-file upload is downloaded as sent
+pub const MANIFEST_HEADER: &str = "x-bytebeam-manifest";
+
+// one file of a multi-file upload, which are sent back to back in this order
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct ManifestEntry {
+    pub name: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<FileAttributes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>, // sent with --keep-symlinks, a symlink to this instead of a file, with no contents
+}
+
+// an uploader sending only the blocks that changed since a copy the downloader already has
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Delta {
+    #[default]
+    None,
+    Requested, // the uploader is waiting for the downloader's block checksums
+    Offered, // the downloader sent them, the uploader hasn't taken them yet
+    Declined, // the downloader has nothing to compare against, so the whole file is sent
+    Sent, // the uploader took the checksums, the upload is a delta against the downloader's copy
+}
+
+// what the uploader's disk says about the file besides its contents, so a script is still executable once it's downloaded
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct FileAttributes {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>, // unix permission bits, windows has none to send
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<i64>, // unix time
+}
+
+// an uploader offering to send straight to the downloader, the relay only passes it along
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct PeerOffer {
+    pub addresses: Vec<String>, // where the uploader is listening, in the order to try them
+    pub secret: String, // the downloader sends this first, so nobody else who finds the port gets the file
+    #[serde(default)]
+    pub failed: bool, // the downloader couldn't reach any of them and is coming through the relay instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reflected: Option<String>, // the address and port the uploader's NAT gave it, as the relay's reflector saw it, for punching through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer: Option<String>, // the same for the downloader, once it's trying to punch through. both sides connect to each other at once
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
 pub struct FileMetadata {
     pub file_name: String, // making getters/setters when nothing depends on this feels kinda useless
     pub file_size: FileSize,
     compression: Compression,
+    #[serde(default)]
+    disposition: Disposition,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<Checksum>, // of the original file, as claimed by the uploader
+    #[serde(default)]
+    direct: bool, // browsers get the file straight away instead of the landing page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>, // short note from the uploader so the recipient knows what this is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<DateTime<Utc>>, // set when a token is minted ahead of time, it waits until then instead of the cull time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    asked_lifetime: Option<i64>, // seconds the uploader asked expires to be, so it's clamped again once they sign in and their tier changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_downloads: Option<usize>, // broadcast tokens can be downloaded this many times, otherwise once
+    #[serde(default)]
+    downloads_started: usize,
+    #[serde(default)]
+    encrypted: bool, // encrypted by the uploader, the relay can't read it and browsers only get the ciphertext
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    manifest: Option<Vec<ManifestEntry>>, // set when several files were sent under this one token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    storage: Option<String>, // the object the upload is kept in on the relay's storage, so the sender doesn't have to wait for the download
+    #[serde(default)]
+    named: bool, // the uploader picked the token themselves, so it only takes an upload once they've signed for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    receiver: Option<String>, // only this user can download, by signing "download/[token]:[user]:[unix time]" with one of their keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peer: Option<PeerOffer>, // the uploader would rather send it directly, the relay is only used if that doesn't work
+    #[serde(default)]
+    text: bool, // a snippet rather than a file, the landing page shows it instead of offering a download
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attributes: Option<FileAttributes>, // mode and modification time, for beam down to put back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>, // what the uploader sniffed it as, or what its name says it is
+    #[serde(default)]
+    delta: Delta,
     path: String,
     upload_key: String,
     upload: FileState,
@@ -32,6 +175,10 @@ pub struct FileMetadata {
     authed_user: Option<String>,
     challenge: String, // this will generate a uuidv4 no matter what, if no authed_user is passed, it is rather useless
     authenticated: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banner: Option<String>, // server announcement, only filled in on responses to the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frames: Option<String>, // how the relay can take the upload framed, also only on responses to the client
 }
 
 impl FileMetadata {
@@ -48,13 +195,30 @@ impl FileMetadata {
             download: FileState::NotStarted,
             created: Utc::now(),
             accessed: Utc::now(),
-            authed_user: match user {
-                Some(u) => Some(u.clone()),
-                None => None,
-            },
+            authed_user: user.cloned(),
             challenge: format!("{}", Uuid::new_v4()),
             authenticated: false,
-            compression: Compression::default()
+            compression: Compression::default(),
+            disposition: Disposition::default(),
+            checksum: None,
+            direct: false,
+            message: None,
+            expires: None,
+            asked_lifetime: None,
+            max_downloads: None,
+            downloads_started: 0,
+            encrypted: false,
+            manifest: None,
+            storage: None,
+            named: false,
+            receiver: None,
+            peer: None,
+            text: false,
+            attributes: None,
+            content_type: None,
+            delta: Delta::None,
+            banner: None,
+            frames: None
         }
     }
 
@@ -63,20 +227,44 @@ impl FileMetadata {
     }
 
     pub fn upload_locked(&self) -> bool { // we cant really allow resumed uploads?
-        return self.upload == FileState::InProgress || self.upload == FileState::Complete
+        self.upload == FileState::InProgress || self.upload == FileState::Complete
     }
 
     pub fn download_finished(&self) -> bool {
-        return self.download == FileState::Complete
+        self.download == FileState::Complete
+    }
+
+    #[cfg(feature = "server")]
+    pub fn upload_finished(&self) -> bool {
+        self.upload == FileState::Complete
     }
 
     pub fn get_token(&self) -> &String {
         &self.path
     }
 
+    #[cfg(feature = "server")]
+    pub fn set_token_name(&mut self, name: String) {
+        self.path = name;
+        self.named = true;
+    }
+
+    #[cfg(feature = "server")]
+    pub fn is_named(&self) -> bool {
+        self.named
+    }
+
+    pub fn get_created(&self) -> DateTime<Utc> {
+        self.created
+    }
+
+    pub fn get_states(&self) -> (&FileState, &FileState) {
+        (&self.upload, &self.download)
+    }
+
     #[cfg(feature = "server")]
     pub fn check_key(&self, key: &String) -> bool {
-        return self.upload_key == *key
+        self.upload_key == *key
     }
 
     #[cfg(feature = "server")]
@@ -96,6 +284,7 @@ impl FileMetadata {
     #[cfg(feature = "server")]
     pub fn start_download(&mut self) { // this is rather simple
         self.download = FileState::InProgress;
+        self.downloads_started += 1;
     }
 
     #[cfg(feature = "server")]
@@ -109,12 +298,51 @@ impl FileMetadata {
     }
 
     pub fn download_locked(&self) -> bool {
-        return self.download == FileState::InProgress || self.download == FileState::Complete;
+        if let Some(max) = self.max_downloads {
+            return self.download == FileState::Complete || self.downloads_started >= max;
+        }
+        self.download == FileState::InProgress || self.download == FileState::Complete
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_max_downloads(&mut self, max: usize) {
+        self.max_downloads = match max {
+            0 | 1 => None,
+            n => Some(n.min(MAX_BROADCAST_DOWNLOADS))
+        };
+    }
+
+    #[cfg(feature = "server")]
+    pub fn is_broadcast(&self) -> bool {
+        self.max_downloads.is_some()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn any_download_started(&self) -> bool {
+        self.downloads_started > 0
+    }
+
+    // no download can start again, even if the last ones are still streaming
+    #[cfg(feature = "server")]
+    pub fn downloads_exhausted(&self) -> bool {
+        self.download == FileState::Complete || self.max_downloads.is_some_and(|max| self.downloads_started >= max)
+    }
+
+    // broadcast tokens are done once every download has been used
+    #[cfg(feature = "server")]
+    pub fn downloads_remaining(&self) -> usize {
+        self.max_downloads.unwrap_or(1).saturating_sub(self.downloads_started)
+    }
+
+    // anything a watcher would want to hear about changed, access times aside
+    #[cfg(feature = "server")]
+    pub fn same_progress(&self, other: &Self) -> bool {
+        self.file_size == other.file_size && self.upload == other.upload && self.download == other.download && self.downloads_started == other.downloads_started
     }
 
     #[cfg(feature = "server")]
     pub fn download_pausable(&self) -> bool {
-        return self.download == FileState::InProgress;
+        self.download == FileState::InProgress
     }
 
     #[cfg(feature = "server")]
@@ -126,12 +354,37 @@ impl FileMetadata {
             upload: self.upload.clone(),
             download: self.download.clone(),
             path: self.path.clone(),
-            created: self.created.clone(),
-            accessed: self.accessed.clone(),
+            created: self.created,
+            accessed: self.accessed,
             authed_user: self.authed_user.clone(), // maybe should be private?
             challenge: self.challenge.clone(),
             authenticated: self.authenticated,
             compression: self.compression.clone(),
+            disposition: self.disposition.clone(),
+            checksum: self.checksum.clone(),
+            direct: self.direct,
+            message: self.message.clone(),
+            expires: self.expires,
+            asked_lifetime: self.asked_lifetime,
+            max_downloads: self.max_downloads,
+            downloads_started: self.downloads_started,
+            encrypted: self.encrypted,
+            manifest: self.manifest.as_ref().map(|files| files.iter().map(|file| ManifestEntry { // names are private like the file name
+                name: "null".to_string(),
+                size: file.size,
+                attributes: file.attributes.clone(),
+                link: file.link.as_ref().map(|_| "null".to_string())
+            }).collect()),
+            storage: self.storage.as_ref().map(|_| "null".to_string()), // where it is is the relay's business
+            named: self.named,
+            receiver: self.receiver.clone(), // the downloader needs to know who has to sign
+            peer: self.peer.clone(), // anyone with the link can see where the uploader is, like they could download it
+            text: self.text,
+            attributes: self.attributes.clone(),
+            content_type: self.content_type.clone(),
+            delta: self.delta, // the downloader has to know whether to send checksums, and whether it got a delta
+            banner: None,
+            frames: None,
         }
     }
 
@@ -145,9 +398,23 @@ impl FileMetadata {
         Utc::now() - self.accessed
     }
 
+    #[cfg(feature = "server")]
+    pub fn is_expired(&self, cull_time: Duration) -> bool {
+        match self.expires {
+            Some(expires) => Utc::now() > expires,
+            None => self.age() > cull_time
+        }
+    }
+
     #[cfg(feature = "server")]
     pub fn is_in_waiting_state(&self) -> bool {
-        self.download == FileState::NotStarted || self.upload == FileState::NotStarted
+        // paused is a broadcast between downloaders, which can go stale like anything else
+        self.download == FileState::NotStarted || self.download == FileState::Paused || self.upload == FileState::NotStarted
+    }
+
+    #[cfg(feature = "server")]
+    pub fn is_transferring(&self) -> bool {
+        self.download == FileState::InProgress || self.upload == FileState::InProgress
     }
 
     pub fn authenticated(&self) -> bool {
@@ -166,7 +433,9 @@ impl FileMetadata {
     #[cfg(feature = "server")]
     pub fn upgrade(&mut self, options: &ServerOptions) { // TODO: if the token formats are the same, don't change the key
             self.authenticated = true;
-            self.path = options.generate_upload_token();
+            if !self.named { // a custom token keeps its name, only the key changes
+                self.path = options.generate_upload_token();
+            }
             self.upload_key = options.generate_key_token();
             self.accessed = Utc::now();
     }
@@ -174,19 +443,174 @@ impl FileMetadata {
     #[cfg(feature = "server")]
     pub fn set_compression(&mut self, compression: Compression) {
         self.compression = compression;
-        if self.compression != Compression::None {
-            self.file_size.set_trustworthiness(false);
-        } else {
-            self.file_size.set_trustworthiness(true);
-        }
+        self.file_size.set_trustworthiness(self.compression == Compression::None && !self.encrypted);
     }
 
     pub fn get_compression(&self) -> Compression {
         self.compression.clone()
     }
+
+    #[cfg(feature = "server")]
+    pub fn set_disposition(&mut self, disposition: Disposition) {
+        self.disposition = disposition;
+    }
+
+    pub fn get_disposition(&self) -> Disposition {
+        self.disposition.clone()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_checksum(&mut self, checksum: Checksum) {
+        self.checksum = Some(checksum);
+    }
+
+    pub fn get_checksum(&self) -> Option<Checksum> {
+        self.checksum.clone()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_message(&mut self, message: String) {
+        // it's a note, not a place to store things
+        self.message = Some(message.chars().take(MAX_MESSAGE_LENGTH).collect());
+    }
+
+    pub fn get_message(&self) -> Option<&String> {
+        self.message.as_ref()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_attributes(&mut self, attributes: FileAttributes) {
+        self.attributes = Some(attributes);
+    }
+
+    pub fn get_attributes(&self) -> Option<&FileAttributes> {
+        self.attributes.as_ref()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_content_type(&mut self, content_type: String) {
+        self.content_type = Some(content_type);
+    }
+
+    pub fn get_content_type(&self) -> Option<&String> {
+        self.content_type.as_ref()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_delta(&mut self, delta: Delta) {
+        self.delta = delta;
+    }
+
+    pub fn get_delta(&self) -> Delta {
+        self.delta
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_storage(&mut self, name: String) {
+        self.storage = Some(name);
+    }
+
+    #[cfg(feature = "server")]
+    pub fn get_storage(&self) -> Option<&String> {
+        self.storage.as_ref()
+    }
+
+    pub fn is_stored(&self) -> bool {
+        self.storage.is_some()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_receiver(&mut self, receiver: String) {
+        self.receiver = Some(receiver);
+    }
+
+    pub fn get_receiver(&self) -> Option<&String> {
+        self.receiver.as_ref()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_peer(&mut self, peer: Option<PeerOffer>) {
+        self.peer = peer;
+    }
+
+    pub fn get_peer(&self) -> Option<&PeerOffer> {
+        self.peer.as_ref()
+    }
+
+    // from when the token was made, so clamping it again later doesn't push it back
+    #[cfg(feature = "server")]
+    pub fn set_lifetime(&mut self, asked: Duration, max: Duration) {
+        self.asked_lifetime = Some(asked.num_seconds());
+        self.expires = Some(self.created + asked.min(max));
+    }
+
+    #[cfg(feature = "server")]
+    pub fn get_asked_lifetime(&self) -> Option<Duration> {
+        self.asked_lifetime.and_then(Duration::try_seconds)
+    }
+
+    pub fn get_expiry(&self) -> Option<&DateTime<Utc>> {
+        self.expires.as_ref()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_encrypted(&mut self, encrypted: bool) {
+        self.encrypted = encrypted;
+        // the claimed size is of the plaintext, what goes out is bigger
+        self.file_size.set_trustworthiness(self.compression == Compression::None && !self.encrypted);
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_manifest(&mut self, manifest: Vec<ManifestEntry>) {
+        self.manifest = Some(manifest);
+    }
+
+    #[cfg(feature = "server")]
+    pub fn get_manifest(&self) -> Option<&Vec<ManifestEntry>> {
+        self.manifest.as_ref()
+    }
+
+    // the relay can only find the file boundaries in data it can read
+    #[cfg(feature = "server")]
+    pub fn can_split(&self) -> bool {
+        self.manifest.is_some() && self.compression == Compression::None && !self.encrypted
+    }
+
+    // single files are read out of the broadcast spool, without one the first file would use up the whole upload
+    #[cfg(feature = "server")]
+    pub fn has_file_links(&self) -> bool {
+        self.can_split() && self.is_broadcast()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_direct(&mut self, direct: bool) {
+        self.direct = direct;
+    }
+
+    #[cfg(feature = "server")]
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    #[cfg(feature = "server")]
+    pub fn set_text(&mut self, text: bool) {
+        self.text = text;
+    }
+
+    // the page can only show what it can read, so not ciphertext, and not something too big to put on a page
+    #[cfg(feature = "server")]
+    pub fn shows_text(&self) -> bool {
+        self.text && !self.encrypted && self.manifest.is_none()
+            && self.file_size.get_content_length().is_some_and(|size| size <= MAX_TEXT_SIZE)
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
 pub struct FileSize {
     file_size: Option<usize>, // raw file size as reported by beam up, pre-compression
     uploaded_size: usize, // total number of bytes uploaded, will be post-compression. This value is constantly increasing. Since this does streaming, this value may never be complete if the file is over the cache size
@@ -196,21 +620,8 @@ pub struct FileSize {
     // file_size is only sent as header when there is no compression, when upload_complete is true, uploaded_size will be defined as the header
 }
 
-#[cfg(feature = "server")]
+// the client reads these too
 impl FileSize {
-    pub fn new(trusted: bool) -> Self {
-        Self { 
-            file_size: None,
-            uploaded_size: 0,
-            downloaded_size: 0,
-            upload_complete: false,
-            file_size_trustworthy: trusted
-        }
-    }
-    pub fn set_file_size(&mut self, size: usize) {
-        self.file_size = Some(size);
-    }
-
     pub fn get_content_length(&self) -> Option<usize> {
         if self.file_size_trustworthy { // this would happen when there's no compression
             self.file_size
@@ -221,25 +632,36 @@ impl FileSize {
         }
     }
 
-    pub fn increase_upload(&mut self, size: usize) {
-        self.uploaded_size += size;
+    // bytes relayed so far, as (uploaded, downloaded)
+    pub fn get_transferred(&self) -> (usize, usize) {
+        (self.uploaded_size, self.downloaded_size)
     }
+}
 
-    pub fn get_uploaded_size(&self) -> usize {
-        self.uploaded_size
+#[cfg(feature = "server")]
+impl FileSize {
+    pub fn new(trusted: bool) -> Self {
+        Self { 
+            file_size: None,
+            uploaded_size: 0,
+            downloaded_size: 0,
+            upload_complete: false,
+            file_size_trustworthy: trusted
+        }
+    }
+    pub fn set_file_size(&mut self, size: usize) {
+        self.file_size = Some(size);
     }
 
-    pub fn increase_download(&mut self, size: usize) {
-        self.downloaded_size += size;
+    // the live counts are kept in the server state, this copies them in for anything reading the metadata
+    pub fn set_transferred(&mut self, uploaded: usize, downloaded: usize) {
+        self.uploaded_size = uploaded;
+        self.downloaded_size = downloaded;
         if self.downloaded_size > self.uploaded_size {
             warn!("Download progress is larger than upload size. This should not happen {} vs {}", self.downloaded_size, self.uploaded_size);
         }
     }
 
-    pub fn get_download_progress(&self) -> usize {
-        self.downloaded_size
-    }
-
     fn set_trustworthiness(&mut self, trusted: bool) {
         self.file_size_trustworthy = trusted;
     }
@@ -254,6 +676,6 @@ impl FileSize {
                 return format!("{} ({} bytes)", ByteSize(size as u64).to_string_as(true), (size));
             }
         }
-        return format!("Unknown");
+        "Unknown".to_string()
     }
 }
\ No newline at end of file
@@ -1,2 +1,11 @@
 pub mod metadata;
-pub mod compression;
\ No newline at end of file
+pub mod compression;
+pub mod stats;
+pub mod digest;
+pub mod checksum;
+pub mod frames;
+pub mod chunked;
+pub mod status;
+pub mod info;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod bucket; // sleeps with the native tokio timer
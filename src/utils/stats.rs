@@ -0,0 +1,30 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+// what /api/v1/me/stats returns, shared so the client can read it back
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct StatsReport {
+    pub user: String,
+    pub tokens: usize, // authenticated tokens created
+    pub uploads: usize, // completed uploads
+    pub bytes: usize,
+    pub bytes_by_month: BTreeMap<String, usize>,
+    pub average_speed: Option<u64>, // bytes per second over all completed uploads
+    pub top_compression: Option<String>,
+}
+
+// what /admin/stats returns, a snapshot of the whole relay
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct RelayStats {
+    pub tokens: usize, // everything the relay is holding right now
+    pub waiting: usize, // minted but nothing uploaded yet
+    pub active_transfers: usize,
+    pub authenticated: usize,
+    pub users: usize, // authenticated users seen since the relay started
+    pub uploads: usize, // completed by those users
+    pub bytes: usize,
+    pub draining: bool,
+    pub read_only: bool,
+}
@@ -0,0 +1,11 @@
+use serde::{Deserialize, Serialize};
+use super::metadata::FileMetadata;
+
+// what the relay pushes to anyone following a token, one json message each
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StatusUpdate {
+    Status { metadata: Box<FileMetadata> }, // redacted, sent whenever the transfer moves
+    Event { event: serde_json::Value }, // entries from the token log, only for whoever holds the upload key
+    Cancelled { reason: String }, // the token is gone, nothing else will be sent
+}
@@ -0,0 +1,117 @@
+// the browser side of the upload page, built with `wasm-pack build --target web --out-name bytebeam` and served by relays
+// that set web_uploader. It sends pieces the same way upload.js does, but can compress them first with the same encoders as beam up
+use js_sys::{Function, Promise, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{File, Request, RequestInit, Response};
+
+use crate::utils::{chunked::{piece_query, ChunkReceipt, Outbox, CHUNK_SIZE}, compression::{Compression, Encoder}};
+
+// network errors and 5xx are worth another try, anything else is the relay saying no
+const RETRIES: u32 = 5;
+
+fn error(e: impl std::fmt::Display) -> JsValue {
+    JsValue::from_str(&e.to_string())
+}
+
+async fn sleep(ms: i32) {
+    let promise = Promise::new(&mut |resolve, _| {
+        if let Some(window) = web_sys::window() {
+            let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, ms);
+        }
+    });
+    let _ = JsFuture::from(promise).await;
+}
+
+async fn read_slice(file: &File, start: usize, end: usize) -> Result<Vec<u8>, JsValue> {
+    let blob = file.slice_with_f64_and_f64(start as f64, end as f64)?;
+    let buffer = JsFuture::from(blob.array_buffer()).await?;
+    Ok(Uint8Array::new(&buffer).to_vec())
+}
+
+async fn put(url: &str, piece: &[u8]) -> Result<Response, JsValue> {
+    let init = RequestInit::new();
+    init.set_method("PUT");
+    init.set_body(&Uint8Array::from(piece));
+    let request = Request::new_with_str_and_init(url, &init)?;
+    let window = web_sys::window().ok_or_else(|| error("No window to send from"))?;
+    JsFuture::from(window.fetch_with_request(&request)).await?.dyn_into::<Response>()
+}
+
+async fn send_piece(url: &str, piece: &[u8]) -> Result<(u16, String), JsValue> {
+    let mut attempt = 0;
+    loop {
+        match put(url, piece).await {
+            Ok(response) if response.status() < 500 => {
+                let text = JsFuture::from(response.text()?).await?.as_string().unwrap_or_default();
+                return Ok((response.status(), text));
+            },
+            Ok(response) => web_sys::console::warn_1(&format!("Piece failed with {}", response.status()).into()),
+            Err(e) => web_sys::console::warn_2(&"Piece failed".into(), &e),
+        }
+        if attempt >= RETRIES {
+            return Err(error("The upload failed, the relay could not be reached."));
+        }
+        sleep(1000 * 2i32.pow(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Sends `file` to the upload key URL `target` in pieces, compressed with `compression` ("none", "gzip", "deflate",
+/// "br", "lz4", or "zstd"). `progress` is called with how much of the file has been read and its size after every piece.
+/// Resolves to the relay's summary once the last piece is in.
+#[wasm_bindgen]
+pub async fn upload(file: File, target: String, compression: String, progress: Function) -> Result<String, JsValue> {
+    let compression = compression.parse::<Compression>().map_err(error)?;
+    let mut encoder = Encoder::new(&compression).map_err(error)?;
+    let size = file.size() as usize;
+
+    let mut outbox = Outbox::default();
+    let mut read = 0;
+    let mut finished = false; // everything has been read and the encoder has given up its last bytes
+    let mut offset = 0; // what the relay has
+    let mut damaged = 0; // times in a row the relay got the current piece changed
+    loop {
+        while outbox.end() < offset + CHUNK_SIZE && !finished {
+            if read < size {
+                let chunk = read_slice(&file, read, (read + CHUNK_SIZE).min(size)).await?;
+                read += chunk.len();
+                match &mut encoder {
+                    Some(encoder) => outbox.push(&encoder.write(&chunk).map_err(error)?),
+                    None => outbox.push(&chunk),
+                }
+            } else {
+                if let Some(encoder) = encoder.take() {
+                    outbox.push(&encoder.finish().map_err(error)?);
+                }
+                finished = true;
+            }
+        }
+
+        let piece = outbox.piece(offset, CHUNK_SIZE).ok_or_else(|| error("The relay asked for a piece that was already sent"))?;
+        let last = finished && offset + piece.len() == outbox.end();
+        let (status, text) = send_piece(&format!("{}?{}", target, piece_query(offset, size, &compression, last, piece)), piece).await?;
+        let receipt = serde_json::from_str::<ChunkReceipt>(&text).ok();
+        match (status, receipt) {
+            (409, Some(receipt)) => offset = receipt.received, // the relay has a different amount than we thought, carry on from there
+            (422, Some(receipt)) => { // the piece didn't match its crc, none of it was kept so it goes again
+                damaged += 1;
+                if damaged > RETRIES {
+                    return Err(error("The upload failed, pieces keep arriving damaged."));
+                }
+                offset = receipt.received;
+            },
+            (status, _) if !(200..300).contains(&status) => return Err(error(format!("The upload failed: {}", text))),
+            (_, None) => { // the final piece answers with the relay's summary instead
+                progress.call2(&JsValue::NULL, &JsValue::from_f64(size as f64), &JsValue::from_f64(size as f64))?;
+                return Ok(text);
+            },
+            (_, Some(receipt)) => {
+                damaged = 0;
+                offset = receipt.received;
+                outbox.confirm(offset);
+                progress.call2(&JsValue::NULL, &JsValue::from_f64(read as f64), &JsValue::from_f64(size as f64))?;
+            }
+        }
+    }
+}